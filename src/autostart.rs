@@ -0,0 +1,93 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tracing::info;
+use winreg::enums::{HKEY_CURRENT_USER, KEY_QUERY_VALUE, KEY_SET_VALUE};
+use winreg::RegKey;
+
+use crate::MINIMIZED_FLAG;
+
+/// Under HKEY_CURRENT_USER, so no administrator is needed.
+pub const RUN_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\Run";
+pub const VALUE_NAME: &str = "osus-proxy";
+
+/// What Windows starts on login, if it starts us at all.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Registration {
+    pub executable: PathBuf,
+}
+
+impl Registration {
+    /// Whether it's this copy of the proxy that gets started.
+    pub fn is_current(&self) -> bool {
+        std::env::current_exe().is_ok_and(|current| same_file(&current, &self.executable))
+    }
+}
+
+pub fn registration() -> io::Result<Option<Registration>> {
+    let run =
+        match RegKey::predef(HKEY_CURRENT_USER).open_subkey_with_flags(RUN_KEY, KEY_QUERY_VALUE) {
+            Ok(run) => run,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+    let command = match run.get_value::<String, _>(VALUE_NAME) {
+        Ok(command) => command,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(Some(Registration {
+        executable: executable_of(&command),
+    }))
+}
+
+/// Starts this copy of the proxy with Windows, replacing whatever was registered before.
+pub fn enable() -> io::Result<()> {
+    let executable = std::env::current_exe()?;
+    let (run, _) =
+        RegKey::predef(HKEY_CURRENT_USER).create_subkey_with_flags(RUN_KEY, KEY_SET_VALUE)?;
+    // Nobody wants the window popping up on login
+    run.set_value(
+        VALUE_NAME,
+        &format!("\"{}\" {}", executable.display(), MINIMIZED_FLAG),
+    )?;
+    info!("Starting {} with Windows", executable.display());
+    Ok(())
+}
+
+pub fn disable() -> io::Result<()> {
+    let run = match RegKey::predef(HKEY_CURRENT_USER).open_subkey_with_flags(RUN_KEY, KEY_SET_VALUE)
+    {
+        Ok(run) => run,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    match run.delete_value(VALUE_NAME) {
+        Ok(()) => {
+            info!("Not starting with Windows anymore");
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The executable in a command line like `"C:\Games\osus-proxy.exe" --minimized`.
+fn executable_of(command: &str) -> PathBuf {
+    let command = command.trim();
+    let executable = match command.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next().unwrap_or(quoted),
+        None => command.split(' ').next().unwrap_or(command),
+    };
+    PathBuf::from(executable)
+}
+
+/// Paths on Windows are case-insensitive, and either may not exist anymore.
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a
+            .to_string_lossy()
+            .eq_ignore_ascii_case(&b.to_string_lossy()),
+    }
+}
@@ -0,0 +1,2036 @@
+use std::io;
+
+use base64::Engine;
+use bytes::{BufMut, Bytes, BytesMut};
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::{FromPrimitive, ToPrimitive};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter, IntoEnumIterator, IntoStaticStr};
+
+use crate::codec::CodecError;
+use crate::logging::RateLimitedWarning;
+
+/// The protocol version the packet layouts here were written against, servers sending another one
+/// may lay some of them out differently.
+pub const BANCHO_PROTOCOL_VERSION: i32 = 19;
+
+/// Id, an unused byte and the length of the data.
+pub const PACKET_HEADER_LENGTH: usize = 7;
+/// Added to the UTC offset in presences. Wrapping, so bytes outside of -24..=231 still make it
+/// through unchanged.
+const UTC_OFFSET_BIAS: u8 = 24;
+
+/// Keyed by packet id, so a server sending one packet differently doesn't hide problems with others.
+static UNEXPECTED_LAYOUT_WARNING: RateLimitedWarning =
+    RateLimitedWarning::keyed("Packets with an unexpected layout", "packet id");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum PacketDirection {
+    ClientToServer,
+    ServerToClient,
+}
+
+impl std::fmt::Display for PacketDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ClientToServer => f.write_str("C→S"),
+            Self::ServerToClient => f.write_str("S→C"),
+        }
+    }
+}
+
+/// Raw bytes in exports, e.g. the data of packets we don't decode.
+pub fn serialize_base64<S: serde::Serializer>(
+    bytes: &[u8],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(bytes))
+}
+
+/// What bancho calls the packet, for the ones we know of but don't decode too. The two directions
+/// use the same ids for different packets.
+pub fn packet_name(id: u16, direction: PacketDirection) -> Option<&'static str> {
+    let name = match direction {
+        PacketDirection::ClientToServer => match id {
+            0 => "ChangeAction",
+            1 => "SendPublicMessage",
+            2 => "Logout",
+            3 => "RequestStatusUpdate",
+            4 => "Ping",
+            16 => "StartSpectating",
+            17 => "StopSpectating",
+            18 => "SendSpectateFrames",
+            20 => "ErrorReport",
+            21 => "CantSpectate",
+            25 => "SendPrivateMessage",
+            29 => "PartLobby",
+            30 => "JoinLobby",
+            31 => "CreateMatch",
+            32 => "JoinMatch",
+            33 => "PartMatch",
+            38 => "MatchChangeSlot",
+            39 => "MatchReady",
+            40 => "MatchLock",
+            41 => "MatchChangeSettings",
+            44 => "MatchStart",
+            47 => "MatchScoreUpdate",
+            49 => "MatchComplete",
+            51 => "MatchChangeMods",
+            52 => "MatchLoadComplete",
+            54 => "MatchNoBeatmap",
+            55 => "MatchNotReady",
+            56 => "MatchFailed",
+            59 => "MatchHasBeatmap",
+            60 => "MatchSkipRequest",
+            63 => "ChannelJoin",
+            68 => "BeatmapInfoRequest",
+            70 => "MatchTransferHost",
+            73 => "FriendAdd",
+            74 => "FriendRemove",
+            77 => "MatchChangeTeam",
+            78 => "ChannelPart",
+            79 => "ReceiveUpdates",
+            82 => "SetAwayMessage",
+            84 => "IrcOnly",
+            85 => "UserStatsRequest",
+            87 => "MatchInvite",
+            90 => "MatchChangePassword",
+            93 => "TournamentMatchInfoRequest",
+            97 => "UserPresenceRequest",
+            98 => "UserPresenceRequestAll",
+            99 => "ToggleBlockNonFriendDms",
+            108 => "TournamentJoinMatchChannel",
+            109 => "TournamentLeaveMatchChannel",
+            _ => return None,
+        },
+        PacketDirection::ServerToClient => match id {
+            5 => "UserId",
+            7 => "SendMessage",
+            8 => "Pong",
+            9 => "HandleIrcChangeUsername",
+            10 => "HandleIrcQuit",
+            11 => "UserStats",
+            12 => "UserLogout",
+            13 => "SpectatorJoined",
+            14 => "SpectatorLeft",
+            15 => "SpectateFrames",
+            19 => "VersionUpdate",
+            22 => "CantSpectate",
+            23 => "GetAttention",
+            24 => "Notification",
+            26 => "UpdateMatch",
+            27 => "NewMatch",
+            28 => "DisposeMatch",
+            34 => "ToggleBlockNonFriendDms",
+            36 => "MatchJoinSuccess",
+            37 => "MatchJoinFail",
+            42 => "FellowSpectatorJoined",
+            43 => "FellowSpectatorLeft",
+            46 => "AllPlayersLoaded",
+            48 => "MatchStart",
+            50 => "MatchScoreUpdate",
+            52 => "MatchTransferHost",
+            53 => "MatchAllPlayersLoaded",
+            57 => "MatchPlayerFailed",
+            58 => "MatchComplete",
+            61 => "MatchSkip",
+            62 => "Unauthorized",
+            64 => "ChannelJoinSuccess",
+            65 => "ChannelInfo",
+            66 => "ChannelKick",
+            67 => "ChannelAutoJoin",
+            69 => "BeatmapInfoReply",
+            71 => "Privilege",
+            72 => "FriendsList",
+            75 => "ProtocolVersion",
+            76 => "MainMenuIcon",
+            80 => "Monitor",
+            81 => "MatchPlayerSkipped",
+            83 => "UserPresence",
+            86 => "Restart",
+            88 => "MatchInvite",
+            89 => "ChannelInfoEnd",
+            91 => "MatchChangePassword",
+            92 => "SilenceEnd",
+            94 => "UserSilenced",
+            95 => "UserPresenceSingle",
+            96 => "UserPresenceBundle",
+            100 => "UserDmBlocked",
+            101 => "TargetIsSilenced",
+            102 => "VersionUpdateForced",
+            103 => "SwitchServer",
+            104 => "AccountRestricted",
+            105 => "Rtx",
+            106 => "MatchAbort",
+            107 => "SwitchTournamentServer",
+            _ => return None,
+        },
+    };
+    Some(name)
+}
+
+pub struct BanchoPacketHeader {
+    id: u16,
+    #[allow(dead_code)]
+    unknown: u8,
+    length: u32,
+}
+
+impl BanchoPacketHeader {
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+
+    pub fn from_bytes(bytes: [u8; PACKET_HEADER_LENGTH]) -> Self {
+        let id = u16::from_le_bytes([bytes[0], bytes[1]]);
+        let unknown = bytes[2];
+        let length = u32::from_le_bytes([bytes[3], bytes[4], bytes[5], bytes[6]]);
+        Self {
+            id,
+            unknown,
+            length,
+        }
+    }
+}
+
+/// What spectators receive of the player, only the start is parsed and the rest is kept as it came
+/// since these are by far the largest and most frequent packets.
+#[derive(Debug, Clone, Serialize)]
+pub struct SpectateFrameBundle {
+    pub frame_count: u16,
+    pub has_score_frame: bool,
+    /// The whole packet data, shared with the body it was decoded from.
+    #[serde(serialize_with = "serialize_base64")]
+    pub data: Bytes,
+}
+
+impl SpectateFrameBundle {
+    /// Extra (i32), frame count (u16), 14 bytes per frame, the action (u8), then the score frame
+    /// if there is one and a sequence number (u16).
+    const FRAMES_OFFSET: usize = 6;
+    const FRAME_LENGTH: usize = 14;
+    const SEQUENCE_LENGTH: usize = 2;
+
+    fn parse(data: Bytes) -> io::Result<Self> {
+        let too_short = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} bytes is too short for the {}", data.len(), what),
+            )
+        };
+        let frame_count = match data.get(4..Self::FRAMES_OFFSET) {
+            Some(&[low, high]) => u16::from_le_bytes([low, high]),
+            _ => return Err(too_short("frame count")),
+        };
+        let action_offset = Self::FRAMES_OFFSET + frame_count as usize * Self::FRAME_LENGTH;
+        if data.len() <= action_offset {
+            return Err(too_short("frames"));
+        }
+        let has_score_frame = data.len() - action_offset - 1 > Self::SEQUENCE_LENGTH;
+        Ok(Self {
+            frame_count,
+            has_score_frame,
+            data,
+        })
+    }
+}
+
+/// A string as it was on the wire. An empty one can be sent as null (0x00) or as an actual empty
+/// string (0x0b 0x00), and some servers care which, so it's re-encoded the way it came.
+#[derive(Clone, PartialEq, Eq, Default)]
+pub struct OsuString {
+    value: String,
+    null: bool,
+}
+
+impl From<String> for OsuString {
+    /// Empty strings are sent as null, like the game does.
+    fn from(value: String) -> Self {
+        let null = value.is_empty();
+        Self { value, null }
+    }
+}
+
+impl std::ops::Deref for OsuString {
+    type Target = String;
+
+    fn deref(&self) -> &String {
+        &self.value
+    }
+}
+
+impl std::ops::DerefMut for OsuString {
+    fn deref_mut(&mut self) -> &mut String {
+        &mut self.value
+    }
+}
+
+impl std::fmt::Debug for OsuString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.null && self.value.is_empty() {
+            f.write_str("null")
+        } else {
+            std::fmt::Debug::fmt(&self.value, f)
+        }
+    }
+}
+
+impl std::fmt::Display for OsuString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.value)
+    }
+}
+
+impl Serialize for OsuString {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.null && self.value.is_empty() {
+            serializer.serialize_none()
+        } else {
+            serializer.serialize_str(&self.value)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OsuMessage {
+    pub sender: OsuString,
+    pub text: OsuString,
+    pub recipient: OsuString,
+    pub sender_id: i32,
+}
+
+impl OsuMessage {
+    /// Sent to a user rather than a channel.
+    pub fn is_private(&self) -> bool {
+        !self.recipient.starts_with('#')
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OsuChannel {
+    pub name: OsuString,
+    pub topic: OsuString,
+    pub player_count: u16,
+}
+
+/// What a string field handed out by [`BanchoPacket::visit_links_mut`] holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkField {
+    /// Free text with any number of links somewhere in it.
+    Text,
+    /// A single URL and nothing else.
+    Url,
+}
+
+pub trait OsuReader {
+    fn read_uleb128(&mut self) -> io::Result<u64>;
+    fn read_osu_string(&mut self) -> io::Result<OsuString>;
+    fn read_osu_message(&mut self) -> io::Result<OsuMessage>;
+    fn read_osu_channel(&mut self) -> io::Result<OsuChannel>;
+    fn read_i32_list(&mut self) -> io::Result<Vec<i32>>;
+}
+
+pub trait OsuWriter {
+    fn write_uleb128(&mut self, value: u64);
+    fn write_osu_string(&mut self, value: &OsuString);
+    fn write_osu_message(&mut self, value: &OsuMessage);
+    fn write_osu_channel(&mut self, value: &OsuChannel);
+    fn write_i32_list(&mut self, value: &[i32]);
+}
+
+/// Reads the fields of a packet off its data, slicing instead of copying where it can. Unlike
+/// `Buf`, running out of data is an error rather than a panic, as the length comes from the server.
+pub struct PacketReader {
+    data: Bytes,
+    position: usize,
+}
+
+impl PacketReader {
+    pub fn new(data: Bytes) -> Self {
+        Self { data, position: 0 }
+    }
+
+    /// How many bytes were read so far.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    pub fn read_bytes(&mut self, length: usize) -> io::Result<Bytes> {
+        self.check_remaining(length)?;
+        let bytes = self.data.slice(self.position..self.position + length);
+        self.position += length;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    pub fn read_u16(&mut self) -> io::Result<u16> {
+        self.read_array().map(u16::from_le_bytes)
+    }
+
+    pub fn read_i16(&mut self) -> io::Result<i16> {
+        self.read_array().map(i16::from_le_bytes)
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        self.read_array().map(u32::from_le_bytes)
+    }
+
+    pub fn read_i32(&mut self) -> io::Result<i32> {
+        self.read_array().map(i32::from_le_bytes)
+    }
+
+    pub fn read_i64(&mut self) -> io::Result<i64> {
+        self.read_array().map(i64::from_le_bytes)
+    }
+
+    pub fn read_f32(&mut self) -> io::Result<f32> {
+        self.read_array().map(f32::from_le_bytes)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        self.check_remaining(N)?;
+        let mut array = [0; N];
+        array.copy_from_slice(&self.data[self.position..self.position + N]);
+        self.position += N;
+        Ok(array)
+    }
+
+    fn check_remaining(&self, length: usize) -> io::Result<()> {
+        if length > self.remaining() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "needed {} bytes at byte {} of the packet but only {} are left",
+                    length,
+                    self.position,
+                    self.remaining()
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+const LEB128_HIGH_ORDER_BIT: u8 = 1 << 7;
+
+impl OsuReader for PacketReader {
+    fn read_uleb128(&mut self) -> io::Result<u64> {
+        let mut result = 0;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.read_u8()?;
+
+            if shift == 63 && byte > 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "integer overflow when reading ULEB128",
+                ));
+            }
+
+            result |= u64::from(byte & !LEB128_HIGH_ORDER_BIT) << shift;
+
+            if byte & LEB128_HIGH_ORDER_BIT == 0 {
+                return Ok(result);
+            }
+
+            shift += 7;
+        }
+    }
+
+    fn read_osu_string(&mut self) -> io::Result<OsuString> {
+        let exists = match self.read_u8()? {
+            0x00 => false,
+            0x0b => true,
+            // Anything else means we're already out of step with the stream
+            prefix => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "unexpected string prefix {:#04x} at byte {} of the packet",
+                        prefix,
+                        self.position - 1
+                    ),
+                ))
+            }
+        };
+
+        if !exists {
+            return Ok(OsuString {
+                value: String::new(),
+                null: true,
+            });
+        }
+
+        let str_length = self.read_uleb128()?;
+        // Don't let a corrupt length allocate more than there is to read
+        let remaining = self.remaining();
+        if str_length > remaining as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "string of {} bytes with only {} left",
+                    str_length, remaining
+                ),
+            ));
+        }
+
+        match String::from_utf8(self.read_bytes(str_length as usize)?.to_vec()) {
+            Ok(value) => Ok(OsuString { value, null: false }),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+
+    fn read_osu_message(&mut self) -> io::Result<OsuMessage> {
+        let sender = self.read_osu_string()?;
+        let text = self.read_osu_string()?;
+        let recipient = self.read_osu_string()?;
+        let sender_id = self.read_i32()?;
+        Ok(OsuMessage {
+            sender,
+            text,
+            recipient,
+            sender_id,
+        })
+    }
+
+    fn read_osu_channel(&mut self) -> io::Result<OsuChannel> {
+        let name = self.read_osu_string()?;
+        let topic = self.read_osu_string()?;
+        let player_count = self.read_u16()?;
+        Ok(OsuChannel {
+            name,
+            topic,
+            player_count,
+        })
+    }
+
+    fn read_i32_list(&mut self) -> io::Result<Vec<i32>> {
+        let length = self.read_i16()?;
+        let remaining = self.remaining();
+        if length < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("negative list length {}", length),
+            ));
+        } else if length as usize * 4 > remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "list of {} ids but only {} bytes are left",
+                    length, remaining
+                ),
+            ));
+        }
+        (0..length).map(|_| self.read_i32()).collect()
+    }
+}
+
+impl OsuWriter for BytesMut {
+    fn write_uleb128(&mut self, mut value: u64) {
+        loop {
+            let mut byte = (value as u8) & !LEB128_HIGH_ORDER_BIT;
+            value >>= 7;
+
+            if value != 0 {
+                byte |= LEB128_HIGH_ORDER_BIT;
+            }
+
+            self.put_u8(byte);
+
+            if value == 0 {
+                return;
+            }
+        }
+    }
+
+    fn write_osu_string(&mut self, value: &OsuString) {
+        let exists = !(value.null && value.is_empty());
+        if !exists {
+            self.put_u8(0x00);
+        } else {
+            self.put_u8(0x0b);
+            let bytes = value.as_bytes();
+            self.write_uleb128(bytes.len() as u64);
+            self.put_slice(bytes);
+        }
+    }
+
+    fn write_osu_message(&mut self, value: &OsuMessage) {
+        self.write_osu_string(&value.sender);
+        self.write_osu_string(&value.text);
+        self.write_osu_string(&value.recipient);
+        self.put_i32_le(value.sender_id);
+    }
+
+    fn write_osu_channel(&mut self, value: &OsuChannel) {
+        self.write_osu_string(&value.name);
+        self.write_osu_string(&value.topic);
+        self.put_u16_le(value.player_count);
+    }
+
+    fn write_i32_list(&mut self, value: &[i32]) {
+        self.put_i16_le(value.len() as i16);
+        for x in value {
+            self.put_i32_le(*x);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameMode {
+    Osu,
+    Taiko,
+    Catch,
+    Mania,
+    /// Kept as is so it's re-encoded to the same byte.
+    Unknown(u8),
+}
+
+impl GameMode {
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            Self::Osu => 0,
+            Self::Taiko => 1,
+            Self::Catch => 2,
+            Self::Mania => 3,
+            Self::Unknown(repr) => *repr,
+        }
+    }
+
+    pub fn from_u8(repr: u8) -> Self {
+        match repr {
+            0 => Self::Osu,
+            1 => Self::Taiko,
+            2 => Self::Catch,
+            3 => Self::Mania,
+            _ => Self::Unknown(repr),
+        }
+    }
+}
+
+impl std::fmt::Display for GameMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unknown(repr) => write!(f, "Mode {}", repr),
+            mode => write!(f, "{:?}", mode),
+        }
+    }
+}
+
+/// As the byte the game sends.
+impl Serialize for GameMode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.as_u8())
+    }
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Mods: u32 {
+        const NO_FAIL = 1 << 0;
+        const EASY = 1 << 1;
+        const TOUCH_DEVICE = 1 << 2;
+        const HIDDEN = 1 << 3;
+        const HARD_ROCK = 1 << 4;
+        const SUDDEN_DEATH = 1 << 5;
+        const DOUBLE_TIME = 1 << 6;
+        const RELAX = 1 << 7;
+        const HALF_TIME = 1 << 8;
+        /// Always comes with `DOUBLE_TIME`.
+        const NIGHTCORE = 1 << 9;
+        const FLASHLIGHT = 1 << 10;
+        const AUTOPLAY = 1 << 11;
+        const SPUN_OUT = 1 << 12;
+        const AUTOPILOT = 1 << 13;
+        /// Always comes with `SUDDEN_DEATH`.
+        const PERFECT = 1 << 14;
+        const KEY_4 = 1 << 15;
+        const KEY_5 = 1 << 16;
+        const KEY_6 = 1 << 17;
+        const KEY_7 = 1 << 18;
+        const KEY_8 = 1 << 19;
+        const FADE_IN = 1 << 20;
+        const RANDOM = 1 << 21;
+        const CINEMA = 1 << 22;
+        const TARGET = 1 << 23;
+        const KEY_9 = 1 << 24;
+        const KEY_COOP = 1 << 25;
+        const KEY_1 = 1 << 26;
+        const KEY_3 = 1 << 27;
+        const KEY_2 = 1 << 28;
+        const SCORE_V2 = 1 << 29;
+        const MIRROR = 1 << 30;
+
+        // Reserved and unknown bits are kept, so the packets are re-encoded exactly as they came
+        const _ = !0;
+    }
+}
+
+/// In the order the game shows them, combined mods before the ones they imply.
+const MOD_ACRONYMS: &[(Mods, &str)] = &[
+    (Mods::NO_FAIL, "NF"),
+    (Mods::EASY, "EZ"),
+    (Mods::TOUCH_DEVICE, "TD"),
+    (Mods::HIDDEN, "HD"),
+    (Mods::FADE_IN, "FI"),
+    (Mods::HARD_ROCK, "HR"),
+    (Mods::PERFECT.union(Mods::SUDDEN_DEATH), "PF"),
+    (Mods::SUDDEN_DEATH, "SD"),
+    (Mods::NIGHTCORE.union(Mods::DOUBLE_TIME), "NC"),
+    (Mods::DOUBLE_TIME, "DT"),
+    (Mods::HALF_TIME, "HT"),
+    (Mods::FLASHLIGHT, "FL"),
+    (Mods::RELAX, "RX"),
+    (Mods::AUTOPILOT, "AP"),
+    (Mods::SPUN_OUT, "SO"),
+    (Mods::AUTOPLAY, "AT"),
+    (Mods::CINEMA, "CN"),
+    (Mods::TARGET, "TP"),
+    (Mods::KEY_1, "1K"),
+    (Mods::KEY_2, "2K"),
+    (Mods::KEY_3, "3K"),
+    (Mods::KEY_4, "4K"),
+    (Mods::KEY_5, "5K"),
+    (Mods::KEY_6, "6K"),
+    (Mods::KEY_7, "7K"),
+    (Mods::KEY_8, "8K"),
+    (Mods::KEY_9, "9K"),
+    (Mods::KEY_COOP, "CO"),
+    (Mods::RANDOM, "RD"),
+    (Mods::MIRROR, "MR"),
+    (Mods::SCORE_V2, "V2"),
+];
+
+impl std::fmt::Display for Mods {
+    /// `+HDDT` style, `NM` without mods. Unknown bits are shown in hex.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return f.write_str("NM");
+        }
+
+        f.write_str("+")?;
+        let mut remaining = *self;
+        for (mods, acronym) in MOD_ACRONYMS {
+            if remaining.contains(*mods) {
+                f.write_str(acronym)?;
+                remaining.remove(*mods);
+            }
+        }
+        if !remaining.is_empty() {
+            write!(f, "({:#x})", remaining.bits())?;
+        }
+        Ok(())
+    }
+}
+
+/// As the raw bits, like the game sends them.
+impl Serialize for Mods {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(self.bits())
+    }
+}
+
+bitflags::bitflags! {
+    /// What the server lets the logged-in user do, from the Privilege packet.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct BanchoPrivileges: u32 {
+        const PLAYER = 1 << 0;
+        const MODERATOR = 1 << 1;
+        const SUPPORTER = 1 << 2;
+        const OWNER = 1 << 3;
+        const DEVELOPER = 1 << 4;
+        const TOURNAMENT_STAFF = 1 << 5;
+
+        // Kept as they came, like the unknown bits of `Mods`
+        const _ = !0;
+    }
+}
+
+impl std::fmt::Display for BanchoPrivileges {
+    /// The known privileges by name, unknown bits in hex.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names = vec![];
+        let mut unknown = self.bits();
+        for (name, flag) in self.iter_names() {
+            names.push(name.to_lowercase());
+            unknown &= !flag.bits();
+        }
+        if unknown != 0 {
+            names.push(format!("{:#x}", unknown));
+        }
+        if names.is_empty() {
+            return f.write_str("none");
+        }
+        f.write_str(&names.join(", "))
+    }
+}
+
+impl Serialize for BanchoPrivileges {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(self.bits())
+    }
+}
+
+impl std::fmt::Display for BanchoPacket {
+    /// The variant and id, e.g. `UserStats(id=11)`. The length of the data is only known without
+    /// encoding the packet again where it was decoded, it's logged there.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name: &'static str = self.into();
+        write!(f, "{}(id={})", name, self.id())
+    }
+}
+
+/// Why the server rejected a login, sent as a negative `UserId`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoginFailure {
+    WrongCredentials,
+    OldClient,
+    Banned,
+    ServerError,
+    SupporterOnly,
+    NeedsVerification,
+    Other(i32),
+}
+
+impl LoginFailure {
+    /// `None` for actual user ids.
+    pub fn from_user_id(user_id: i32) -> Option<Self> {
+        match user_id {
+            1.. => None,
+            -1 => Some(Self::WrongCredentials),
+            -2 => Some(Self::OldClient),
+            -3 => Some(Self::Banned),
+            -5 => Some(Self::ServerError),
+            -6 => Some(Self::SupporterOnly),
+            -8 => Some(Self::NeedsVerification),
+            _ => Some(Self::Other(user_id)),
+        }
+    }
+}
+
+impl std::fmt::Display for LoginFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::WrongCredentials => f.write_str("wrong username or password"),
+            Self::OldClient => f.write_str("the game is too old, update it"),
+            Self::Banned => f.write_str("banned"),
+            Self::ServerError => f.write_str("the server ran into an error"),
+            Self::SupporterOnly => f.write_str("this build of the game needs osu!supporter"),
+            Self::NeedsVerification => {
+                f.write_str("the account needs to be verified on the website")
+            }
+            Self::Other(user_id) => write!(f, "unknown reason ({})", user_id),
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, PartialEq, FromPrimitive, ToPrimitive, Serialize)]
+pub enum UserAction {
+    Idle = 0,
+    Afk = 1,
+    Playing = 2,
+    Editing = 3,
+    Modding = 4,
+    Multiplayer = 5,
+    Watching = 6,
+    Unknown = 7,
+    Testing = 8,
+    Submitting = 9,
+    Paused = 10,
+    Lobby = 11,
+    Multiplaying = 12,
+    OsuDirect = 13,
+}
+
+impl UserAction {
+    pub fn as_u8(&self) -> u8 {
+        ToPrimitive::to_u8(self).expect("How do we even have a self of this...")
+    }
+
+    pub fn from_u8(repr: u8) -> Self {
+        FromPrimitive::from_u8(repr).unwrap_or(Self::Unknown)
+    }
+}
+
+#[repr(u8)]
+#[derive(
+    Debug,
+    PartialEq,
+    Clone,
+    Display,
+    FromPrimitive,
+    ToPrimitive,
+    EnumIter,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+)]
+pub enum Country {
+    Unknown = 0,
+    UnitedArabEmirates = 4,
+    Argentina = 13,
+    Austria = 15,
+    Australia = 16,
+    Azerbaijan = 18,
+    Barbados = 20,
+    Bangladesh = 21,
+    Belgium = 22,
+    Bulgaria = 24,
+    Bahrain = 25,
+    Brunei = 29,
+    Brazil = 31,
+    Bhutan = 33,
+    Botswana = 35,
+    Belarus = 36,
+    Canada = 38,
+    Switzerland = 43,
+    CoteDIvoire = 44,
+    Chile = 46,
+    China = 48,
+    Colombia = 49,
+    CostaRica = 50,
+    Cuba = 51,
+    Cyprus = 54,
+    Czechia = 55,
+    Germany = 56,
+    Djibouti = 57,
+    Denmark = 58,
+    Algeria = 61,
+    Ecuador = 62,
+    Estonia = 63,
+    Egypt = 64,
+    Spain = 67,
+    Ethiopia = 68,
+    Finland = 69,
+    Fiji = 70,
+    France = 74,
+    Gabon = 76,
+    UnitedKingdom = 77,
+    Ghana = 81,
+    Greece = 88,
+    Guam = 91,
+    HongKong = 94,
+    Honduras = 96,
+    Croatia = 97,
+    Hungary = 99,
+    Indonesia = 100,
+    Ireland = 101,
+    Israel = 102,
+    India = 103,
+    Iraq = 105,
+    Iran = 106,
+    Iceland = 107,
+    Italy = 108,
+    Jamaica = 109,
+    Jordan = 110,
+    Japan = 111,
+    Kenya = 112,
+    Cambodia = 114,
+    SouthKorea = 119,
+    Kuwait = 120,
+    Liechtenstein = 126,
+    SriLanka = 127,
+    Lithuania = 130,
+    Luxembourg = 131,
+    Latvia = 132,
+    Morocco = 134,
+    Monaco = 135,
+    Madagascar = 137,
+    NorthMacedonia = 139,
+    Myanmar = 141,
+    Mongolia = 142,
+    Malta = 148,
+    Mauritius = 149,
+    Maldives = 150,
+    Mexico = 152,
+    Malaysia = 153,
+    NewCaledonia = 156,
+    Nigeria = 159,
+    Netherlands = 161,
+    Norway = 162,
+    Nepal = 163,
+    NewZealand = 166,
+    Oman = 167,
+    Panama = 168,
+    Peru = 169,
+    PapuaNewGuinea = 171,
+    Philippines = 172,
+    Pakistan = 173,
+    Poland = 174,
+    Portugal = 179,
+    Paraguay = 181,
+    Qatar = 182,
+    Romania = 184,
+    RussianFederation = 185,
+    SaudiArabia = 187,
+    Sudan = 190,
+    Sweden = 191,
+    Singapore = 192,
+    Slovenia = 194,
+    Slovakia = 196,
+    SierraLeone = 197,
+    Senegal = 199,
+    ElSalvador = 203,
+    SyrianArabRepublic = 204,
+    Togo = 209,
+    Thailand = 210,
+    Tunisia = 214,
+    Turkey = 217,
+    TrinidadAndTobago = 218,
+    Taiwan = 220,
+    Tanzania = 221,
+    Ukraine = 222,
+    UnitedStates = 225,
+    Uruguay = 226,
+    Venezuela = 230,
+    Vietnam = 233,
+    SouthAfrica = 240,
+    Zimbabwe = 243,
+}
+
+impl Country {
+    pub fn as_u8(&self) -> u8 {
+        ToPrimitive::to_u8(self).expect("How do we even have a self of this...")
+    }
+
+    pub fn from_u8(repr: u8) -> Self {
+        FromPrimitive::from_u8(repr).unwrap_or(Self::Unknown)
+    }
+
+    /// ISO 3166-1 alpha-2, `XX` for [`Self::Unknown`].
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Unknown => "XX",
+            Self::UnitedArabEmirates => "AE",
+            Self::Argentina => "AR",
+            Self::Austria => "AT",
+            Self::Australia => "AU",
+            Self::Azerbaijan => "AZ",
+            Self::Barbados => "BB",
+            Self::Bangladesh => "BD",
+            Self::Belgium => "BE",
+            Self::Bulgaria => "BG",
+            Self::Bahrain => "BH",
+            Self::Brunei => "BN",
+            Self::Brazil => "BR",
+            Self::Bhutan => "BT",
+            Self::Botswana => "BW",
+            Self::Belarus => "BY",
+            Self::Canada => "CA",
+            Self::Switzerland => "CH",
+            Self::CoteDIvoire => "CI",
+            Self::Chile => "CL",
+            Self::China => "CN",
+            Self::Colombia => "CO",
+            Self::CostaRica => "CR",
+            Self::Cuba => "CU",
+            Self::Cyprus => "CY",
+            Self::Czechia => "CZ",
+            Self::Germany => "DE",
+            Self::Djibouti => "DJ",
+            Self::Denmark => "DK",
+            Self::Algeria => "DZ",
+            Self::Ecuador => "EC",
+            Self::Estonia => "EE",
+            Self::Egypt => "EG",
+            Self::Spain => "ES",
+            Self::Ethiopia => "ET",
+            Self::Finland => "FI",
+            Self::Fiji => "FJ",
+            Self::France => "FR",
+            Self::Gabon => "GA",
+            Self::UnitedKingdom => "GB",
+            Self::Ghana => "GH",
+            Self::Greece => "GR",
+            Self::Guam => "GU",
+            Self::HongKong => "HK",
+            Self::Honduras => "HN",
+            Self::Croatia => "HR",
+            Self::Hungary => "HU",
+            Self::Indonesia => "ID",
+            Self::Ireland => "IE",
+            Self::Israel => "IL",
+            Self::India => "IN",
+            Self::Iraq => "IQ",
+            Self::Iran => "IR",
+            Self::Iceland => "IS",
+            Self::Italy => "IT",
+            Self::Jamaica => "JM",
+            Self::Jordan => "JO",
+            Self::Japan => "JP",
+            Self::Kenya => "KE",
+            Self::Cambodia => "KH",
+            Self::SouthKorea => "KR",
+            Self::Kuwait => "KW",
+            Self::Liechtenstein => "LI",
+            Self::SriLanka => "LK",
+            Self::Lithuania => "LT",
+            Self::Luxembourg => "LU",
+            Self::Latvia => "LV",
+            Self::Morocco => "MA",
+            Self::Monaco => "MC",
+            Self::Madagascar => "MG",
+            Self::NorthMacedonia => "MK",
+            Self::Myanmar => "MM",
+            Self::Mongolia => "MN",
+            Self::Malta => "MT",
+            Self::Mauritius => "MU",
+            Self::Maldives => "MV",
+            Self::Mexico => "MX",
+            Self::Malaysia => "MY",
+            Self::NewCaledonia => "NC",
+            Self::Nigeria => "NG",
+            Self::Netherlands => "NL",
+            Self::Norway => "NO",
+            Self::Nepal => "NP",
+            Self::NewZealand => "NZ",
+            Self::Oman => "OM",
+            Self::Panama => "PA",
+            Self::Peru => "PE",
+            Self::PapuaNewGuinea => "PG",
+            Self::Philippines => "PH",
+            Self::Pakistan => "PK",
+            Self::Poland => "PL",
+            Self::Portugal => "PT",
+            Self::Paraguay => "PY",
+            Self::Qatar => "QA",
+            Self::Romania => "RO",
+            Self::RussianFederation => "RU",
+            Self::SaudiArabia => "SA",
+            Self::Sudan => "SD",
+            Self::Sweden => "SE",
+            Self::Singapore => "SG",
+            Self::Slovenia => "SI",
+            Self::Slovakia => "SK",
+            Self::SierraLeone => "SL",
+            Self::Senegal => "SN",
+            Self::ElSalvador => "SV",
+            Self::SyrianArabRepublic => "SY",
+            Self::Togo => "TG",
+            Self::Thailand => "TH",
+            Self::Tunisia => "TN",
+            Self::Turkey => "TR",
+            Self::TrinidadAndTobago => "TT",
+            Self::Taiwan => "TW",
+            Self::Tanzania => "TZ",
+            Self::Ukraine => "UA",
+            Self::UnitedStates => "US",
+            Self::Uruguay => "UY",
+            Self::Venezuela => "VE",
+            Self::Vietnam => "VN",
+            Self::SouthAfrica => "ZA",
+            Self::Zimbabwe => "ZW",
+        }
+    }
+
+    /// Case-insensitive, e.g. `jp`.
+    pub fn from_code(code: &str) -> Option<Self> {
+        Self::iter()
+            .find(|country| *country != Self::Unknown && country.code().eq_ignore_ascii_case(code))
+    }
+}
+
+#[repr(u16)]
+#[derive(Debug, Serialize, IntoStaticStr)]
+#[serde(tag = "name", content = "fields")]
+pub enum BanchoPacket {
+    ChangeAction {
+        action: UserAction,
+        info_text: OsuString,
+        map_md5: OsuString,
+        mods: Mods,
+        mode: GameMode,
+        map_id: i32,
+    } = 0,
+    SendPublicMessage(OsuMessage) = 1,
+    /// Sent whenever the client has nothing else to send, keeps the session alive.
+    Ping = 4,
+    UserId(i32) = 5,
+    SendMessage(OsuMessage) = 7,
+    UserStats {
+        user_id: i32,
+        action: UserAction,
+        info_text: OsuString,
+        map_md5: OsuString,
+        mods: Mods,
+        mode: GameMode,
+        map_id: i32,
+        ranked_score: i64,
+        /// 0 to 1
+        accuracy: f32,
+        playcount: i32,
+        total_score: i64,
+        global_rank: i32,
+        pp: i16,
+    } = 11,
+    UserLogout {
+        user_id: i32,
+        /// Always 0 from bancho.
+        quit_state: u8,
+    } = 12,
+    /// Frames of the player we're watching.
+    SpectateFrames(SpectateFrameBundle) = 15,
+    /// Our frames, for whoever is watching.
+    SendSpectateFrames(SpectateFrameBundle) = 18,
+    /// Someone started watching us.
+    SpectatorJoined {
+        user_id: i32,
+    } = 13,
+    SpectatorLeft {
+        user_id: i32,
+    } = 14,
+    /// A spectator doesn't have the map.
+    CantSpectate {
+        user_id: i32,
+    } = 22,
+    Notification(OsuString) = 24,
+    SendPrivateMessage(OsuMessage) = 25,
+    /// The name of a channel the client joined.
+    ChannelJoinSuccess(OsuString) = 64,
+    ChannelInfo(OsuChannel) = 65,
+    /// The name of a channel the client was removed from.
+    ChannelKick(OsuString) = 66,
+    /// Same as `ChannelInfo`, for channels the client should join right away.
+    ChannelAutoJoin(OsuChannel) = 67,
+    /// The user ids of our friends, online or not.
+    FriendsList(Vec<i32>) = 72,
+    /// Someone else started watching the player we're watching.
+    FellowSpectatorJoined {
+        user_id: i32,
+    } = 42,
+    FellowSpectatorLeft {
+        user_id: i32,
+    } = 43,
+    Privilege {
+        privileges: BanchoPrivileges,
+    } = 71,
+    ProtocolVersion(i32) = 75,
+    /// Sent as `image_url|click_url`, or an empty string for no icon.
+    MainMenuIcon {
+        image_url: OsuString,
+        /// `None` when there was no `|`.
+        click_url: Option<OsuString>,
+    } = 76,
+    UserPresence {
+        user_id: i32,
+        name: OsuString,
+        /// Hours from UTC, sent offset by 24 so it fits in a byte.
+        utc_offset: i8,
+        country_code: u8,
+        bancho_privileges: u8,
+        longitude: f32,
+        latitude: f32,
+        global_rank: i32,
+    } = 83,
+    UserStatsRequest(Vec<i32>) = 85,
+    /// The server is about to go down, the client reconnects after `ms`.
+    Restart {
+        ms: i32,
+    } = 86,
+    /// How long we're still silenced for, 0 when the silence is lifted.
+    SilenceEnd {
+        seconds: i32,
+    } = 92,
+    /// Someone else was silenced, the client hides their messages.
+    UserSilenced {
+        user_id: i32,
+    } = 94,
+    /// The user ids of everyone online.
+    UserPresenceBundle(Vec<i32>) = 96,
+    UserPresenceRequest(Vec<i32>) = 97,
+    /// Asks for the presence of everyone online, sent by clients on the user panel screen.
+    UserPresenceRequestAll {
+        ingame_time: i32,
+    } = 98,
+    /// The bancho host a tournament client should connect to instead.
+    SwitchTournamentServer(OsuString) = 107,
+    Other {
+        id: u16,
+        /// Shared with the body it was decoded from.
+        #[serde(serialize_with = "serialize_base64")]
+        data: Bytes,
+    } = u16::MAX,
+}
+
+impl BanchoPacket {
+    /// Reads exactly the `header.length` bytes of the packet. Known packets that don't decode to
+    /// exactly that length are kept as `Other`, some private servers send packets with the same id
+    /// but a different layout, and reading past them would corrupt the rest of the stream.
+    /// Packets sent in the other `direction` than the one they're known for are kept as `Other` too.
+    /// Warnings give the offset of the packet's data in the body, to find it in a capture.
+    /// `data` is exactly the packet data, `offset` where it starts in the body.
+    pub fn from_header_and_bytes(
+        header: &BanchoPacketHeader,
+        data: Bytes,
+        offset: usize,
+        direction: PacketDirection,
+    ) -> Self {
+        if Self::sent_in(header.id) != Some(direction) {
+            return Self::Other {
+                id: header.id,
+                data,
+            };
+        }
+
+        let decoded = match header.id {
+            15 => SpectateFrameBundle::parse(data.clone()).map(Self::SpectateFrames),
+            18 => SpectateFrameBundle::parse(data.clone()).map(Self::SendSpectateFrames),
+            _ => {
+                let mut reader = PacketReader::new(data.clone());
+                match Self::decode_fields(header.id, &mut reader) {
+                    Ok(packet) if reader.remaining() == 0 => Ok(packet),
+                    Ok(_) => Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "it is {} bytes long but its fields take {}",
+                            data.len(),
+                            reader.position()
+                        ),
+                    )),
+                    Err(e) => Err(e),
+                }
+            }
+        };
+        decoded.unwrap_or_else(|e| {
+            UNEXPECTED_LAYOUT_WARNING.warn_for(header.id.into(), || {
+                format!(
+                    "Failed to decode packet {} at byte {}, keeping it as is: {}",
+                    header.id, offset, e
+                )
+            });
+            Self::Other {
+                id: header.id,
+                data,
+            }
+        })
+    }
+
+    /// The direction the packets we decode are sent in, the same id means something else (or
+    /// nothing) the other way.
+    fn sent_in(id: u16) -> Option<PacketDirection> {
+        match id {
+            0 | 1 | 4 | 18 | 25 | 85 | 97 | 98 => Some(PacketDirection::ClientToServer),
+            5 | 7 | 11 | 12 | 13 | 14 | 15 | 22 | 24 | 42 | 43 | 64 | 65 | 66 | 67 | 71 | 72
+            | 75 | 76 | 83 | 86 | 92 | 94 | 96 | 107 => Some(PacketDirection::ServerToClient),
+            _ => None,
+        }
+    }
+
+    /// Unknown actions would be re-encoded as `Unknown`, so they fail the packet to keep it as it is.
+    fn read_user_action(bytebuf: &mut PacketReader) -> io::Result<UserAction> {
+        let raw_action = bytebuf.read_u8()?;
+        let action = UserAction::from_u8(raw_action);
+        if action.as_u8() != raw_action {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown action {}", raw_action),
+            ));
+        }
+        Ok(action)
+    }
+
+    fn decode_fields(id: u16, bytebuf: &mut PacketReader) -> io::Result<Self> {
+        match id {
+            0 => {
+                let action = Self::read_user_action(bytebuf)?;
+                let info_text = bytebuf.read_osu_string()?;
+                let map_md5 = bytebuf.read_osu_string()?;
+                let mods = Mods::from_bits_truncate(bytebuf.read_u32()?);
+                let mode = GameMode::from_u8(bytebuf.read_u8()?);
+                let map_id = bytebuf.read_i32()?;
+                Ok(Self::ChangeAction {
+                    action,
+                    info_text,
+                    map_md5,
+                    mods,
+                    mode,
+                    map_id,
+                })
+            }
+            1 => {
+                let message = bytebuf.read_osu_message()?;
+                Ok(Self::SendPublicMessage(message))
+            }
+            4 => Ok(Self::Ping),
+            5 => {
+                let user_id = bytebuf.read_i32()?;
+                Ok(Self::UserId(user_id))
+            }
+            7 => {
+                let message = bytebuf.read_osu_message()?;
+                Ok(Self::SendMessage(message))
+            }
+            11 => {
+                let user_id = bytebuf.read_i32()?;
+                let action = Self::read_user_action(bytebuf)?;
+                let info_text = bytebuf.read_osu_string()?;
+                let map_md5 = bytebuf.read_osu_string()?;
+                let mods = Mods::from_bits_truncate(bytebuf.read_u32()?);
+                let mode = GameMode::from_u8(bytebuf.read_u8()?);
+                let map_id = bytebuf.read_i32()?;
+                let ranked_score = bytebuf.read_i64()?;
+                let accuracy = bytebuf.read_f32()?;
+                let playcount = bytebuf.read_i32()?;
+                let total_score = bytebuf.read_i64()?;
+                let global_rank = bytebuf.read_i32()?;
+                let pp = bytebuf.read_i16()?;
+                Ok(Self::UserStats {
+                    user_id,
+                    action,
+                    info_text,
+                    map_md5,
+                    mods,
+                    mode,
+                    map_id,
+                    ranked_score,
+                    accuracy,
+                    playcount,
+                    total_score,
+                    global_rank,
+                    pp,
+                })
+            }
+            12 => {
+                let user_id = bytebuf.read_i32()?;
+                let quit_state = bytebuf.read_u8()?;
+                Ok(Self::UserLogout {
+                    user_id,
+                    quit_state,
+                })
+            }
+            13 => {
+                let user_id = bytebuf.read_i32()?;
+                Ok(Self::SpectatorJoined { user_id })
+            }
+            14 => {
+                let user_id = bytebuf.read_i32()?;
+                Ok(Self::SpectatorLeft { user_id })
+            }
+            22 => {
+                let user_id = bytebuf.read_i32()?;
+                Ok(Self::CantSpectate { user_id })
+            }
+            24 => {
+                let text = bytebuf.read_osu_string()?;
+                Ok(Self::Notification(text))
+            }
+            25 => {
+                let message = bytebuf.read_osu_message()?;
+                Ok(Self::SendPrivateMessage(message))
+            }
+            42 => {
+                let user_id = bytebuf.read_i32()?;
+                Ok(Self::FellowSpectatorJoined { user_id })
+            }
+            43 => {
+                let user_id = bytebuf.read_i32()?;
+                Ok(Self::FellowSpectatorLeft { user_id })
+            }
+            64 => {
+                let name = bytebuf.read_osu_string()?;
+                Ok(Self::ChannelJoinSuccess(name))
+            }
+            65 => {
+                let channel = bytebuf.read_osu_channel()?;
+                Ok(Self::ChannelInfo(channel))
+            }
+            66 => {
+                let name = bytebuf.read_osu_string()?;
+                Ok(Self::ChannelKick(name))
+            }
+            67 => {
+                let channel = bytebuf.read_osu_channel()?;
+                Ok(Self::ChannelAutoJoin(channel))
+            }
+            71 => {
+                let privileges = BanchoPrivileges::from_bits_truncate(bytebuf.read_u32()?);
+                Ok(Self::Privilege { privileges })
+            }
+            72 => {
+                let user_ids = bytebuf.read_i32_list()?;
+                Ok(Self::FriendsList(user_ids))
+            }
+            75 => {
+                let version = bytebuf.read_i32()?;
+                Ok(Self::ProtocolVersion(version))
+            }
+            76 => {
+                let icon = bytebuf.read_osu_string()?;
+                let (image_url, click_url) = match icon.split_once('|') {
+                    Some((image_url, click_url)) => (
+                        image_url.to_owned().into(),
+                        Some(click_url.to_owned().into()),
+                    ),
+                    None => (icon, None),
+                };
+                Ok(Self::MainMenuIcon {
+                    image_url,
+                    click_url,
+                })
+            }
+            83 => {
+                let user_id = bytebuf.read_i32()?;
+                let name = bytebuf.read_osu_string()?;
+                let utc_offset = bytebuf.read_u8()?.wrapping_sub(UTC_OFFSET_BIAS) as i8;
+                let country_code = bytebuf.read_u8()?;
+                let bancho_privileges = bytebuf.read_u8()?;
+                let longitude = bytebuf.read_f32()?;
+                let latitude = bytebuf.read_f32()?;
+                let global_rank = bytebuf.read_i32()?;
+                Ok(Self::UserPresence {
+                    user_id,
+                    name,
+                    utc_offset,
+                    country_code,
+                    bancho_privileges,
+                    longitude,
+                    latitude,
+                    global_rank,
+                })
+            }
+            85 => {
+                let user_ids = bytebuf.read_i32_list()?;
+                Ok(Self::UserStatsRequest(user_ids))
+            }
+            86 => {
+                let ms = bytebuf.read_i32()?;
+                Ok(Self::Restart { ms })
+            }
+            92 => {
+                let seconds = bytebuf.read_i32()?;
+                Ok(Self::SilenceEnd { seconds })
+            }
+            94 => {
+                let user_id = bytebuf.read_i32()?;
+                Ok(Self::UserSilenced { user_id })
+            }
+            96 => {
+                let user_ids = bytebuf.read_i32_list()?;
+                Ok(Self::UserPresenceBundle(user_ids))
+            }
+            97 => {
+                let user_ids = bytebuf.read_i32_list()?;
+                Ok(Self::UserPresenceRequest(user_ids))
+            }
+            98 => {
+                let ingame_time = bytebuf.read_i32()?;
+                Ok(Self::UserPresenceRequestAll { ingame_time })
+            }
+            107 => {
+                let host = bytebuf.read_osu_string()?;
+                Ok(Self::SwitchTournamentServer(host))
+            }
+            _ => {
+                let data = bytebuf.read_bytes(bytebuf.remaining())?;
+                Ok(Self::Other { id, data })
+            }
+        }
+    }
+
+    pub fn id(&self) -> u16 {
+        use BanchoPacket as BP;
+        match self {
+            BP::ChangeAction { .. } => 0,
+            BP::SendPublicMessage(_) => 1,
+            BP::Ping => 4,
+            BP::UserId(_) => 5,
+            BP::SendMessage(_) => 7,
+            BP::UserStats { .. } => 11,
+            BP::UserLogout { .. } => 12,
+            BP::SpectatorJoined { .. } => 13,
+            BP::SpectateFrames(_) => 15,
+            BP::SpectatorLeft { .. } => 14,
+            BP::SendSpectateFrames(_) => 18,
+            BP::CantSpectate { .. } => 22,
+            BP::Notification(_) => 24,
+            BP::SendPrivateMessage(_) => 25,
+            BP::ChannelJoinSuccess(_) => 64,
+            BP::ChannelInfo(_) => 65,
+            BP::ChannelKick(_) => 66,
+            BP::FellowSpectatorJoined { .. } => 42,
+            BP::FellowSpectatorLeft { .. } => 43,
+            BP::ChannelAutoJoin(_) => 67,
+            BP::Privilege { .. } => 71,
+            BP::FriendsList(_) => 72,
+            BP::ProtocolVersion(_) => 75,
+            BP::MainMenuIcon { .. } => 76,
+            BP::UserPresence { .. } => 83,
+            BP::UserStatsRequest(_) => 85,
+            BP::Restart { .. } => 86,
+            BP::SilenceEnd { .. } => 92,
+            BP::UserSilenced { .. } => 94,
+            BP::UserPresenceBundle(_) => 96,
+            BP::UserPresenceRequest(_) => 97,
+            BP::UserPresenceRequestAll { .. } => 98,
+            BP::SwitchTournamentServer(_) => 107,
+            BP::Other { id, .. } => *id,
+        }
+    }
+
+    /// Calls `visit` with every string field that can hold a link: message text, notifications,
+    /// channel names and topics and the main menu icon. Usernames, map hashes and the like never
+    /// do, so they're left out.
+    pub fn visit_links_mut(&mut self, mut visit: impl FnMut(LinkField, &mut OsuString)) {
+        use BanchoPacket as BP;
+        match self {
+            BP::SendPublicMessage(message)
+            | BP::SendMessage(message)
+            | BP::SendPrivateMessage(message) => visit(LinkField::Text, &mut message.text),
+            BP::Notification(text) | BP::ChannelJoinSuccess(text) | BP::ChannelKick(text) => {
+                visit(LinkField::Text, text)
+            }
+            BP::ChannelInfo(channel) | BP::ChannelAutoJoin(channel) => {
+                visit(LinkField::Text, &mut channel.topic)
+            }
+            BP::MainMenuIcon {
+                image_url,
+                click_url,
+            } => {
+                visit(LinkField::Url, image_url);
+                if let Some(click_url) = click_url {
+                    visit(LinkField::Url, click_url);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The data of the packet, without its header. Spectate frames and packets we don't decode
+    /// share the body they came in.
+    pub fn encode(&self) -> Bytes {
+        match self {
+            Self::SpectateFrames(bundle) | Self::SendSpectateFrames(bundle) => bundle.data.clone(),
+            Self::Other { data, .. } => data.clone(),
+            _ => {
+                let mut bytebuf = BytesMut::new();
+                self.encode_fields(&mut bytebuf);
+                bytebuf.freeze()
+            }
+        }
+    }
+
+    fn encode_fields(&self, bytebuf: &mut BytesMut) {
+        use BanchoPacket as BP;
+
+        match self {
+            BP::ChangeAction {
+                action,
+                info_text,
+                map_md5,
+                mods,
+                mode,
+                map_id,
+            } => {
+                bytebuf.put_u8(action.as_u8());
+                bytebuf.write_osu_string(info_text);
+                bytebuf.write_osu_string(map_md5);
+                bytebuf.put_u32_le(mods.bits());
+                bytebuf.put_u8(mode.as_u8());
+                bytebuf.put_i32_le(*map_id);
+            }
+            BP::SendPublicMessage(message) => {
+                bytebuf.write_osu_message(message);
+            }
+            BP::Ping => {}
+            BP::UserId(user_id) => {
+                bytebuf.put_i32_le(*user_id);
+            }
+            BP::SendMessage(message) => {
+                bytebuf.write_osu_message(message);
+            }
+            BP::UserStats {
+                user_id,
+                action,
+                info_text,
+                map_md5,
+                mods,
+                mode,
+                map_id,
+                ranked_score,
+                accuracy,
+                playcount,
+                total_score,
+                global_rank,
+                pp,
+            } => {
+                bytebuf.put_i32_le(*user_id);
+                bytebuf.put_u8(action.as_u8());
+                bytebuf.write_osu_string(info_text);
+                bytebuf.write_osu_string(map_md5);
+                bytebuf.put_u32_le(mods.bits());
+                bytebuf.put_u8(mode.as_u8());
+                bytebuf.put_i32_le(*map_id);
+                bytebuf.put_i64_le(*ranked_score);
+                bytebuf.put_f32_le(*accuracy);
+                bytebuf.put_i32_le(*playcount);
+                bytebuf.put_i64_le(*total_score);
+                bytebuf.put_i32_le(*global_rank);
+                bytebuf.put_i16_le(*pp);
+            }
+            BP::UserLogout {
+                user_id,
+                quit_state,
+            } => {
+                bytebuf.put_i32_le(*user_id);
+                bytebuf.put_u8(*quit_state);
+            }
+            BP::SpectateFrames(bundle) | BP::SendSpectateFrames(bundle) => {
+                bytebuf.put_slice(&bundle.data);
+            }
+            BP::SpectatorJoined { user_id }
+            | BP::SpectatorLeft { user_id }
+            | BP::CantSpectate { user_id }
+            | BP::FellowSpectatorJoined { user_id }
+            | BP::FellowSpectatorLeft { user_id } => {
+                bytebuf.put_i32_le(*user_id);
+            }
+            BP::Notification(text) => {
+                bytebuf.write_osu_string(text);
+            }
+            BP::SendPrivateMessage(message) => {
+                bytebuf.write_osu_message(message);
+            }
+            BP::ChannelJoinSuccess(name) => {
+                bytebuf.write_osu_string(name);
+            }
+            BP::ChannelInfo(channel) => {
+                bytebuf.write_osu_channel(channel);
+            }
+            BP::ChannelKick(name) => {
+                bytebuf.write_osu_string(name);
+            }
+            BP::ChannelAutoJoin(channel) => {
+                bytebuf.write_osu_channel(channel);
+            }
+            BP::Privilege { privileges } => {
+                bytebuf.put_u32_le(privileges.bits());
+            }
+            BP::FriendsList(user_ids) => {
+                bytebuf.write_i32_list(user_ids);
+            }
+            BP::ProtocolVersion(version) => {
+                bytebuf.put_i32_le(*version);
+            }
+            BP::MainMenuIcon {
+                image_url,
+                click_url,
+            } => match click_url {
+                Some(click_url) => {
+                    bytebuf.write_osu_string(&format!("{}|{}", image_url, click_url).into())
+                }
+                None => bytebuf.write_osu_string(image_url),
+            },
+            BP::UserPresence {
+                user_id,
+                name,
+                utc_offset,
+                country_code,
+                bancho_privileges,
+                longitude,
+                latitude,
+                global_rank,
+            } => {
+                bytebuf.put_i32_le(*user_id);
+                bytebuf.write_osu_string(name);
+                bytebuf.put_u8((*utc_offset as u8).wrapping_add(UTC_OFFSET_BIAS));
+                bytebuf.put_u8(*country_code);
+                bytebuf.put_u8(*bancho_privileges);
+                bytebuf.put_f32_le(*longitude);
+                bytebuf.put_f32_le(*latitude);
+                bytebuf.put_i32_le(*global_rank);
+            }
+            BP::UserStatsRequest(user_ids) => {
+                bytebuf.write_i32_list(user_ids);
+            }
+            BP::Restart { ms } => {
+                bytebuf.put_i32_le(*ms);
+            }
+            BP::SilenceEnd { seconds } => {
+                bytebuf.put_i32_le(*seconds);
+            }
+            BP::UserSilenced { user_id } => {
+                bytebuf.put_i32_le(*user_id);
+            }
+            BP::UserPresenceBundle(user_ids) => {
+                bytebuf.write_i32_list(user_ids);
+            }
+            BP::UserPresenceRequest(user_ids) => {
+                bytebuf.write_i32_list(user_ids);
+            }
+            BP::UserPresenceRequestAll { ingame_time } => {
+                bytebuf.put_i32_le(*ingame_time);
+            }
+            BP::SwitchTournamentServer(host) => {
+                bytebuf.write_osu_string(host);
+            }
+            BP::Other { data, .. } => {
+                bytebuf.put_slice(data);
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Bytes, CodecError> {
+        let mut bytes = BytesMut::new();
+        self.write_to(&mut bytes)?;
+        Ok(bytes.freeze())
+    }
+
+    /// Appends the packet with its header to `bytes`, encoding the fields in place. The length in
+    /// the header is filled in once they're written. Fails without appending anything if the packet
+    /// is too long for its header.
+    pub fn write_to(&self, bytes: &mut BytesMut) -> Result<(), CodecError> {
+        // Header
+        let header_start = bytes.len();
+        bytes.put_u16_le(self.id());
+        bytes.put_u8(0);
+        bytes.put_u32_le(0);
+
+        let data_start = bytes.len();
+        self.encode_fields(bytes);
+        let length = bytes.len() - data_start;
+        let Ok(header_length) = u32::try_from(length) else {
+            bytes.truncate(header_start);
+            return Err(CodecError::TooLongToEncode {
+                id: self.id(),
+                length,
+            });
+        };
+        bytes[header_start + 3..data_start].copy_from_slice(&header_length.to_le_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::decode_bancho_packets;
+
+    /// Large enough for any of the packets here.
+    const MAX_PACKET_LENGTH: usize = 1024;
+
+    fn raw_packet(id: u16, data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.put_u16_le(id);
+        bytes.put_u8(0);
+        bytes.put_u32_le(data.len() as u32);
+        bytes.put_slice(data);
+        bytes
+    }
+
+    fn user_presence(name: OsuString) -> BanchoPacket {
+        BanchoPacket::UserPresence {
+            user_id: 2,
+            name,
+            utc_offset: 0,
+            country_code: 0,
+            bancho_privileges: 0,
+            longitude: 0.0,
+            latitude: 0.0,
+            global_rank: 1,
+        }
+    }
+
+    #[test]
+    fn over_long_packets_are_kept_as_is_without_touching_the_next_one() {
+        let presence = user_presence("peppy".to_owned().into()).to_bytes().unwrap();
+        let mut over_long = presence[PACKET_HEADER_LENGTH..].to_vec();
+        over_long.extend_from_slice(&[0xaa, 0xbb]);
+        let mut body = raw_packet(83, &over_long);
+        body.extend_from_slice(&BanchoPacket::UserId(1000).to_bytes().unwrap());
+
+        let packets = decode_bancho_packets(
+            &body.into(),
+            PacketDirection::ServerToClient,
+            MAX_PACKET_LENGTH,
+        )
+        .unwrap();
+        assert_eq!(packets.len(), 2);
+        match &packets[0] {
+            BanchoPacket::Other { id, data } => {
+                assert_eq!(*id, 83);
+                assert_eq!(data[..], over_long[..]);
+            }
+            packet => panic!("expected the presence to be kept as is, got {:?}", packet),
+        }
+        assert!(
+            matches!(packets[1], BanchoPacket::UserId(1000)),
+            "{:?}",
+            packets[1]
+        );
+    }
+
+    /// Re-encoding the decoded packet must give back the exact bytes it was decoded from.
+    fn assert_round_trips(packet: &BanchoPacket) {
+        let bytes = packet.to_bytes().unwrap();
+        let direction = BanchoPacket::sent_in(packet.id()).unwrap();
+        let decoded = decode_bancho_packets(&bytes, direction, MAX_PACKET_LENGTH).unwrap();
+        assert_eq!(decoded.len(), 1);
+        assert!(
+            !matches!(decoded[0], BanchoPacket::Other { .. }),
+            "{:?} didn't decode",
+            packet
+        );
+        assert_eq!(decoded[0].to_bytes().unwrap(), bytes, "{:?}", packet);
+    }
+
+    /// Every packet with string fields, all of them set to `string`.
+    fn packets_with_strings(string: &OsuString) -> Vec<BanchoPacket> {
+        let message = || OsuMessage {
+            sender: string.clone(),
+            text: string.clone(),
+            recipient: string.clone(),
+            sender_id: 2,
+        };
+        let channel = || OsuChannel {
+            name: string.clone(),
+            topic: string.clone(),
+            player_count: 1,
+        };
+        vec![
+            BanchoPacket::ChangeAction {
+                action: UserAction::Playing,
+                info_text: string.clone(),
+                map_md5: string.clone(),
+                mods: Mods::empty(),
+                mode: GameMode::Osu,
+                map_id: 75,
+            },
+            BanchoPacket::SendPublicMessage(message()),
+            BanchoPacket::SendMessage(message()),
+            BanchoPacket::UserStats {
+                user_id: 2,
+                action: UserAction::Playing,
+                info_text: string.clone(),
+                map_md5: string.clone(),
+                mods: Mods::empty(),
+                mode: GameMode::Osu,
+                map_id: 75,
+                ranked_score: 1,
+                accuracy: 1.0,
+                playcount: 1,
+                total_score: 1,
+                global_rank: 1,
+                pp: 1,
+            },
+            BanchoPacket::Notification(string.clone()),
+            BanchoPacket::SendPrivateMessage(message()),
+            BanchoPacket::ChannelJoinSuccess(string.clone()),
+            BanchoPacket::ChannelInfo(channel()),
+            BanchoPacket::ChannelKick(string.clone()),
+            BanchoPacket::ChannelAutoJoin(channel()),
+            BanchoPacket::MainMenuIcon {
+                image_url: string.clone(),
+                click_url: None,
+            },
+            user_presence(string.clone()),
+            BanchoPacket::SwitchTournamentServer(string.clone()),
+        ]
+    }
+
+    #[test]
+    fn null_and_empty_strings_round_trip() {
+        let null = OsuString {
+            value: String::new(),
+            null: true,
+        };
+        let empty = OsuString {
+            value: String::new(),
+            null: false,
+        };
+        for string in [null, empty] {
+            for packet in packets_with_strings(&string) {
+                assert_round_trips(&packet);
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_actions_are_kept_as_is() {
+        let change_action = BanchoPacket::ChangeAction {
+            action: UserAction::Idle,
+            info_text: OsuString::default(),
+            map_md5: OsuString::default(),
+            mods: Mods::empty(),
+            mode: GameMode::Osu,
+            map_id: 0,
+        };
+        let mut data = change_action.encode().to_vec();
+        // The action is the first byte of ChangeAction
+        data[0] = 200;
+        let body = raw_packet(0, &data);
+        let packets = decode_bancho_packets(
+            &body.into(),
+            PacketDirection::ClientToServer,
+            MAX_PACKET_LENGTH,
+        )
+        .unwrap();
+        match &packets[..] {
+            [BanchoPacket::Other { id: 0, data: kept }] => assert_eq!(kept[..], data[..]),
+            packets => panic!("expected the packet to be kept as is, got {:?}", packets),
+        }
+    }
+
+    fn read_error(
+        bytes: &[u8],
+        read: impl FnOnce(&mut PacketReader) -> io::Result<()>,
+    ) -> io::ErrorKind {
+        let mut reader = PacketReader::new(Bytes::copy_from_slice(bytes));
+        read(&mut reader)
+            .expect_err("read something out of corrupt data")
+            .kind()
+    }
+
+    #[test]
+    fn corrupt_lengths_are_invalid_data() {
+        let read_uleb128 = |reader: &mut PacketReader| reader.read_uleb128().map(drop);
+        let read_osu_string = |reader: &mut PacketReader| reader.read_osu_string().map(drop);
+        // More continuation bytes than a u64 can take
+        assert_eq!(
+            read_error(&[0xff; 16], read_uleb128),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            read_error(&[0x80; 16], read_uleb128),
+            io::ErrorKind::InvalidData
+        );
+        // The 10th byte can only hold the top bit
+        let mut overflowing = [0x80; 10];
+        overflowing[9] = 0x02;
+        assert_eq!(
+            read_error(&overflowing, read_uleb128),
+            io::ErrorKind::InvalidData
+        );
+        // A string claiming more than is left, small and huge
+        assert_eq!(
+            read_error(&[0x0b, 0x05, b'a'], read_osu_string),
+            io::ErrorKind::InvalidData
+        );
+        let mut huge = vec![0x0b];
+        huge.extend_from_slice(&[0xff; 9]);
+        huge.push(0x01);
+        assert_eq!(
+            read_error(&huge, read_osu_string),
+            io::ErrorKind::InvalidData
+        );
+        // Same for lists
+        let read_i32_list = |reader: &mut PacketReader| reader.read_i32_list().map(drop);
+        assert_eq!(
+            read_error(&[0x02, 0x00, 1, 0, 0, 0], read_i32_list),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt::{Display, Formatter};
+
+use bytes::{Bytes, BytesMut};
+use tracing::debug;
+
+use crate::bancho::{
+    self, BanchoPacket, BanchoPacketHeader, PacketDirection, PACKET_HEADER_LENGTH,
+};
+use crate::stream::PacketSplitter;
+
+/// Why a body couldn't be split into packets, or packets couldn't be written out.
+#[derive(Debug)]
+pub enum CodecError {
+    /// A packet claims to be longer than the limit it was decoded with. Its header is most likely
+    /// corrupt, or the body isn't bancho packets at all.
+    PacketTooLong {
+        id: u16,
+        length: usize,
+        offset: usize,
+    },
+    /// The body ended before its last packet did.
+    Truncated {
+        id: u16,
+        length: usize,
+        offset: usize,
+    },
+    /// The data of a packet doesn't fit the 32-bit length in its header.
+    TooLongToEncode { id: u16, length: usize },
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PacketTooLong { id, length, offset } => {
+                write!(
+                    f,
+                    "packet {} at byte {} claims to be {} bytes long",
+                    id, offset, length
+                )
+            }
+            Self::Truncated { id, length, offset } => write!(
+                f,
+                "packet {} at byte {} claims to be {} bytes long but the body ends before that",
+                id, offset, length
+            ),
+            Self::TooLongToEncode { id, length } => {
+                write!(
+                    f,
+                    "packet {} is {} bytes long, more than its header can hold",
+                    id, length
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Decodes a whole body at once. Fails on packets claiming to be longer than `max_packet_length`
+/// or the rest of the body.
+pub fn decode_bancho_packets(
+    bytes: &Bytes,
+    direction: PacketDirection,
+    max_packet_length: usize,
+) -> Result<Vec<BanchoPacket>, CodecError> {
+    let mut splitter = PacketSplitter::new(direction, max_packet_length);
+    splitter.push(bytes.clone());
+    let (_, packets) = splitter.split()?;
+    splitter.finish()?;
+
+    debug!("{} {}", direction, summarize_packets(&packets, direction));
+    Ok(packets)
+}
+
+/// Writes the packets one after the other into a single buffer, sized for the body they were
+/// decoded from as they rarely end up much longer.
+pub fn encode_bancho_packets(
+    packets: Vec<BanchoPacket>,
+    original_length: usize,
+) -> Result<Bytes, CodecError> {
+    let mut bytes = BytesMut::with_capacity(original_length);
+    for packet in packets {
+        packet.write_to(&mut bytes)?;
+    }
+
+    Ok(bytes.freeze())
+}
+
+/// The id and size, header included, of each packet in `bytes` without decoding them. Stops at
+/// the first one that's cut off.
+pub fn packet_sizes(bytes: &[u8]) -> impl Iterator<Item = (u16, usize)> + '_ {
+    let mut position = 0;
+    std::iter::from_fn(move || {
+        let header_bytes = bytes.get(position..position + PACKET_HEADER_LENGTH)?;
+        let header = BanchoPacketHeader::from_bytes(header_bytes.try_into().ok()?);
+        let size = PACKET_HEADER_LENGTH + header.length() as usize;
+        if position + size > bytes.len() {
+            return None;
+        }
+        position += size;
+        Some((header.id(), size))
+    })
+}
+
+/// How many of each packet there are, e.g. `3 packets: UserStats ×2, Pong`.
+pub fn summarize_packets(packets: &[BanchoPacket], direction: PacketDirection) -> String {
+    let mut counts: Vec<(String, usize)> = vec![];
+    for packet in packets {
+        let name = bancho::packet_name(packet.id(), direction)
+            .map(str::to_owned)
+            .unwrap_or_else(|| format!("Unknown({})", packet.id()));
+        match counts.iter_mut().find(|(counted, _)| *counted == name) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((name, 1)),
+        }
+    }
+
+    let counts = counts
+        .into_iter()
+        .map(|(name, count)| {
+            if count == 1 {
+                name
+            } else {
+                format!("{} ×{}", name, count)
+            }
+        })
+        .collect::<Vec<_>>();
+    format!("{} packets: {}", packets.len(), counts.join(", "))
+}
@@ -0,0 +1,138 @@
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::janitor::DATA_DIRECTORY;
+use crate::log_buffer::LogBuffer;
+use crate::osus_proxy::chat_log::utc_date_and_time;
+use crate::osus_proxy::CHAT_LOG_TARGET;
+use crate::preferences::Preferences;
+use crate::state::ProxyState;
+
+const MAX_LOG_LINES: usize = 100;
+const MAX_PACKET_ROWS: usize = 20;
+/// Preferences that are about other people or may hold credentials, as JSON pointers. Only
+/// whether they're set makes it into the diagnostics.
+const REDACTED_PREFERENCES: &[&str] = &[
+    "/blocked_users",
+    "/chat_filter/patterns",
+    "/auto_reply/text",
+    "/do_not_disturb/allowed_senders",
+    "/presence_watchlist",
+    "/header_overrides",
+    "/forwarded_ip",
+    "/replay_fallback_url_template",
+];
+
+/// A Markdown snapshot of what the proxy is doing for bug reports. Leaves out chat, tokens and
+/// anything in the preferences about other people.
+pub fn collect(preferences: &Preferences, state: &ProxyState, log_buffer: &LogBuffer) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default();
+    let (date, time) = utc_date_and_time(now);
+    let mut markdown = String::new();
+    let _ = writeln!(markdown, "## osus-proxy diagnostics, {} {} UTC", date, time);
+    let _ = writeln!(markdown);
+    let _ = writeln!(markdown, "- Version: {}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(
+        markdown,
+        "- OS: {} ({})",
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    );
+    let listener = match &state.listener {
+        Some(Ok(addr)) => format!("listening on {}", addr),
+        Some(Err(e)) => format!("failed to bind: {}", e),
+        None => "not started".to_owned(),
+    };
+    let _ = writeln!(markdown, "- Listener: {}", listener);
+    let _ = writeln!(markdown, "- Server: {}", preferences.server_address);
+    let login = match (&state.login_failure, state.sessions.is_empty()) {
+        (Some(failure), _) => format!("rejected, {}", failure),
+        (None, false) => format!("logged in, {} sessions", state.sessions.iter().count()),
+        (None, true) => "no login yet".to_owned(),
+    };
+    let _ = writeln!(markdown, "- Last login: {}", login);
+    if let Some(protocol_version) = state.protocol_version {
+        let _ = writeln!(markdown, "- Protocol version: {}", protocol_version);
+    }
+    let upstream_error = match &state.last_upstream_error {
+        Some((at, error)) => format!("{}s ago, {}", at.elapsed().as_secs(), error),
+        None => "none".to_owned(),
+    };
+    let _ = writeln!(markdown, "- Last upstream error: {}", upstream_error);
+    let down_hosts = state.upstream_availability.down_hosts().collect::<Vec<_>>();
+    if !down_hosts.is_empty() {
+        let _ = writeln!(markdown, "- Unreachable: {}", down_hosts.join(", "));
+    }
+    let _ = writeln!(markdown, "- Bancho exchanges: {}", state.bancho_exchanges);
+
+    let mut counters = state.packet_stats.counters().collect::<Vec<_>>();
+    counters.sort_by_key(|counter| std::cmp::Reverse(counter.count));
+    let _ = writeln!(markdown, "\n### Packets\n");
+    let _ = writeln!(markdown, "| Packet | Direction | Count | Bytes |");
+    let _ = writeln!(markdown, "|---|---|---|---|");
+    for counter in counters.iter().take(MAX_PACKET_ROWS) {
+        let _ = writeln!(
+            markdown,
+            "| {} | {} | {} | {} |",
+            counter.name(),
+            counter.direction,
+            counter.count,
+            counter.bytes
+        );
+    }
+
+    let _ = writeln!(markdown, "\n### Preferences\n");
+    let _ = writeln!(
+        markdown,
+        "```json\n{}\n```",
+        redacted_preferences(preferences)
+    );
+
+    let log_lines = log_buffer
+        .lines_after(0)
+        .into_iter()
+        .filter(|line| line.target != CHAT_LOG_TARGET)
+        .collect::<Vec<_>>();
+    let _ = writeln!(markdown, "\n### Last {} log lines\n", MAX_LOG_LINES);
+    let _ = writeln!(markdown, "```");
+    for line in &log_lines[log_lines.len().saturating_sub(MAX_LOG_LINES)..] {
+        let _ = writeln!(markdown, "{}", line);
+    }
+    let _ = writeln!(markdown, "```");
+    markdown
+}
+
+/// Writes `diagnostics-<unix timestamp>.txt` next to the log file.
+pub fn write_file(diagnostics: &str) -> io::Result<PathBuf> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default();
+    let path = Path::new(DATA_DIRECTORY).join(format!("diagnostics-{}.txt", now));
+    std::fs::write(&path, diagnostics)?;
+    Ok(path)
+}
+
+fn redacted_preferences(preferences: &Preferences) -> String {
+    let mut value = match serde_json::to_value(preferences) {
+        Ok(value) => value,
+        Err(e) => return format!("failed to serialize: {}", e),
+    };
+    for pointer in REDACTED_PREFERENCES {
+        if let Some(redacted) = value.pointer_mut(pointer) {
+            let set = match redacted {
+                serde_json::Value::Array(items) => !items.is_empty(),
+                serde_json::Value::String(text) => !text.is_empty(),
+                serde_json::Value::Null => false,
+                _ => true,
+            };
+            *redacted = (if set { "<redacted>" } else { "<not set>" }).into();
+        }
+    }
+    serde_json::to_string_pretty(&value).unwrap_or_else(|e| format!("failed to serialize: {}", e))
+}
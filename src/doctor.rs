@@ -0,0 +1,100 @@
+use std::path::Path;
+
+use crate::hosts;
+use crate::janitor::{self, DATA_DIRECTORY};
+use crate::osus_proxy::{self, idle_failures};
+use crate::preferences::Preferences;
+use crate::LOG_FILE_NAME;
+
+/// Runs checks that don't change anything and prints what they found.
+pub fn run(preferences: &Preferences) {
+    println!("osus-proxy doctor");
+
+    println!("These hosts have to point at the proxy:");
+    for subdomain in &preferences.subdomains {
+        println!("  127.0.0.1 {}", osus_proxy::source_host(subdomain));
+    }
+
+    let mut upstream_hosts = vec!["osu.ppy.sh".to_owned(), "c.ppy.sh".to_owned()];
+    for subdomain in ["osu", "c"] {
+        let host = format!("{}.{}", subdomain, preferences.server_address);
+        if !upstream_hosts.contains(&host) {
+            upstream_hosts.push(host);
+        }
+    }
+    let mut conflicts = false;
+    for host in &upstream_hosts {
+        if let Some(hint) = hosts::conflict_hint(host) {
+            println!("{}", hint);
+            conflicts = true;
+        }
+    }
+    if !conflicts {
+        println!("No other server switcher seems to be redirecting the upstream hosts");
+    }
+
+    let log =
+        std::fs::read_to_string(Path::new(DATA_DIRECTORY).join(LOG_FILE_NAME)).unwrap_or_default();
+    match idle_failure_hint(&idle_failures::idle_times_in_log(&log), preferences) {
+        Some(hint) => println!("{}", hint),
+        None => println!("No requests failed after the upstream connection sat idle"),
+    }
+
+    let report = janitor::clean(&preferences.retention_policies, true);
+    if report.deleted.is_empty() {
+        println!("No old files to clean up");
+    } else {
+        println!(
+            "The cleanup on startup would delete {} files ({} bytes):",
+            report.deleted.len(),
+            report.freed_bytes
+        );
+        for path in &report.deleted {
+            println!("  {}", path.display());
+        }
+    }
+}
+
+/// The first request after the connection sat idle failing again and again means something
+/// between us and the server closes idle connections before the pool does.
+fn idle_failure_hint(idle_times: &[u64], preferences: &Preferences) -> Option<String> {
+    let shortest = *idle_times.first()?;
+    let failures = format!(
+        "{} requests failed after the upstream connection sat idle for {}s or more",
+        idle_times.len(),
+        shortest
+    );
+    let pool = &preferences.upstream_pool;
+    if !pool.keep_alive || pool.idle_timeout_secs <= shortest {
+        return Some(format!(
+            "{}, the pool doesn't keep connections that long anymore",
+            failures
+        ));
+    }
+    Some(format!(
+        "{}, lower the idle connection timeout (Advanced) below {}s, it's {}s now",
+        failures, shortest, pool.idle_timeout_secs
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggests_an_idle_timeout_below_the_shortest_idle_failure() {
+        let mut preferences = Preferences::default();
+        assert_eq!(idle_failure_hint(&[], &preferences), None);
+        let hint = idle_failure_hint(&[41, 75], &preferences).unwrap();
+        assert!(hint.starts_with("2 requests failed"), "{}", hint);
+        assert!(hint.ends_with("below 41s, it's 90s now"), "{}", hint);
+
+        preferences.upstream_pool.idle_timeout_secs = 30;
+        let hint = idle_failure_hint(&[41, 75], &preferences).unwrap();
+        assert!(
+            hint.ends_with("doesn't keep connections that long anymore"),
+            "{}",
+            hint
+        );
+    }
+}
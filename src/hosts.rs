@@ -0,0 +1,60 @@
+use std::io;
+use std::net::{IpAddr, ToSocketAddrs};
+
+#[cfg(windows)]
+pub const HOSTS_PATH: &str = r"C:\Windows\System32\drivers\etc\hosts";
+#[cfg(not(windows))]
+pub const HOSTS_PATH: &str = "/etc/hosts";
+
+/// Lines in the hosts file that aren't commented out and mention `domain`.
+pub fn lines_mentioning(domain: &str) -> io::Result<Vec<String>> {
+    let hosts = std::fs::read_to_string(HOSTS_PATH)?;
+    Ok(hosts
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#') && line.contains(domain))
+        .map(str::to_owned)
+        .collect())
+}
+
+/// The local addresses `host` resolves to. Upstream hosts never should, when they do it's
+/// usually leftovers from another server switcher.
+fn local_addresses(host: &str) -> io::Result<Vec<IpAddr>> {
+    Ok((host, 443)
+        .to_socket_addrs()?
+        .map(|addr| addr.ip())
+        .filter(is_local)
+        .collect())
+}
+
+/// A hint for the log or doctor if `host` resolves somewhere it shouldn't.
+pub fn conflict_hint(host: &str) -> Option<String> {
+    let addresses = local_addresses(host).ok()?;
+    if addresses.is_empty() {
+        return None;
+    }
+    let mut hint = format!(
+        "{} resolves to {:?}, probably left over from another server switcher",
+        host, addresses
+    );
+    match lines_mentioning(host) {
+        Ok(lines) if !lines.is_empty() => {
+            hint += &format!(
+                ", remove these lines from {}: {}",
+                HOSTS_PATH,
+                lines.join(" | ")
+            )
+        }
+        _ => hint += &format!(", check {} and your DNS settings", HOSTS_PATH),
+    }
+    Some(hint)
+}
+
+fn is_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+        }
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unspecified(),
+    }
+}
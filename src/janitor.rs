@@ -0,0 +1,491 @@
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::osus_proxy::capture::CAPTURES_DIRECTORY;
+use crate::osus_proxy::chat_log::CHAT_LOGS_DIRECTORY;
+
+/// Everything we write lives relative to the working directory, same as the log file.
+pub const DATA_DIRECTORY: &str = "./";
+
+/// The files we write, by the directory relative to [`DATA_DIRECTORY`] they go in and with `*` standing
+/// for anything. Nothing else is ever deleted, so a policy for a directory we share with the preferences
+/// or the live log leaves those alone. A policy only covers the files written to its own directory or
+/// below, one for the data directory doesn't reach into the captures or the chat logs.
+const OWN_FILES: &[(&str, &str)] = &[
+    (CAPTURES_DIRECTORY, "*/*_c2s.bin"),
+    (CAPTURES_DIRECTORY, "*/*_s2c.bin"),
+    (CAPTURES_DIRECTORY, "*/index.ndjson"),
+    (CHAT_LOGS_DIRECTORY, "*/*/????-??-??.txt"),
+    ("", "diagnostics-*.txt"),
+];
+
+/// Creates `directory`, relative to [`DATA_DIRECTORY`], if needed and opens it in the file manager.
+pub fn open_data_directory(directory: &str) -> io::Result<()> {
+    let directory = Path::new(DATA_DIRECTORY).join(directory);
+    fs::create_dir_all(&directory)?;
+    open_externally(&directory)
+}
+
+/// Opens a directory in the file manager, or a URL in the browser.
+pub fn open_externally(target: impl AsRef<OsStr>) -> io::Result<()> {
+    #[cfg(target_os = "windows")]
+    let opener = "explorer";
+    #[cfg(target_os = "macos")]
+    let opener = "open";
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let opener = "xdg-open";
+    std::process::Command::new(opener).arg(target).spawn()?;
+    Ok(())
+}
+
+/// Which of our files to clean up in a directory and when.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RetentionPolicy {
+    /// Relative to [`DATA_DIRECTORY`], anything pointing outside of it is ignored.
+    pub directory: PathBuf,
+    /// Only our own files with these extensions are ever touched.
+    pub extensions: Vec<String>,
+    pub max_age_days: Option<u64>,
+    /// Oldest files are deleted first until the directory fits.
+    pub max_total_bytes: Option<u64>,
+}
+
+/// Keeps a month of captures and diagnostics, and at most 1 GiB of captures.
+pub fn default_retention_policies() -> Vec<RetentionPolicy> {
+    vec![
+        RetentionPolicy {
+            directory: CAPTURES_DIRECTORY.into(),
+            extensions: vec!["bin".to_owned(), "ndjson".to_owned()],
+            max_age_days: Some(30),
+            max_total_bytes: Some(1 << 30),
+        },
+        RetentionPolicy {
+            directory: ".".into(),
+            extensions: vec!["txt".to_owned()],
+            max_age_days: Some(30),
+            max_total_bytes: None,
+        },
+    ]
+}
+
+#[derive(Debug, Default)]
+pub struct CleanupReport {
+    pub deleted: Vec<PathBuf>,
+    pub freed_bytes: u64,
+}
+
+struct Candidate {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Applies every policy, only reporting what would be deleted if `dry_run` is set.
+pub fn clean(policies: &[RetentionPolicy], dry_run: bool) -> CleanupReport {
+    clean_in(Path::new(DATA_DIRECTORY), policies, dry_run)
+}
+
+fn clean_in(data_directory: &Path, policies: &[RetentionPolicy], dry_run: bool) -> CleanupReport {
+    let mut report = CleanupReport::default();
+
+    for policy in policies {
+        if let Err(e) = apply_policy(data_directory, policy, dry_run, &mut report) {
+            warn!("Failed to clean up {}: {}", policy.directory.display(), e);
+        }
+    }
+
+    let verb = if dry_run { "Would delete" } else { "Deleted" };
+    info!(
+        "{} {} old files ({} bytes)",
+        verb,
+        report.deleted.len(),
+        report.freed_bytes
+    );
+
+    report
+}
+
+fn apply_policy(
+    data_directory: &Path,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+    report: &mut CleanupReport,
+) -> io::Result<()> {
+    let stays_inside = policy
+        .directory
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !stays_inside {
+        warn!(
+            "Ignoring retention policy for {}, it points outside of the data directory",
+            policy.directory.display()
+        );
+        return Ok(());
+    }
+
+    let directory = data_directory.join(&policy.directory);
+    let own_files: Vec<_> = OWN_FILES
+        .iter()
+        .filter(|(written_to, _)| normalized(&policy.directory).starts_with(written_to))
+        .map(|(written_to, pattern)| format!("{}/{}", written_to, pattern))
+        .collect();
+    if !directory.exists() || own_files.is_empty() {
+        return Ok(());
+    }
+
+    let mut candidates = vec![];
+    let search = Search {
+        data_directory,
+        extensions: &policy.extensions,
+        own_files: &own_files,
+    };
+    search.collect_candidates(&directory, &mut candidates)?;
+    // Newest first, so whatever is left over the size cap is the oldest
+    candidates.sort_by_key(|candidate| Reverse(candidate.modified));
+
+    let now = SystemTime::now();
+    let mut kept_bytes = 0;
+    let mut emptied = BTreeSet::new();
+    for candidate in candidates {
+        let too_old = policy.max_age_days.is_some_and(|max_age_days| {
+            now.duration_since(candidate.modified)
+                .is_ok_and(|age| age > Duration::from_secs(max_age_days * 24 * 60 * 60))
+        });
+        let too_big = policy
+            .max_total_bytes
+            .is_some_and(|max_total_bytes| kept_bytes + candidate.size > max_total_bytes);
+
+        if !too_old && !too_big {
+            kept_bytes += candidate.size;
+            continue;
+        }
+
+        if dry_run {
+            debug!("Would delete {}", candidate.path.display());
+        } else if let Err(e) = fs::remove_file(&candidate.path) {
+            // Likely open elsewhere, e.g. a capture still being written on Windows
+            warn!("Failed to delete {}: {}", candidate.path.display(), e);
+            continue;
+        } else {
+            debug!("Deleted {}", candidate.path.display());
+            if let Some(parent) = candidate.path.parent() {
+                emptied.insert(parent.to_path_buf());
+            }
+        }
+        report.freed_bytes += candidate.size;
+        report.deleted.push(candidate.path);
+    }
+
+    remove_emptied_directories(&directory, emptied);
+
+    Ok(())
+}
+
+struct Search<'a> {
+    data_directory: &'a Path,
+    extensions: &'a [String],
+    /// Patterns for whole paths relative to the data directory.
+    own_files: &'a [String],
+}
+
+impl Search<'_> {
+    fn collect_candidates(
+        &self,
+        directory: &Path,
+        candidates: &mut Vec<Candidate>,
+    ) -> io::Result<()> {
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+            // symlink_metadata so we never follow links out of the data directory
+            let metadata = fs::symlink_metadata(&path)?;
+            if metadata.file_type().is_symlink() {
+                continue;
+            }
+            let Ok(relative_path) = path.strip_prefix(self.data_directory) else {
+                continue;
+            };
+
+            if metadata.is_dir() {
+                // Only where some of our files could be, the data directory can be anywhere
+                if self.matches(relative_path, false) {
+                    self.collect_candidates(&path, candidates)?;
+                }
+                continue;
+            }
+
+            let matches_extension = path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| self.extensions.iter().any(|x| x == extension));
+            if matches_extension && self.matches(relative_path, true) {
+                candidates.push(Candidate {
+                    path,
+                    size: metadata.len(),
+                    modified: metadata.modified()?,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `relative_path` is one of our files, or a directory leading to some if not `whole`.
+    fn matches(&self, relative_path: &Path, whole: bool) -> bool {
+        let relative_path = normalized(relative_path);
+        let names: Option<Vec<_>> = relative_path.iter().map(|name| name.to_str()).collect();
+        let Some(names) = names else {
+            return false;
+        };
+
+        self.own_files.iter().any(|own_file| {
+            let patterns: Vec<_> = own_file
+                .split('/')
+                .filter(|pattern| !pattern.is_empty())
+                .collect();
+            let right_depth = if whole {
+                names.len() == patterns.len()
+            } else {
+                names.len() < patterns.len()
+            };
+            right_depth
+                && names
+                    .iter()
+                    .zip(patterns)
+                    .all(|(name, pattern)| matches_pattern(name, pattern))
+        })
+    }
+}
+
+/// Without the `.` components, so `./captures` starts with `captures`.
+fn normalized(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| !matches!(component, Component::CurDir))
+        .collect()
+}
+
+/// `*` matches any run of characters and `?` a single digit, enough for the names we write.
+fn matches_pattern(name: &str, pattern: &str) -> bool {
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) => {
+            name.len() >= prefix.len() + suffix.len()
+                && name
+                    .get(..prefix.len())
+                    .is_some_and(|head| matches_pattern(head, prefix))
+                && name.ends_with(suffix)
+        }
+        None => {
+            name.len() == pattern.len()
+                && name
+                    .chars()
+                    .zip(pattern.chars())
+                    .all(|(c, p)| c == p || (p == '?' && c.is_ascii_digit()))
+        }
+    }
+}
+
+/// Removes the directories we deleted files from once they're empty, like a capture session, going up
+/// to but never including `directory` itself. Empty directories we didn't touch are left alone.
+fn remove_emptied_directories(directory: &Path, emptied: BTreeSet<PathBuf>) {
+    // Deepest first, so a parent is only looked at after its children
+    let mut emptied: Vec<_> = emptied.into_iter().collect();
+    emptied.sort_by_key(|path| Reverse(path.components().count()));
+
+    for path in emptied {
+        let mut current = Some(path.as_path());
+        while let Some(path) =
+            current.filter(|path| path.starts_with(directory) && *path != directory)
+        {
+            let is_empty = fs::read_dir(path).is_ok_and(|mut entries| entries.next().is_none());
+            if !is_empty {
+                break;
+            }
+            if let Err(e) = fs::remove_dir(path) {
+                warn!("Failed to remove {}: {}", path.display(), e);
+                break;
+            }
+            current = path.parent();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("osus-proxy-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(data_directory: &Path, relative_path: &str, size: usize, age_days: u64) -> PathBuf {
+        let path = data_directory.join(relative_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, vec![0; size]).unwrap();
+        let modified = SystemTime::now() - Duration::from_secs(age_days * 24 * 60 * 60);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        path
+    }
+
+    fn policy(directory: &str, extensions: &[&str]) -> RetentionPolicy {
+        RetentionPolicy {
+            directory: directory.into(),
+            extensions: extensions.iter().map(|x| x.to_string()).collect(),
+            max_age_days: None,
+            max_total_bytes: None,
+        }
+    }
+
+    #[test]
+    fn deletes_files_past_their_age() {
+        let dir = temp_dir("janitor-age");
+        let old = write(&dir, "captures/100/0_c2s.bin", 10, 40);
+        let recent = write(&dir, "captures/200/0_c2s.bin", 10, 1);
+        let policy = RetentionPolicy {
+            max_age_days: Some(30),
+            ..policy("captures", &["bin"])
+        };
+
+        let report = clean_in(&dir, &[policy], false);
+
+        assert_eq!(report.deleted, vec![old]);
+        assert_eq!(report.freed_bytes, 10);
+        assert!(recent.exists());
+        // The session it emptied goes too, but not the captures directory
+        assert!(!dir.join("captures/100").exists());
+        assert!(dir.join("captures").exists());
+    }
+
+    #[test]
+    fn deletes_the_oldest_files_over_the_size_cap() {
+        let dir = temp_dir("janitor-size");
+        let oldest = write(&dir, "captures/1/0_c2s.bin", 10, 3);
+        let older = write(&dir, "captures/1/1_s2c.bin", 10, 2);
+        let newest = write(&dir, "captures/1/2_c2s.bin", 10, 1);
+        let policy = RetentionPolicy {
+            max_total_bytes: Some(25),
+            ..policy("captures", &["bin"])
+        };
+
+        let report = clean_in(&dir, &[policy], false);
+
+        assert_eq!(report.deleted, vec![oldest]);
+        assert!(older.exists());
+        assert!(newest.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn never_follows_or_deletes_symlinks() {
+        let dir = temp_dir("janitor-symlink");
+        let outside = temp_dir("janitor-symlink-target");
+        let target = write(&outside, "0_c2s.bin", 10, 40);
+        fs::create_dir_all(dir.join("captures/1")).unwrap();
+        let link = dir.join("captures/1/0_c2s.bin");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("captures/2")).unwrap();
+        let policy = RetentionPolicy {
+            max_total_bytes: Some(0),
+            ..policy("captures", &["bin"])
+        };
+
+        let report = clean_in(&dir, &[policy], false);
+
+        assert!(report.deleted.is_empty());
+        assert!(fs::symlink_metadata(&link).is_ok());
+        assert!(target.exists());
+    }
+
+    #[test]
+    fn ignores_policies_pointing_outside_of_the_data_directory() {
+        let root = temp_dir("janitor-outside");
+        let dir = root.join("data");
+        let outside = write(&root, "captures/1/0_c2s.bin", 10, 40);
+        let policies = [
+            RetentionPolicy {
+                max_total_bytes: Some(0),
+                ..policy("..", &["bin"])
+            },
+            RetentionPolicy {
+                max_total_bytes: Some(0),
+                ..policy(root.to_str().unwrap(), &["bin"])
+            },
+        ];
+        fs::create_dir_all(&dir).unwrap();
+
+        let report = clean_in(&dir, &policies, false);
+
+        assert!(report.deleted.is_empty());
+        assert!(outside.exists());
+    }
+
+    #[test]
+    fn dry_runs_delete_nothing() {
+        let dir = temp_dir("janitor-dry-run");
+        let capture = write(&dir, "captures/1/0_c2s.bin", 10, 40);
+        let policy = RetentionPolicy {
+            max_age_days: Some(30),
+            ..policy("captures", &["bin"])
+        };
+
+        let report = clean_in(&dir, &[policy], true);
+
+        assert_eq!(report.deleted, vec![capture.clone()]);
+        assert_eq!(report.freed_bytes, 10);
+        assert!(capture.exists());
+    }
+
+    #[test]
+    fn only_deletes_our_own_files() {
+        let dir = temp_dir("janitor-own-files");
+        let diagnostics = write(&dir, "diagnostics-1700000000.txt", 10, 40);
+        let kept = [
+            write(&dir, "osus-proxy.json", 10, 40),
+            write(&dir, "osus-proxy.log", 10, 40),
+            write(&dir, "notes.txt", 10, 40),
+            // Another policy's directory
+            write(&dir, "chatlogs/ppy.sh/#osu/2024-01-01.txt", 10, 40),
+            write(&dir, "other/diagnostics-1700000000.txt", 10, 40),
+        ];
+        fs::create_dir_all(dir.join("captures/empty")).unwrap();
+        let policy = RetentionPolicy {
+            max_total_bytes: Some(0),
+            ..policy(".", &["json", "log", "txt"])
+        };
+
+        let report = clean_in(&dir, &[policy], false);
+
+        assert_eq!(report.deleted.len(), 1);
+        assert!(!diagnostics.exists());
+        for path in kept {
+            assert!(path.exists(), "{} was deleted", path.display());
+        }
+        assert!(dir.join("captures/empty").exists());
+    }
+
+    #[test]
+    fn matches_the_names_we_write() {
+        assert!(matches_pattern("12_c2s.bin", "*_c2s.bin"));
+        assert!(!matches_pattern("12_s2c.bin", "*_c2s.bin"));
+        assert!(matches_pattern("2024-01-31.txt", "????-??-??.txt"));
+        assert!(!matches_pattern("notes-ab-cd.txt", "????-??-??.txt"));
+        assert!(matches_pattern("diagnostics-1.txt", "diagnostics-*.txt"));
+        assert!(!matches_pattern("diagnostics.txt", "diagnostics-*.txt"));
+    }
+}
@@ -0,0 +1,11 @@
+//! The bancho protocol as osus-proxy reads and writes it: the packets it knows, splitting bodies
+//! into them and writing them back out. None of it needs the proxy's HTTP or UI dependencies,
+//! depend on this crate with `default-features = false` to leave them out.
+
+pub mod bancho;
+pub mod codec;
+pub mod logging;
+pub mod stream;
+
+/// How much of a body with a bogus packet header gets logged, starting at the header.
+pub const HEXDUMP_BYTES: usize = 64;
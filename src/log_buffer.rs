@@ -0,0 +1,161 @@
+use std::collections::VecDeque;
+use std::fmt::{Debug, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::Layer;
+
+use crate::osus_proxy::chat_log::utc_date_and_time;
+
+/// The oldest lines are dropped once there are more than this.
+const MAX_LINES: usize = 5_000;
+
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    /// Counting up from 1, to tell which lines a reader has already seen.
+    pub seq: u64,
+    /// Unix timestamp in milliseconds
+    pub timestamp_ms: u128,
+    pub level: Level,
+    pub target: String,
+    /// The message followed by any other fields, e.g. `Failed to connect error=…`.
+    pub text: String,
+}
+
+impl std::fmt::Display for LogLine {
+    /// E.g. `13:37:00.250  INFO osus_proxy::osus_proxy: Proxy started`, times in UTC like the log file.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (_, time) = utc_date_and_time((self.timestamp_ms / 1_000) as u64);
+        write!(
+            f,
+            "{}.{:03} {:>5} {}: {}",
+            time,
+            self.timestamp_ms % 1_000,
+            self.level,
+            self.target,
+            self.text
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct Lines {
+    lines: VecDeque<LogLine>,
+    next_seq: u64,
+}
+
+/// A tracing layer keeping the latest log lines in memory for the log viewer in the UI, so it
+/// doesn't depend on where the log file ends up. Clones share the same lines.
+#[derive(Debug, Clone, Default)]
+pub struct LogBuffer {
+    inner: Arc<Mutex<Lines>>,
+}
+
+impl LogBuffer {
+    /// The lines logged after the one with `seq`, 0 for all of them. The lock is only held for
+    /// copying, anything logged while the UI draws them would otherwise wait on it.
+    pub fn lines_after(&self, seq: u64) -> Vec<LogLine> {
+        let inner = self.inner.lock().unwrap();
+        let newer = inner.lines.partition_point(|line| line.seq <= seq);
+        inner.lines.range(newer..).cloned().collect()
+    }
+}
+
+impl<S: Subscriber> Layer<S> for LogBuffer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut text = String::new();
+        event.record(&mut LineVisitor(&mut text));
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_millis())
+            .unwrap_or_default();
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_seq += 1;
+        let line = LogLine {
+            seq: inner.next_seq,
+            timestamp_ms,
+            level: *event.metadata().level(),
+            target: event.metadata().target().to_owned(),
+            text,
+        };
+        if inner.lines.len() >= MAX_LINES {
+            inner.lines.pop_front();
+        }
+        inner.lines.push_back(line);
+    }
+}
+
+/// What the log viewer in the UI shows, its own copy of the lines so drawing them doesn't hold
+/// the buffer's lock.
+#[derive(Debug)]
+pub struct LogView {
+    buffer: LogBuffer,
+    lines: VecDeque<LogLine>,
+    /// The most verbose level shown.
+    pub level: Level,
+    pub search: String,
+    pub auto_scroll: bool,
+}
+
+impl LogView {
+    pub fn new(buffer: LogBuffer) -> Self {
+        Self {
+            buffer,
+            lines: VecDeque::new(),
+            level: Level::INFO,
+            search: String::new(),
+            auto_scroll: true,
+        }
+    }
+
+    /// Takes the lines logged since the last call.
+    pub fn refresh(&mut self) {
+        let last_seq = self.lines.back().map(|line| line.seq).unwrap_or_default();
+        for line in self.buffer.lines_after(last_seq) {
+            if self.lines.len() >= MAX_LINES {
+                self.lines.pop_front();
+            }
+            self.lines.push_back(line);
+        }
+    }
+
+    /// The lines at [`Self::level`] or above containing [`Self::search`], case-insensitively.
+    pub fn matching(&self) -> impl Iterator<Item = &LogLine> {
+        let search = self.search.trim().to_lowercase();
+        self.lines.iter().filter(move |line| {
+            line.level <= self.level
+                && (search.is_empty()
+                    || line.text.to_lowercase().contains(&search)
+                    || line.target.to_lowercase().contains(&search))
+        })
+    }
+
+    /// The last `count` of the matching lines, one per line, for pasting into bug reports.
+    pub fn last_lines_text(&self, count: usize) -> String {
+        let matching = self.matching().collect::<Vec<_>>();
+        matching[matching.len().saturating_sub(count)..]
+            .iter()
+            .map(|line| line.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+struct LineVisitor<'a>(&'a mut String);
+
+impl Visit for LineVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        let _ = if field.name() == "message" {
+            write!(self.0, "{:?}", value)
+        } else {
+            write!(self.0, "{}={:?}", field.name(), value)
+        };
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+/// How often a repeating warning is summarized at most.
+pub const SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Every warning that fired so far, for [`flush_rate_limited_warnings`].
+static FIRED_WARNINGS: Mutex<Vec<&'static RateLimitedWarning>> = Mutex::new(Vec::new());
+
+/// A warning that can fire on every request, logged in full the first time and only counted after
+/// that. Keyed warnings count each key, like a packet id, on its own, so one noisy key doesn't hide
+/// the details of the others.
+pub struct RateLimitedWarning {
+    name: &'static str,
+    /// What the keys are, for the summaries. `None` if the warning isn't keyed.
+    key_name: Option<&'static str>,
+    fired: AtomicBool,
+    windows: Mutex<BTreeMap<u32, Window>>,
+}
+
+/// Starts when a warning is logged in full, what happens until it ends is only counted.
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    suppressed: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Summary {
+    key: u32,
+    suppressed: u64,
+    over: Duration,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Occurrence {
+    /// Logged in full, after the summary of the window it ended if anything was suppressed in it.
+    Logged(Option<Summary>),
+    Suppressed,
+}
+
+impl RateLimitedWarning {
+    pub const fn new(name: &'static str) -> Self {
+        Self::with_key_name(name, None)
+    }
+
+    /// A warning for [`RateLimitedWarning::warn_for`], `key_name` says what the keys are.
+    pub const fn keyed(name: &'static str, key_name: &'static str) -> Self {
+        Self::with_key_name(name, Some(key_name))
+    }
+
+    const fn with_key_name(name: &'static str, key_name: Option<&'static str>) -> Self {
+        Self {
+            name,
+            key_name,
+            fired: AtomicBool::new(false),
+            windows: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// `message` is only built when it's actually logged.
+    pub fn warn(&'static self, message: impl FnOnce() -> String) {
+        self.warn_for(0, message);
+    }
+
+    /// Like [`RateLimitedWarning::warn`], counted separately for each `key`.
+    pub fn warn_for(&'static self, key: u32, message: impl FnOnce() -> String) {
+        if !self.fired.swap(true, Ordering::Relaxed) {
+            FIRED_WARNINGS.lock().unwrap().push(self);
+        }
+        if let Occurrence::Logged(summary) = self.occur(key, Instant::now()) {
+            if let Some(summary) = summary {
+                warn!("{}", self.describe(&summary));
+            }
+            warn!("{}", message());
+        }
+    }
+
+    fn occur(&self, key: u32, now: Instant) -> Occurrence {
+        let mut windows = self.windows.lock().unwrap();
+        let Some(window) = windows.get_mut(&key) else {
+            windows.insert(
+                key,
+                Window {
+                    started: now,
+                    suppressed: 0,
+                },
+            );
+            return Occurrence::Logged(None);
+        };
+        let over = now.saturating_duration_since(window.started);
+        if over < SUMMARY_INTERVAL {
+            window.suppressed += 1;
+            return Occurrence::Suppressed;
+        }
+        let summary = (window.suppressed > 0).then_some(Summary {
+            key,
+            suppressed: window.suppressed,
+            over,
+        });
+        *window = Window {
+            started: now,
+            suppressed: 0,
+        };
+        Occurrence::Logged(summary)
+    }
+
+    /// Ends the windows that are over by `now`, with the summaries of those that suppressed anything.
+    fn end_windows(&self, now: Instant) -> Vec<Summary> {
+        let mut summaries = vec![];
+        self.windows.lock().unwrap().retain(|&key, window| {
+            let over = now.saturating_duration_since(window.started);
+            if over < SUMMARY_INTERVAL {
+                return true;
+            }
+            if window.suppressed > 0 {
+                summaries.push(Summary {
+                    key,
+                    suppressed: window.suppressed,
+                    over,
+                });
+            }
+            false
+        });
+        summaries
+    }
+
+    fn describe(&self, summary: &Summary) -> String {
+        let name = match self.key_name {
+            Some(key_name) => format!("{} ({} {})", self.name, key_name, summary.key),
+            None => self.name.to_owned(),
+        };
+        format!(
+            "{}: happened {} more times in the last {} seconds",
+            name,
+            summary.suppressed,
+            summary.over.as_secs()
+        )
+    }
+}
+
+/// Logs the summaries of warnings that stopped firing, which no new occurrence would trigger. Meant
+/// to be called every [`SUMMARY_INTERVAL`] or so.
+pub fn flush_rate_limited_warnings() {
+    let now = Instant::now();
+    for warning in FIRED_WARNINGS.lock().unwrap().iter() {
+        for summary in warning.end_windows(now) {
+            warn!("{}", warning.describe(&summary));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn logs_the_first_occurrence_and_suppresses_the_rest_of_the_window() {
+        let warning = RateLimitedWarning::new("Test warnings");
+        let start = Instant::now();
+        assert_eq!(warning.occur(0, start), Occurrence::Logged(None));
+        assert_eq!(warning.occur(0, start + SECOND), Occurrence::Suppressed);
+        assert_eq!(
+            warning.occur(0, start + SUMMARY_INTERVAL - SECOND),
+            Occurrence::Suppressed
+        );
+    }
+
+    #[test]
+    fn summarizes_the_window_on_the_first_occurrence_after_it() {
+        let warning = RateLimitedWarning::new("Test warnings");
+        let start = Instant::now();
+        warning.occur(0, start);
+        warning.occur(0, start + SECOND);
+        warning.occur(0, start + 2 * SECOND);
+        let later = start + SUMMARY_INTERVAL + SECOND;
+        let summary = Summary {
+            key: 0,
+            suppressed: 2,
+            over: SUMMARY_INTERVAL + SECOND,
+        };
+        assert_eq!(
+            warning.describe(&summary),
+            "Test warnings: happened 2 more times in the last 61 seconds"
+        );
+        assert_eq!(warning.occur(0, later), Occurrence::Logged(Some(summary)));
+        // Which starts a new window
+        assert_eq!(warning.occur(0, later + SECOND), Occurrence::Suppressed);
+        // Nothing to summarize if nothing was suppressed
+        let quiet = RateLimitedWarning::new("Test warnings");
+        quiet.occur(0, start);
+        assert_eq!(quiet.occur(0, later), Occurrence::Logged(None));
+    }
+
+    #[test]
+    fn summarizes_bursts_that_stopped() {
+        let warning = RateLimitedWarning::new("Test warnings");
+        let start = Instant::now();
+        warning.occur(0, start);
+        warning.occur(0, start + SECOND);
+        assert_eq!(warning.end_windows(start + 2 * SECOND), vec![]);
+        assert_eq!(
+            warning.end_windows(start + SUMMARY_INTERVAL),
+            vec![Summary {
+                key: 0,
+                suppressed: 1,
+                over: SUMMARY_INTERVAL,
+            }]
+        );
+        // Summarized only once, and the next occurrence is logged in full again
+        assert_eq!(warning.end_windows(start + 2 * SUMMARY_INTERVAL), vec![]);
+        assert_eq!(
+            warning.occur(0, start + 2 * SUMMARY_INTERVAL),
+            Occurrence::Logged(None)
+        );
+    }
+
+    #[test]
+    fn counts_each_key_on_its_own() {
+        let warning = RateLimitedWarning::keyed("Test warnings", "packet id");
+        let start = Instant::now();
+        assert_eq!(warning.occur(11, start), Occurrence::Logged(None));
+        assert_eq!(warning.occur(11, start + SECOND), Occurrence::Suppressed);
+        assert_eq!(warning.occur(83, start + SECOND), Occurrence::Logged(None));
+        let summaries = warning.end_windows(start + SUMMARY_INTERVAL + SECOND);
+        assert_eq!(
+            summaries,
+            vec![Summary {
+                key: 11,
+                suppressed: 1,
+                over: SUMMARY_INTERVAL + SECOND,
+            }]
+        );
+        assert_eq!(
+            warning.describe(&summaries[0]),
+            "Test warnings (packet id 11): happened 1 more times in the last 61 seconds"
+        );
+    }
+}
@@ -1,50 +1,172 @@
 #![windows_subsystem = "windows"]
 
+use crate::log_buffer::LogBuffer;
+use crate::osus_proxy::bancho::PacketDirection;
+use crate::osus_proxy::mirror_stats::MirrorStats;
+use crate::osus_proxy::replay::Replay;
 use crate::preferences::Preferences;
-use color_eyre::Result;
+use crate::state::{ProxyState, StartupTimeline};
+use color_eyre::{eyre::eyre, Result};
+use osus_bancho::logging;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 use tracing::metadata::LevelFilter;
-use tracing::Level;
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::Layer;
 
+#[cfg(windows)]
+mod autostart;
+mod diagnostics;
+mod doctor;
+mod hosts;
+mod janitor;
+mod log_buffer;
 mod osus_proxy;
 mod preferences;
+mod state;
+mod telemetry;
 mod ui;
+mod uninstall;
+
+pub const LOG_FILE_NAME: &str = "osus-proxy.log";
+/// Starts the window minimized, or hidden in the tray where there is one.
+pub const MINIMIZED_FLAG: &str = "--minimized";
+/// How long the requests in flight get to finish once the window is closed.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 fn main() -> Result<()> {
-    let file_appender = tracing_appender::rolling::never("./", "osus-proxy.log");
+    let mut startup_timeline =
+        StartupTimeline::new(std::env::args().any(|arg| arg == "--profile-startup"));
+
+    let file_appender = tracing_appender::rolling::never(janitor::DATA_DIRECTORY, LOG_FILE_NAME);
     let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
+    let log_buffer = LogBuffer::default();
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::fmt::Layer::default()
                 .with_writer(non_blocking)
                 .with_filter(LevelFilter::from(Level::DEBUG)),
         )
+        .with(
+            log_buffer
+                .clone()
+                .with_filter(LevelFilter::from(Level::DEBUG)),
+        )
         .with(tracing_subscriber::fmt::layer().with_filter(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()),
         ))
         .init();
+    startup_timeline.mark("logging initialized");
+
+    if std::env::args().nth(1).as_deref() == Some("schema") {
+        println!("{}", serde_json::to_string_pretty(&Preferences::schema())?);
+        return Ok(());
+    }
+
+    if std::env::args().nth(1).as_deref() == Some("inspect") {
+        // osus-proxy inspect <captured body> [--from-client]
+        let path = std::env::args()
+            .nth(2)
+            .ok_or_else(|| eyre!("usage: osus-proxy inspect <file> [--from-client]"))?;
+        let direction = if std::env::args().any(|arg| arg == "--from-client") {
+            PacketDirection::ClientToServer
+        } else {
+            PacketDirection::ServerToClient
+        };
+        println!("{}", osus_proxy::export::file_to_json(&path, direction)?);
+        return Ok(());
+    }
 
-    // TODO: implement preferences saving and loading?
-    let preferences = Arc::new(Mutex::new(Preferences::default()));
+    if std::env::args().nth(1).as_deref() == Some("uninstall") {
+        let keep_config = std::env::args().any(|arg| arg == "--keep-config");
+        print!("{}", uninstall::run(keep_config));
+        return Ok(());
+    }
+
+    let preferences = Preferences::load().unwrap_or_else(|e| {
+        error!("Failed to load preferences, using the defaults: {}", e);
+        match Preferences::back_up_invalid_file() {
+            Ok(backup_path) => info!("Moved the invalid preferences to {}", backup_path),
+            Err(e) => error!("Failed to back up the invalid preferences: {}", e),
+        }
+        Preferences::default()
+    });
+    startup_timeline.mark("preferences loaded");
+
+    if std::env::args().any(|arg| arg == "--doctor") {
+        doctor::run(&preferences);
+        return Ok(());
+    }
+
+    // osus-proxy --replay <capture directory> [--replay-loop]
+    let replay = match std::env::args().skip_while(|arg| arg != "--replay").nth(1) {
+        Some(directory) => {
+            let looping = std::env::args().any(|arg| arg == "--replay-loop");
+            Some(Replay::load(Path::new(&directory), looping)?)
+        }
+        None => None,
+    };
+
+    let retention_policies = preferences.retention_policies.clone();
+    let preferences = Arc::new(Mutex::new(preferences));
+    let state = Arc::new(Mutex::new(ProxyState {
+        startup_timeline,
+        mirror_stats: MirrorStats::load(),
+        replay,
+        ..Default::default()
+    }));
 
     let preferences_clone = preferences.clone();
-    let _proxy_thread = std::thread::spawn(|| {
+    let state_clone = state.clone();
+    let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+    let proxy_thread = std::thread::spawn(|| {
         tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
             .unwrap()
             .block_on(async {
-                osus_proxy::start(preferences_clone)
+                tokio::task::spawn_blocking(move || janitor::clean(&retention_policies, false));
+                tokio::spawn(telemetry::run(
+                    preferences_clone.clone(),
+                    state_clone.clone(),
+                ));
+                tokio::spawn(osus_proxy::desktop_notifications::run(state_clone.clone()));
+                tokio::spawn(async {
+                    loop {
+                        tokio::time::sleep(logging::SUMMARY_INTERVAL).await;
+                        logging::flush_rate_limited_warnings();
+                    }
+                });
+                let shutdown = async {
+                    // Dropping the sender stops the proxy just the same
+                    let _ = shutdown_receiver.await;
+                };
+                osus_proxy::start(preferences_clone, state_clone, shutdown)
                     .await
                     .expect("Failed to run proxy")
             })
     });
 
-    ui::run(preferences).unwrap();
+    let start_minimized = std::env::args().any(|arg| arg == MINIMIZED_FLAG);
+    ui::run(preferences, state, log_buffer, start_minimized).unwrap();
+
+    let _ = shutdown_sender.send(());
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    while !proxy_thread.is_finished() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    if !proxy_thread.is_finished() {
+        warn!(
+            "The proxy didn't stop within {}s, exiting anyway",
+            SHUTDOWN_TIMEOUT.as_secs()
+        );
+    } else if proxy_thread.join().is_ok() {
+        info!("The proxy stopped");
+    }
 
     Ok(())
 
@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::osus_proxy::bancho::OsuMessage;
+use crate::preferences::AutoReply;
+
+/// Who got an auto-reply when, so nobody gets one for every message they send.
+#[derive(Debug, Default)]
+pub struct AutoReplies {
+    /// By lowercase sender name.
+    last_replied: HashMap<String, Instant>,
+}
+
+impl AutoReplies {
+    /// The private message to send back for `message`, if it's a private message to us and its
+    /// sender didn't get a reply within the cooldown.
+    pub fn reply_to(
+        &mut self,
+        message: &OsuMessage,
+        own_user_id: i32,
+        settings: &AutoReply,
+    ) -> Option<OsuMessage> {
+        let wanted = settings.enabled && !settings.text.trim().is_empty();
+        if !wanted || !message.is_private() || message.sender_id == own_user_id {
+            return None;
+        }
+
+        let sender = message.sender.trim().to_lowercase();
+        let cooldown = Duration::from_secs(settings.cooldown_secs);
+        if self
+            .last_replied
+            .get(&sender)
+            .is_some_and(|last_replied| last_replied.elapsed() < cooldown)
+        {
+            return None;
+        }
+        self.last_replied
+            .retain(|_, last_replied| last_replied.elapsed() < cooldown);
+        self.last_replied.insert(sender, Instant::now());
+
+        // Private messages are addressed to the name of the logged in user
+        Some(OsuMessage {
+            sender: message.recipient.clone(),
+            text: settings.text.clone().into(),
+            recipient: message.sender.clone(),
+            sender_id: own_user_id,
+        })
+    }
+
+    /// Forgets the cooldowns, everyone gets a reply again.
+    pub fn reset(&mut self) {
+        self.last_replied.clear();
+    }
+}
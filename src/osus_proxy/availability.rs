@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+use http::StatusCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestOutcome {
+    /// Any response from the server, even 4xx ones, means it's up.
+    Responded,
+    /// Couldn't connect, timed out, or the CDN in front of it couldn't reach it.
+    Unreachable,
+}
+
+impl RequestOutcome {
+    /// `status` is `None` when there was no response at all.
+    pub fn of(status: Option<StatusCode>) -> Self {
+        match status {
+            Some(
+                StatusCode::BAD_GATEWAY
+                | StatusCode::SERVICE_UNAVAILABLE
+                | StatusCode::GATEWAY_TIMEOUT,
+            )
+            | None => Self::Unreachable,
+            Some(_) => Self::Responded,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AvailabilityChange {
+    WentDown,
+    Recovered,
+}
+
+#[derive(Debug, Default)]
+struct HostAvailability {
+    consecutive_failures: u32,
+    down: bool,
+}
+
+/// Keeps track of which upstream hosts look like they're down.
+#[derive(Debug, Default)]
+pub struct UpstreamAvailability {
+    hosts: HashMap<String, HostAvailability>,
+}
+
+impl UpstreamAvailability {
+    /// A host is considered down after `failure_threshold` unreachable requests in a row.
+    pub fn record(
+        &mut self,
+        host: &str,
+        outcome: RequestOutcome,
+        failure_threshold: u32,
+    ) -> Option<AvailabilityChange> {
+        let availability = self.hosts.entry(host.to_owned()).or_default();
+        match outcome {
+            RequestOutcome::Responded => {
+                availability.consecutive_failures = 0;
+                if availability.down {
+                    availability.down = false;
+                    return Some(AvailabilityChange::Recovered);
+                }
+            }
+            RequestOutcome::Unreachable => {
+                availability.consecutive_failures =
+                    availability.consecutive_failures.saturating_add(1);
+                if !availability.down && availability.consecutive_failures >= failure_threshold {
+                    availability.down = true;
+                    return Some(AvailabilityChange::WentDown);
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn down_hosts(&self) -> impl Iterator<Item = &str> {
+        self.hosts
+            .iter()
+            .filter(|(_, availability)| availability.down)
+            .map(|(host, _)| host.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "c.example.com";
+    const THRESHOLD: u32 = 3;
+
+    #[test]
+    fn only_gateway_errors_and_no_response_count_as_unreachable() {
+        assert_eq!(RequestOutcome::of(None), RequestOutcome::Unreachable);
+        for status in [
+            StatusCode::BAD_GATEWAY,
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::GATEWAY_TIMEOUT,
+        ] {
+            assert_eq!(
+                RequestOutcome::of(Some(status)),
+                RequestOutcome::Unreachable
+            );
+        }
+        let responses = [
+            StatusCode::OK,
+            StatusCode::NOT_FOUND,
+            StatusCode::FORBIDDEN,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ];
+        for status in responses {
+            assert_eq!(RequestOutcome::of(Some(status)), RequestOutcome::Responded);
+        }
+    }
+
+    #[test]
+    fn goes_down_after_the_threshold_and_recovers_once() {
+        let mut availability = UpstreamAvailability::default();
+        assert_eq!(
+            availability.record(HOST, RequestOutcome::Unreachable, THRESHOLD),
+            None
+        );
+        assert_eq!(
+            availability.record(HOST, RequestOutcome::Unreachable, THRESHOLD),
+            None
+        );
+        assert_eq!(
+            availability.record(HOST, RequestOutcome::Unreachable, THRESHOLD),
+            Some(AvailabilityChange::WentDown)
+        );
+        // Only told once
+        assert_eq!(
+            availability.record(HOST, RequestOutcome::Unreachable, THRESHOLD),
+            None
+        );
+        assert_eq!(availability.down_hosts().collect::<Vec<_>>(), vec![HOST]);
+
+        assert_eq!(
+            availability.record(HOST, RequestOutcome::Responded, THRESHOLD),
+            Some(AvailabilityChange::Recovered)
+        );
+        assert_eq!(
+            availability.record(HOST, RequestOutcome::Responded, THRESHOLD),
+            None
+        );
+        assert_eq!(availability.down_hosts().count(), 0);
+    }
+
+    #[test]
+    fn a_response_in_between_resets_the_count() {
+        let mut availability = UpstreamAvailability::default();
+        for _ in 0..THRESHOLD - 1 {
+            availability.record(HOST, RequestOutcome::Unreachable, THRESHOLD);
+        }
+        // Like a 4xx, which still means the server is up
+        assert_eq!(
+            availability.record(HOST, RequestOutcome::Responded, THRESHOLD),
+            None
+        );
+        for _ in 0..THRESHOLD - 1 {
+            assert_eq!(
+                availability.record(HOST, RequestOutcome::Unreachable, THRESHOLD),
+                None
+            );
+        }
+        assert_eq!(availability.down_hosts().count(), 0);
+    }
+
+    #[test]
+    fn hosts_are_tracked_separately() {
+        let mut availability = UpstreamAvailability::default();
+        for _ in 0..THRESHOLD {
+            availability.record(HOST, RequestOutcome::Unreachable, THRESHOLD);
+        }
+        assert_eq!(
+            availability.record("osu.example.com", RequestOutcome::Unreachable, THRESHOLD),
+            None
+        );
+        assert_eq!(availability.down_hosts().collect::<Vec<_>>(), vec![HOST]);
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::janitor::DATA_DIRECTORY;
+use crate::osus_proxy::bancho::PacketDirection;
+
+/// Relative to [`DATA_DIRECTORY`], with a directory per session.
+pub const CAPTURES_DIRECTORY: &str = "captures";
+const INDEX_FILE_NAME: &str = "index.ndjson";
+
+struct CapturedBody {
+    osu_token: String,
+    direction: PacketDirection,
+    timestamp_ms: u128,
+    chunks: Vec<Bytes>,
+    max_session_bytes: u64,
+}
+
+#[derive(Serialize)]
+struct IndexRecord<'a> {
+    seq: u32,
+    file: &'a str,
+    direction: PacketDirection,
+    /// Unix timestamp in milliseconds
+    timestamp_ms: u128,
+    length: usize,
+    osu_token: &'a str,
+}
+
+/// Writes bancho bodies as they came, one `<seq>_<c2s|s2c>.bin` file each, for `inspect` or replaying
+/// them later. The files are written on a thread of their own so recording doesn't hold up the
+/// exchange.
+#[derive(Debug, Default)]
+pub struct Capture {
+    sender: Option<Sender<CapturedBody>>,
+}
+
+impl Capture {
+    /// `chunks` make up the body, in order. Bodies past `max_session_bytes` in the session of
+    /// `osu_token` are dropped.
+    pub fn record(
+        &mut self,
+        osu_token: &str,
+        direction: PacketDirection,
+        chunks: Vec<Bytes>,
+        max_session_bytes: u64,
+    ) {
+        let sender = self.sender.get_or_insert_with(start_writer);
+        let body = CapturedBody {
+            osu_token: osu_token.to_owned(),
+            direction,
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since_epoch| since_epoch.as_millis())
+                .unwrap_or_default(),
+            chunks,
+            max_session_bytes,
+        };
+        if sender.send(body).is_err() {
+            warn!("The capture writer stopped, dropping a {} body", direction);
+            // Started again on the next body
+            self.sender = None;
+        }
+    }
+}
+
+pub fn open_directory() -> io::Result<()> {
+    crate::janitor::open_data_directory(CAPTURES_DIRECTORY)
+}
+
+fn start_writer() -> Sender<CapturedBody> {
+    let (sender, receiver) = mpsc::channel();
+    if let Err(e) = std::thread::Builder::new()
+        .name("capture writer".to_owned())
+        .spawn(move || write_captures(receiver))
+    {
+        warn!("Failed to start the capture writer: {}", e);
+    }
+    sender
+}
+
+/// Runs until every sender is gone.
+fn write_captures(receiver: Receiver<CapturedBody>) {
+    let mut sessions: HashMap<String, SessionCapture> = HashMap::new();
+    for body in receiver {
+        let session = match sessions.get_mut(&body.osu_token) {
+            Some(session) => session,
+            None => match SessionCapture::create() {
+                Ok(session) => {
+                    info!(
+                        "Recording bancho traffic to {}",
+                        session.directory.display()
+                    );
+                    sessions.entry(body.osu_token.clone()).or_insert(session)
+                }
+                Err(e) => {
+                    warn!("Failed to start a capture: {}", e);
+                    continue;
+                }
+            },
+        };
+        if let Err(e) = session.write(&body) {
+            warn!(
+                "Failed to write to the capture in {}: {}",
+                session.directory.display(),
+                e
+            );
+        }
+    }
+}
+
+struct SessionCapture {
+    directory: PathBuf,
+    index: File,
+    next_seq: u32,
+    written_bytes: u64,
+    capped: bool,
+}
+
+impl SessionCapture {
+    /// In a directory named after the time it started.
+    fn create() -> io::Result<Self> {
+        let captures = Path::new(DATA_DIRECTORY).join(CAPTURES_DIRECTORY);
+        fs::create_dir_all(&captures)?;
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or_default();
+        let mut directory = captures.join(started.to_string());
+        let mut attempt = 1;
+        // Another session starting in the same second, e.g. tournament clients
+        while let Err(e) = fs::create_dir(&directory) {
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                return Err(e);
+            }
+            attempt += 1;
+            directory = captures.join(format!("{}-{}", started, attempt));
+        }
+
+        let index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(directory.join(INDEX_FILE_NAME))?;
+        Ok(Self {
+            directory,
+            index,
+            next_seq: 1,
+            written_bytes: 0,
+            capped: false,
+        })
+    }
+
+    fn write(&mut self, body: &CapturedBody) -> io::Result<()> {
+        if self.capped {
+            return Ok(());
+        }
+        let length: usize = body.chunks.iter().map(Bytes::len).sum();
+        if self.written_bytes + length as u64 > body.max_session_bytes {
+            warn!(
+                "The capture in {} reached {} bytes, not recording the rest of the session",
+                self.directory.display(),
+                self.written_bytes
+            );
+            self.capped = true;
+            return Ok(());
+        }
+
+        let direction = match body.direction {
+            PacketDirection::ClientToServer => "c2s",
+            PacketDirection::ServerToClient => "s2c",
+        };
+        let file_name = format!("{}_{}.bin", self.next_seq, direction);
+        let mut file = File::create(self.directory.join(&file_name))?;
+        for chunk in &body.chunks {
+            file.write_all(chunk)?;
+        }
+
+        let record = IndexRecord {
+            seq: self.next_seq,
+            file: &file_name,
+            direction: body.direction,
+            timestamp_ms: body.timestamp_ms,
+            length,
+            osu_token: &body.osu_token,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.index.write_all(&line)?;
+
+        self.next_seq += 1;
+        self.written_bytes += length as u64;
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+use std::borrow::Cow;
+
+use regex::{Captures, Regex, RegexBuilder};
+use tracing::warn;
+
+use crate::preferences::{ChatFilter, ChatFilterMode, ChatFilterPattern};
+
+/// What [`CompiledChatFilter::apply`] did to a message.
+#[derive(Debug)]
+pub enum Filtered {
+    Unchanged,
+    /// The text with every match replaced by asterisks, one for each character.
+    Censored(String),
+    /// The pattern that matched first.
+    Dropped(String),
+}
+
+/// The patterns of [`ChatFilter`] compiled, recompiled whenever they change.
+#[derive(Debug, Default)]
+pub struct CompiledChatFilter {
+    compiled_from: Vec<ChatFilterPattern>,
+    /// The enabled patterns that compiled, along with what the user wrote.
+    regexes: Vec<(String, Regex)>,
+}
+
+impl CompiledChatFilter {
+    pub fn apply(&mut self, text: &str, filter: &ChatFilter) -> Filtered {
+        if self.compiled_from != filter.patterns {
+            self.compile(&filter.patterns);
+        }
+
+        match filter.mode {
+            ChatFilterMode::Drop => {
+                match self.regexes.iter().find(|(_, regex)| regex.is_match(text)) {
+                    Some((pattern, _)) => Filtered::Dropped(pattern.clone()),
+                    None => Filtered::Unchanged,
+                }
+            }
+            ChatFilterMode::Censor => {
+                let mut censored = Cow::Borrowed(text);
+                for (_, regex) in &self.regexes {
+                    let replaced = match regex.replace_all(&censored, asterisks) {
+                        Cow::Owned(replaced) => Some(replaced),
+                        Cow::Borrowed(_) => None,
+                    };
+                    if let Some(replaced) = replaced {
+                        censored = Cow::Owned(replaced);
+                    }
+                }
+                match censored {
+                    Cow::Owned(censored) => Filtered::Censored(censored),
+                    Cow::Borrowed(_) => Filtered::Unchanged,
+                }
+            }
+        }
+    }
+
+    /// Patterns that don't compile are left out, with a warning.
+    fn compile(&mut self, patterns: &[ChatFilterPattern]) {
+        self.regexes = patterns
+            .iter()
+            .filter(|pattern| pattern.enabled && !pattern.pattern.is_empty())
+            .filter_map(|pattern| match build_regex(pattern) {
+                Ok(regex) => Some((pattern.pattern.clone(), regex)),
+                Err(e) => {
+                    warn!(
+                        "Ignoring the chat filter pattern {:?}: {}",
+                        pattern.pattern, e
+                    );
+                    None
+                }
+            })
+            .collect();
+        self.compiled_from = patterns.to_vec();
+    }
+}
+
+/// Characters rather than bytes, so a censored word looks as long as it was.
+fn asterisks(captures: &Captures) -> String {
+    "*".repeat(captures[0].chars().count())
+}
+
+/// Plain substrings are escaped, so they match literally.
+pub fn build_regex(pattern: &ChatFilterPattern) -> Result<Regex, regex::Error> {
+    let source = if pattern.regex {
+        pattern.pattern.clone()
+    } else {
+        regex::escape(&pattern.pattern)
+    };
+    RegexBuilder::new(&source)
+        .case_insensitive(!pattern.case_sensitive)
+        .build()
+}
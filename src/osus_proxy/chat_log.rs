@@ -0,0 +1,162 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tracing::{info, warn};
+
+use crate::janitor::DATA_DIRECTORY;
+use crate::osus_proxy::bancho::{OsuMessage, PacketDirection};
+
+/// Relative to [`DATA_DIRECTORY`], then `<server>/<channel or user>/<date>.txt`.
+pub const CHAT_LOGS_DIRECTORY: &str = "chatlogs";
+
+struct ChatLine {
+    server: String,
+    /// The channel, or the other user for private messages.
+    conversation: String,
+    timestamp_secs: u64,
+    sender: String,
+    text: String,
+}
+
+/// Appends chat to a text file per conversation and day. Like [`crate::osus_proxy::capture::Capture`]
+/// the files are written on a thread of their own.
+#[derive(Debug, Default)]
+pub struct ChatLog {
+    sender: Option<Sender<ChatLine>>,
+}
+
+impl ChatLog {
+    /// `own_name` stands in for the sender of the messages we send, the game leaves it empty.
+    pub fn record(
+        &mut self,
+        server: &str,
+        direction: PacketDirection,
+        message: &OsuMessage,
+        own_name: Option<&str>,
+    ) {
+        let conversation = match direction {
+            PacketDirection::ServerToClient if message.is_private() => &message.sender,
+            _ => &message.recipient,
+        };
+        let sender = match direction {
+            PacketDirection::ClientToServer if message.sender.trim().is_empty() => {
+                own_name.unwrap_or("me")
+            }
+            _ => message.sender.as_str(),
+        };
+        let line = ChatLine {
+            server: server.to_owned(),
+            conversation: conversation.to_string(),
+            timestamp_secs: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since_epoch| since_epoch.as_secs())
+                .unwrap_or_default(),
+            sender: sender.to_owned(),
+            text: message.text.to_string(),
+        };
+
+        let sender = self.sender.get_or_insert_with(start_writer);
+        if sender.send(line).is_err() {
+            warn!(
+                "The chat log writer stopped, not logging a message in {}",
+                conversation
+            );
+            // Started again on the next message
+            self.sender = None;
+        }
+    }
+}
+
+pub fn open_directory() -> io::Result<()> {
+    crate::janitor::open_data_directory(CHAT_LOGS_DIRECTORY)
+}
+
+fn start_writer() -> Sender<ChatLine> {
+    let (sender, receiver) = mpsc::channel();
+    if let Err(e) = std::thread::Builder::new()
+        .name("chat log writer".to_owned())
+        .spawn(move || write_chat_logs(receiver))
+    {
+        warn!("Failed to start the chat log writer: {}", e);
+    }
+    sender
+}
+
+/// Runs until every sender is gone.
+fn write_chat_logs(receiver: Receiver<ChatLine>) {
+    let mut logging_to = None;
+    for line in receiver {
+        let (date, time) = utc_date_and_time(line.timestamp_secs);
+        let path = Path::new(DATA_DIRECTORY)
+            .join(CHAT_LOGS_DIRECTORY)
+            .join(file_name_safe(&line.server))
+            .join(file_name_safe(&line.conversation))
+            .join(format!("{}.txt", date));
+        if logging_to.as_ref() != Some(&path) {
+            info!(
+                "Logging chat in {} to {}",
+                line.conversation,
+                path.display()
+            );
+            logging_to = Some(path.clone());
+        }
+        // Keeps every message on a line of its own
+        let text = line.text.replace(['\r', '\n'], " ");
+        if let Err(e) = append_line(&path, &format!("[{}] {}: {}", time, line.sender, text)) {
+            warn!("Failed to write to the chat log {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn append_line(path: &Path, line: &str) -> io::Result<()> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)
+}
+
+/// Channel and user names can have characters that aren't allowed in file names on Windows.
+fn file_name_safe(name: &str) -> String {
+    let name = name
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    match name.as_str() {
+        "" | "." | ".." => "_".to_owned(),
+        _ => name,
+    }
+}
+
+/// E.g. `2024-05-01` and `13:37:00`, in UTC so a day's file doesn't depend on the time zone.
+pub fn utc_date_and_time(timestamp_secs: u64) -> (String, String) {
+    let days = (timestamp_secs / 86_400) as i64;
+    let secs = timestamp_secs % 86_400;
+    // civil_from_days from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (
+        format!("{:04}-{:02}-{:02}", year, month, day),
+        format!("{:02}:{:02}:{:02}", secs / 3_600, secs / 60 % 60, secs % 60),
+    )
+}
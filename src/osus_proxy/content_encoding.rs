@@ -0,0 +1,74 @@
+use std::io::{self, Read};
+
+use bytes::Bytes;
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use http::{header, HeaderMap};
+
+use crate::logging::RateLimitedWarning;
+
+static UNDECODABLE_BODY_WARNING: RateLimitedWarning =
+    RateLimitedWarning::new("Bancho bodies with an encoding we can't undo");
+
+/// The bancho body without its `Content-Encoding`, or `None` if the encoding is unknown or the body
+/// doesn't decompress, in which case it should be passed through untouched. Whoever sends on the
+/// decoded body has to [`strip`] the header.
+pub fn decode(headers: &HeaderMap, body: &Bytes) -> Option<Bytes> {
+    let Some(encoding) = headers.get(header::CONTENT_ENCODING) else {
+        return Some(body.clone());
+    };
+    let encoding = encoding
+        .to_str()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let decoded = match encoding.as_str() {
+        "" | "identity" => return Some(body.clone()),
+        "gzip" | "x-gzip" => read_to_end(GzDecoder::new(body.as_ref())),
+        // Supposed to be zlib, but some servers send raw deflate
+        "deflate" => read_to_end(ZlibDecoder::new(body.as_ref()))
+            .or_else(|_| read_to_end(DeflateDecoder::new(body.as_ref()))),
+        _ => {
+            UNDECODABLE_BODY_WARNING.warn(|| {
+                format!(
+                    "Unknown Content-Encoding {:?}, passing the body through",
+                    encoding
+                )
+            });
+            return None;
+        }
+    };
+
+    match decoded {
+        Ok(decoded) => Some(decoded.into()),
+        Err(e) => {
+            UNDECODABLE_BODY_WARNING.warn(|| {
+                format!(
+                    "Failed to decompress a {} body, passing it through: {}",
+                    encoding, e
+                )
+            });
+            None
+        }
+    }
+}
+
+/// Whether the body is sent as it is, so it can be decoded as it arrives.
+pub fn is_identity(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_ENCODING)
+        .is_none_or(|encoding| {
+            let encoding = encoding.to_str().unwrap_or_default().trim();
+            encoding.is_empty() || encoding.eq_ignore_ascii_case("identity")
+        })
+}
+
+/// For bodies replaced by one built from the decoded packets, those are never compressed.
+pub fn strip(headers: &mut HeaderMap) {
+    headers.remove(header::CONTENT_ENCODING);
+}
+
+fn read_to_end(mut reader: impl Read) -> io::Result<Vec<u8>> {
+    let mut decoded = vec![];
+    reader.read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
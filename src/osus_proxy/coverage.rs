@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::osus_proxy::bancho::PacketDirection;
+
+/// How many distinct packet ids we keep track of, so a server spamming garbage can't grow this forever.
+const MAX_TRACKED_IDS: usize = 50;
+/// Only this many bytes of the first occurrence of a packet are kept as a sample.
+const MAX_SAMPLE_BYTES: usize = 256;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CoverageEntry {
+    pub id: u16,
+    pub count: u64,
+    pub first_seen_direction: PacketDirection,
+    pub sample_length: usize,
+    pub sample_hexdump: String,
+}
+
+/// Packets that we don't decode yet (the ones that end up as `BanchoPacket::Other`) seen this session.
+#[derive(Debug, Default)]
+pub struct ProtocolCoverage {
+    entries: BTreeMap<u16, CoverageEntry>,
+    untracked_count: u64,
+}
+
+impl ProtocolCoverage {
+    pub fn record(&mut self, id: u16, direction: PacketDirection, data: &[u8]) {
+        if let Some(entry) = self.entries.get_mut(&id) {
+            entry.count += 1;
+            return;
+        }
+
+        if self.entries.len() >= MAX_TRACKED_IDS {
+            self.untracked_count += 1;
+            return;
+        }
+
+        let sample = &data[..data.len().min(MAX_SAMPLE_BYTES)];
+        self.entries.insert(
+            id,
+            CoverageEntry {
+                id,
+                count: 1,
+                first_seen_direction: direction,
+                sample_length: data.len(),
+                sample_hexdump: rhexdump::rhexdumps!(sample),
+            },
+        );
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &CoverageEntry> {
+        self.entries.values()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Packets with ids we stopped tracking because of the cap.
+    pub fn untracked_count(&self) -> u64 {
+        self.untracked_count
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        #[derive(Serialize)]
+        struct Export<'a> {
+            packets: Vec<&'a CoverageEntry>,
+            untracked_count: u64,
+        }
+
+        serde_json::to_string_pretty(&Export {
+            packets: self.entries.values().collect(),
+            untracked_count: self.untracked_count,
+        })
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use notify_rust::Notification;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::osus_proxy::bancho::{OsuMessage, UserAction};
+use crate::preferences::DesktopNotifications;
+use crate::state::ProxyState;
+
+/// Longer messages are cut off, notifications only have room for a few lines.
+const MAX_BODY_CHARS: usize = 160;
+
+#[derive(Debug)]
+struct Notice {
+    title: String,
+    body: String,
+}
+
+/// Picks the messages worth a desktop notification and hands them to [`run`]. Showing one blocks
+/// until it's clicked or dismissed, which can't happen while processing packets.
+#[derive(Debug, Default)]
+pub struct DesktopNotifier {
+    /// `None` until [`run`] started.
+    sender: Option<UnboundedSender<Notice>>,
+    /// By lowercase sender name.
+    last_notified: HashMap<String, Instant>,
+    game_in_background: bool,
+    /// A notification was clicked, the UI brings its window to the front.
+    pub focus_requested: bool,
+}
+
+impl DesktopNotifier {
+    pub fn action_changed(&mut self, action: &UserAction) {
+        self.game_in_background = likely_unfocused(action);
+    }
+
+    /// Notifies about `message` if it's a private message to us or, with mentions on, a channel
+    /// message with `own_name` in it, unless its sender caused one within the cooldown.
+    pub fn message_received(
+        &mut self,
+        message: &OsuMessage,
+        own_user_id: i32,
+        own_name: Option<&str>,
+        settings: &DesktopNotifications,
+    ) {
+        if !settings.enabled || message.sender_id == own_user_id {
+            return;
+        }
+        if settings.only_when_away && !self.game_in_background {
+            return;
+        }
+        let title = if message.is_private() {
+            format!("{} messaged you", message.sender)
+        } else if settings.mentions && own_name.is_some_and(|name| mentions(&message.text, name)) {
+            format!("{} mentioned you in {}", message.sender, message.recipient)
+        } else {
+            return;
+        };
+        let Some(sender) = &self.sender else {
+            return;
+        };
+
+        let sender_name = message.sender.trim().to_lowercase();
+        let cooldown = Duration::from_secs(settings.cooldown_secs);
+        if self
+            .last_notified
+            .get(&sender_name)
+            .is_some_and(|last_notified| last_notified.elapsed() < cooldown)
+        {
+            debug!(
+                "Not notifying about another message from {} yet",
+                message.sender
+            );
+            return;
+        }
+        self.last_notified
+            .retain(|_, last_notified| last_notified.elapsed() < cooldown);
+        self.last_notified.insert(sender_name, Instant::now());
+
+        let mut body = message
+            .text
+            .chars()
+            .take(MAX_BODY_CHARS)
+            .collect::<String>();
+        if message.text.chars().count() > MAX_BODY_CHARS {
+            body.push('…');
+        }
+        if sender.send(Notice { title, body }).is_err() {
+            warn!(
+                "The desktop notifier stopped, not notifying about a message from {}",
+                message.sender
+            );
+        }
+    }
+}
+
+/// The client doesn't say whether it has focus. It goes afk after a while in the background,
+/// pauses a play when tabbed out of, and sits idle in the menus otherwise.
+pub fn likely_unfocused(action: &UserAction) -> bool {
+    matches!(
+        action,
+        UserAction::Afk | UserAction::Paused | UserAction::Idle
+    )
+}
+
+/// Case-insensitive, as a whole word so `peppy` isn't mentioned by `peppyfan`.
+fn mentions(text: &str, name: &str) -> bool {
+    let text = text.to_lowercase();
+    let name = name.trim().to_lowercase();
+    !name.is_empty()
+        && text.match_indices(&name).any(|(i, _)| {
+            let before = text[..i].chars().next_back();
+            let after = text[i + name.len()..].chars().next();
+            !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+        })
+}
+
+/// Shows the notifications [`DesktopNotifier`] queues, for as long as the proxy runs.
+pub async fn run(state: Arc<Mutex<ProxyState>>) {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    state.lock().await.desktop_notifier.sender = Some(sender);
+
+    while let Some(notice) = receiver.recv().await {
+        let state = state.clone();
+        tokio::task::spawn_blocking(move || match show(&notice) {
+            Ok(true) => {
+                info!("Desktop notification {:?} clicked", notice.title);
+                state.blocking_lock().desktop_notifier.focus_requested = true;
+            }
+            Ok(false) => {}
+            Err(e) => warn!("Failed to show a desktop notification: {}", e),
+        });
+    }
+}
+
+/// Whether the notification was clicked, only the freedesktop notification servers tell.
+fn show(notice: &Notice) -> notify_rust::error::Result<bool> {
+    let mut notification = Notification::new();
+    notification
+        .appname("osus proxy")
+        .summary(&notice.title)
+        .body(&notice.body);
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    {
+        let handle = notification.action("default", "Open osus proxy").show()?;
+        let mut clicked = false;
+        handle.wait_for_action(|action| clicked = action == "default");
+        Ok(clicked)
+    }
+    #[cfg(not(all(unix, not(target_os = "macos"))))]
+    {
+        notification.show()?;
+        Ok(false)
+    }
+}
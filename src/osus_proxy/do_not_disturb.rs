@@ -0,0 +1,56 @@
+use std::collections::VecDeque;
+
+use tracing::warn;
+
+use crate::osus_proxy::bancho::OsuMessage;
+use crate::osus_proxy::CHAT_LOG_TARGET;
+
+/// The oldest messages are dropped once there are more than this.
+const MAX_HELD_MESSAGES: usize = 200;
+/// Handed back with each response once do not disturb is off, so the injected packets stay
+/// within the processing limits.
+pub const RELEASE_BATCH: usize = 8;
+
+/// Private messages held back while do not disturb is on, see
+/// [`crate::preferences::DoNotDisturb`].
+#[derive(Debug, Default)]
+pub struct HeldMessages {
+    /// With the id of the user they were sent to, oldest first.
+    messages: VecDeque<(i32, OsuMessage)>,
+}
+
+impl HeldMessages {
+    pub fn hold(&mut self, user_id: i32, message: OsuMessage) {
+        if self.messages.len() >= MAX_HELD_MESSAGES {
+            if let Some((_, dropped)) = self.messages.pop_front() {
+                warn!(
+                    target: CHAT_LOG_TARGET,
+                    "Holding more than {} messages, dropping one from {}: {:?}",
+                    MAX_HELD_MESSAGES, dropped.sender, dropped.text
+                );
+            }
+        }
+        self.messages.push_back((user_id, message));
+    }
+
+    /// Up to `limit` of the messages held for `user_id`, oldest first.
+    pub fn release(&mut self, user_id: i32, limit: usize) -> Vec<OsuMessage> {
+        let mut released = vec![];
+        self.messages.retain(|(held_for, message)| {
+            if *held_for != user_id || released.len() >= limit {
+                return true;
+            }
+            released.push(message.clone());
+            false
+        });
+        released
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
@@ -0,0 +1,225 @@
+use color_eyre::Result;
+use osus_bancho::codec::decode_bancho_packets;
+use serde::Serialize;
+
+use super::bancho::{BanchoPacket, PacketDirection};
+use crate::preferences::ProcessingLimits;
+
+/// Bump this when the layout of the export changes.
+const EXPORT_SCHEMA_VERSION: u32 = 3;
+/// Shipped inside every export, so whoever gets the file doesn't need the source to read it.
+const EXPORT_CONVENTIONS: &str = "Every packet has its numeric id, the direction it was sent in and the name of its \
+     BanchoPacket variant. `fields` holds the decoded fields in snake_case, or the plain value for single-field \
+     packets. Raw bytes, e.g. the data of packets the proxy doesn't decode (`Other`), are base64 encoded. Strings \
+     the game sent as null are null, strings it sent empty are \"\".";
+
+#[derive(Serialize)]
+struct PacketExport<'a> {
+    schema_version: u32,
+    conventions: &'static str,
+    packets: Vec<ExportedPacket<'a>>,
+}
+
+#[derive(Serialize)]
+struct ExportedPacket<'a> {
+    id: u16,
+    direction: PacketDirection,
+    #[serde(flatten)]
+    packet: &'a BanchoPacket,
+}
+
+/// The decoded packets as pretty JSON for sharing.
+pub fn packets_to_json(packets: &[BanchoPacket], direction: PacketDirection) -> Result<String> {
+    let export = PacketExport {
+        schema_version: EXPORT_SCHEMA_VERSION,
+        conventions: EXPORT_CONVENTIONS,
+        packets: packets
+            .iter()
+            .map(|packet| ExportedPacket {
+                id: packet.id(),
+                direction,
+                packet,
+            })
+            .collect(),
+    };
+    Ok(serde_json::to_string_pretty(&export)?)
+}
+
+/// Decodes a captured bancho body, e.g. from a request or response saved from the log.
+pub fn file_to_json(path: &str, direction: PacketDirection) -> Result<String> {
+    let bytes = std::fs::read(path)?;
+    let packets = decode_bancho_packets(
+        &bytes.into(),
+        direction,
+        ProcessingLimits::default().max_packet_length,
+    )?;
+    packets_to_json(&packets, direction)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::osus_proxy::bancho::{
+        BanchoPrivileges, GameMode, Mods, OsuChannel, OsuMessage, OsuString, SpectateFrameBundle,
+        UserAction,
+    };
+
+    /// One of each, with values that are awkward to serialize where a field can hold them.
+    fn every_packet() -> Vec<BanchoPacket> {
+        let text = |text: &str| OsuString::from(text.to_owned());
+        let message = || OsuMessage {
+            sender: text("peppy"),
+            text: text("hi \u{1f44b}"),
+            recipient: text("#osu"),
+            sender_id: 2,
+        };
+        let channel = || OsuChannel {
+            name: text("#osu"),
+            topic: OsuString::default(),
+            player_count: u16::MAX,
+        };
+        let frames = || SpectateFrameBundle {
+            frame_count: 0,
+            has_score_frame: false,
+            data: Bytes::from_static(&[0, 0, 0, 0, 0, 0, 0, 0, 0]),
+        };
+        vec![
+            BanchoPacket::ChangeAction {
+                action: UserAction::Unknown,
+                info_text: text(""),
+                map_md5: OsuString::default(),
+                mods: Mods::all(),
+                mode: GameMode::Unknown(9),
+                map_id: -1,
+            },
+            BanchoPacket::SendPublicMessage(message()),
+            BanchoPacket::Ping,
+            BanchoPacket::UserId(-1),
+            BanchoPacket::SendMessage(message()),
+            BanchoPacket::UserStats {
+                user_id: i32::MAX,
+                action: UserAction::Playing,
+                info_text: text("Artist - Title [Insane]"),
+                map_md5: text("d41d8cd98f00b204e9800998ecf8427e"),
+                mods: Mods::HIDDEN | Mods::DOUBLE_TIME,
+                mode: GameMode::Mania,
+                map_id: 75,
+                ranked_score: i64::MAX,
+                accuracy: f32::NAN,
+                playcount: 0,
+                total_score: i64::MIN,
+                global_rank: 0,
+                pp: i16::MIN,
+            },
+            BanchoPacket::UserLogout {
+                user_id: 3,
+                quit_state: 0,
+            },
+            BanchoPacket::SpectateFrames(frames()),
+            BanchoPacket::SendSpectateFrames(frames()),
+            BanchoPacket::SpectatorJoined { user_id: 3 },
+            BanchoPacket::SpectatorLeft { user_id: 3 },
+            BanchoPacket::CantSpectate { user_id: 3 },
+            BanchoPacket::Notification(text("\"quoted\"\n\\")),
+            BanchoPacket::SendPrivateMessage(message()),
+            BanchoPacket::ChannelJoinSuccess(text("#osu")),
+            BanchoPacket::ChannelInfo(channel()),
+            BanchoPacket::ChannelKick(text("#osu")),
+            BanchoPacket::ChannelAutoJoin(channel()),
+            BanchoPacket::FriendsList(vec![]),
+            BanchoPacket::FellowSpectatorJoined { user_id: 3 },
+            BanchoPacket::FellowSpectatorLeft { user_id: 3 },
+            BanchoPacket::Privilege {
+                privileges: BanchoPrivileges::all(),
+            },
+            BanchoPacket::ProtocolVersion(19),
+            BanchoPacket::MainMenuIcon {
+                image_url: OsuString::default(),
+                click_url: None,
+            },
+            BanchoPacket::UserPresence {
+                user_id: 3,
+                name: text("BanchoBot"),
+                utc_offset: i8::MIN,
+                country_code: 0,
+                bancho_privileges: u8::MAX,
+                longitude: f32::INFINITY,
+                latitude: f32::NEG_INFINITY,
+                global_rank: 0,
+            },
+            BanchoPacket::UserStatsRequest(vec![2, 3]),
+            BanchoPacket::Restart { ms: 0 },
+            BanchoPacket::SilenceEnd { seconds: 0 },
+            BanchoPacket::UserSilenced { user_id: 3 },
+            BanchoPacket::UserPresenceBundle(vec![i32::MIN]),
+            BanchoPacket::UserPresenceRequest(vec![]),
+            BanchoPacket::UserPresenceRequestAll { ingame_time: 0 },
+            BanchoPacket::SwitchTournamentServer(text("c.example.com")),
+            BanchoPacket::Other {
+                id: 999,
+                data: Bytes::new(),
+            },
+        ]
+    }
+
+    #[test]
+    fn every_packet_serializes() {
+        let packets = every_packet();
+        for packet in &packets {
+            // Adding a variant breaks this match, add one to every_packet too
+            match packet {
+                BanchoPacket::ChangeAction { .. }
+                | BanchoPacket::SendPublicMessage(_)
+                | BanchoPacket::Ping
+                | BanchoPacket::UserId(_)
+                | BanchoPacket::SendMessage(_)
+                | BanchoPacket::UserStats { .. }
+                | BanchoPacket::UserLogout { .. }
+                | BanchoPacket::SpectateFrames(_)
+                | BanchoPacket::SendSpectateFrames(_)
+                | BanchoPacket::SpectatorJoined { .. }
+                | BanchoPacket::SpectatorLeft { .. }
+                | BanchoPacket::CantSpectate { .. }
+                | BanchoPacket::Notification(_)
+                | BanchoPacket::SendPrivateMessage(_)
+                | BanchoPacket::ChannelJoinSuccess(_)
+                | BanchoPacket::ChannelInfo(_)
+                | BanchoPacket::ChannelKick(_)
+                | BanchoPacket::ChannelAutoJoin(_)
+                | BanchoPacket::FriendsList(_)
+                | BanchoPacket::FellowSpectatorJoined { .. }
+                | BanchoPacket::FellowSpectatorLeft { .. }
+                | BanchoPacket::Privilege { .. }
+                | BanchoPacket::ProtocolVersion(_)
+                | BanchoPacket::MainMenuIcon { .. }
+                | BanchoPacket::UserPresence { .. }
+                | BanchoPacket::UserStatsRequest(_)
+                | BanchoPacket::Restart { .. }
+                | BanchoPacket::SilenceEnd { .. }
+                | BanchoPacket::UserSilenced { .. }
+                | BanchoPacket::UserPresenceBundle(_)
+                | BanchoPacket::UserPresenceRequest(_)
+                | BanchoPacket::UserPresenceRequestAll { .. }
+                | BanchoPacket::SwitchTournamentServer(_)
+                | BanchoPacket::Other { .. } => {}
+            }
+        }
+        let names = packets
+            .iter()
+            .map(<&'static str>::from)
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(names.len(), packets.len(), "a variant is in there twice");
+
+        let json = packets_to_json(&packets, PacketDirection::ServerToClient).unwrap();
+        let export: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(export["schema_version"], EXPORT_SCHEMA_VERSION);
+        let exported = export["packets"].as_array().unwrap();
+        assert_eq!(exported.len(), packets.len());
+        for (exported, packet) in exported.iter().zip(&packets) {
+            assert_eq!(exported["id"], packet.id());
+            assert_eq!(exported["name"], <&'static str>::from(packet));
+        }
+    }
+}
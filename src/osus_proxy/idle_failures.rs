@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Shorter gaps happen all the time between bancho polls, a connection doesn't get killed for those.
+const MIN_IDLE: Duration = Duration::from_secs(30);
+/// What the warning about such a failure says, the doctor looks for it in the log.
+pub const IDLE_FAILURE_MARKER: &str = "failed after its connection sat idle for";
+
+/// Some servers sit behind proxies that kill idle connections well before the pool lets go of
+/// them, and the first request after idling then fails on a dead connection. Remembers when each
+/// upstream host was last sent a request to spot that.
+#[derive(Debug, Default)]
+pub struct IdleFailures {
+    last_request: HashMap<String, Instant>,
+}
+
+impl IdleFailures {
+    /// How long the host was idle if this request failed on a connection the pool kept around
+    /// for at least [`MIN_IDLE`], which is probably why it failed.
+    pub fn record(
+        &mut self,
+        host: &str,
+        started: Instant,
+        failed: bool,
+        pool_idle_timeout: Duration,
+    ) -> Option<Duration> {
+        let idle = self
+            .last_request
+            .insert(host.to_owned(), started)
+            .map(|last_request| started.saturating_duration_since(last_request))?;
+        (failed && idle >= MIN_IDLE && idle < pool_idle_timeout).then_some(idle)
+    }
+}
+
+/// The warning for a request [`IdleFailures::record`] flagged.
+pub fn describe(host: &str, idle: Duration) -> String {
+    format!(
+        "The first request to {} {} {}s, the server may close idle connections sooner than the pool does",
+        host,
+        IDLE_FAILURE_MARKER,
+        idle.as_secs()
+    )
+}
+
+/// How long the connections had been idle in the warnings in `log`, shortest first.
+pub fn idle_times_in_log(log: &str) -> Vec<u64> {
+    let mut idle_times = log
+        .lines()
+        .filter_map(|line| line.split_once(IDLE_FAILURE_MARKER))
+        .filter_map(|(_, rest)| rest.trim_start().split('s').next()?.parse().ok())
+        .collect::<Vec<_>>();
+    idle_times.sort_unstable();
+    idle_times
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+    #[test]
+    fn flags_failures_after_idling() {
+        let mut idle_failures = IdleFailures::default();
+        let start = Instant::now();
+        assert_eq!(
+            idle_failures.record("c.example.com", start, false, POOL_IDLE_TIMEOUT),
+            None
+        );
+        let later = start + Duration::from_secs(45);
+        assert_eq!(
+            idle_failures.record("c.example.com", later, true, POOL_IDLE_TIMEOUT),
+            Some(Duration::from_secs(45))
+        );
+    }
+
+    #[test]
+    fn ignores_the_first_request_short_gaps_and_expired_connections() {
+        let mut idle_failures = IdleFailures::default();
+        let start = Instant::now();
+        // Nothing to have been idle since
+        assert_eq!(
+            idle_failures.record("c.example.com", start, true, POOL_IDLE_TIMEOUT),
+            None
+        );
+        let soon = start + Duration::from_secs(5);
+        assert_eq!(
+            idle_failures.record("c.example.com", soon, true, POOL_IDLE_TIMEOUT),
+            None
+        );
+        // The pool had already dropped the connection, so it wasn't reused
+        let much_later = soon + Duration::from_secs(120);
+        assert_eq!(
+            idle_failures.record("c.example.com", much_later, true, POOL_IDLE_TIMEOUT),
+            None
+        );
+        // Hosts are tracked separately
+        assert_eq!(
+            idle_failures.record("osu.example.com", much_later, true, POOL_IDLE_TIMEOUT),
+            None
+        );
+    }
+
+    #[test]
+    fn finds_the_warnings_in_the_log() {
+        let log = format!(
+            "2023-10-01T12:00:00Z  WARN osus_proxy::osus_proxy: {}\n\
+             2023-10-01T12:00:05Z  INFO osus_proxy::osus_proxy: Changing action to Idle\n\
+             2023-10-01T12:03:00Z  WARN osus_proxy::osus_proxy: {}\n",
+            describe("c.example.com", Duration::from_secs(75)),
+            describe("c.example.com", Duration::from_secs(41)),
+        );
+        assert_eq!(idle_times_in_log(&log), vec![41, 75]);
+        assert!(idle_times_in_log("nothing to see here").is_empty());
+    }
+}
@@ -0,0 +1,33 @@
+use std::collections::HashMap;
+
+use crate::osus_proxy::bancho::{BanchoPacket, PacketDirection};
+
+/// Packets the proxy wants to send on behalf of a logged in user, or to them. Bancho only talks
+/// when the client polls, so they ride along with the next body going that way in the user's
+/// session.
+#[derive(Debug, Default)]
+pub struct PendingInjections {
+    packets: HashMap<(i32, PacketDirection), Vec<BanchoPacket>>,
+}
+
+impl PendingInjections {
+    pub fn push(&mut self, user_id: i32, direction: PacketDirection, packet: BanchoPacket) {
+        self.packets
+            .entry((user_id, direction))
+            .or_default()
+            .push(packet);
+    }
+
+    /// Everything queued for the next body going in `direction`, oldest first.
+    pub fn take(&mut self, user_id: i32, direction: PacketDirection) -> Vec<BanchoPacket> {
+        self.packets
+            .remove(&(user_id, direction))
+            .unwrap_or_default()
+    }
+
+    /// Drops what was queued for a session that's over, so none of it ends up in the next one.
+    pub fn forget(&mut self, user_id: i32) {
+        self.packets
+            .retain(|(queued_for, _), _| *queued_for != user_id);
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+
+use crate::osus_proxy::bancho::{BanchoPrivileges, Country};
+
+/// Users past this are forgotten, least recently seen first.
+const MAX_KNOWN_USERS: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct KnownUser {
+    pub name: String,
+    pub country: Country,
+    /// As in the presence, which only has room for the lowest bits.
+    pub privileges: BanchoPrivileges,
+    /// When the user was last seen, in presences recorded so far.
+    last_seen: u64,
+}
+
+/// Names and countries of the users the server sent the presence of, so logs and features that
+/// only get a user id can tell who it is. Ids only mean something on one server, switching to
+/// another one starts over.
+#[derive(Debug, Default)]
+pub struct KnownUsers {
+    server: String,
+    users: HashMap<i32, KnownUser>,
+    recorded: u64,
+}
+
+impl KnownUsers {
+    /// Forgets everyone if `target_domain` isn't the server they were seen on.
+    pub fn for_server(&mut self, target_domain: &str) {
+        if self.server != target_domain {
+            self.users.clear();
+            self.server = target_domain.to_owned();
+        }
+    }
+
+    pub fn record(&mut self, user_id: i32, name: &str, country_code: u8, bancho_privileges: u8) {
+        self.recorded += 1;
+        if self.users.len() >= MAX_KNOWN_USERS && !self.users.contains_key(&user_id) {
+            let least_recently_seen = self
+                .users
+                .iter()
+                .min_by_key(|(_, user)| user.last_seen)
+                .map(|(user_id, _)| *user_id);
+            if let Some(user_id) = least_recently_seen {
+                self.users.remove(&user_id);
+            }
+        }
+        self.users.insert(
+            user_id,
+            KnownUser {
+                name: name.to_owned(),
+                country: Country::from_u8(country_code),
+                privileges: BanchoPrivileges::from_bits_truncate(bancho_privileges.into()),
+                last_seen: self.recorded,
+            },
+        );
+    }
+
+    pub fn get(&self, user_id: i32) -> Option<&KnownUser> {
+        self.users.get(&user_id)
+    }
+
+    /// Case-insensitive.
+    pub fn find_by_name(&self, name: &str) -> Option<(i32, &KnownUser)> {
+        let name = name.trim();
+        self.users
+            .iter()
+            .find(|(_, user)| user.name.eq_ignore_ascii_case(name))
+            .map(|(user_id, user)| (*user_id, user))
+    }
+
+    /// For logs, e.g. `peppy (2)`, or just the id for users we haven't seen.
+    pub fn describe(&self, user_id: i32) -> String {
+        match self.get(user_id) {
+            Some(user) => format!("{} ({})", user.name, user_id),
+            None => user_id.to_string(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
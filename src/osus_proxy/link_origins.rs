@@ -0,0 +1,158 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Plenty for the links of a session's chat, the oldest are forgotten first.
+const MAX_LINK_ORIGINS: usize = 1000;
+/// Added to the query of the links we point at the proxy, with the id of their origin.
+const MARKER_PARAM: &str = "osus_origin";
+
+/// Which server each beatmap link pointed at before it was rewritten to point at the proxy. The
+/// server may have been switched since the link came in, and clicking or sending it should still
+/// lead to the server it came from. Every rewritten link carries the id of its origin in a marker,
+/// so the same `/b/1` linked from two servers leads back to each.
+#[derive(Debug)]
+pub struct LinkOrigins {
+    /// The path and domain of each link, by id.
+    links: HashMap<u64, (String, String)>,
+    ids: HashMap<(String, String), u64>,
+    /// Ids in the order they were first given out.
+    order: VecDeque<u64>,
+    next_id: u64,
+}
+
+impl Default for LinkOrigins {
+    fn default() -> Self {
+        // So links left in the client from an earlier run aren't mistaken for ones from this one
+        let next_id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_millis() as u64)
+            .unwrap_or_default();
+        Self {
+            links: HashMap::new(),
+            ids: HashMap::new(),
+            order: VecDeque::new(),
+            next_id,
+        }
+    }
+}
+
+impl LinkOrigins {
+    /// The id for the link with `path` from `domain`, the same one each time the link comes again.
+    pub fn record(&mut self, path: &str, domain: &str) -> u64 {
+        let link = (path.to_owned(), domain.to_owned());
+        if let Some(&id) = self.ids.get(&link) {
+            return id;
+        }
+
+        if self.order.len() >= MAX_LINK_ORIGINS {
+            if let Some(oldest) = self.order.pop_front() {
+                if let Some(forgotten) = self.links.remove(&oldest) {
+                    self.ids.remove(&forgotten);
+                }
+            }
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.order.push_back(id);
+        self.ids.insert(link.clone(), id);
+        self.links.insert(id, link);
+        id
+    }
+
+    /// The domain of the server the link with `path` and the origin `id` came from, if one did.
+    pub fn get(&self, id: u64, path: &str) -> Option<&str> {
+        match self.links.get(&id) {
+            Some((link_path, domain)) if link_path == path => Some(domain),
+            _ => None,
+        }
+    }
+}
+
+/// `after_host`, everything after the host of a link, with the marker for the origin `id` at the
+/// end of its query.
+pub fn mark(after_host: &str, id: u64) -> String {
+    let (before_fragment, fragment) = split_fragment(after_host);
+    let separator = if before_fragment.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    format!(
+        "{}{}{}={}{}",
+        before_fragment, separator, MARKER_PARAM, id, fragment
+    )
+}
+
+/// `after_host` without the origin marker, along with the id it had. `None` if it has no marker.
+pub fn unmark(after_host: &str) -> Option<(String, u64)> {
+    let (before_fragment, fragment) = split_fragment(after_host);
+    let (path, query) = before_fragment.split_once('?')?;
+    let mut id = None;
+    let rest: Vec<_> = query
+        .split('&')
+        .filter(|param| match param.split_once('=') {
+            Some((MARKER_PARAM, value)) => {
+                id = value.parse().ok();
+                false
+            }
+            _ => true,
+        })
+        .collect();
+    let query = if rest.is_empty() {
+        String::new()
+    } else {
+        format!("?{}", rest.join("&"))
+    };
+    Some((format!("{}{}{}", path, query, fragment), id?))
+}
+
+fn split_fragment(after_host: &str) -> (&str, &str) {
+    after_host.split_at(after_host.find('#').unwrap_or(after_host.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_an_origin_per_link_and_forgets_the_oldest_links() {
+        let mut origins = LinkOrigins::default();
+        let first = origins.record("/b/1", "first.example");
+        let second = origins.record("/b/1", "second.example");
+        assert_ne!(first, second);
+        assert_eq!(origins.record("/b/1", "first.example"), first);
+        assert_eq!(origins.get(first, "/b/1"), Some("first.example"));
+        assert_eq!(origins.get(second, "/b/1"), Some("second.example"));
+        // The marker of one link moved onto another
+        assert_eq!(origins.get(first, "/b/2"), None);
+
+        for id in 3..=MAX_LINK_ORIGINS {
+            origins.record(&format!("/b/{}", id), "first.example");
+        }
+        assert_eq!(origins.get(first, "/b/1"), Some("first.example"));
+        let overflow = origins.record("/b/overflow", "first.example");
+        assert_eq!(origins.get(first, "/b/1"), None);
+        assert_eq!(origins.get(second, "/b/1"), Some("second.example"));
+        assert_eq!(origins.get(overflow, "/b/overflow"), Some("first.example"));
+    }
+
+    #[test]
+    fn markers_go_in_the_query_before_the_fragment() {
+        for (after_host, marked) in [
+            ("/b/1", "/b/1?osus_origin=7"),
+            ("/b/1?m=3", "/b/1?m=3&osus_origin=7"),
+            ("/beatmapsets/1#osu/2", "/beatmapsets/1?osus_origin=7#osu/2"),
+            (
+                "/beatmapsets/1?a=b#osu/2",
+                "/beatmapsets/1?a=b&osus_origin=7#osu/2",
+            ),
+        ] {
+            assert_eq!(mark(after_host, 7), marked);
+            assert_eq!(unmark(marked), Some((after_host.to_owned(), 7)));
+        }
+        assert_eq!(unmark("/b/1?m=3"), None);
+        assert_eq!(unmark("/b/1#osus_origin=7"), None);
+        // Not an id we could have given out
+        assert_eq!(unmark("/b/1?osus_origin=x&m=3"), None);
+    }
+}
@@ -0,0 +1,49 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The client polls every few seconds, no exchange for this long while logged in means the
+/// connection is stuck somewhere.
+pub const STALLED_AFTER: Duration = Duration::from_secs(30);
+const PING_WINDOW: Duration = Duration::from_secs(60);
+
+/// Whether bancho traffic is still flowing through the proxy.
+#[derive(Debug, Default)]
+pub struct Liveness {
+    last_contact: Option<Instant>,
+    pings: VecDeque<Instant>,
+}
+
+impl Liveness {
+    /// A bancho exchange went through.
+    pub fn record_contact(&mut self) {
+        self.last_contact = Some(Instant::now());
+    }
+
+    pub fn record_ping(&mut self) {
+        let now = Instant::now();
+        while self
+            .pings
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= PING_WINDOW)
+        {
+            self.pings.pop_front();
+        }
+        self.pings.push_back(now);
+    }
+
+    pub fn since_last_contact(&self) -> Option<Duration> {
+        self.last_contact.map(|at| at.elapsed())
+    }
+
+    pub fn pings_per_minute(&self) -> usize {
+        self.pings
+            .iter()
+            .filter(|at| at.elapsed() < PING_WINDOW)
+            .count()
+    }
+
+    pub fn is_stalled(&self) -> bool {
+        self.since_last_contact()
+            .is_some_and(|elapsed| elapsed > STALLED_AFTER)
+    }
+}
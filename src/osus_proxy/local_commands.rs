@@ -0,0 +1,116 @@
+use tracing::info;
+
+use crate::osus_proxy::bancho::Country;
+use crate::preferences::{BeatmapMirror, Preferences};
+use crate::state::ProxyState;
+
+/// Either works, `!osus` came first.
+const PREFIXES: &[&str] = &["!proxy", "!osus"];
+const USAGE: &str = "!proxy status | ping | off | on | supporter on/off | country <code>/off | \
+                     mirror chimu/beatconnect/nerinyan/server";
+
+/// Handles the commands meant for the proxy, `!proxy` followed by a subcommand. Returns what to
+/// tell the user if the message was one, these never reach the server.
+pub fn handle_local_command(
+    text: &str,
+    preferences: &mut Preferences,
+    state: &mut ProxyState,
+) -> Option<String> {
+    let mut words = text.split_whitespace();
+    let prefix = words.next()?;
+    if !PREFIXES
+        .iter()
+        .any(|known| known.eq_ignore_ascii_case(prefix))
+    {
+        return None;
+    }
+
+    let arguments = words.map(str::to_lowercase).collect::<Vec<_>>();
+    let arguments = arguments.iter().map(String::as_str).collect::<Vec<_>>();
+    let reply = match arguments.as_slice() {
+        ["status"] => status(preferences),
+        ["ping"] => {
+            info!("Measuring the round trip of the next bancho exchange");
+            state.ping_requested = true;
+            "timing the next bancho exchange".to_owned()
+        }
+        ["off"] => {
+            preferences.pause_spoofing();
+            "spoofing paused".to_owned()
+        }
+        ["on"] => {
+            preferences.restore_spoofing();
+            "spoofing restored".to_owned()
+        }
+        ["supporter", "on"] => {
+            preferences.fake_supporter = true;
+            "supporter faked from the next login".to_owned()
+        }
+        ["supporter", "off"] => {
+            preferences.fake_supporter = false;
+            "supporter no longer faked from the next login".to_owned()
+        }
+        ["country", "off"] => {
+            preferences.fake_country = None;
+            "showing the real country".to_owned()
+        }
+        ["country", code] => match Country::from_code(code) {
+            Some(country) => {
+                let reply = format!("country shown as {}", country);
+                preferences.fake_country = Some(country);
+                reply
+            }
+            None => format!("no country with the code {}", code.to_uppercase()),
+        },
+        ["mirror", name] => match mirror_by_name(name) {
+            Some(mirror) => {
+                let reply = format!("downloading beatmaps from {}", mirror);
+                preferences.beatmap_mirror = mirror;
+                reply
+            }
+            None => format!(
+                "unknown mirror {}, try chimu, beatconnect, nerinyan or server",
+                name
+            ),
+        },
+        _ => format!("usage: {}", USAGE),
+    };
+    info!("Local command {:?}: {}", text.trim(), reply);
+    Some(reply)
+}
+
+/// What `!proxy status` says, e.g. `server ppy.sh, mirror chimu.moe, supporter faked, country real`.
+pub fn status(preferences: &Preferences) -> String {
+    let country = match &preferences.fake_country {
+        Some(country) => country.to_string(),
+        None => "real".to_owned(),
+    };
+    let mut status = format!(
+        "server {}, mirror {}, supporter {}, country {}",
+        preferences.server_address,
+        preferences.beatmap_mirror,
+        if preferences.fake_supporter {
+            "faked"
+        } else {
+            "not faked"
+        },
+        country
+    );
+    if preferences.hide_activity {
+        status.push_str(", activity hidden");
+    }
+    if preferences.spoofing_paused.is_some() {
+        status.push_str(", spoofing paused");
+    }
+    status
+}
+
+fn mirror_by_name(name: &str) -> Option<BeatmapMirror> {
+    match name {
+        "chimu" => Some(BeatmapMirror::Chimu),
+        "beatconnect" => Some(BeatmapMirror::BeatConnect),
+        "nerinyan" => Some(BeatmapMirror::Nerinyan),
+        "server" | "default" => Some(BeatmapMirror::ServerDefault),
+        _ => None,
+    }
+}
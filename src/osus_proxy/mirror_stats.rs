@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::preferences::BeatmapMirror;
+
+/// Kept apart from the preferences, these change on every download.
+pub const MIRROR_STATS_PATH: &str = "./mirror-stats.json";
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MirrorCounts {
+    #[serde(alias = "redirects")]
+    pub downloads: u64,
+    /// Times the game asked for the same set again right after being redirected.
+    pub failed_redirects: u64,
+    /// Download through the proxy instead of redirecting the game, learned from a failed redirect.
+    pub proxy_downloads: bool,
+}
+
+impl Display for MirrorCounts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} downloads, {} failed redirects",
+            self.downloads, self.failed_redirects
+        )?;
+        if self.proxy_downloads {
+            f.write_str(" (downloading through the proxy)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Which mirrors downloads were sent to, across restarts.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MirrorStats {
+    mirrors: BTreeMap<String, MirrorCounts>,
+    /// Mirror key, set id and when.
+    #[serde(skip)]
+    last_redirect: Option<(String, u32, Instant)>,
+}
+
+impl MirrorStats {
+    /// Starts over if the file is missing or unreadable, losing some stats is no big deal.
+    pub fn load() -> Self {
+        let json = match std::fs::read_to_string(MIRROR_STATS_PATH) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                warn!("Failed to read {}: {}", MIRROR_STATS_PATH, e);
+                return Self::default();
+            }
+        };
+        serde_json::from_str(&json).unwrap_or_else(|e| {
+            warn!("Invalid {}, starting over: {}", MIRROR_STATS_PATH, e);
+            Self::default()
+        })
+    }
+
+    /// Counts a download of `set_id` and returns whether it should go through the proxy. The game
+    /// asking for the same set again within `retry_window` of a redirect means the redirect failed,
+    /// from then on that mirror is downloaded through the proxy.
+    pub fn record_download(
+        &mut self,
+        mirror: &BeatmapMirror,
+        set_id: u32,
+        retry_window: Duration,
+    ) -> bool {
+        self.record_download_at(mirror, set_id, retry_window, Instant::now())
+    }
+
+    fn record_download_at(
+        &mut self,
+        mirror: &BeatmapMirror,
+        set_id: u32,
+        retry_window: Duration,
+        now: Instant,
+    ) -> bool {
+        let key = format!("{:?}", mirror);
+        let retried =
+            self.last_redirect
+                .take()
+                .is_some_and(|(last_key, last_set_id, redirected_at)| {
+                    last_key == key
+                        && last_set_id == set_id
+                        && now.saturating_duration_since(redirected_at) <= retry_window
+                });
+
+        let counts = self.mirrors.entry(key.clone()).or_default();
+        counts.downloads += 1;
+        if retried && !counts.proxy_downloads {
+            warn!(
+                "The game asked for beatmap set {} again right after being redirected to {}, downloading through the proxy from now on",
+                set_id, mirror
+            );
+            counts.failed_redirects += 1;
+            counts.proxy_downloads = true;
+        }
+
+        if !counts.proxy_downloads {
+            self.last_redirect = Some((key, set_id, now));
+        }
+        counts.proxy_downloads
+    }
+
+    pub fn get(&self, mirror: &BeatmapMirror) -> Option<&MirrorCounts> {
+        self.mirrors.get(&format!("{:?}", mirror))
+    }
+
+    pub fn total_downloads(&self) -> u64 {
+        self.mirrors.values().map(|counts| counts.downloads).sum()
+    }
+
+    pub fn reset(&mut self) {
+        self.mirrors.clear();
+        self.last_redirect = None;
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    pub fn write_file(json: &str) -> io::Result<()> {
+        std::fs::write(MIRROR_STATS_PATH, json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RETRY_WINDOW: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn a_quick_re_request_switches_the_mirror_to_proxy_downloads() {
+        let mut stats = MirrorStats::default();
+        let start = Instant::now();
+        assert!(!stats.record_download_at(&BeatmapMirror::Chimu, 1, RETRY_WINDOW, start));
+        assert!(stats.record_download_at(
+            &BeatmapMirror::Chimu,
+            1,
+            RETRY_WINDOW,
+            start + Duration::from_secs(3)
+        ));
+        let counts = stats.get(&BeatmapMirror::Chimu).unwrap();
+        assert_eq!(
+            (
+                counts.downloads,
+                counts.failed_redirects,
+                counts.proxy_downloads
+            ),
+            (2, 1, true)
+        );
+
+        // And stays that way, without counting more failures
+        let later = start + Duration::from_secs(60);
+        assert!(stats.record_download_at(&BeatmapMirror::Chimu, 2, RETRY_WINDOW, later));
+        assert!(stats.record_download_at(&BeatmapMirror::Chimu, 2, RETRY_WINDOW, later));
+        assert_eq!(
+            stats.get(&BeatmapMirror::Chimu).unwrap().failed_redirects,
+            1
+        );
+        // Other mirrors aren't affected
+        assert!(!stats.record_download_at(&BeatmapMirror::Nerinyan, 2, RETRY_WINDOW, later));
+    }
+
+    #[test]
+    fn other_downloads_dont_count_as_failed_redirects() {
+        let mut stats = MirrorStats::default();
+        let start = Instant::now();
+        let soon = start + Duration::from_secs(1);
+        stats.record_download_at(&BeatmapMirror::Chimu, 1, RETRY_WINDOW, start);
+        // Another set
+        assert!(!stats.record_download_at(&BeatmapMirror::Chimu, 2, RETRY_WINDOW, soon));
+        // The same set from another mirror
+        assert!(!stats.record_download_at(&BeatmapMirror::BeatConnect, 2, RETRY_WINDOW, soon));
+        // The same set again, but after the window
+        let late = soon + RETRY_WINDOW + Duration::from_secs(1);
+        assert!(!stats.record_download_at(&BeatmapMirror::BeatConnect, 2, RETRY_WINDOW, late));
+        assert_eq!(stats.total_downloads(), 4);
+        assert!(stats
+            .mirrors
+            .values()
+            .all(|counts| counts.failed_redirects == 0 && !counts.proxy_downloads));
+    }
+
+    #[test]
+    fn the_learned_mode_is_kept_across_restarts_until_reset() {
+        let mut stats = MirrorStats::default();
+        let start = Instant::now();
+        stats.record_download_at(&BeatmapMirror::Chimu, 1, RETRY_WINDOW, start);
+        stats.record_download_at(&BeatmapMirror::Chimu, 1, RETRY_WINDOW, start);
+
+        let mut loaded: MirrorStats = serde_json::from_str(&stats.to_json().unwrap()).unwrap();
+        assert!(loaded.get(&BeatmapMirror::Chimu).unwrap().proxy_downloads);
+        assert!(loaded.record_download_at(&BeatmapMirror::Chimu, 3, RETRY_WINDOW, start));
+
+        loaded.reset();
+        assert!(loaded.get(&BeatmapMirror::Chimu).is_none());
+        assert!(!loaded.record_download_at(&BeatmapMirror::Chimu, 3, RETRY_WINDOW, start));
+    }
+}
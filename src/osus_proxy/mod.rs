@@ -1,39 +1,129 @@
+use std::borrow::Cow;
+use std::future::Future;
 use std::io;
-use std::io::Read;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::vec::Vec;
 
-use bytebuffer::{ByteBuffer, Endian};
+use bytes::Bytes;
 use color_eyre::{eyre::eyre, Result};
-use http::uri::{Authority, Scheme};
-use http::{header, HeaderValue, Method};
+use http::uri::{Authority, PathAndQuery, Scheme};
+use http::{header, HeaderMap, HeaderName, HeaderValue, Method};
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
 use hyper::server::conn::AddrIncoming;
 use hyper::service::{make_service_fn, service_fn, Service};
 use hyper::{Body, Client, Request, Response, Server, StatusCode, Uri};
-use hyper_rustls::{acceptor::TlsStream, ConfigBuilderExt, TlsAcceptor};
+use hyper_rustls::{acceptor::TlsStream, ConfigBuilderExt, HttpsConnector, TlsAcceptor};
+use osus_bancho::codec::{
+    decode_bancho_packets, encode_bancho_packets, summarize_packets, CodecError,
+};
+use osus_bancho::stream::PacketSplitter;
+use osus_bancho::HEXDUMP_BYTES;
 use tokio::sync::Mutex;
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
 
-pub mod bancho;
+pub mod auto_reply;
+pub mod availability;
+pub mod capture;
+pub mod chat_filter;
+pub mod chat_log;
+pub mod content_encoding;
+pub mod coverage;
+pub mod desktop_notifications;
+pub mod do_not_disturb;
+pub mod export;
+pub mod idle_failures;
+pub mod injections;
+pub mod known_users;
+pub mod link_origins;
+pub mod liveness;
+pub mod local_commands;
+pub mod mirror_stats;
+pub mod overhead;
+pub mod overlay;
+pub mod packet_log;
+pub mod packet_stats;
+pub mod packet_viewer;
+pub mod replay;
+pub mod sessions;
+pub mod settings_notices;
+pub mod spectate_stats;
+pub mod tls;
 
-use crate::preferences::{BeatmapMirror, Preferences};
-use bancho::{BanchoPacket, BanchoPacketHeader};
-use crate::osus_proxy::bancho::UserAction;
+pub use osus_bancho::bancho;
 
-const SUBDOMAINS: &[&str] = &["c", "ce", "c4", "osu", "b", "api", "a"];
+use crate::hosts;
+use crate::logging::RateLimitedWarning;
+use crate::osus_proxy::bancho::{
+    BanchoPrivileges, GameMode, LoginFailure, Mods, OsuMessage, UserAction,
+};
+use crate::preferences::{
+    BeatmapMirror, ForwardedIpMode, HeaderOverride, LatencyInjection, Preferences,
+    ProcessingLimits, ReplayFallback, UpstreamPoolSettings, UpstreamWatchdog,
+};
+use crate::state::{NowPlaying, PingResult, ProxyState, UpstreamError};
+use availability::{AvailabilityChange, RequestOutcome};
+use bancho::{BanchoPacket, LinkField, PacketDirection};
+use chat_filter::Filtered;
+use link_origins::LinkOrigins;
+use mirror_stats::MirrorStats;
+use tls::ScopedInsecureVerifier;
 
-const SOURCE_DOMAIN: &str = "osus.zihad.dev";
+/// Used until the user changes [`Preferences::subdomains`].
+const DEFAULT_SUBDOMAINS: &[&str] = &["c", "ce", "c4", "osu", "b", "api", "a"];
+
+pub const SOURCE_DOMAIN: &str = "osus.zihad.dev";
+/// Headers that header overrides must not touch, the proxy or the session depend on them. Lowercase.
+const PROTECTED_HEADERS: &[&str] = &[
+    "host",
+    "content-length",
+    "transfer-encoding",
+    "osu-token",
+    "cho-token",
+];
+const MAX_LOGGED_INFO_TEXT_CHARS: usize = 64;
+/// What beatmap links start with after the host: the website's, and the short ones older
+/// clients and some servers still use.
+const BEATMAP_LINK_PATHS: &[&str] = &["/beatmapsets/", "/beatmaps/", "/b/", "/s/"];
+const WATCHLIST_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
 const DEFAULT_TARGET_DOMAIN: &str = "osu.ppy.sh";
+const OFFICIAL_DOMAIN: &str = "ppy.sh";
+/// Query parameters `/web/` requests authenticate with.
+const WEB_CREDENTIAL_PARAMS: &[&str] = &["u", "h"];
+const REDACTED: &str = "<redacted>";
+const MAX_DOWNLOAD_REDIRECTS: usize = 5;
+/// Log lines quoting chat use this target, so diagnostics can leave them out.
+pub const CHAT_LOG_TARGET: &str = "osus_proxy::chat";
+
+static ROUNDTRIP_MISMATCH_WARNING: RateLimitedWarning =
+    RateLimitedWarning::new("Bodies that re-encode differently");
+static UNKNOWN_HOST_WARNING: RateLimitedWarning =
+    RateLimitedWarning::new("Requests for unknown hosts");
+static UPSTREAM_FAILURE_WARNING: RateLimitedWarning =
+    RateLimitedWarning::new("Failed upstream requests");
 
-pub async fn start(preferences: Arc<Mutex<Preferences>>) -> Result<()> {
+/// Serves until `shutdown` resolves, then stops taking connections and lets the requests in flight
+/// finish.
+pub async fn start(
+    preferences: Arc<Mutex<Preferences>>,
+    state: Arc<Mutex<ProxyState>>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
     let addr = ([127, 0, 0, 1], 443).into();
 
     let certs = load_certs()?;
     let key = load_private_key()?;
 
-    let incoming = AddrIncoming::bind(&addr)?;
+    let incoming = match AddrIncoming::bind(&addr) {
+        Ok(incoming) => incoming,
+        Err(e) => {
+            state.lock().await.listener = Some(Err(e.to_string()));
+            return Err(e.into());
+        }
+    };
     let acceptor = TlsAcceptor::builder()
         .with_single_cert(certs, key)
         .map_err(|e| eyre!("{}", e))?
@@ -45,8 +135,10 @@ pub async fn start(preferences: Arc<Mutex<Preferences>>) -> Result<()> {
         let mut inner_svc = service_fn(handle_requests);
 
         let preferences_clone = preferences.clone();
+        let state_clone = state.clone();
         let outer_svc = service_fn(move |mut req: Request<Body>| {
             req.extensions_mut().insert(preferences_clone.clone());
+            req.extensions_mut().insert(state_clone.clone());
 
             if let Some(remote_addr) = remote_addr {
                 req.extensions_mut().insert(remote_addr);
@@ -58,7 +150,17 @@ pub async fn start(preferences: Arc<Mutex<Preferences>>) -> Result<()> {
         async move { Ok::<_, String>(outer_svc) }
     });
 
-    let server = Server::builder(acceptor).serve(make_svc);
+    let server = Server::builder(acceptor)
+        .serve(make_svc)
+        .with_graceful_shutdown(async {
+            shutdown.await;
+            info!("Shutting down, waiting for the requests in flight");
+        });
+    {
+        let mut state = state.lock().await;
+        state.startup_timeline.mark("listener bound");
+        state.listener = Some(Ok(addr));
+    }
 
     info!("Starting to serve on https://{}.", addr);
 
@@ -73,125 +175,561 @@ async fn handle_requests(mut req: Request<Body>) -> Result<Response<Body>> {
         .get("Host")
         .and_then(|x| x.to_str().ok())
         .map(|x| x.to_owned())
-        else {
-            let mut response = Response::new(Body::from("host header not found"));
-            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            return Ok(response);
-        };
-    let Some((subdomain, _)) = SUBDOMAINS
-        .iter()
-        .map(|&subdomain| subdomain.to_owned())
-        .map(|subdomain| {
-            (
-                subdomain.clone(),
-                subdomain + &format!(".{}", SOURCE_DOMAIN),
-            )
-        })
-        .find(|(_subdomain, full_source_host)| full_source_host == &host)
-        else {
-            let mut response = Response::new(Body::from(format!(
-                "target domain for host {} not found",
-                host
-            )));
-            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            return Ok(response);
-        };
+    else {
+        let mut response = Response::new(Body::from("host header not found"));
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        return Ok(response);
+    };
+    if let Some(overlay_path) = req.uri().path().strip_prefix(overlay::OVERLAY_PATH_PREFIX) {
+        if let (Some(preferences), Some(state)) = (
+            req.extensions().get::<Arc<Mutex<Preferences>>>(),
+            req.extensions().get::<Arc<Mutex<ProxyState>>>(),
+        ) {
+            let preferences = preferences.lock().await;
+            let state = state.lock().await;
+            return Ok(overlay::response(overlay_path, &preferences, &state));
+        }
+    }
+    let subdomains = match req.extensions().get::<Arc<Mutex<Preferences>>>() {
+        Some(preferences) => preferences.lock().await.subdomains.clone(),
+        None => default_subdomains(),
+    };
+    let Some(subdomain) = subdomains
+        .into_iter()
+        .find(|subdomain| source_host(subdomain) == host)
+    else {
+        UNKNOWN_HOST_WARNING.warn(|| format!("Rejecting a request for unknown host {}", host));
+        let mut response = Response::new(Body::from(format!(
+            "target domain for host {} not found",
+            host
+        )));
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        return Ok(response);
+    };
+    let is_bancho_or_web_host = matches!(subdomain.as_str(), "c" | "ce" | "c4" | "osu");
     let (target_host, target_domain) = {
         let target_domain =
             if let Some(preferences) = req.extensions().get::<Arc<Mutex<Preferences>>>() {
-                let preferences = preferences.lock().await;
-                preferences.server_address.clone()
+                let mut preferences = preferences.lock().await;
+                let server_address = preferences.server_address.clone();
+                preferences.remember_server_address(&server_address);
+                server_address
             } else {
                 DEFAULT_TARGET_DOMAIN.to_owned()
             };
-        (subdomain + &format!(".{}", &target_domain), target_domain)
+        let bancho_host_override = match req.extensions().get::<Arc<Mutex<ProxyState>>>() {
+            Some(state) if matches!(subdomain.as_str(), "c" | "ce" | "c4") => state
+                .lock()
+                .await
+                .bancho_host_override
+                .clone()
+                .filter(|(server, _)| *server == target_domain)
+                .map(|(_, host)| host),
+            _ => None,
+        };
+        let target_host =
+            bancho_host_override.unwrap_or_else(|| subdomain + &format!(".{}", &target_domain));
+        (target_host, target_domain)
     };
 
     let mut uri_parts = req.uri().clone().into_parts();
     uri_parts.scheme.get_or_insert(Scheme::HTTPS);
     uri_parts.authority = Some(Authority::from_str(&target_host).unwrap());
+    // Beatmap links we pointed at the proxy carry which server they came from, not passed on
+    let mut link_origin_id = None;
+    if host == "osu.".to_owned() + SOURCE_DOMAIN {
+        let unmarked = uri_parts
+            .path_and_query
+            .as_ref()
+            .and_then(|path_and_query| link_origins::unmark(path_and_query.as_str()));
+        if let Some((path_and_query, id)) = unmarked {
+            if let Ok(path_and_query) = PathAndQuery::from_str(&path_and_query) {
+                uri_parts.path_and_query = Some(path_and_query);
+                link_origin_id = Some(id);
+            }
+        }
+    }
     let mut new_uri = Uri::from_parts(uri_parts).unwrap();
     std::mem::swap(req.uri_mut(), &mut new_uri);
 
-    let client_ip_addr = req
-        .extensions()
-        .get::<SocketAddr>()
-        .map(|x| x.ip().to_string())
-        .unwrap_or_else(String::new);
-
-    let headers = req.headers_mut();
-    headers.insert(
-        "X-Forwarded-For",
-        HeaderValue::from_str(&client_ip_addr).unwrap(),
-    );
-    headers.insert("X-Real-IP", HeaderValue::from_str(&client_ip_addr).unwrap());
-    headers.insert("Host", HeaderValue::from_str(&target_host).unwrap());
-
-    let tls = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_native_roots()
-        .with_no_client_auth();
-    let https = hyper_rustls::HttpsConnectorBuilder::new()
-        .with_tls_config(tls)
-        .https_or_http()
-        .enable_http1()
-        .build();
-
-    let client = Client::builder().build(https);
+    req.headers_mut()
+        .insert("Host", HeaderValue::from_str(&target_host).unwrap());
 
     let req_path = req.uri().path().to_owned();
+    let req_query = req.uri().query().unwrap_or_default().to_owned();
     let req_method = req.method().clone();
     let preferences = req
         .extensions()
         .get::<Arc<Mutex<Preferences>>>()
         .map(|x| x.clone());
+    let state = req
+        .extensions()
+        .get::<Arc<Mutex<ProxyState>>>()
+        .cloned()
+        .unwrap_or_default();
+
+    if host == "osu.".to_owned() + SOURCE_DOMAIN && req_method == Method::GET {
+        // The link may have come from another server than the one we're connected to now
+        let link_origin = match link_origin_id {
+            Some(id) => state
+                .lock()
+                .await
+                .link_origins
+                .get(id, &req_path)
+                .map(str::to_owned),
+            None => None,
+        };
+        if let Some(set_id) = beatmap_page_set_id(&req_path) {
+            let official = match &preferences {
+                Some(preferences) => preferences.lock().await.beatmap_pages_on_official_site,
+                None => false,
+            };
+            let page_domain = match &link_origin {
+                _ if official => OFFICIAL_DOMAIN,
+                Some(domain) => domain.as_str(),
+                None => target_domain.as_str(),
+            };
+            let link = format!("{}/{}", beatmapsets_url(page_domain), set_id);
+            info!("Redirecting the page of beatmap set {} to {}", set_id, link);
+            // Browsers carry the fragment (e.g. #osu/123) over to the new location themselves
+            return Ok(Response::builder()
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, link)
+                .body(Body::empty())
+                .unwrap());
+        } else if let Some(domain) = link_origin.filter(|domain| *domain != target_domain) {
+            let query = req
+                .uri()
+                .query()
+                .map(|query| format!("?{}", query))
+                .unwrap_or_default();
+            let link = format!("https://osu.{}{}{}", domain, req_path, query);
+            info!(
+                "Redirecting {} to {}, the server it was linked from",
+                req_path, link
+            );
+            return Ok(Response::builder()
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, link)
+                .body(Body::empty())
+                .unwrap());
+        }
+    }
+
+    let (
+        pool_settings,
+        insecure_domain,
+        watchdog,
+        upstream_down_threshold,
+        latency_injection,
+        forwarded_ip,
+        header_overrides,
+        max_packet_length,
+    ) = if let Some(preferences) = &preferences {
+        let preferences = preferences.lock().await;
+        (
+            preferences.upstream_pool.clone(),
+            preferences.insecure_upstream_domain.clone(),
+            preferences.upstream_watchdog.clone(),
+            preferences.upstream_down_threshold,
+            preferences.latency_injection.clone(),
+            forwarded_ip(&preferences, &req),
+            preferences.header_overrides.clone(),
+            preferences.processing_limits.max_packet_length,
+        )
+    } else {
+        let preferences = Preferences::default();
+        let forwarded_ip = forwarded_ip(&preferences, &req);
+        (
+            preferences.upstream_pool,
+            preferences.insecure_upstream_domain,
+            preferences.upstream_watchdog,
+            preferences.upstream_down_threshold,
+            preferences.latency_injection,
+            forwarded_ip,
+            preferences.header_overrides,
+            preferences.processing_limits.max_packet_length,
+        )
+    };
+    let client = upstream_client(&mut *state.lock().await, &pool_settings, &insecure_domain);
+
+    let headers = req.headers_mut();
+    set_forwarded_ip_headers(headers, forwarded_ip);
+    if is_bancho_or_web_host {
+        apply_header_overrides(headers, &header_overrides);
+    }
+
+    // The login is the only bancho request without a token
+    let is_login_request =
+        req_path == "/" && req_method == Method::POST && !req.headers().contains_key("osu-token");
+    let mut login_username = None;
+    if is_login_request {
+        if let Some(preferences) = &preferences {
+            if preferences.lock().await.hide_forwarded_ip_on_login {
+                let headers = req.headers_mut();
+                headers.remove("X-Forwarded-For");
+                headers.remove("X-Real-IP");
+                info!("Not forwarding the client address on login, the server will geolocate our connection");
+            }
+        }
+        let (parts, body) = req.into_parts();
+        let body_bytes = match hyper::body::to_bytes(body).await {
+            Ok(body_bytes) => body_bytes,
+            Err(e) => return Ok(unreadable_body_response(&req_path, e)),
+        };
+        login_username = sessions::login_username(&body_bytes);
+        req = Request::from_parts(parts, Body::from(body_bytes));
+    }
 
+    // The session this request belongs to, a new one is started when the login response comes
+    let session_token = req
+        .headers()
+        .get("osu-token")
+        .and_then(|token| token.to_str().ok())
+        .map(str::to_owned);
+    let mut session_user_id = match &session_token {
+        Some(token) => state
+            .lock()
+            .await
+            .sessions
+            .touch(token)
+            .and_then(|session| session.user_id),
+        None => None,
+    };
+
+    let mut request_body_len = 0;
+    let mut request_processing = Duration::ZERO;
     if req.headers().contains_key("osu-token") {
         if let Some(preferences) = preferences.clone() {
             if req_path == "/" && req_method == Method::POST {
                 let (mut parts, body) = req.into_parts();
-                let body_bytes = hyper::body::to_bytes(body).await.unwrap();
-                let mut packets = decode_bancho_packets(body_bytes.as_ref()).await.unwrap();
-                let mut preferences = preferences.lock().await;
-                process_bancho_packets(&mut preferences, &mut packets, &target_domain).await;
-                let body_bytes = encode_bancho_packets(packets).await.unwrap();
-                parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body_bytes.len()));
+                let body_bytes = match hyper::body::to_bytes(body).await {
+                    Ok(body_bytes) => body_bytes,
+                    Err(e) => return Ok(unreadable_body_response(&req_path, e)),
+                };
+                request_body_len = body_bytes.len();
+                let processing_started = Instant::now();
+                let body_bytes = match content_encoding::decode(&parts.headers, &body_bytes) {
+                    Some(plain_body) => {
+                        if let Some(token) = &session_token {
+                            capture_bancho_body(
+                                &preferences,
+                                &state,
+                                token,
+                                PacketDirection::ClientToServer,
+                                vec![plain_body.clone()],
+                            )
+                            .await;
+                        }
+                        match decode_bancho_packets(
+                            &plain_body,
+                            PacketDirection::ClientToServer,
+                            max_packet_length,
+                        ) {
+                            Ok(mut packets) => {
+                                let decoded_count = packets.len();
+                                let mut preferences = preferences.lock().await;
+                                let mut state = state.lock().await;
+                                state
+                                    .packet_stats
+                                    .record_body(PacketDirection::ClientToServer, &plain_body);
+                                state.packet_viewer.record_body(
+                                    PacketDirection::ClientToServer,
+                                    &plain_body,
+                                    &packets,
+                                );
+                                let reencoded = preferences
+                                    .verify_roundtrip
+                                    .then(|| reencode_each(&packets))
+                                    .and_then(Result::ok);
+                                process_bancho_packets(
+                                    &mut preferences,
+                                    &mut state,
+                                    &mut session_user_id,
+                                    &mut packets,
+                                    PacketDirection::ClientToServer,
+                                    &target_domain,
+                                )
+                                .await;
+                                let processed_count = packets.len();
+                                match encode_bancho_packets(packets, plain_body.len()) {
+                                    Ok(encoded) => {
+                                        let roundtrip_broken = reencoded.is_some_and(|reencoded| {
+                                            reencoded.concat() == encoded
+                                                && !roundtrip_matches(
+                                                    &plain_body,
+                                                    &reencoded,
+                                                    PacketDirection::ClientToServer,
+                                                )
+                                        });
+                                        if !roundtrip_broken
+                                            && within_processing_limits(
+                                                &preferences.processing_limits,
+                                                PacketDirection::ClientToServer,
+                                                (decoded_count, processed_count),
+                                                (plain_body.len(), encoded.len()),
+                                            )
+                                        {
+                                            content_encoding::strip(&mut parts.headers);
+                                            encoded
+                                        } else {
+                                            body_bytes
+                                        }
+                                    }
+                                    Err(e) => {
+                                        warn!("Failed to encode the bancho request, passing it through: {}", e);
+                                        body_bytes
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                if preferences.lock().await.strict_packet_decoding {
+                                    return Err(eyre!(
+                                        "failed to decode the bancho request: {}",
+                                        e
+                                    ));
+                                }
+                                warn!(
+                                    "Failed to decode the bancho request, passing it through: {}",
+                                    e
+                                );
+                                body_bytes
+                            }
+                        }
+                    }
+                    None => body_bytes,
+                };
+                request_processing = processing_started.elapsed();
+                set_body_length(&mut parts.headers, body_bytes.len());
                 req = Request::from_parts(parts, Body::from(body_bytes));
             }
         }
     }
 
-    match client.request(req).await {
+    if req_path.starts_with("/web/") {
+        debug!(
+            "Forwarding {} {}?{}",
+            req_method,
+            req_path,
+            redact_query(&req_query)
+        );
+    }
+
+    let is_bancho_request = req_path == "/" && req_method == Method::POST;
+    let is_download_request = host == "osu.".to_owned() + SOURCE_DOMAIN
+        && req_method == Method::GET
+        && req_path.starts_with("/d/");
+    if let Some(delay) = injected_delay(&latency_injection, is_bancho_request, is_download_request)
+    {
+        debug!("Delaying {} by {:?}", req_path, delay);
+        tokio::time::sleep(delay).await;
+    }
+
+    let replayed = match state.lock().await.replay.as_mut() {
+        Some(replay) if is_bancho_request => Some(replay.next_response(is_login_request)),
+        Some(_) => return Ok(replay::not_recorded()),
+        None => None,
+    };
+
+    let request_started = Instant::now();
+    let (upstream_response, timed_out) = match replayed {
+        Some(response) => (Ok(response), false),
+        None => match tokio::time::timeout(watchdog.request_timeout(), client.request(req)).await {
+            Ok(result) => (result.map_err(|e| e.to_string()), false),
+            Err(_) => (
+                Err(format!(
+                    "no response within {} seconds",
+                    watchdog.request_timeout_secs
+                )),
+                true,
+            ),
+        },
+    };
+    let outcome = RequestOutcome::of(upstream_response.as_ref().ok().map(Response::status));
+    let availability_change = {
+        let mut state = state.lock().await;
+        watch_for_stalled_connections(&mut state, &target_host, timed_out, &watchdog);
+        // Only connections the pool kept around can have gone stale
+        let failed = upstream_response.is_err() && !timed_out && pool_settings.keep_alive;
+        let idle = state.idle_failures.record(
+            &target_host,
+            request_started,
+            failed,
+            pool_settings.idle_timeout(),
+        );
+        if let Some(idle) = idle {
+            warn!("{}", idle_failures::describe(&target_host, idle));
+        }
+        if let Err(e) = &upstream_response {
+            state.last_upstream_error = Some((
+                Instant::now(),
+                format!("{}{}: {}", target_host, req_path, e),
+            ));
+        }
+        state
+            .upstream_availability
+            .record(&target_host, outcome, upstream_down_threshold)
+    };
+    match availability_change {
+        Some(AvailabilityChange::WentDown) => {
+            warn!("{} appears to be unreachable", target_host);
+            let target_host = target_host.clone();
+            tokio::task::spawn_blocking(move || {
+                if let Some(hint) = hosts::conflict_hint(&target_host) {
+                    warn!("{}", hint);
+                }
+            });
+        }
+        Some(AvailabilityChange::Recovered) => info!("{} is reachable again", target_host),
+        None => {}
+    }
+
+    match upstream_response {
         Ok(mut response) => {
             if let Some(preferences) = preferences {
                 if req_path == "/" && req_method == Method::POST {
-                    let (parts, body) = response.into_parts();
-                    let body_bytes = hyper::body::to_bytes(body).await.unwrap();
-                    let mut packets = decode_bancho_packets(body_bytes.as_ref()).await.unwrap();
-                    let mut preferences = preferences.lock().await;
-                    process_bancho_packets(&mut preferences, &mut packets, &target_domain).await;
-                    let body_bytes = encode_bancho_packets(packets).await.unwrap();
-                    response = Response::from_parts(parts, Body::from(body_bytes));
+                    let (mut parts, body) = response.into_parts();
+                    let round_trip = request_started.elapsed();
+                    let (strict, capturing) = {
+                        let preferences = preferences.lock().await;
+                        (
+                            preferences.strict_packet_decoding,
+                            preferences.record_bancho_traffic,
+                        )
+                    };
+                    // Ok to process, Err to pass through
+                    let body = if let Some(error) = error_response(&parts.status, &parts.headers) {
+                        warn!("Bancho responded with {}, passing it through", parts.status);
+                        state.lock().await.upstream_error = Some(error);
+                        Err(body)
+                    } else if content_encoding::is_identity(&parts.headers) {
+                        Ok(body)
+                    } else {
+                        // Compressed bodies can only be decoded whole
+                        let body_bytes = hyper::body::to_bytes(body).await?;
+                        match content_encoding::decode(&parts.headers, &body_bytes) {
+                            Some(plain_body) => {
+                                content_encoding::strip(&mut parts.headers);
+                                Ok(Body::from(plain_body))
+                            }
+                            None => Err(Body::from(body_bytes)),
+                        }
+                    };
+                    response = match body {
+                        Ok(body) => {
+                            let cho_token = parts
+                                .headers
+                                .get("cho-token")
+                                .and_then(|token| token.to_str().ok())
+                                .map(str::to_owned);
+                            // The login response goes with the session it starts
+                            let capture_token = capturing
+                                .then(|| session_token.clone().or_else(|| cho_token.clone()))
+                                .flatten();
+                            let exchange = BanchoExchange {
+                                preferences,
+                                state,
+                                target_domain,
+                                session_token,
+                                session_user_id,
+                                cho_token,
+                                capture_token,
+                                login_username,
+                                round_trip,
+                                request_body_len,
+                                request_processing,
+                            };
+                            // Sent as it's processed, the length isn't known up front
+                            parts.headers.remove(header::CONTENT_LENGTH);
+                            Response::from_parts(
+                                parts,
+                                stream_bancho_response(body, exchange, strict, max_packet_length),
+                            )
+                        }
+                        Err(body) => Response::from_parts(parts, body),
+                    };
                 } else if host == "osu.".to_owned() + &*SOURCE_DOMAIN && req_method == Method::GET {
                     if req_path.starts_with("/d/") {
                         if let Ok(id) = req_path.replace("/d/", "").replace('n', "").parse::<u32>()
                         {
-                            let preferences = preferences.lock().await;
-                            match &preferences.beatmap_mirror {
-                                BeatmapMirror::ServerDefault => {}
-                                mirror => {
-                                    let link = mirror.direct_download_link(id, false);
-                                    info!(
-                                        "Redirecting download request for beatmap set {} to {}",
-                                        id, mirror
+                            let (mirror, retry_window) = {
+                                let preferences = preferences.lock().await;
+                                (
+                                    preferences.beatmap_mirror.clone(),
+                                    Duration::from_secs(preferences.download_retry_window_secs),
+                                )
+                            };
+                            if mirror != BeatmapMirror::ServerDefault {
+                                let link = mirror.direct_download_link(id, false);
+                                let through_proxy = {
+                                    let mut state = state.lock().await;
+                                    let through_proxy = state.mirror_stats.record_download(
+                                        &mirror,
+                                        id,
+                                        retry_window,
                                     );
-                                    response = Response::builder()
-                                        .status(StatusCode::FOUND)
-                                        .header("Location", link)
-                                        .body(Body::empty())
-                                        .unwrap()
-                                }
+                                    save_mirror_stats(&state.mirror_stats);
+                                    through_proxy
+                                };
+
+                                let proxied = if through_proxy {
+                                    download_through_proxy(&client, &link).await
+                                } else {
+                                    None
+                                };
+                                response = match proxied {
+                                    Some(proxied) => {
+                                        info!(
+                                            "Downloading beatmap set {} from {} through the proxy",
+                                            id, mirror
+                                        );
+                                        proxied
+                                    }
+                                    None => {
+                                        info!(
+                                            "Redirecting download request for beatmap set {} to {}",
+                                            id, mirror
+                                        );
+                                        Response::builder()
+                                            .status(StatusCode::FOUND)
+                                            .header("Location", link)
+                                            .body(Body::empty())
+                                            .unwrap()
+                                    }
+                                };
+                            }
+                        }
+                    } else if req_path == "/web/osu-getreplay.php" {
+                        let score_id = query_param(&req_query, "c").unwrap_or_default();
+                        if matches!(
+                            response.status(),
+                            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+                        ) {
+                            let (fallback, url_template) = {
+                                let preferences = preferences.lock().await;
+                                (
+                                    preferences.replay_fallback.clone(),
+                                    preferences.replay_fallback_url_template.clone(),
+                                )
+                            };
+                            if let Some(fallback_response) = replay_fallback_response(
+                                &client,
+                                &fallback,
+                                &url_template,
+                                &req_query,
+                            )
+                            .await
+                            {
+                                response = fallback_response;
+                            } else {
+                                info!(
+                                    "Passing through the server's {} for the replay of score {}",
+                                    response.status(),
+                                    score_id
+                                );
                             }
+                        } else {
+                            info!("Serving the replay of score {} from the server", score_id);
                         }
                     }
                 }
@@ -199,6 +737,8 @@ async fn handle_requests(mut req: Request<Body>) -> Result<Response<Body>> {
             Ok(response)
         }
         Err(err) => {
+            UPSTREAM_FAILURE_WARNING
+                .warn(|| format!("Failed to fetch {}{}: {}", target_host, req_path, err));
             let mut response = Response::new(Body::from(format!("error fetching: {}", err)));
             *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
             Ok(response)
@@ -206,119 +746,2877 @@ async fn handle_requests(mut req: Request<Body>) -> Result<Response<Body>> {
     }
 }
 
-async fn decode_bancho_packets(bytes: &[u8]) -> io::Result<Vec<BanchoPacket>> {
-    let mut packets = vec![];
+/// For a request whose body couldn't be read, the client most likely went away in the middle of it.
+fn unreadable_body_response(req_path: &str, e: hyper::Error) -> Response<Body> {
+    warn!(
+        "Failed to read the body of the request to {}: {}",
+        req_path, e
+    );
+    let mut response = Response::new(Body::from(format!("error reading the request body: {}", e)));
+    *response.status_mut() = StatusCode::BAD_REQUEST;
+    response
+}
+
+pub fn default_subdomains() -> Vec<String> {
+    DEFAULT_SUBDOMAINS
+        .iter()
+        .map(|&subdomain| subdomain.to_owned())
+        .collect()
+}
+
+/// The host the client uses for `subdomain`, which has to point at the proxy.
+pub fn source_host(subdomain: &str) -> String {
+    format!("{}.{}", subdomain, SOURCE_DOMAIN)
+}
+
+/// The client for the upstream server along with the settings it was built with.
+#[derive(Debug)]
+pub struct UpstreamClient {
+    pool_settings: UpstreamPoolSettings,
+    insecure_domain: String,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl UpstreamClient {
+    /// Whether the client is what these settings would build, other preferences don't matter to it.
+    fn built_with(&self, pool_settings: &UpstreamPoolSettings, insecure_domain: &str) -> bool {
+        &self.pool_settings == pool_settings && self.insecure_domain == insecure_domain
+    }
+}
+
+/// Reuses the last client unless the settings changed since. Requests that are already running keep
+/// the client they started with.
+fn upstream_client(
+    state: &mut ProxyState,
+    pool_settings: &UpstreamPoolSettings,
+    insecure_domain: &str,
+) -> Client<HttpsConnector<HttpConnector>> {
+    match &state.upstream_client {
+        Some(cached) if cached.built_with(pool_settings, insecure_domain) => cached.client.clone(),
+        _ => {
+            debug!("Building a new upstream client with {:?}", pool_settings);
+            if !insecure_domain.trim().is_empty() {
+                warn!(
+                    "Certificates of {} and its subdomains won't be verified",
+                    insecure_domain
+                );
+            }
+            let client = build_client(pool_settings, insecure_domain);
+            state.upstream_client = Some(UpstreamClient {
+                pool_settings: pool_settings.clone(),
+                insecure_domain: insecure_domain.to_owned(),
+                client: client.clone(),
+            });
+            client
+        }
+    }
+}
+
+/// A half-open pooled connection takes our request and never answers, and keeps getting reused.
+/// After enough timeouts in a row the client is rebuilt, which drops all of its pooled connections.
+fn watch_for_stalled_connections(
+    state: &mut ProxyState,
+    host: &str,
+    timed_out: bool,
+    watchdog: &UpstreamWatchdog,
+) {
+    if !timed_out {
+        state.consecutive_timeouts = 0;
+        return;
+    }
+
+    state.consecutive_timeouts += 1;
+    if state.consecutive_timeouts >= watchdog.stalled_request_threshold {
+        warn!(
+            "{} requests in a row timed out (last one to {}), dropping the pooled upstream connections",
+            state.consecutive_timeouts, host
+        );
+        state.upstream_client = None;
+        state.consecutive_timeouts = 0;
+    }
+}
 
-    let mut bytebuf = ByteBuffer::from_bytes(bytes);
-    bytebuf.set_endian(Endian::LittleEndian);
+/// What the connection pool of the upstream client is configured with. hyper's defaults are no
+/// limit of idle connections per host and a 90 second idle timeout, same as the preferences'.
+#[derive(Debug, PartialEq)]
+struct PoolConfig {
+    idle_timeout: Duration,
+    max_idle_per_host: usize,
+}
 
-    loop {
-        let remaining_bytes = bytebuf.len() - bytebuf.get_rpos();
-        if remaining_bytes == 0 {
-            break;
-        } else if remaining_bytes < 7 {
-            let leftover = bytebuf.read_bytes(remaining_bytes)?;
-            warn!("Encountered {remaining_bytes} leftover bytes:\n{}", rhexdump::rhexdumps!(&leftover));
-            break;
+impl PoolConfig {
+    fn of(pool_settings: &UpstreamPoolSettings) -> Self {
+        let max_idle_per_host = if pool_settings.keep_alive {
+            pool_settings.max_idle_per_host.unwrap_or(usize::MAX)
         } else {
-            let mut header_bytes = [0; 7];
-            bytebuf.read_exact(&mut header_bytes)?;
-            let header = BanchoPacketHeader::from_bytes(header_bytes)?;
-            let packet = BanchoPacket::from_header_and_bytebuf(&header, &mut bytebuf)?;
-            packets.push(packet);
+            // hyper has no keep-alive switch for the client, not keeping idle connections around is the same thing
+            0
+        };
+        Self {
+            idle_timeout: pool_settings.idle_timeout(),
+            max_idle_per_host,
         }
     }
 
-    Ok(packets)
+    fn apply(&self, builder: &mut hyper::client::Builder) {
+        builder.pool_idle_timeout(self.idle_timeout);
+        builder.pool_max_idle_per_host(self.max_idle_per_host);
+    }
 }
 
-async fn process_bancho_packets(
-    preferences: &mut Preferences,
-    packets: &mut Vec<BanchoPacket>,
-    target_domain: &str,
+/// `insecure_domain` is the one server, with its subdomains, whose certificate isn't verified. Empty for none.
+pub fn build_client(
+    pool_settings: &UpstreamPoolSettings,
+    insecure_domain: &str,
+) -> Client<HttpsConnector<HttpConnector>> {
+    let tls = if insecure_domain.trim().is_empty() {
+        rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_native_roots()
+            .with_no_client_auth()
+    } else {
+        rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(ScopedInsecureVerifier::new(
+                tls::native_roots(),
+                insecure_domain,
+            )))
+            .with_no_client_auth()
+    };
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(tls)
+        .https_or_http()
+        .enable_http1()
+        .build();
+
+    let mut builder = Client::builder();
+    PoolConfig::of(pool_settings).apply(&mut builder);
+    builder.build(https)
+}
+
+/// Each packet on its own, as it would be sent if nothing changed it.
+fn reencode_each(packets: &[BanchoPacket]) -> Result<Vec<Bytes>, CodecError> {
+    packets.iter().map(BanchoPacket::to_bytes).collect()
+}
+
+/// Whether the packets re-encoded straight after decoding are byte for byte the body they came
+/// from, logs where they first diverge if not.
+fn roundtrip_matches(original: &[u8], reencoded: &[Bytes], direction: PacketDirection) -> bool {
+    let mut offset = 0;
+    for (index, packet) in reencoded.iter().enumerate() {
+        let original_packet =
+            &original[offset.min(original.len())..(offset + packet.len()).min(original.len())];
+        let divergence = original_packet
+            .iter()
+            .zip(packet)
+            .position(|(original_byte, byte)| original_byte != byte)
+            .or_else(|| {
+                (original_packet.len() != packet.len())
+                    .then(|| original_packet.len().min(packet.len()))
+            });
+        if let Some(at) = divergence {
+            let from = at.saturating_sub(HEXDUMP_BYTES / 4);
+            let region = |bytes: &[u8]| {
+                bytes[from.min(bytes.len())..(from + HEXDUMP_BYTES).min(bytes.len())].to_vec()
+            };
+            ROUNDTRIP_MISMATCH_WARNING.warn(|| {
+                format!(
+                    "{} packet #{} (id {}) at byte {} re-encodes differently from byte {} of it, sending the original body\noriginal:\n{}\nre-encoded:\n{}",
+                    direction,
+                    index,
+                    u16::from_le_bytes([packet[0], packet[1]]),
+                    offset,
+                    at,
+                    rhexdump::rhexdumps!(&region(original_packet)),
+                    rhexdump::rhexdumps!(&region(packet))
+                )
+            });
+            return false;
+        }
+        offset += packet.len();
+    }
+
+    if offset != original.len() {
+        ROUNDTRIP_MISMATCH_WARNING.warn(|| {
+            format!(
+                "{} body has {} bytes after its last packet that weren't re-encoded, sending the original body",
+                direction,
+                original.len() - offset
+            )
+        });
+        return false;
+    }
+    true
+}
+
+/// Whether a modified body is sane enough to send, as (before, after) pairs. Otherwise a bug in
+/// the processing could turn a small exchange into a huge one, so the original bytes go out instead.
+fn within_processing_limits(
+    limits: &ProcessingLimits,
+    direction: PacketDirection,
+    packet_counts: (usize, usize),
+    body_lengths: (usize, usize),
+) -> bool {
+    let injected = packet_counts.1.saturating_sub(packet_counts.0);
+    if injected > limits.max_injected_packets {
+        warn!(
+            "Processing {:?} packets injected {} packets (limit {}), sending the original instead",
+            direction, injected, limits.max_injected_packets
+        );
+        return false;
+    }
+
+    let allowed_length = body_lengths.0.max(ProcessingLimits::MIN_BODY_BUDGET) as f64
+        * limits.max_body_growth_factor;
+    if body_lengths.1 as f64 > allowed_length {
+        warn!(
+            "Processing {:?} packets grew the body from {} to {} bytes, sending the original instead",
+            direction, body_lengths.0, body_lengths.1
+        );
+        return false;
+    }
+
+    true
+}
+
+/// Queues a plain bancho body for the capture, if recording is on.
+async fn capture_bancho_body(
+    preferences: &Mutex<Preferences>,
+    state: &Mutex<ProxyState>,
+    osu_token: &str,
+    direction: PacketDirection,
+    chunks: Vec<Bytes>,
 ) {
-    packets.retain_mut(|packet| {
-        match packet {
-            BanchoPacket::SendPublicMessage(message) => {
-                info!("Sending public message {:?}", message);
-                if message.text.contains("ACTION is listening to") {
-                    message.text = message.text.replace("https://osu.osus.zihad.dev/beatmapsets", &*format!("https://osu.{}/beatmapsets", target_domain));
+    let max_session_bytes = {
+        let preferences = preferences.lock().await;
+        if !preferences.record_bancho_traffic {
+            return;
+        }
+        preferences.capture_max_session_mb * 1024 * 1024
+    };
+    state
+        .lock()
+        .await
+        .capture
+        .record(osu_token, direction, chunks, max_session_bytes);
+}
+
+/// Fixes the framing headers of a body we rebuilt. The original may have been chunked, and keeping
+/// Transfer-Encoding next to the new Content-Length makes an invalid message.
+fn set_body_length(headers: &mut HeaderMap, length: usize) {
+    headers.remove(header::TRANSFER_ENCODING);
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+}
+
+/// What processing a bancho response needs from the request it answers.
+struct BanchoExchange {
+    preferences: Arc<Mutex<Preferences>>,
+    state: Arc<Mutex<ProxyState>>,
+    target_domain: String,
+    session_token: Option<String>,
+    session_user_id: Option<i32>,
+    /// Only in login responses, taken once the session starts.
+    cho_token: Option<String>,
+    /// The session to capture the response in, if recording.
+    capture_token: Option<String>,
+    login_username: Option<String>,
+    round_trip: Duration,
+    request_body_len: usize,
+    request_processing: Duration,
+}
+
+impl BanchoExchange {
+    /// Runs packets of the response through the proxy, returns what to send instead of `original`.
+    async fn process(&mut self, original: Bytes, mut packets: Vec<BanchoPacket>) -> Bytes {
+        if packets.is_empty() {
+            return original;
+        }
+        debug!(
+            "{} {}",
+            PacketDirection::ServerToClient,
+            summarize_packets(&packets, PacketDirection::ServerToClient)
+        );
+
+        let decoded_count = packets.len();
+        let mut preferences = self.preferences.lock().await;
+        let mut state = self.state.lock().await;
+        state
+            .packet_stats
+            .record_body(PacketDirection::ServerToClient, &original);
+        state
+            .packet_viewer
+            .record_body(PacketDirection::ServerToClient, &original, &packets);
+        // A packet that doesn't re-encode fails the encoding below too, that's where it's reported
+        let reencoded = preferences
+            .verify_roundtrip
+            .then(|| reencode_each(&packets))
+            .and_then(Result::ok);
+        let had_session_user = self.session_user_id.is_some();
+        process_bancho_packets(
+            &mut preferences,
+            &mut state,
+            &mut self.session_user_id,
+            &mut packets,
+            PacketDirection::ServerToClient,
+            &self.target_domain,
+        )
+        .await;
+        // Logged out or the server restarting
+        let session_ended = had_session_user && self.session_user_id.is_none();
+        if let Some(token) = self.session_token.as_deref().filter(|_| session_ended) {
+            state.sessions.end(token);
+        }
+        if self.session_user_id.is_some() {
+            // Taken, so this only happens once for each session token
+            if let Some(token) = self.cho_token.take() {
+                state.sessions.start(
+                    &token,
+                    self.session_user_id,
+                    self.login_username.take(),
+                    &self.target_domain,
+                    preferences.fake_supporter,
+                );
+                if !preferences.quiet_mode {
+                    packets.push(login_greeting(&preferences));
                 }
             }
-            BanchoPacket::UserId(user_id) => {
-                preferences.user_id = Some(*user_id);
+        }
+
+        let processed_count = packets.len();
+        let encoded = match encode_bancho_packets(packets, original.len()) {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                warn!(
+                    "Failed to encode the bancho response, passing it through: {}",
+                    e
+                );
+                return original;
             }
-            BanchoPacket::SendPrivateMessage(message) => {
-                info!("Sending private message {:?}", message);
-                if message.text.contains("ACTION is listening to") {
-                    message.text = message.text.replace("https://osu.osus.zihad.dev/beatmapsets", &*format!("https://osu.{}/beatmapsets", target_domain));
+        };
+        let roundtrip_broken = reencoded.is_some_and(|reencoded| {
+            reencoded.concat() == encoded
+                && !roundtrip_matches(&original, &reencoded, PacketDirection::ServerToClient)
+        });
+        if !roundtrip_broken
+            && within_processing_limits(
+                &preferences.processing_limits,
+                PacketDirection::ServerToClient,
+                (decoded_count, processed_count),
+                (original.len(), encoded.len()),
+            )
+        {
+            encoded
+        } else {
+            original
+        }
+    }
+
+    /// Counts the exchange once the whole response went through, returns packets to append to it:
+    /// the ping result if the client asked for one, and whatever is still queued for the client if
+    /// `decoded`, i.e. the body didn't end in something that isn't packets. `received` is the body
+    /// as it came, for the capture.
+    async fn finish(
+        self,
+        response_body_len: usize,
+        processing: Duration,
+        received: Vec<Bytes>,
+        decoded: bool,
+    ) -> Vec<BanchoPacket> {
+        let preferences = self.preferences.lock().await;
+        let mut state = self.state.lock().await;
+        let mut trailing = vec![];
+        if std::mem::take(&mut state.ping_requested) {
+            let ping = PingResult {
+                round_trip: self.round_trip,
+                exchange_bytes: self.request_body_len + response_body_len,
+            };
+            info!("Ping: {}", ping);
+            state.last_ping = Some(ping);
+            trailing.push(BanchoPacket::Notification(ping.to_string().into()));
+        }
+        // Responses without packets never went through process_bancho_packets
+        if let Some(user_id) = self.session_user_id.filter(|_| decoded) {
+            trailing.extend(
+                state
+                    .pending_injections
+                    .take(user_id, PacketDirection::ServerToClient),
+            );
+        }
+
+        if let Some(token) = &self.capture_token {
+            let max_session_bytes = preferences.capture_max_session_mb * 1024 * 1024;
+            state.capture.record(
+                token,
+                PacketDirection::ServerToClient,
+                received,
+                max_session_bytes,
+            );
+        }
+
+        state.bancho_exchanges += 1;
+        state.upstream_error = None;
+        state.liveness.record_contact();
+        let overhead = self.request_processing + processing;
+        let budget = Duration::from_millis(preferences.processing_overhead_budget_ms);
+        if state.processing_overhead.record(overhead, budget) {
+            warn!(
+                "The proxy adds more than {:?} to most bancho exchanges (p95 {:?})",
+                budget,
+                state.processing_overhead.percentile(95).unwrap_or_default()
+            );
+        }
+        trailing
+    }
+}
+
+/// Processes a bancho response as it arrives, packets go out to the client as soon as they're
+/// complete instead of once the whole body is in. Anything that isn't packets, like an error page
+/// from a reverse proxy, is passed through as is, and so is the rest of a body once a packet
+/// doesn't decode, unless `strict`.
+fn stream_bancho_response(
+    mut body: Body,
+    mut exchange: BanchoExchange,
+    strict: bool,
+    max_packet_length: usize,
+) -> Body {
+    let (mut sender, streamed) = Body::channel();
+    tokio::spawn(async move {
+        let mut splitter = PacketSplitter::new(PacketDirection::ServerToClient, max_packet_length);
+        let mut is_html = false;
+        let mut passing_through = false;
+        let mut body_len = 0;
+        let mut processing = Duration::ZERO;
+        // Only kept when capturing, the chunks are shared with what's sent on
+        let mut received = vec![];
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    warn!("Failed to read the bancho response: {}", e);
+                    sender.abort();
+                    return;
                 }
+            };
+            if body_len == 0 && looks_like_html(&chunk) {
+                warn!("Bancho responded with an HTML page instead of packets, passing it through");
+                exchange.state.lock().await.upstream_error = Some(UpstreamError {
+                    status: StatusCode::OK,
+                    html: true,
+                });
+                is_html = true;
+                passing_through = true;
             }
-            BanchoPacket::SendMessage(message) => {
-                info!("Receiving message {:?}", message);
-                if message.text.contains("ACTION is listening to") {
-                    message.text = message.text.replace(&format!("https://osu.{}/beatmapsets", target_domain), "https://osu.osus.zihad.dev/beatmapsets");
-                }
+            body_len += chunk.len();
+            if exchange.capture_token.is_some() {
+                received.push(chunk.clone());
             }
-            BanchoPacket::Privilege {
-                privileges_bitfield,
-            } => {
-                if preferences.fake_supporter {
-                    // Add supporter if does not already exist
-                    *privileges_bitfield = *privileges_bitfield | (1 << 2);
 
-                    // Remove supporter if exists, to test with local bancho.py or cmyui.xyz since those give supporter by default
-                    // *privileges_bitfield = *privileges_bitfield & !(1 << 2);
-                }
+            let outgoing = if passing_through {
+                chunk
+            } else {
+                let processing_started = Instant::now();
+                splitter.push(chunk);
+                let outgoing = match splitter.split() {
+                    Ok((original, packets)) => exchange.process(original, packets).await,
+                    Err(e) if strict => {
+                        warn!("Failed to decode the bancho response, aborting it: {}", e);
+                        sender.abort();
+                        return;
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Failed to decode the bancho response, passing the rest through: {}",
+                            e
+                        );
+                        passing_through = true;
+                        splitter.take_pending()
+                    }
+                };
+                processing += processing_started.elapsed();
+                outgoing
+            };
+            // The client going away isn't worth a warning
+            if !outgoing.is_empty() && sender.send_data(outgoing).await.is_err() {
+                return;
             }
-            BanchoPacket::ChangeAction { action, .. } => {
-                if action == &UserAction::OsuDirect && preferences.fake_supporter {
-                    return false;
+        }
+
+        if !passing_through {
+            if let Err(e) = splitter.finish() {
+                if strict {
+                    warn!("Failed to decode the bancho response, aborting it: {}", e);
+                    sender.abort();
+                    return;
                 }
+                warn!(
+                    "Failed to decode the bancho response, passing the rest through: {}",
+                    e
+                );
+                let _ = sender.send_data(splitter.take_pending()).await;
             }
-            BanchoPacket::UserPresence { user_id, country_code, .. } => {
-                if let Some(country) = &preferences.fake_country {
-                    if let Some(logged_in_user_id) = preferences.user_id {
-                        if logged_in_user_id == *user_id {
-                            *country_code = country.as_u8();
-                        }
-                    }
+        }
+        if !is_html {
+            let trailing = exchange
+                .finish(body_len, processing, received, !passing_through)
+                .await;
+            // The body is sent chunked, so there's no Content-Length to keep in sync
+            match encode_bancho_packets(trailing, 0) {
+                Ok(trailing) if !trailing.is_empty() => {
+                    let _ = sender.send_data(trailing).await;
                 }
+                Ok(_) => {}
+                Err(e) => warn!(
+                    "Failed to encode the packets appended to the bancho response: {}",
+                    e
+                ),
             }
-            _ => {}
         }
-
-        true
     });
+    streamed
 }
 
-async fn encode_bancho_packets(packets: Vec<BanchoPacket>) -> io::Result<Vec<u8>> {
-    let mut bytes = vec![];
-    for packet in packets {
-        bytes.append(&mut packet.to_bytes());
+/// What's wrong with a bancho response that can't have any packets in it, only `200 OK` ones can.
+fn error_response(status: &StatusCode, headers: &HeaderMap) -> Option<UpstreamError> {
+    if *status == StatusCode::OK {
+        return None;
     }
-
-    Ok(bytes)
+    let html = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.trim_start().starts_with("text/html"));
+    Some(UpstreamError {
+        status: *status,
+        html,
+    })
 }
 
-fn load_certs() -> Result<Vec<rustls::Certificate>> {
-    let cert_bytes = include_bytes!("../../server.crt");
-    let mut reader = io::Cursor::new(cert_bytes);
-
-    let certs =
-        rustls_pemfile::certs(&mut reader).map_err(|_| eyre!("failed to load certificate"))?;
-    Ok(certs.into_iter().map(rustls::Certificate).collect())
+/// No packet id starts with these, so it's safe to tell them apart by the first couple of bytes.
+fn looks_like_html(bytes: &[u8]) -> bool {
+    let start = bytes.trim_ascii_start();
+    start.starts_with(b"<!")
+        || start
+            .get(..5)
+            .is_some_and(|tag| tag.eq_ignore_ascii_case(b"<html"))
 }
 
-fn load_private_key() -> Result<rustls::PrivateKey> {
-    let key_bytes = include_bytes!("../../server.key");
-    let mut reader = io::Cursor::new(key_bytes);
+/// How a ChangeAction is logged, the info text is cut short as it can be a whole map title.
+fn action_summary(
+    action: &UserAction,
+    info_text: &str,
+    mods: &Mods,
+    mode: &GameMode,
+    map_id: i32,
+) -> String {
+    format!(
+        "{:?} [{}] (mods {}, map {}): {}",
+        action,
+        mode,
+        mods,
+        map_id,
+        info_text
+            .chars()
+            .take(MAX_LOGGED_INFO_TEXT_CHARS)
+            .collect::<String>()
+    )
+}
 
-    let keys = rustls_pemfile::rsa_private_keys(&mut reader)
-        .map_err(|_| eyre!("failed to load private key"))?;
-    if keys.len() != 1 {
-        return Err(eyre!("expected a single private key"));
+/// `summary` if it differs from the last action, which it then replaces. The client keeps resending
+/// the same action while idle, and those aren't worth logging again.
+fn changed_action(last_action_summary: &mut Option<String>, summary: String) -> Option<&str> {
+    if last_action_summary.as_ref() == Some(&summary) {
+        return None;
     }
+    Some(last_action_summary.insert(summary))
+}
 
-    Ok(rustls::PrivateKey(keys[0].clone()))
+/// `session_user_id` is the user owning the exchange, if they logged in through the proxy.
+async fn process_bancho_packets(
+    preferences: &mut Preferences,
+    state: &mut ProxyState,
+    session_user_id: &mut Option<i32>,
+    packets: &mut Vec<BanchoPacket>,
+    direction: PacketDirection,
+    target_domain: &str,
+) {
+    // What was actually changed in this exchange, for the login notification
+    let mut applied = vec![];
+    let mut logged_in = false;
+    // Before the packet log sees them, so the coordinates don't end up in an export either
+    if preferences.hide_location && hide_own_location(packets, *session_user_id) {
+        applied.push("location hidden".to_owned());
+    }
+    state.known_users.for_server(target_domain);
+    if preferences.record_packet_log {
+        for packet in packets.iter() {
+            state
+                .packet_log
+                .record(packet, direction, preferences.packet_log_full_dump);
+        }
+    }
+    for packet in packets.iter_mut() {
+        packet.visit_links_mut(|field, text| {
+            let link_origins = &mut state.link_origins;
+            let rewritten = rewrite_domains(
+                text,
+                field,
+                direction,
+                target_domain,
+                preferences,
+                link_origins,
+            );
+            if let Cow::Owned(rewritten) = rewritten {
+                **text = rewritten;
+            }
+        });
+    }
+    if !preferences.auto_reply.enabled {
+        state.auto_replies.reset();
+    }
+    packets.retain_mut(|packet| {
+        match packet {
+            // Sent by the client
+            BanchoPacket::SendPublicMessage(message) => {
+                if intercept_local_command(&message.text, preferences, state, *session_user_id) {
+                    return false;
+                }
+                info!(
+                    target: CHAT_LOG_TARGET,
+                    "Sending public message {:?}{}",
+                    message,
+                    channel_membership(state, *session_user_id, &message.recipient)
+                );
+                if preferences.chat_filter.outgoing && !filter_chat_message(message, direction, preferences, state) {
+                    return false;
+                }
+                log_chat(message, direction, target_domain, preferences, state, *session_user_id);
+            }
+            BanchoPacket::SendPrivateMessage(message) => {
+                if intercept_local_command(&message.text, preferences, state, *session_user_id) {
+                    return false;
+                }
+                info!(target: CHAT_LOG_TARGET, "Sending private message {:?}", message);
+                if preferences.chat_filter.outgoing && !filter_chat_message(message, direction, preferences, state) {
+                    return false;
+                }
+                log_chat(message, direction, target_domain, preferences, state, *session_user_id);
+            }
+            BanchoPacket::ChangeAction {
+                action,
+                info_text,
+                mods,
+                mode,
+                map_id,
+                ..
+            } => {
+                let summary = action_summary(action, info_text, mods, mode, *map_id);
+                if let Some(summary) = changed_action(&mut state.last_action_summary, summary) {
+                    info!("Changing action to {}", summary);
+                }
+                state.desktop_notifier.action_changed(action);
+                state.now_playing = matches!(action, UserAction::Playing | UserAction::Multiplaying).then(|| {
+                    NowPlaying {
+                        map_id: *map_id,
+                        info_text: info_text.to_string(),
+                        mode: *mode,
+                    }
+                });
+                if action == &UserAction::OsuDirect && preferences.fake_supporter {
+                    return false;
+                }
+            }
+            BanchoPacket::Ping => {
+                state.liveness.record_ping();
+            }
+            BanchoPacket::UserPresenceRequest(user_ids) => {
+                debug!("Requesting the presence of users {:?}", user_ids);
+            }
+            BanchoPacket::UserPresenceRequestAll { .. } => {
+                debug!("Requesting the presence of everyone online");
+            }
+            // Sent by the server
+            BanchoPacket::UserId(user_id) => match LoginFailure::from_user_id(*user_id) {
+                Some(failure) => {
+                    warn!("Login rejected by {}: {}", target_domain, failure);
+                    state.login_failure = Some(failure);
+                }
+                None => {
+                    *session_user_id = Some(*user_id);
+                    state.joined_channels.remove(user_id);
+                    state.pending_injections.forget(*user_id);
+                    state.settings_notices.forget(*user_id);
+                    logged_in = true;
+                    state.login_failure = None;
+                    state.server_restart = None;
+                    state.online_users.clear();
+                }
+            },
+            BanchoPacket::SendMessage(message) => {
+                let sender_id = message.sender_id;
+                if preferences.blocked_users.iter().any(|blocked| blocked.matches(&message.sender, sender_id)) {
+                    info!("Blocking a message from {} ({}) to {}", message.sender, sender_id, message.recipient);
+                    *state.blocked_messages.entry(message.sender.trim().to_owned()).or_default() += 1;
+                    return false;
+                }
+                info!(
+                    target: CHAT_LOG_TARGET,
+                    "Receiving message {:?}{}",
+                    message,
+                    channel_membership(state, *session_user_id, &message.recipient)
+                );
+                if !filter_chat_message(message, direction, preferences, state) {
+                    return false;
+                }
+                log_chat(message, direction, target_domain, preferences, state, *session_user_id);
+                if let Some(user_id) = *session_user_id {
+                    if let Some(reply) = state.auto_replies.reply_to(message, user_id, &preferences.auto_reply) {
+                        info!("Auto-replying to {}", reply.recipient);
+                        let reply = BanchoPacket::SendPrivateMessage(reply);
+                        state.pending_injections.push(user_id, PacketDirection::ClientToServer, reply);
+                    }
+                    let do_not_disturb = &preferences.do_not_disturb;
+                    if do_not_disturb.enabled
+                        && message.is_private()
+                        && !do_not_disturb.lets_through(&message.sender, message.sender_id, &state.friends)
+                    {
+                        debug!("Holding a message from {} until do not disturb is off", message.sender);
+                        state.held_messages.hold(user_id, message.clone());
+                        return false;
+                    }
+                    state.desktop_notifier.message_received(
+                        message,
+                        user_id,
+                        state.known_users.get(user_id).map(|user| user.name.as_str()),
+                        &preferences.desktop_notifications,
+                    );
+                }
+            }
+            BanchoPacket::Notification(text) => {
+                info!("Receiving notification {:?}", text);
+            }
+            BanchoPacket::ChannelJoinSuccess(name) => {
+                if let Some(user_id) = *session_user_id {
+                    debug!("User {} joined {}", state.known_users.describe(user_id), name);
+                    state.joined_channels.entry(user_id).or_default().insert(name.to_string());
+                }
+            }
+            BanchoPacket::ChannelKick(name) => {
+                if let Some(user_id) = *session_user_id {
+                    info!("User {} was removed from {}", state.known_users.describe(user_id), name);
+                    if let Some(channels) = state.joined_channels.get_mut(&user_id) {
+                        channels.remove(name.as_str());
+                    }
+                }
+            }
+            BanchoPacket::SpectatorJoined { user_id } if preferences.block_spectators => {
+                info!("Blocking user {} from spectating", state.known_users.describe(*user_id));
+                return false;
+            }
+            BanchoPacket::UserLogout { user_id, .. } => {
+                state.online_users.remove(user_id);
+                if *session_user_id == Some(*user_id) {
+                    info!("The server logged out user {}", state.known_users.describe(*user_id));
+                    state.joined_channels.remove(user_id);
+                    state.pending_injections.forget(*user_id);
+                    state.settings_notices.forget(*user_id);
+                    *session_user_id = None;
+                }
+            }
+            BanchoPacket::SilenceEnd { seconds } => {
+                if *seconds > 0 {
+                    let remaining = Duration::from_secs(*seconds as u64);
+                    warn!("Silenced on {} for another {:?}", target_domain, remaining);
+                    state.silenced_until = Some(Instant::now() + remaining);
+                } else if state.silenced_until.take().is_some() {
+                    info!("No longer silenced on {}", target_domain);
+                }
+            }
+            BanchoPacket::UserSilenced { user_id } => {
+                info!("User {} was silenced", state.known_users.describe(*user_id));
+            }
+            BanchoPacket::SwitchTournamentServer(host) => {
+                info!("{} asked the client to switch to the bancho server at {}", target_domain, host);
+                if preferences.rewrite_tournament_server_switch && !host.is_empty() {
+                    state.bancho_host_override = Some((target_domain.to_owned(), host.to_string()));
+                    **host = source_host("c");
+                }
+            }
+            BanchoPacket::UserPresenceBundle(user_ids) => {
+                debug!("{} users are online", user_ids.len());
+                state.online_users.extend(user_ids.iter().copied());
+            }
+            BanchoPacket::FriendsList(user_ids) => {
+                debug!("Got a friends list of {} users", user_ids.len());
+                state.friends = user_ids.clone();
+            }
+            BanchoPacket::Restart { ms } => {
+                let delay = Duration::from_millis((*ms).max(0) as u64);
+                warn!("{} is restarting, the client should reconnect in {:?}", target_domain, delay);
+                state.server_restart = Some(delay);
+                if let Some(user_id) = session_user_id.take() {
+                    state.joined_channels.remove(&user_id);
+                    state.pending_injections.forget(user_id);
+                    state.settings_notices.forget(user_id);
+                }
+            }
+            BanchoPacket::ProtocolVersion(version) => {
+                info!("{} speaks bancho protocol version {}", target_domain, version);
+                if *version != bancho::BANCHO_PROTOCOL_VERSION {
+                    warn!(
+                        "Expected bancho protocol version {}, some packets from {} may be decoded wrong",
+                        bancho::BANCHO_PROTOCOL_VERSION, target_domain
+                    );
+                }
+                state.protocol_version = Some(*version);
+            }
+            BanchoPacket::MainMenuIcon { image_url, click_url } => {
+                debug!("Main menu icon {:?} linking to {:?}", image_url, click_url);
+            }
+            BanchoPacket::Privilege { privileges } => {
+                info!("The server granted {}", privileges);
+                state.last_privileges = Some(*privileges);
+                if preferences.fake_supporter {
+                    if !privileges.contains(BanchoPrivileges::SUPPORTER) {
+                        applied.push("supporter faked".to_owned());
+                    }
+                    privileges.insert(BanchoPrivileges::SUPPORTER);
+                    // Remove supporter if exists, to test with local bancho.py or cmyui.xyz since those give supporter by default
+                    // privileges.remove(BanchoPrivileges::SUPPORTER);
+                }
+            }
+            BanchoPacket::UserPresence {
+                user_id,
+                name,
+                country_code,
+                bancho_privileges,
+                utc_offset,
+                global_rank,
+                ..
+            } => {
+                state.online_users.insert(*user_id);
+                // As the server sent it, before faking anything
+                state.known_users.record(*user_id, name, *country_code, *bancho_privileges);
+                if *session_user_id == Some(*user_id) {
+                    let mut modifications = vec![];
+                    if let Some(country) = &preferences.fake_country {
+                        *country_code = country.as_u8();
+                        modifications.push(format!("country shown as {}", country));
+                    }
+                    if let Some(offset) = preferences.fake_utc_offset {
+                        *utc_offset = offset;
+                        modifications.push(format!("time zone shown as {}", format_utc_offset(offset)));
+                    }
+                    if let Some(rank) = fake_rank(preferences) {
+                        *global_rank = rank;
+                        modifications.push(format!("rank shown as #{}", rank));
+                    }
+                    for modification in modifications {
+                        if !applied.contains(&modification) {
+                            applied.push(modification);
+                        }
+                    }
+                }
+            }
+            BanchoPacket::UserStats {
+                user_id,
+                action,
+                info_text,
+                map_md5,
+                mods,
+                map_id,
+                global_rank,
+                ..
+            } if *session_user_id == Some(*user_id) => {
+                if preferences.hide_activity {
+                    *action = UserAction::Idle;
+                    info_text.clear();
+                    map_md5.clear();
+                    *mods = Mods::empty();
+                    *map_id = 0;
+                }
+                if let Some(rank) = fake_rank(preferences) {
+                    *global_rank = rank;
+                }
+            }
+            // Either way
+            BanchoPacket::SpectateFrames(bundle) | BanchoPacket::SendSpectateFrames(bundle) => {
+                state
+                    .spectate_stats
+                    .record(bundle.frame_count, bundle.data.len(), bundle.has_score_frame);
+            }
+            BanchoPacket::Other { id, data } => {
+                state.protocol_coverage.record(*id, direction, data);
+            }
+            _ => {}
+        }
+
+        true
+    });
+
+    if direction == PacketDirection::ClientToServer && session_user_id.is_some() {
+        inject_watchlist_requests(preferences, state, packets);
+    }
+    if let Some(user_id) = *session_user_id {
+        if !preferences.do_not_disturb.enabled {
+            for message in state
+                .held_messages
+                .release(user_id, do_not_disturb::RELEASE_BATCH)
+            {
+                let message = BanchoPacket::SendMessage(message);
+                state
+                    .pending_injections
+                    .push(user_id, PacketDirection::ServerToClient, message);
+            }
+        }
+        let changes = state.settings_notices.changes(user_id, preferences);
+        if !changes.is_empty() {
+            queue_notification(state, user_id, &changes.join(", "));
+        }
+        let pending = state.pending_injections.take(user_id, direction);
+        if !pending.is_empty() {
+            debug!("Injecting {} queued packets", pending.len());
+            packets.extend(pending);
+        }
+    }
+
+    if logged_in && preferences.login_notification && !applied.is_empty() {
+        let summary = format!("osus proxy: {}", applied.join(", "));
+        info!("Login modifications: {}", summary);
+        packets.push(BanchoPacket::Notification(summary.into()));
+    }
+}
+
+/// Ranks start at 1, anything else in the preferences file means no fake rank.
+fn fake_rank(preferences: &Preferences) -> Option<i32> {
+    preferences.fake_rank.filter(|rank| *rank > 0)
+}
+
+/// E.g. `UTC+9`, `UTC-5` or `UTC+0`.
+pub fn format_utc_offset(offset: i8) -> String {
+    format!("UTC{:+}", offset)
+}
+
+/// Zeroes the coordinates in the presence of the session's user, which the server guesses from the
+/// IP they logged in from. Returns whether there was one. In the login response the user id comes
+/// in the same body.
+fn hide_own_location(packets: &mut [BanchoPacket], session_user_id: Option<i32>) -> bool {
+    let own_user_id = session_user_id.or_else(|| {
+        packets.iter().find_map(|packet| match packet {
+            BanchoPacket::UserId(user_id) if LoginFailure::from_user_id(*user_id).is_none() => {
+                Some(*user_id)
+            }
+            _ => None,
+        })
+    });
+    let Some(own_user_id) = own_user_id else {
+        return false;
+    };
+
+    let mut hidden = false;
+    for packet in packets {
+        if let BanchoPacket::UserPresence {
+            user_id,
+            longitude,
+            latitude,
+            ..
+        } = packet
+        {
+            if *user_id == own_user_id {
+                *longitude = 0.0;
+                *latitude = 0.0;
+                hidden = true;
+            }
+        }
+    }
+    hidden
+}
+
+/// Censors `message` according to the chat filter, or returns false if it's to be dropped.
+fn filter_chat_message(
+    message: &mut OsuMessage,
+    direction: PacketDirection,
+    preferences: &Preferences,
+    state: &mut ProxyState,
+) -> bool {
+    match state
+        .chat_filter
+        .apply(&message.text, &preferences.chat_filter)
+    {
+        Filtered::Unchanged => true,
+        Filtered::Censored(text) => {
+            debug!("Censored a message to {}", message.recipient);
+            *message.text = text;
+            true
+        }
+        Filtered::Dropped(pattern) => {
+            match direction {
+                PacketDirection::ClientToServer => info!(
+                    target: CHAT_LOG_TARGET,
+                    "Not sending a message to {} matching {:?}: {:?}",
+                    message.recipient, pattern, message.text
+                ),
+                PacketDirection::ServerToClient => info!(
+                    target: CHAT_LOG_TARGET,
+                    "Hiding a message from {} to {} matching {:?}: {:?}",
+                    message.sender, message.recipient, pattern, message.text
+                ),
+            }
+            false
+        }
+    }
+}
+
+/// Hands `message` to the chat log if it's on, with our name from the presence the server sent.
+fn log_chat(
+    message: &OsuMessage,
+    direction: PacketDirection,
+    target_domain: &str,
+    preferences: &Preferences,
+    state: &mut ProxyState,
+    session_user_id: Option<i32>,
+) {
+    let chat_logs = &preferences.chat_logs;
+    if !chat_logs.enabled || (message.is_private() && !chat_logs.private_messages) {
+        return;
+    }
+    let own_name = session_user_id
+        .and_then(|user_id| state.known_users.get(user_id))
+        .map(|user| user.name.as_str());
+    state
+        .chat_log
+        .record(target_domain, direction, message, own_name);
+}
+
+/// Notes channel messages to channels the session's user isn't known to be in, for the log.
+fn channel_membership(
+    state: &ProxyState,
+    session_user_id: Option<i32>,
+    recipient: &str,
+) -> &'static str {
+    let not_joined = recipient.starts_with('#')
+        && session_user_id
+            .and_then(|user_id| state.joined_channels.get(&user_id))
+            .is_some_and(|channels| !channels.contains(recipient));
+    if not_joined {
+        " (not in that channel)"
+    } else {
+        ""
+    }
+}
+
+/// Runs `text` as a local command if it is one, see [`local_commands`], the reply is shown to the
+/// user as a notification. Returns whether it was one.
+fn intercept_local_command(
+    text: &str,
+    preferences: &mut Preferences,
+    state: &mut ProxyState,
+    session_user_id: Option<i32>,
+) -> bool {
+    let Some(reply) = local_commands::handle_local_command(text, preferences, state) else {
+        return false;
+    };
+    if let Some(user_id) = session_user_id {
+        // Settings the command changed are told the way they would be when changed in the UI
+        let changes = state.settings_notices.changes(user_id, preferences);
+        let text = if changes.is_empty() {
+            reply
+        } else {
+            changes.join(", ")
+        };
+        queue_notification(state, user_id, &text);
+    }
+    true
+}
+
+/// Shows that the game really goes through the proxy, and how it's set up.
+fn login_greeting(preferences: &Preferences) -> BanchoPacket {
+    let greeting = format!(
+        "osus proxy {} is on: {}",
+        env!("CARGO_PKG_VERSION"),
+        local_commands::status(preferences)
+    );
+    info!("Greeting: {}", greeting);
+    BanchoPacket::Notification(greeting.into())
+}
+
+/// Shows `text` in the game of `user_id` with the next response it gets.
+fn queue_notification(state: &mut ProxyState, user_id: i32, text: &str) {
+    info!(
+        "Telling user {}: {}",
+        state.known_users.describe(user_id),
+        text
+    );
+    let notification = BanchoPacket::Notification(format!("osus proxy: {}", text).into());
+    state
+        .pending_injections
+        .push(user_id, PacketDirection::ServerToClient, notification);
+}
+
+/// Asks the server for the presence and stats of the watchlisted users every now and then.
+fn inject_watchlist_requests(
+    preferences: &Preferences,
+    state: &mut ProxyState,
+    packets: &mut Vec<BanchoPacket>,
+) {
+    if preferences.presence_watchlist.is_empty() {
+        return;
+    }
+
+    let refresh_due = state
+        .last_watchlist_refresh
+        .is_none_or(|last_refresh| last_refresh.elapsed() >= WATCHLIST_REFRESH_INTERVAL);
+    if !refresh_due {
+        return;
+    }
+
+    debug!(
+        "Requesting presence and stats of {:?}",
+        preferences.presence_watchlist
+    );
+    packets.push(BanchoPacket::UserPresenceRequest(
+        preferences.presence_watchlist.clone(),
+    ));
+    packets.push(BanchoPacket::UserStatsRequest(
+        preferences.presence_watchlist.clone(),
+    ));
+    state.last_watchlist_refresh = Some(Instant::now());
+}
+
+fn apply_header_overrides(headers: &mut HeaderMap, header_overrides: &[HeaderOverride]) {
+    for header_override in header_overrides {
+        if header_override.name.trim().is_empty() {
+            continue;
+        }
+        let name = match HeaderName::from_str(header_override.name.trim()) {
+            Ok(name) => name,
+            Err(_) => {
+                warn!(
+                    "Not overriding header {:?}, invalid name",
+                    header_override.name
+                );
+                continue;
+            }
+        };
+        if PROTECTED_HEADERS.contains(&name.as_str()) {
+            warn!("Not overriding header {}, it can't be overridden", name);
+            continue;
+        }
+        let Ok(value) = HeaderValue::from_str(&header_override.value) else {
+            warn!(
+                "Not overriding header {}, invalid value {:?}",
+                name, header_override.value
+            );
+            continue;
+        };
+
+        debug!(
+            "Overriding header {}: {:?} -> {:?}",
+            name,
+            headers.get(&name),
+            value
+        );
+        headers.insert(name, value);
+    }
+}
+
+fn forwarded_ip(preferences: &Preferences, req: &Request<Body>) -> Option<IpAddr> {
+    match preferences.forwarded_ip_mode {
+        ForwardedIpMode::Omit => None,
+        ForwardedIpMode::RemoteAddress => req.extensions().get::<SocketAddr>().map(|x| x.ip()),
+        ForwardedIpMode::Custom => match preferences.forwarded_ip.trim().parse() {
+            Ok(ip) => Some(ip),
+            Err(_) => {
+                warn!(
+                    "Not forwarding an IP, {:?} is not a valid IP address",
+                    preferences.forwarded_ip
+                );
+                None
+            }
+        },
+    }
+}
+
+fn set_forwarded_ip_headers(headers: &mut HeaderMap, forwarded_ip: Option<IpAddr>) {
+    // The client never sends these, but make sure nothing we don't want to send gets through
+    headers.remove("X-Forwarded-For");
+    headers.remove("X-Real-IP");
+    if let Some(forwarded_ip) = forwarded_ip {
+        let forwarded_ip = HeaderValue::from_str(&forwarded_ip.to_string()).unwrap();
+        headers.insert("X-Forwarded-For", forwarded_ip.clone());
+        headers.insert("X-Real-IP", forwarded_ip);
+    }
+}
+
+fn injected_delay(
+    latency_injection: &LatencyInjection,
+    is_bancho_request: bool,
+    is_download_request: bool,
+) -> Option<Duration> {
+    let applies = (latency_injection.bancho && is_bancho_request)
+        || (latency_injection.downloads && is_download_request);
+    if !latency_injection.enabled || !applies {
+        return None;
+    }
+
+    // Doesn't need to be a good random number
+    let jitter_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos() as u64
+        % (latency_injection.jitter_ms + 1);
+    let delay_ms = (latency_injection.delay_ms + jitter_ms).min(LatencyInjection::MAX_TOTAL_MS);
+
+    Some(Duration::from_millis(delay_ms))
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Some `/web/` endpoints take the username and password hash in the query, this hides them so
+/// the query can be logged.
+fn redact_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, _)) if WEB_CREDENTIAL_PARAMS.contains(&key) => {
+                format!("{}={}", key, REDACTED)
+            }
+            _ => pair.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+async fn replay_fallback_response(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    fallback: &ReplayFallback,
+    url_template: &str,
+    query: &str,
+) -> Option<Response<Body>> {
+    let score_id = query_param(query, "c").unwrap_or_default();
+    match fallback {
+        ReplayFallback::Disabled => None,
+        ReplayFallback::EmptyReplay => {
+            info!(
+                "The server refused the replay of score {}, serving an empty one",
+                score_id
+            );
+            Some(Response::new(Body::empty()))
+        }
+        ReplayFallback::Url => {
+            let fill = |username: &str, password_hash: &str| {
+                url_template
+                    .replace("{score_id}", score_id)
+                    .replace("{mode}", query_param(query, "m").unwrap_or("0"))
+                    .replace("{username}", username)
+                    .replace("{password_hash}", password_hash)
+            };
+            // Only ever log this one
+            let url = fill(REDACTED, REDACTED);
+            let uri = match Uri::from_str(&fill(
+                query_param(query, "u").unwrap_or_default(),
+                query_param(query, "h").unwrap_or_default(),
+            )) {
+                Ok(uri) => uri,
+                Err(e) => {
+                    warn!("Invalid replay fallback URL {}: {}", url, e);
+                    return None;
+                }
+            };
+
+            match client.get(uri).await {
+                Ok(response) if response.status().is_success() => {
+                    info!(
+                        "The server refused the replay of score {}, serving it from {}",
+                        score_id, url
+                    );
+                    Some(response)
+                }
+                Ok(response) => {
+                    warn!(
+                        "Replay fallback {} responded with {}",
+                        url,
+                        response.status()
+                    );
+                    None
+                }
+                Err(e) => {
+                    warn!("Failed to fetch the replay from {}: {}", url, e);
+                    None
+                }
+            }
+        }
+    }
+}
+
+fn save_mirror_stats(mirror_stats: &MirrorStats) {
+    if let Ok(json) = mirror_stats.to_json() {
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = MirrorStats::write_file(&json) {
+                warn!("Failed to save the mirror stats: {}", e);
+            }
+        });
+    }
+}
+
+/// Fetches a download ourselves for mirrors the game can't download from directly, following
+/// their redirects. `None` if that didn't work either.
+async fn download_through_proxy(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    link: &str,
+) -> Option<Response<Body>> {
+    let mut uri = Uri::from_str(link).ok()?;
+    for _ in 0..MAX_DOWNLOAD_REDIRECTS {
+        let response = match client.get(uri.clone()).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Failed to download {}: {}", uri, e);
+                return None;
+            }
+        };
+        if !response.status().is_redirection() {
+            if !response.status().is_success() {
+                warn!("{} responded with {}", uri, response.status());
+                return None;
+            }
+            return Some(response);
+        }
+        uri = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| Uri::from_str(location).ok())?;
+    }
+    warn!("Too many redirects downloading {}", link);
+    None
+}
+
+/// The set id in `/s/<id>` and `/beatmapsets/<id>`, the pages the client opens in the browser.
+/// Browsers don't send the fragment, but a link pasted whole may still have one like `#osu/75`.
+fn beatmap_page_set_id(path: &str) -> Option<u32> {
+    let path = path.split_once('#').map_or(path, |(path, _)| path);
+    let id = path
+        .strip_prefix("/s/")
+        .or_else(|| path.strip_prefix("/beatmapsets/"))?;
+    let id = id.strip_suffix('/').unwrap_or(id);
+    // parse() would also take a leading +
+    if !id.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    id.parse().ok()
+}
+
+fn beatmapsets_url(domain: &str) -> String {
+    format!("https://osu.{}/beatmapsets", domain)
+}
+
+/// Points links in `text` from one server to the other: links the client sends from the proxy to
+/// the server they came from, or the one we're connected to, links it receives from that server,
+/// or one used recently, back to the proxy. Where each link came from goes in `link_origins`.
+/// `text` is returned as is, without allocating, when there is nothing to rewrite.
+///
+/// In [`LinkField::Text`] only beatmap links are rewritten, whether they're on the osu subdomain
+/// or the bare domain (some private servers link that), over http or https. Everything after the
+/// host is kept, e.g. the mode and difficulty in `/beatmapsets/1#mania/2`. A [`LinkField::Url`]
+/// is rewritten on any proxied subdomain, so the client doesn't talk to the server directly.
+fn rewrite_domains<'t>(
+    text: &'t str,
+    field: LinkField,
+    direction: PacketDirection,
+    target_domain: &str,
+    preferences: &Preferences,
+    link_origins: &mut LinkOrigins,
+) -> Cow<'t, str> {
+    if !text.contains("://") {
+        return Cow::Borrowed(text);
+    }
+
+    let mut rewritten = String::new();
+    // How much of `text` is in `rewritten` already
+    let mut copied = 0;
+    for (start, end) in link_spans(text) {
+        let link = &text[start..end];
+        let replacement = match field {
+            LinkField::Text => {
+                rewrite_beatmap_link(link, direction, target_domain, preferences, link_origins)
+            }
+            LinkField::Url => rewrite_proxied_link(link, direction, target_domain, preferences),
+        };
+        if let Some(replacement) = replacement {
+            rewritten.push_str(&text[copied..start]);
+            rewritten.push_str(&replacement);
+            copied = end;
+        }
+    }
+    if copied == 0 {
+        return Cow::Borrowed(text);
+    }
+    rewritten.push_str(&text[copied..]);
+    Cow::Owned(rewritten)
+}
+
+/// Where the http(s) links in `text` start and end. Chat puts them in brackets with a title, like
+/// `[https://osu.ppy.sh/b/1 Title]` or `(Title)[https://osu.ppy.sh/b/1]`.
+fn link_spans(text: &str) -> impl Iterator<Item = (usize, usize)> + '_ {
+    let mut position = 0;
+    std::iter::from_fn(move || loop {
+        let separator = position + text[position..].find("://")?;
+        let start = ["https", "http"]
+            .iter()
+            .find(|scheme| text[..separator].ends_with(*scheme))
+            .map(|scheme| separator - scheme.len());
+        let end = text[separator..]
+            .find(|c: char| c.is_whitespace() || "[]()<>\"'".contains(c))
+            .map_or(text.len(), |length| separator + length);
+        position = end;
+        if let Some(start) = start {
+            return Some((start, end));
+        }
+    })
+}
+
+/// The server we're connected to and the ones used recently, links from any of them point at the proxy.
+fn known_servers<'p>(
+    target_domain: &'p str,
+    preferences: &'p Preferences,
+) -> impl Iterator<Item = &'p str> {
+    std::iter::once(target_domain).chain(
+        preferences
+            .recent_server_addresses
+            .iter()
+            .map(String::as_str),
+    )
+}
+
+/// The scheme, host and everything after the host of `link`.
+fn split_link(link: &str) -> Option<(&str, &str, &str)> {
+    let (scheme, rest) = link.split_once("://")?;
+    let (host, after_host) = rest.split_at(rest.find(['/', '?', '#']).unwrap_or(rest.len()));
+    Some((scheme, host, after_host))
+}
+
+/// Which of `domains` the beatmap link host `host` belongs to, on the osu subdomain or the bare domain.
+fn beatmap_link_domain<'d>(
+    host: &str,
+    mut domains: impl Iterator<Item = &'d str>,
+) -> Option<&'d str> {
+    domains.find(|domain| host == *domain || host.strip_prefix("osu.") == Some(*domain))
+}
+
+fn rewrite_beatmap_link(
+    link: &str,
+    direction: PacketDirection,
+    target_domain: &str,
+    preferences: &Preferences,
+    link_origins: &mut LinkOrigins,
+) -> Option<String> {
+    let (_, host, after_host) = split_link(link)?;
+    if !BEATMAP_LINK_PATHS
+        .iter()
+        .any(|path| after_host.starts_with(path))
+    {
+        return None;
+    }
+    // What the browser asks the proxy for when the link is clicked
+    let path = after_host.split(['?', '#']).next().unwrap_or(after_host);
+    match direction {
+        PacketDirection::ClientToServer => {
+            beatmap_link_domain(host, std::iter::once(SOURCE_DOMAIN))?;
+            match link_origins::unmark(after_host) {
+                Some((after_host, id)) => {
+                    let domain = link_origins.get(id, path).unwrap_or(target_domain);
+                    Some(format!("https://osu.{}{}", domain, after_host))
+                }
+                None => Some(format!("https://osu.{}{}", target_domain, after_host)),
+            }
+        }
+        PacketDirection::ServerToClient => {
+            let domain = beatmap_link_domain(host, known_servers(target_domain, preferences))?;
+            let id = link_origins.record(path, domain);
+            Some(format!(
+                "https://osu.{}{}",
+                SOURCE_DOMAIN,
+                link_origins::mark(after_host, id)
+            ))
+        }
+    }
+}
+
+fn rewrite_proxied_link(
+    link: &str,
+    direction: PacketDirection,
+    target_domain: &str,
+    preferences: &Preferences,
+) -> Option<String> {
+    let (scheme, host, after_host) = split_link(link)?;
+    let (subdomain, domain) = host.split_once('.')?;
+    if !preferences
+        .subdomains
+        .iter()
+        .any(|proxied| proxied == subdomain)
+    {
+        return None;
+    }
+    let to_domain = match direction {
+        PacketDirection::ClientToServer => (domain == SOURCE_DOMAIN).then_some(target_domain)?,
+        PacketDirection::ServerToClient => {
+            let mut servers = known_servers(target_domain, preferences);
+            servers
+                .any(|server| server == domain)
+                .then_some(SOURCE_DOMAIN)?
+        }
+    };
+    Some(format!(
+        "{}://{}.{}{}",
+        scheme, subdomain, to_domain, after_host
+    ))
+}
+
+fn load_certs() -> Result<Vec<rustls::Certificate>> {
+    let cert_bytes = include_bytes!("../../server.crt");
+    let mut reader = io::Cursor::new(cert_bytes);
+
+    let certs =
+        rustls_pemfile::certs(&mut reader).map_err(|_| eyre!("failed to load certificate"))?;
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+fn load_private_key() -> Result<rustls::PrivateKey> {
+    let key_bytes = include_bytes!("../../server.key");
+    let mut reader = io::Cursor::new(key_bytes);
+
+    let keys = rustls_pemfile::rsa_private_keys(&mut reader)
+        .map_err(|_| eyre!("failed to load private key"))?;
+    if keys.len() != 1 {
+        return Err(eyre!("expected a single private key"));
+    }
+
+    Ok(rustls::PrivateKey(keys[0].clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osus_proxy::bancho::{Country, OsuChannel, OsuString};
+
+    fn preferences_with_recent_servers(servers: &[&str]) -> Preferences {
+        Preferences {
+            recent_server_addresses: servers.iter().map(|&server| server.to_owned()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rewrites_links_from_two_previous_servers_back_to_where_they_came_from() {
+        let preferences = preferences_with_recent_servers(&[
+            "current.example",
+            "first.example",
+            "second.example",
+        ]);
+        let mut link_origins = LinkOrigins::default();
+        // The same beatmap from two servers too
+        let received = "[https://osu.first.example/beatmapsets/1#osu/2 One] and \
+                        (Two)[http://second.example/b/3?m=3] and https://osu.first.example/b/3 and \
+                        https://osu.unrelated.example/b/4";
+        let rewritten = rewrite_domains(
+            received,
+            LinkField::Text,
+            PacketDirection::ServerToClient,
+            "current.example",
+            &preferences,
+            &mut link_origins,
+        );
+        let one = link_origins.record("/beatmapsets/1", "first.example");
+        let two = link_origins.record("/b/3", "second.example");
+        let three = link_origins.record("/b/3", "first.example");
+        assert_eq!(
+            rewritten,
+            format!(
+                "[https://osu.{0}/beatmapsets/1?osus_origin={1}#osu/2 One] and \
+                 (Two)[https://osu.{0}/b/3?m=3&osus_origin={2}] and https://osu.{0}/b/3?osus_origin={3} and \
+                 https://osu.unrelated.example/b/4",
+                SOURCE_DOMAIN, one, two, three
+            )
+        );
+
+        // Sending them on leads to the server each came from, not the one we're connected to
+        let sent = rewrite_domains(
+            &rewritten,
+            LinkField::Text,
+            PacketDirection::ClientToServer,
+            "current.example",
+            &preferences,
+            &mut link_origins,
+        );
+        assert_eq!(
+            sent,
+            "[https://osu.first.example/beatmapsets/1#osu/2 One] and (Two)[https://osu.second.example/b/3?m=3] and \
+             https://osu.first.example/b/3 and https://osu.unrelated.example/b/4"
+        );
+        // Links nobody sent us go to the current server
+        let typed = format!("https://osu.{}/s/5", SOURCE_DOMAIN);
+        let sent = rewrite_domains(
+            &typed,
+            LinkField::Text,
+            PacketDirection::ClientToServer,
+            "current.example",
+            &preferences,
+            &mut link_origins,
+        );
+        assert_eq!(sent, "https://osu.current.example/s/5");
+    }
+
+    #[tokio::test]
+    async fn clicked_links_lead_to_the_server_they_came_from() {
+        let preferences = Preferences {
+            server_address: "current.example".to_owned(),
+            ..Default::default()
+        };
+        let state = Arc::new(Mutex::new(ProxyState::default()));
+        let first = state
+            .lock()
+            .await
+            .link_origins
+            .record("/b/3", "first.example");
+        let second = state
+            .lock()
+            .await
+            .link_origins
+            .record("/b/3", "second.example");
+
+        for (id, expected) in [
+            (first, "https://osu.first.example/b/3?m=3"),
+            (second, "https://osu.second.example/b/3?m=3"),
+        ] {
+            let uri = format!("http://osu.{}/b/3?m=3&osus_origin={}", SOURCE_DOMAIN, id);
+            let mut req = Request::get(uri)
+                .header(header::HOST, source_host("osu"))
+                .body(Body::empty())
+                .unwrap();
+            req.extensions_mut()
+                .insert(Arc::new(Mutex::new(preferences.clone())));
+            req.extensions_mut().insert(state.clone());
+            let response = handle_requests(req).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FOUND);
+            assert_eq!(
+                response.headers().get(header::LOCATION),
+                Some(&HeaderValue::from_static(expected))
+            );
+        }
+    }
+
+    #[test]
+    fn rewrites_np_actions_the_way_stable_sends_them() {
+        let preferences = preferences_with_recent_servers(&["current.example"]);
+        let mut link_origins = LinkOrigins::default();
+        let actions = [
+            "\x01ACTION is listening to [https://osu.{domain}/beatmapsets/123456#mania/654321 Artist - Title]\x01",
+            "\x01ACTION is playing [https://osu.{domain}/beatmapsets/123456#mania/654321 Artist - Title [4K Hard]] \
+             <osu!mania> +HardRock\x01",
+            "\x01ACTION is playing [https://osu.{domain}/beatmapsets/1#osu/75 Artist - Title [Normal]] +Hidden\x01",
+            "\x01ACTION is editing [https://osu.{domain}/b/75 Artist - Title [Normal]]\x01",
+            "\x01ACTION is watching [http://osu.{domain}/s/1 Artist - Title]\x01",
+        ];
+        for action in actions {
+            let sent = action.replace("{domain}", SOURCE_DOMAIN);
+            let forwarded = rewrite_domains(
+                &sent,
+                LinkField::Text,
+                PacketDirection::ClientToServer,
+                "current.example",
+                &preferences,
+                &mut link_origins,
+            );
+            // Links go over https to the server, the mode, difficulty and mods stay as they were
+            let forwarded_action = action
+                .replace("{domain}", "current.example")
+                .replace("http://", "https://");
+            assert_eq!(forwarded, forwarded_action);
+
+            // And back when the server echoes it to the channel
+            let received = rewrite_domains(
+                &forwarded,
+                LinkField::Text,
+                PacketDirection::ServerToClient,
+                "current.example",
+                &preferences,
+                &mut link_origins,
+            );
+            let (start, end) = link_spans(&forwarded).next().unwrap();
+            let (_, _, after_host) = split_link(&forwarded[start..end]).unwrap();
+            let path = after_host.split(['?', '#']).next().unwrap();
+            let marked =
+                link_origins::mark(after_host, link_origins.record(path, "current.example"));
+            assert_eq!(
+                received,
+                forwarded_action
+                    .replace(after_host, &marked)
+                    .replace("current.example", SOURCE_DOMAIN)
+            );
+        }
+    }
+
+    #[test]
+    fn leaves_text_without_links_to_rewrite_alone() {
+        let preferences = preferences_with_recent_servers(&["current.example"]);
+        let mut link_origins = LinkOrigins::default();
+        for text in [
+            "no links here",
+            "https://osu.current.example/home",
+            "see https://example.com/b/1",
+        ] {
+            let rewritten = rewrite_domains(
+                text,
+                LinkField::Text,
+                PacketDirection::ServerToClient,
+                "current.example",
+                &preferences,
+                &mut link_origins,
+            );
+            assert!(matches!(rewritten, Cow::Borrowed(_)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn rewrites_urls_on_proxied_subdomains() {
+        let preferences = preferences_with_recent_servers(&["current.example", "first.example"]);
+        let mut link_origins = LinkOrigins::default();
+        let rewritten = rewrite_domains(
+            "https://a.first.example/menu.png",
+            LinkField::Url,
+            PacketDirection::ServerToClient,
+            "current.example",
+            &preferences,
+            &mut link_origins,
+        );
+        assert_eq!(rewritten, format!("https://a.{}/menu.png", SOURCE_DOMAIN));
+        let rewritten = rewrite_domains(
+            "https://cdn.first.example/menu.png",
+            LinkField::Url,
+            PacketDirection::ServerToClient,
+            "current.example",
+            &preferences,
+            &mut link_origins,
+        );
+        assert_eq!(rewritten, "https://cdn.first.example/menu.png");
+    }
+
+    #[test]
+    fn latency_is_only_injected_into_the_selected_requests() {
+        let disabled = LatencyInjection {
+            bancho: true,
+            downloads: true,
+            ..Default::default()
+        };
+        assert_eq!(injected_delay(&disabled, true, false), None);
+        assert_eq!(injected_delay(&disabled, false, true), None);
+
+        let bancho_only = LatencyInjection {
+            enabled: true,
+            delay_ms: 100,
+            jitter_ms: 0,
+            bancho: true,
+            downloads: false,
+        };
+        assert_eq!(
+            injected_delay(&bancho_only, true, false),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(injected_delay(&bancho_only, false, true), None);
+        assert_eq!(injected_delay(&bancho_only, false, false), None);
+
+        let downloads_only = LatencyInjection {
+            bancho: false,
+            downloads: true,
+            ..bancho_only
+        };
+        assert_eq!(injected_delay(&downloads_only, true, false), None);
+        assert_eq!(
+            injected_delay(&downloads_only, false, true),
+            Some(Duration::from_millis(100))
+        );
+    }
+
+    #[test]
+    fn injected_latency_is_capped() {
+        let huge = LatencyInjection {
+            enabled: true,
+            delay_ms: 60_000,
+            jitter_ms: 60_000,
+            ..Default::default()
+        };
+        assert_eq!(
+            injected_delay(&huge, true, false),
+            Some(Duration::from_millis(LatencyInjection::MAX_TOTAL_MS))
+        );
+        let jittery = LatencyInjection {
+            enabled: true,
+            delay_ms: 100,
+            jitter_ms: 50,
+            ..Default::default()
+        };
+        let delay = injected_delay(&jittery, true, false).unwrap();
+        assert!(
+            (Duration::from_millis(100)..=Duration::from_millis(150)).contains(&delay),
+            "{:?}",
+            delay
+        );
+    }
+
+    #[test]
+    fn pool_config_follows_the_preferences() {
+        // The defaults are hyper's
+        assert_eq!(
+            PoolConfig::of(&UpstreamPoolSettings::default()),
+            PoolConfig {
+                idle_timeout: Duration::from_secs(90),
+                max_idle_per_host: usize::MAX,
+            }
+        );
+        let configured = UpstreamPoolSettings {
+            keep_alive: true,
+            max_idle_per_host: Some(2),
+            idle_timeout_secs: 15,
+        };
+        assert_eq!(
+            PoolConfig::of(&configured),
+            PoolConfig {
+                idle_timeout: Duration::from_secs(15),
+                max_idle_per_host: 2,
+            }
+        );
+        let no_keep_alive = UpstreamPoolSettings {
+            keep_alive: false,
+            ..configured
+        };
+        assert_eq!(PoolConfig::of(&no_keep_alive).max_idle_per_host, 0);
+    }
+
+    #[test]
+    fn repeated_actions_are_only_logged_once() {
+        let mut last_action_summary = None;
+        let idle = || action_summary(&UserAction::Idle, "", &Mods::empty(), &GameMode::Osu, 0);
+        let playing = action_summary(
+            &UserAction::Playing,
+            "Artist - Title [Hard]",
+            &Mods::HIDDEN,
+            &GameMode::Osu,
+            75,
+        );
+        assert_eq!(
+            changed_action(&mut last_action_summary, idle()),
+            Some(idle().as_str())
+        );
+        assert_eq!(changed_action(&mut last_action_summary, idle()), None);
+        assert_eq!(
+            changed_action(&mut last_action_summary, playing.clone()),
+            Some(playing.as_str())
+        );
+        assert_eq!(
+            changed_action(&mut last_action_summary, playing.clone()),
+            None
+        );
+        // Going back is a change again
+        assert!(changed_action(&mut last_action_summary, idle()).is_some());
+    }
+
+    #[test]
+    fn actions_differing_only_past_the_logged_info_text_count_as_repeats() {
+        let long_text = "x".repeat(MAX_LOGGED_INFO_TEXT_CHARS);
+        let summary = |suffix: &str| {
+            let info_text = format!("{}{}", long_text, suffix);
+            action_summary(
+                &UserAction::Editing,
+                &info_text,
+                &Mods::empty(),
+                &GameMode::Mania,
+                1,
+            )
+        };
+        let mut last_action_summary = None;
+        assert!(changed_action(&mut last_action_summary, summary("a")).is_some());
+        assert_eq!(changed_action(&mut last_action_summary, summary("b")), None);
+    }
+
+    /// The packets added to an otherwise empty exchange.
+    async fn exchange(
+        preferences: &mut Preferences,
+        state: &mut ProxyState,
+        direction: PacketDirection,
+        mut session_user_id: Option<i32>,
+    ) -> Vec<BanchoPacket> {
+        let mut packets = vec![];
+        process_bancho_packets(
+            preferences,
+            state,
+            &mut session_user_id,
+            &mut packets,
+            direction,
+            "example.com",
+        )
+        .await;
+        packets
+    }
+
+    #[tokio::test]
+    async fn watchlist_requests_are_only_appended_to_outgoing_exchanges() {
+        let mut preferences = Preferences {
+            presence_watchlist: vec![2, 1000],
+            ..Default::default()
+        };
+        let mut state = ProxyState::default();
+
+        assert!(exchange(
+            &mut preferences,
+            &mut state,
+            PacketDirection::ServerToClient,
+            Some(3)
+        )
+        .await
+        .is_empty());
+        // Nobody to ask for yet
+        assert!(exchange(
+            &mut preferences,
+            &mut state,
+            PacketDirection::ClientToServer,
+            None
+        )
+        .await
+        .is_empty());
+
+        let injected = exchange(
+            &mut preferences,
+            &mut state,
+            PacketDirection::ClientToServer,
+            Some(3),
+        )
+        .await;
+        let encoded = encode_bancho_packets(injected, 0).unwrap();
+        let decoded =
+            decode_bancho_packets(&encoded, PacketDirection::ClientToServer, 1024).unwrap();
+        assert!(
+            matches!(
+                decoded.as_slice(),
+                [BanchoPacket::UserPresenceRequest(presence), BanchoPacket::UserStatsRequest(stats)]
+                    if presence == &[2, 1000] && stats == &[2, 1000]
+            ),
+            "{:?}",
+            decoded
+        );
+
+        // Not again until the interval has passed
+        assert!(exchange(
+            &mut preferences,
+            &mut state,
+            PacketDirection::ClientToServer,
+            Some(3)
+        )
+        .await
+        .is_empty());
+    }
+
+    /// The forwarding headers sent upstream for a request from `remote_address` that tried to
+    /// forward an address of its own.
+    fn forwarded_headers(
+        preferences: &Preferences,
+        remote_address: SocketAddr,
+    ) -> (Option<String>, Option<String>) {
+        let mut req = Request::new(Body::empty());
+        req.extensions_mut().insert(remote_address);
+        req.headers_mut()
+            .insert("X-Forwarded-For", HeaderValue::from_static("10.0.0.1"));
+        let forwarded_ip = forwarded_ip(preferences, &req);
+        let headers = req.headers_mut();
+        set_forwarded_ip_headers(headers, forwarded_ip);
+        let header = |name| {
+            headers
+                .get(name)
+                .map(|value: &HeaderValue| value.to_str().unwrap().to_owned())
+        };
+        (header("X-Forwarded-For"), header("X-Real-IP"))
+    }
+
+    #[test]
+    fn forwards_the_ip_the_mode_asks_for() {
+        let remote_address = SocketAddr::from(([192, 168, 1, 20], 51234));
+        let omit = Preferences::default();
+        assert_eq!(forwarded_headers(&omit, remote_address), (None, None));
+
+        let remote = Preferences {
+            forwarded_ip_mode: ForwardedIpMode::RemoteAddress,
+            ..Default::default()
+        };
+        let expected = Some("192.168.1.20".to_owned());
+        assert_eq!(
+            forwarded_headers(&remote, remote_address),
+            (expected.clone(), expected)
+        );
+
+        let custom = Preferences {
+            forwarded_ip_mode: ForwardedIpMode::Custom,
+            forwarded_ip: " 2001:db8::1 ".to_owned(),
+            ..Default::default()
+        };
+        let expected = Some("2001:db8::1".to_owned());
+        assert_eq!(
+            forwarded_headers(&custom, remote_address),
+            (expected.clone(), expected)
+        );
+
+        let invalid = Preferences {
+            forwarded_ip: "localhost".to_owned(),
+            ..custom
+        };
+        assert_eq!(forwarded_headers(&invalid, remote_address), (None, None));
+    }
+
+    #[test]
+    fn header_overrides_leave_protected_and_invalid_headers_alone() {
+        let header_override = |name: &str, value: &str| HeaderOverride {
+            name: name.to_owned(),
+            value: value.to_owned(),
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("Host", HeaderValue::from_static("c.example.com"));
+        headers.insert("osu-token", HeaderValue::from_static("token"));
+        headers.insert("User-Agent", HeaderValue::from_static("osu!"));
+        apply_header_overrides(
+            &mut headers,
+            &[
+                header_override(" user-agent ", "not osu!"),
+                header_override("X-Extra", "1"),
+                header_override("HOST", "evil.example.com"),
+                header_override("Osu-Token", "stolen"),
+                header_override("Content-Length", "0"),
+                header_override("Transfer-Encoding", "chunked"),
+                header_override("cho-token", "forged"),
+                header_override("bad header", "1"),
+                header_override("X-Bad-Value", "line\nbreak"),
+                header_override("", "ignored"),
+            ],
+        );
+
+        let mut expected = HeaderMap::new();
+        expected.insert("Host", HeaderValue::from_static("c.example.com"));
+        expected.insert("osu-token", HeaderValue::from_static("token"));
+        expected.insert("User-Agent", HeaderValue::from_static("not osu!"));
+        expected.insert("X-Extra", HeaderValue::from_static("1"));
+        assert_eq!(headers, expected);
+    }
+
+    /// What a reverse proxy in front of a server sends while the server is down.
+    const ERROR_PAGE_FIXTURE: &str = "<!DOCTYPE html>\n\
+        <html lang=\"en-US\">\n\
+        <head><title>c.example.com | 502: Bad gateway</title></head>\n\
+        <body><h1>Bad gateway</h1><p>Error code 502</p><p>Visit cloudflare.com for more information.</p></body>\n\
+        </html>\n";
+
+    fn exchange_with(state: Arc<Mutex<ProxyState>>) -> BanchoExchange {
+        BanchoExchange {
+            preferences: Arc::new(Mutex::new(Preferences::default())),
+            state,
+            target_domain: "example.com".to_owned(),
+            session_token: None,
+            session_user_id: None,
+            cho_token: None,
+            capture_token: None,
+            login_username: None,
+            round_trip: Duration::ZERO,
+            request_body_len: 0,
+            request_processing: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn only_ok_bancho_responses_can_have_packets() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(error_response(&StatusCode::OK, &headers), None);
+        assert_eq!(
+            error_response(&StatusCode::SERVICE_UNAVAILABLE, &headers),
+            Some(UpstreamError {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                html: false,
+            })
+        );
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=UTF-8"),
+        );
+        assert_eq!(
+            error_response(&StatusCode::BAD_GATEWAY, &headers),
+            Some(UpstreamError {
+                status: StatusCode::BAD_GATEWAY,
+                html: true,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn error_pages_are_passed_through_untouched() {
+        assert!(looks_like_html(ERROR_PAGE_FIXTURE.as_bytes()));
+        let state = Arc::new(Mutex::new(ProxyState::default()));
+
+        // Split mid-tag, only the start of the body decides
+        let (first, rest) = ERROR_PAGE_FIXTURE.split_at(20);
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for chunk in [first, rest] {
+                sender
+                    .send_data(Bytes::from_static(chunk.as_bytes()))
+                    .await
+                    .unwrap();
+            }
+        });
+        let streamed = stream_bancho_response(body, exchange_with(state.clone()), true, 1024);
+        let streamed = hyper::body::to_bytes(streamed).await.unwrap();
+        assert_eq!(streamed, ERROR_PAGE_FIXTURE.as_bytes());
+        assert_eq!(
+            state.lock().await.upstream_error,
+            Some(UpstreamError {
+                status: StatusCode::OK,
+                html: true,
+            })
+        );
+
+        // Until a response that could have packets comes in
+        let streamed =
+            stream_bancho_response(Body::empty(), exchange_with(state.clone()), true, 1024);
+        assert!(hyper::body::to_bytes(streamed).await.unwrap().is_empty());
+        assert_eq!(state.lock().await.upstream_error, None);
+    }
+
+    #[test]
+    fn finds_the_set_id_of_beatmap_pages() {
+        assert_eq!(beatmap_page_set_id("/s/1"), Some(1));
+        assert_eq!(beatmap_page_set_id("/beatmapsets/123456"), Some(123456));
+        assert_eq!(beatmap_page_set_id("/beatmapsets/123456/"), Some(123456));
+        assert_eq!(
+            beatmap_page_set_id("/beatmapsets/123456#mania/654321"),
+            Some(123456)
+        );
+        assert_eq!(beatmap_page_set_id("/s/39804#"), Some(39804));
+    }
+
+    #[test]
+    fn ignores_other_pages_and_bad_set_ids() {
+        for path in [
+            "/",
+            "/beatmapsets",
+            "/beatmapsets/",
+            "/beatmapsets/#osu/75",
+            "/b/75",
+            "/beatmaps/75",
+            "/s/+1",
+            "/s/-1",
+            "/s/1e3",
+            "/s/ 1",
+            "/s/abc",
+            "/beatmapsets/123/discussion",
+            "/beatmapsets/99999999999",
+            "/users/2/beatmapsets/1",
+        ] {
+            assert_eq!(beatmap_page_set_id(path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn the_upstream_client_is_only_rebuilt_when_its_settings_change() {
+        let mut preferences = Preferences::default();
+        let mut state = ProxyState::default();
+        let built_with = |state: &ProxyState, preferences: &Preferences| {
+            let cached = state.upstream_client.as_ref().unwrap();
+            cached.built_with(
+                &preferences.upstream_pool,
+                &preferences.insecure_upstream_domain,
+            )
+        };
+        upstream_client(
+            &mut state,
+            &preferences.upstream_pool,
+            &preferences.insecure_upstream_domain,
+        );
+        assert!(built_with(&state, &preferences));
+
+        preferences.fake_supporter = !preferences.fake_supporter;
+        preferences.header_overrides.push(HeaderOverride {
+            name: "osu-version".to_owned(),
+            value: "b20231001".to_owned(),
+        });
+        assert!(built_with(&state, &preferences));
+
+        preferences.insecure_upstream_domain = "example.com".to_owned();
+        assert!(!built_with(&state, &preferences));
+        upstream_client(
+            &mut state,
+            &preferences.upstream_pool,
+            &preferences.insecure_upstream_domain,
+        );
+        assert!(built_with(&state, &preferences));
+
+        preferences.upstream_pool.idle_timeout_secs += 1;
+        assert!(!built_with(&state, &preferences));
+    }
+
+    /// A plain HTTP server standing in for the upstream server, `respond` answers every request.
+    async fn mock_upstream<F, R>(respond: F) -> SocketAddr
+    where
+        F: Fn(Request<Body>) -> R + Clone + Send + Sync + 'static,
+        R: Future<Output = Response<Body>> + Send + 'static,
+    {
+        let make_service = make_service_fn(move |_| {
+            let respond = respond.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req| {
+                    let response = respond(req);
+                    async move { Ok::<_, hyper::Error>(response.await) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    /// `bytes` split into chunks at `splits`, sent without a length like a chunked body.
+    fn chunked(bytes: &Bytes, splits: &[usize]) -> Body {
+        let mut chunks = vec![];
+        let mut start = 0;
+        for &end in splits.iter().chain([bytes.len()].iter()) {
+            chunks.push(bytes.slice(start..end));
+            start = end;
+        }
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for chunk in chunks {
+                if sender.send_data(chunk).await.is_err() {
+                    return;
+                }
+            }
+        });
+        body
+    }
+
+    /// What requests get in their extensions when the proxy serves them.
+    type Proxy = (Arc<Mutex<Preferences>>, Arc<Mutex<ProxyState>>);
+
+    /// What the proxy runs with, its bancho requests go to `upstream` instead of the server.
+    fn proxying_to(upstream: SocketAddr, preferences: Preferences) -> Proxy {
+        let state = ProxyState {
+            bancho_host_override: Some((preferences.server_address.clone(), upstream.to_string())),
+            ..Default::default()
+        };
+        (
+            Arc::new(Mutex::new(preferences)),
+            Arc::new(Mutex::new(state)),
+        )
+    }
+
+    /// A chunked bancho request of a logged in client, as it reaches the proxy.
+    fn bancho_request((preferences, state): &Proxy, body: Body) -> Request<Body> {
+        let mut req = Request::post("http://c.osus.zihad.dev/")
+            .header(header::HOST, source_host("c"))
+            .header("osu-token", "token")
+            .header(header::TRANSFER_ENCODING, "chunked")
+            .body(body)
+            .unwrap();
+        req.extensions_mut().insert(preferences.clone());
+        req.extensions_mut().insert(state.clone());
+        req
+    }
+
+    #[tokio::test]
+    async fn chunked_bancho_bodies_are_framed_once() {
+        let request_body = encode_bancho_packets(
+            vec![BanchoPacket::Ping, BanchoPacket::UserStatsRequest(vec![2])],
+            0,
+        )
+        .unwrap();
+        let response_body = encode_bancho_packets(
+            vec![
+                BanchoPacket::Notification("Welcome to the mock server".to_owned().into()),
+                BanchoPacket::ProtocolVersion(19),
+            ],
+            0,
+        )
+        .unwrap();
+        let received = Arc::new(std::sync::Mutex::new(None));
+        let upstream = {
+            let received = received.clone();
+            let response_body = response_body.clone();
+            mock_upstream(move |req: Request<Body>| {
+                let received = received.clone();
+                let response_body = response_body.clone();
+                async move {
+                    let (parts, body) = req.into_parts();
+                    let body = hyper::body::to_bytes(body).await.unwrap();
+                    *received.lock().unwrap() = Some((parts.headers, body));
+                    // Like nginx in front of bancho.py, split mid-header
+                    Response::builder()
+                        .header(header::TRANSFER_ENCODING, "chunked")
+                        .body(chunked(&response_body, &[3, 10]))
+                        .unwrap()
+                }
+            })
+            .await
+        };
+
+        let proxy = proxying_to(upstream, Preferences::default());
+        let response = handle_requests(bancho_request(&proxy, chunked(&request_body, &[5])))
+            .await
+            .unwrap();
+
+        let (headers, body) = received.lock().unwrap().take().unwrap();
+        assert_eq!(
+            headers.get(header::CONTENT_LENGTH),
+            Some(&HeaderValue::from(request_body.len()))
+        );
+        assert!(!headers.contains_key(header::TRANSFER_ENCODING));
+        assert_eq!(body, request_body);
+
+        assert_eq!(response.status(), StatusCode::OK);
+        // Streamed on, so the length isn't known up front
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, response_body);
+    }
+
+    #[tokio::test]
+    async fn pooled_connections_are_dropped_after_requests_to_a_silent_upstream_time_out() {
+        // Takes the request and never answers, like a half-open connection
+        let upstream = mock_upstream(|_| std::future::pending::<Response<Body>>()).await;
+        let preferences = Preferences {
+            upstream_watchdog: UpstreamWatchdog {
+                request_timeout_secs: 1,
+                stalled_request_threshold: 2,
+            },
+            ..Default::default()
+        };
+        let proxy = proxying_to(upstream, preferences);
+
+        let response = handle_requests(bancho_request(&proxy, Body::empty()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        {
+            let state = proxy.1.lock().await;
+            assert_eq!(state.consecutive_timeouts, 1);
+            assert!(state.upstream_client.is_some());
+            let (_, error) = state.last_upstream_error.as_ref().unwrap();
+            assert!(error.ends_with("no response within 1 seconds"), "{}", error);
+        }
+
+        let response = handle_requests(bancho_request(&proxy, Body::empty()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let state = proxy.1.lock().await;
+        assert_eq!(state.consecutive_timeouts, 0);
+        assert!(
+            state.upstream_client.is_none(),
+            "the client with the dead connection was kept"
+        );
+    }
+
+    /// A busy bancho response: what comes after a login on a populated server, and some chat.
+    fn busy_response_fixture() -> Bytes {
+        let text = |text: String| OsuString::from(text);
+        let mut packets = vec![
+            BanchoPacket::ProtocolVersion(19),
+            BanchoPacket::UserId(3),
+            BanchoPacket::Privilege {
+                privileges: BanchoPrivileges::SUPPORTER,
+            },
+            BanchoPacket::FriendsList((1..200).collect()),
+            BanchoPacket::UserPresenceBundle((1..1000).collect()),
+        ];
+        for channel in 0..30 {
+            packets.push(BanchoPacket::ChannelInfo(OsuChannel {
+                name: text(format!("#channel{}", channel)),
+                topic: text(
+                    "Talk about anything, links like https://osu.example.com/beatmapsets/1 too"
+                        .to_owned(),
+                ),
+                player_count: 100,
+            }));
+        }
+        for user_id in 1..200 {
+            packets.push(BanchoPacket::UserPresence {
+                user_id,
+                name: text(format!("player{}", user_id)),
+                utc_offset: 24,
+                country_code: 1,
+                bancho_privileges: 1,
+                longitude: 0.0,
+                latitude: 0.0,
+                global_rank: user_id,
+            });
+            packets.push(BanchoPacket::UserStats {
+                user_id,
+                action: UserAction::Playing,
+                info_text: text("Artist - Title [Insane]".to_owned()),
+                map_md5: text("d41d8cd98f00b204e9800998ecf8427e".to_owned()),
+                mods: Mods::HIDDEN,
+                mode: GameMode::Osu,
+                map_id: 75,
+                ranked_score: 1_000_000,
+                accuracy: 0.98,
+                playcount: 1000,
+                total_score: 2_000_000,
+                global_rank: user_id,
+                pp: 4000,
+            });
+        }
+        for message in 0..50 {
+            packets.push(BanchoPacket::SendMessage(OsuMessage {
+                sender: text(format!("player{}", message)),
+                text: text(format!(
+                    "try https://osu.example.com/beatmapsets/{}#osu/{} it's great",
+                    message, message
+                )),
+                recipient: text("#osu".to_owned()),
+                sender_id: message,
+            }));
+        }
+        encode_bancho_packets(packets, 0).unwrap()
+    }
+
+    /// What the proxy adds to an exchange goes straight into players' latency, this catches a gross
+    /// regression. Run with `cargo test --release -- --ignored` for numbers worth comparing.
+    #[tokio::test]
+    #[ignore = "timing on a shared machine is flaky"]
+    async fn processing_a_busy_response_stays_within_the_overhead_budget() {
+        let body = busy_response_fixture();
+        let mut exchange = exchange_with(Arc::new(Mutex::new(ProxyState::default())));
+        let mut timings = vec![];
+        for _ in 0..50 {
+            let started = Instant::now();
+            let packets =
+                decode_bancho_packets(&body, PacketDirection::ServerToClient, usize::MAX).unwrap();
+            exchange.process(body.clone(), packets).await;
+            timings.push(started.elapsed());
+        }
+        timings.sort_unstable();
+        let (median, p95) = (
+            timings[timings.len() / 2],
+            timings[timings.len() * 95 / 100],
+        );
+        let budget = Duration::from_millis(Preferences::default().processing_overhead_budget_ms);
+        // Unoptimized test builds are several times slower than what players run
+        assert!(
+            median < budget * 10,
+            "processing {} bytes took a median of {:?} (p95 {:?}), over 10 times the budget of {:?}",
+            body.len(),
+            median,
+            p95,
+            budget
+        );
+    }
+
+    #[test]
+    fn processing_limits_cap_injected_packets_and_growth() {
+        let limits = ProcessingLimits::default();
+        let direction = PacketDirection::ServerToClient;
+        assert!(within_processing_limits(
+            &limits,
+            direction,
+            (50, 50 + limits.max_injected_packets),
+            (100, 100)
+        ));
+        assert!(!within_processing_limits(
+            &limits,
+            direction,
+            (50, 51 + limits.max_injected_packets),
+            (100, 100)
+        ));
+        // Dropping packets is never too much
+        assert!(within_processing_limits(
+            &limits,
+            direction,
+            (50, 0),
+            (10_000, 0)
+        ));
+
+        let budget = ProcessingLimits::MIN_BODY_BUDGET;
+        let growth = limits.max_body_growth_factor as usize;
+        // Small bodies get the minimum budget
+        assert!(within_processing_limits(
+            &limits,
+            direction,
+            (1, 2),
+            (7, budget * growth)
+        ));
+        assert!(!within_processing_limits(
+            &limits,
+            direction,
+            (1, 2),
+            (7, budget * growth + 1)
+        ));
+        assert!(within_processing_limits(
+            &limits,
+            direction,
+            (1, 2),
+            (budget * 2, budget * 2 * growth)
+        ));
+        assert!(!within_processing_limits(
+            &limits,
+            direction,
+            (1, 2),
+            (budget * 2, budget * 2 * growth + 1)
+        ));
+    }
+
+    /// What a response with a single ping comes out as when `misbehave` went wild on the packets
+    /// queued for the client first.
+    async fn process_after(misbehave: impl FnOnce(&mut ProxyState)) -> (Bytes, Bytes) {
+        let state = Arc::new(Mutex::new(ProxyState::default()));
+        misbehave(&mut *state.lock().await);
+        let mut exchange = BanchoExchange {
+            session_user_id: Some(3),
+            ..exchange_with(state)
+        };
+        let original = encode_bancho_packets(vec![BanchoPacket::Ping], 0).unwrap();
+        let packets =
+            decode_bancho_packets(&original, PacketDirection::ServerToClient, 1024).unwrap();
+        let processed = exchange.process(original.clone(), packets).await;
+        (original, processed)
+    }
+
+    #[tokio::test]
+    async fn misbehaving_processing_falls_back_to_the_original_body() {
+        let notify = |state: &mut ProxyState, text: String| {
+            let notification = BanchoPacket::Notification(text.into());
+            state
+                .pending_injections
+                .push(3, PacketDirection::ServerToClient, notification);
+        };
+
+        // Within the limits, the injections go through
+        let (original, processed) = process_after(|state| notify(state, "hi".to_owned())).await;
+        assert_ne!(processed, original);
+
+        // Floods the client with packets
+        let (original, processed) = process_after(|state| {
+            for _ in 0..1000 {
+                notify(state, "spam".to_owned());
+            }
+        })
+        .await;
+        assert_eq!(processed, original);
+
+        // A single packet, but a huge one
+        let (original, processed) =
+            process_after(|state| notify(state, "x".repeat(1024 * 1024))).await;
+        assert_eq!(processed, original);
+    }
+
+    #[test]
+    fn credentials_are_redacted_from_logged_queries() {
+        assert_eq!(
+            redact_query("u=player&h=5f4dcc3b5aa765d61d8327deb882cf99&c=123&m=0"),
+            "u=<redacted>&h=<redacted>&c=123&m=0"
+        );
+        // Only whole keys count, and pairs without a value are kept as they are
+        assert_eq!(redact_query("us=a&hh=b&u&flag"), "us=a&hh=b&u&flag");
+        assert_eq!(redact_query(""), "");
+    }
+
+    #[test]
+    fn finds_query_params() {
+        let query = "c=123&m=3&u=player&h=&c=456";
+        assert_eq!(query_param(query, "c"), Some("123"));
+        assert_eq!(query_param(query, "m"), Some("3"));
+        assert_eq!(query_param(query, "h"), Some(""));
+        assert_eq!(query_param(query, "x"), None);
+    }
+
+    /// Collects what's logged while it's the default subscriber.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_fallback_passes_the_credentials_on_without_logging_them() {
+        const PASSWORD_HASH: &str = "5f4dcc3b5aa765d61d8327deb882cf99";
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt().with_writer({
+            let logs = logs.clone();
+            move || logs.clone()
+        });
+        let _subscriber = tracing::subscriber::set_default(subscriber.finish());
+
+        let requested = Arc::new(std::sync::Mutex::new(vec![]));
+        let upstream = {
+            let requested = requested.clone();
+            mock_upstream(move |req: Request<Body>| {
+                requested.lock().unwrap().push(req.uri().to_string());
+                let status = if req.uri().path() == "/replays" {
+                    StatusCode::OK
+                } else {
+                    StatusCode::NOT_FOUND
+                };
+                async move {
+                    Response::builder()
+                        .status(status)
+                        .body(Body::from("replay"))
+                        .unwrap()
+                }
+            })
+            .await
+        };
+        let client = build_client(&UpstreamPoolSettings::default(), "");
+        let query = format!("c=123&m=1&u=player&h={}", PASSWORD_HASH);
+
+        let template = format!(
+            "http://{}/replays?id={{score_id}}&mode={{mode}}&u={{username}}&h={{password_hash}}",
+            upstream
+        );
+        let response = replay_fallback_response(&client, &ReplayFallback::Url, &template, &query)
+            .await
+            .unwrap();
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "replay"
+        );
+        let missing_template = format!(
+            "http://{}/missing?u={{username}}&h={{password_hash}}",
+            upstream
+        );
+        assert!(
+            replay_fallback_response(&client, &ReplayFallback::Url, &missing_template, &query)
+                .await
+                .is_none()
+        );
+
+        assert_eq!(
+            *requested.lock().unwrap(),
+            [
+                format!("/replays?id=123&mode=1&u=player&h={}", PASSWORD_HASH),
+                format!("/missing?u=player&h={}", PASSWORD_HASH),
+            ]
+        );
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("u=<redacted>&h=<redacted>"), "{}", logs);
+        assert!(!logs.contains(PASSWORD_HASH), "{}", logs);
+        assert!(!logs.contains("player"), "{}", logs);
+    }
+
+    /// What a server sends on login, for user 3 who is no supporter there.
+    fn login_fixture(privileges: BanchoPrivileges) -> Vec<BanchoPacket> {
+        let presence = |user_id, name: &str| BanchoPacket::UserPresence {
+            user_id,
+            name: OsuString::from(name.to_owned()),
+            utc_offset: 24,
+            country_code: 1,
+            bancho_privileges: 1,
+            longitude: 13.4,
+            latitude: 52.5,
+            global_rank: 1000,
+        };
+        vec![
+            BanchoPacket::ProtocolVersion(19),
+            BanchoPacket::UserId(3),
+            BanchoPacket::Privilege { privileges },
+            BanchoPacket::ChannelJoinSuccess(OsuString::from("#osu".to_owned())),
+            presence(3, "player"),
+            presence(4, "someone else"),
+        ]
+    }
+
+    /// The login notification the client gets along with `packets`, if any.
+    async fn login_notification(
+        mut preferences: Preferences,
+        mut packets: Vec<BanchoPacket>,
+    ) -> Option<String> {
+        let mut state = ProxyState::default();
+        let mut user_id = None;
+        process_bancho_packets(
+            &mut preferences,
+            &mut state,
+            &mut user_id,
+            &mut packets,
+            PacketDirection::ServerToClient,
+            "example.com",
+        )
+        .await;
+        assert_eq!(user_id, Some(3));
+        let notifications = packets
+            .iter()
+            .filter_map(|packet| match packet {
+                BanchoPacket::Notification(text) => Some(text.to_string()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert!(notifications.len() <= 1, "{:?}", notifications);
+        notifications.into_iter().next()
+    }
+
+    #[tokio::test]
+    async fn the_login_notification_lists_what_was_actually_applied() {
+        let spoofing = Preferences {
+            fake_supporter: true,
+            fake_country: Some(Country::Germany),
+            fake_utc_offset: None,
+            fake_rank: None,
+            hide_location: true,
+            login_notification: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            login_notification(spoofing.clone(), login_fixture(BanchoPrivileges::PLAYER))
+                .await
+                .as_deref(),
+            Some("osus proxy: location hidden, supporter faked, country shown as Germany")
+        );
+
+        // Nothing to fake where the server grants supporter itself
+        let supporter = BanchoPrivileges::PLAYER | BanchoPrivileges::SUPPORTER;
+        assert_eq!(
+            login_notification(spoofing.clone(), login_fixture(supporter))
+                .await
+                .as_deref(),
+            Some("osus proxy: location hidden, country shown as Germany")
+        );
+
+        let nothing_applied = Preferences {
+            fake_supporter: false,
+            fake_country: None,
+            hide_location: false,
+            ..spoofing.clone()
+        };
+        assert_eq!(
+            login_notification(nothing_applied, login_fixture(supporter)).await,
+            None
+        );
+
+        let turned_off = Preferences {
+            login_notification: false,
+            ..spoofing
+        };
+        assert_eq!(
+            login_notification(turned_off, login_fixture(BanchoPrivileges::PLAYER)).await,
+            None
+        );
+    }
+
+    /// Forwards the login and a bancho request after it, returning the headers and body upstream got for each.
+    async fn forwarded_login(
+        hide_forwarded_ip_on_login: bool,
+        login_body: &'static [u8],
+    ) -> Vec<(HeaderMap, Bytes)> {
+        let received = Arc::new(std::sync::Mutex::new(vec![]));
+        let upstream = {
+            let received = received.clone();
+            mock_upstream(move |req: Request<Body>| {
+                let received = received.clone();
+                async move {
+                    let (parts, body) = req.into_parts();
+                    let body = hyper::body::to_bytes(body).await.unwrap();
+                    received.lock().unwrap().push((parts.headers, body));
+                    Response::new(Body::empty())
+                }
+            })
+            .await
+        };
+        let proxy = proxying_to(
+            upstream,
+            Preferences {
+                hide_forwarded_ip_on_login,
+                forwarded_ip_mode: ForwardedIpMode::Custom,
+                forwarded_ip: "203.0.113.7".to_owned(),
+                ..Default::default()
+            },
+        );
+
+        let mut login = Request::post("http://c.osus.zihad.dev/")
+            .header(header::HOST, source_host("c"))
+            .body(Body::from(login_body))
+            .unwrap();
+        login.extensions_mut().insert(proxy.0.clone());
+        login.extensions_mut().insert(proxy.1.clone());
+        handle_requests(login).await.unwrap();
+        let ping = encode_bancho_packets(vec![BanchoPacket::Ping], 0).unwrap();
+        handle_requests(bancho_request(&proxy, Body::from(ping)))
+            .await
+            .unwrap();
+
+        let received = received.lock().unwrap().clone();
+        received
+    }
+
+    #[tokio::test]
+    async fn the_client_address_is_only_hidden_on_login() {
+        const LOGIN_BODY: &[u8] =
+            b"player\n5f4dcc3b5aa765d61d8327deb882cf99\nb20231030|0|1|a:b:c:d:e:|0\n";
+        fn forwarded(headers: &HeaderMap) -> [Option<&str>; 2] {
+            ["X-Forwarded-For", "X-Real-IP"]
+                .map(|name| headers.get(name).and_then(|value| value.to_str().ok()))
+        }
+
+        let received = forwarded_login(true, LOGIN_BODY).await;
+        assert_eq!(received.len(), 2);
+        let (login_headers, login_body) = &received[0];
+        assert_eq!(forwarded(login_headers), [None, None]);
+        assert_eq!(login_body, LOGIN_BODY);
+        assert_eq!(forwarded(&received[1].0), [Some("203.0.113.7"); 2]);
+
+        let received = forwarded_login(false, LOGIN_BODY).await;
+        assert_eq!(received.len(), 2);
+        let (login_headers, login_body) = &received[0];
+        assert_eq!(forwarded(login_headers), [Some("203.0.113.7"); 2]);
+        assert_eq!(login_body, LOGIN_BODY);
+        assert_eq!(forwarded(&received[1].0), [Some("203.0.113.7"); 2]);
+    }
+
+    /// A body that fails halfway through, like when the client goes away.
+    fn aborted_body() -> Body {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            let _ = sender.send_data(Bytes::from_static(b"player\n")).await;
+            sender.abort();
+        });
+        body
+    }
+
+    #[tokio::test]
+    async fn an_unreadable_login_body_is_a_bad_request() {
+        let (preferences, state) = proxying_to(
+            SocketAddr::from(([127, 0, 0, 1], 9)),
+            Preferences::default(),
+        );
+        let mut req = Request::post("http://c.osus.zihad.dev/")
+            .header(header::HOST, source_host("c"))
+            .body(aborted_body())
+            .unwrap();
+        req.extensions_mut().insert(preferences);
+        req.extensions_mut().insert(state);
+        let response = handle_requests(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn an_unreadable_bancho_body_is_a_bad_request() {
+        let proxy = proxying_to(
+            SocketAddr::from(([127, 0, 0, 1], 9)),
+            Preferences::default(),
+        );
+        let response = handle_requests(bancho_request(&proxy, aborted_body()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
 }
@@ -0,0 +1,35 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// How many of the latest exchanges the percentiles are taken over.
+const WINDOW: usize = 100;
+
+/// Time spent decoding, processing and encoding bancho packets, without the upstream latency.
+/// All of it is added on top of every request the client makes.
+#[derive(Debug, Default)]
+pub struct ProcessingOverhead {
+    samples: VecDeque<Duration>,
+    over_budget: bool,
+}
+
+impl ProcessingOverhead {
+    /// Returns whether the p95 just went over `budget`.
+    pub fn record(&mut self, sample: Duration, budget: Duration) -> bool {
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+
+        let over_budget = self.percentile(95).is_some_and(|p95| p95 > budget);
+        let went_over = over_budget && !self.over_budget;
+        self.over_budget = over_budget;
+        went_over
+    }
+
+    pub fn percentile(&self, percentile: usize) -> Option<Duration> {
+        let mut sorted: Vec<_> = self.samples.iter().copied().collect();
+        sorted.sort();
+        let index = (sorted.len() * percentile / 100).min(sorted.len().checked_sub(1)?);
+        Some(sorted[index])
+    }
+}
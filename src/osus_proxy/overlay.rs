@@ -0,0 +1,54 @@
+use hyper::{header, Body, Response, StatusCode};
+use serde::Serialize;
+
+use crate::preferences::Preferences;
+use crate::state::{NowPlaying, ProxyState};
+
+/// Served on every host the proxy answers for, e.g. `https://osu.<source domain>/__osus/overlay/state.json`.
+pub const OVERLAY_PATH_PREFIX: &str = "/__osus/overlay/";
+
+/// Everything in here ends up on stream, so nothing about the session itself.
+#[derive(Serialize)]
+struct OverlayState<'a> {
+    server: &'a str,
+    logged_in: bool,
+    now_playing: Option<&'a NowPlaying>,
+}
+
+/// Renders the overlay file at `path` (relative to [`OVERLAY_PATH_PREFIX`]).
+pub fn response(path: &str, preferences: &Preferences, state: &ProxyState) -> Response<Body> {
+    if !preferences.overlay_endpoints {
+        return not_found();
+    }
+
+    match path {
+        "nowplaying.txt" => {
+            let text = match &state.now_playing {
+                Some(now_playing) => format!("Now playing: {}", now_playing.info_text),
+                None => format!("Connected to {} via osus proxy", preferences.server_address),
+            };
+            Response::builder()
+                .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(Body::from(text))
+                .unwrap()
+        }
+        "state.json" => {
+            let overlay_state = OverlayState {
+                server: &preferences.server_address,
+                logged_in: !state.sessions.is_empty(),
+                now_playing: state.now_playing.as_ref(),
+            };
+            Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_string(&overlay_state).unwrap()))
+                .unwrap()
+        }
+        _ => not_found(),
+    }
+}
+
+fn not_found() -> Response<Body> {
+    let mut response = Response::new(Body::from("not found"));
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    response
+}
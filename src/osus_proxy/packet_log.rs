@@ -0,0 +1,109 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tracing::warn;
+
+use crate::osus_proxy::bancho::{BanchoPacket, PacketDirection};
+
+pub const PACKET_LOG_PATH: &str = "./packet-log.ndjson";
+
+/// The oldest packets are dropped once there are more than this.
+const MAX_RECORDS: usize = 10_000;
+/// How much of the data of packets we don't decode is kept, unless keeping all of it.
+const MAX_RAW_BYTES: usize = 256;
+
+#[derive(Serialize)]
+struct PacketRecord<'a> {
+    /// Unix timestamp in milliseconds
+    timestamp_ms: u128,
+    direction: PacketDirection,
+    id: u16,
+    #[serde(flatten)]
+    packet: &'a BanchoPacket,
+    /// How long the data was before it got cut down to `MAX_RAW_BYTES`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncated_from: Option<usize>,
+}
+
+/// Packets that went through the proxy this session as they arrived, before the proxy changed
+/// anything, one JSON object per line. Same layout as the packets in the inspect export, plus
+/// when each one came.
+#[derive(Debug, Default)]
+pub struct PacketLog {
+    lines: VecDeque<String>,
+    dropped: u64,
+}
+
+impl PacketLog {
+    pub fn record(&mut self, packet: &BanchoPacket, direction: PacketDirection, full_dump: bool) {
+        let truncated;
+        let (packet, truncated_from) = match packet {
+            BanchoPacket::Other { id, data } if !full_dump && data.len() > MAX_RAW_BYTES => {
+                truncated = BanchoPacket::Other {
+                    id: *id,
+                    data: data.slice(..MAX_RAW_BYTES),
+                };
+                (&truncated, Some(data.len()))
+            }
+            _ => (packet, None),
+        };
+        let record = PacketRecord {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since_epoch| since_epoch.as_millis())
+                .unwrap_or_default(),
+            direction,
+            id: packet.id(),
+            packet,
+            truncated_from,
+        };
+
+        match serde_json::to_string(&record) {
+            Ok(line) => {
+                if self.lines.len() >= MAX_RECORDS {
+                    self.lines.pop_front();
+                    self.dropped += 1;
+                }
+                self.lines.push_back(line);
+            }
+            Err(e) => warn!(
+                "Failed to record packet {} in the packet log: {}",
+                record.id, e
+            ),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Packets dropped because of the cap.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.dropped = 0;
+    }
+
+    pub fn write_ndjson(&self, writer: &mut impl Write) -> io::Result<()> {
+        for line in &self.lines {
+            writeln!(writer, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_file(&self) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(PACKET_LOG_PATH)?);
+        self.write_ndjson(&mut writer)?;
+        writer.flush()
+    }
+}
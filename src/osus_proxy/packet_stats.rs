@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use osus_bancho::codec::packet_sizes;
+
+use crate::osus_proxy::bancho::{self, PacketDirection};
+
+/// Distinct packets counted, so a server sending garbage ids can't grow this forever.
+const MAX_TRACKED_PACKETS: usize = 256;
+/// The rate is over the last minute, counted per second.
+const RATE_WINDOW_SECS: usize = 60;
+
+#[derive(Debug, Clone)]
+pub struct PacketCounter {
+    pub id: u16,
+    pub direction: PacketDirection,
+    pub count: u64,
+    /// Headers included, as they came before the proxy changed anything.
+    pub bytes: u64,
+    /// Counts of the last seconds, by second since [`PacketStats::started`], a slot per second
+    /// in the window.
+    recent: [(u64, u32); RATE_WINDOW_SECS],
+}
+
+impl PacketCounter {
+    pub fn name(&self) -> String {
+        bancho::packet_name(self.id, self.direction)
+            .map(str::to_owned)
+            .unwrap_or_else(|| format!("Unknown({})", self.id))
+    }
+}
+
+/// How many of each packet went through and how big they were, for the statistics in the UI.
+/// Counted from the headers of the bodies, nothing gets decoded for it.
+#[derive(Debug)]
+pub struct PacketStats {
+    counters: HashMap<(u16, PacketDirection), PacketCounter>,
+    untracked_count: u64,
+    started: Instant,
+}
+
+impl Default for PacketStats {
+    fn default() -> Self {
+        Self {
+            counters: HashMap::new(),
+            untracked_count: 0,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl PacketStats {
+    /// Counts every packet in `body`, a bancho body as it came.
+    pub fn record_body(&mut self, direction: PacketDirection, body: &[u8]) {
+        let second = self.started.elapsed().as_secs();
+        for (id, size) in packet_sizes(body) {
+            if self.counters.len() >= MAX_TRACKED_PACKETS
+                && !self.counters.contains_key(&(id, direction))
+            {
+                self.untracked_count += 1;
+                continue;
+            }
+            let counter = self
+                .counters
+                .entry((id, direction))
+                .or_insert_with(|| PacketCounter {
+                    id,
+                    direction,
+                    count: 0,
+                    bytes: 0,
+                    recent: [(0, 0); RATE_WINDOW_SECS],
+                });
+            counter.count += 1;
+            counter.bytes += size as u64;
+            let slot = &mut counter.recent[second as usize % RATE_WINDOW_SECS];
+            if slot.0 != second {
+                *slot = (second, 0);
+            }
+            slot.1 += 1;
+        }
+    }
+
+    pub fn counters(&self) -> impl Iterator<Item = &PacketCounter> {
+        self.counters.values()
+    }
+
+    /// How many times `counter`'s packet went through in the last minute.
+    pub fn last_minute(&self, counter: &PacketCounter) -> u32 {
+        let second = self.started.elapsed().as_secs();
+        counter
+            .recent
+            .iter()
+            .filter(|(slot_second, _)| second - slot_second < RATE_WINDOW_SECS as u64)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// Packets with ids we stopped tracking because of the cap.
+    pub fn untracked_count(&self) -> u64 {
+        self.untracked_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
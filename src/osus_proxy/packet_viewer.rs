@@ -0,0 +1,286 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use osus_bancho::codec::packet_sizes;
+
+use crate::osus_proxy::bancho::{self, BanchoPacket, PacketDirection};
+use crate::osus_proxy::chat_log::utc_date_and_time;
+
+/// Entries waiting for the UI, past this they're dropped until it catches up.
+const CHANNEL_CAPACITY: usize = 512;
+/// The oldest entries scroll out of the viewer once there are more than this.
+const MAX_SHOWN_ENTRIES: usize = 2_000;
+const MAX_SUMMARY_CHARS: usize = 80;
+/// Spectate frames and the like are mostly data, nobody reads all of it in the viewer.
+const MAX_DETAIL_CHARS: usize = 4_000;
+/// How much of a raw payload the hexdump shows at first, and how much more each "show more" adds.
+pub const HEXDUMP_PAGE_BYTES: usize = 4 * 1024;
+const HEXDUMP_ROW_BYTES: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct ViewerEntry {
+    /// Counting up from 1 since the viewer was connected.
+    pub seq: u64,
+    /// Unix timestamp in milliseconds
+    pub timestamp_ms: u128,
+    pub direction: PacketDirection,
+    pub id: u16,
+    pub name: String,
+    /// Header included, as it came.
+    pub length: usize,
+    /// The interesting part, e.g. the text of a message or the user a packet is about.
+    pub summary: String,
+    /// The packet as JSON, like in the packet log.
+    pub detail: String,
+    /// The data of packets we don't decode, shared with the body they came in.
+    pub raw: Option<Bytes>,
+}
+
+impl ViewerEntry {
+    /// E.g. `13:37:00.250`, in UTC like the chat logs.
+    pub fn time(&self) -> String {
+        let (_, time) = utc_date_and_time((self.timestamp_ms / 1_000) as u64);
+        format!("{}.{:03}", time, self.timestamp_ms % 1_000)
+    }
+
+    /// Case-insensitive, `filter` is lowercase.
+    fn matches(&self, filter: &str) -> bool {
+        filter.is_empty()
+            || self.name.to_lowercase().contains(filter)
+            || self.summary.to_lowercase().contains(filter)
+            || self.detail.to_lowercase().contains(filter)
+    }
+}
+
+/// The proxy's end of the packet viewer. Hands the packets going through to the UI without ever
+/// waiting for it, what doesn't fit in the channel is dropped.
+#[derive(Debug, Default)]
+pub struct PacketViewerFeed {
+    sender: Option<SyncSender<ViewerEntry>>,
+    next_seq: u64,
+    dropped: u64,
+}
+
+impl PacketViewerFeed {
+    /// Starts feeding the returned receiver, any earlier one stops getting entries.
+    pub fn connect(&mut self) -> Receiver<ViewerEntry> {
+        let (sender, receiver) = mpsc::sync_channel(CHANNEL_CAPACITY);
+        self.sender = Some(sender);
+        self.next_seq = 1;
+        self.dropped = 0;
+        receiver
+    }
+
+    pub fn disconnect(&mut self) {
+        self.sender = None;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    /// `body` is what `packets` were decoded from, before the proxy changed anything.
+    pub fn record_body(
+        &mut self,
+        direction: PacketDirection,
+        body: &[u8],
+        packets: &[BanchoPacket],
+    ) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_millis())
+            .unwrap_or_default();
+        for ((id, length), packet) in packet_sizes(body).zip(packets) {
+            let entry = ViewerEntry {
+                seq: self.next_seq,
+                timestamp_ms,
+                direction,
+                id,
+                name: bancho::packet_name(id, direction)
+                    .map(str::to_owned)
+                    .unwrap_or_else(|| format!("Unknown({})", id)),
+                length,
+                summary: truncate(summarize(packet), MAX_SUMMARY_CHARS),
+                detail: truncate(
+                    serde_json::to_string_pretty(packet).unwrap_or_else(|e| e.to_string()),
+                    MAX_DETAIL_CHARS,
+                ),
+                raw: match packet {
+                    BanchoPacket::Other { data, .. } => Some(data.clone()),
+                    _ => None,
+                },
+            };
+            self.next_seq += 1;
+            match sender.try_send(entry) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => self.dropped += 1,
+                Err(TrySendError::Disconnected(_)) => {
+                    self.sender = None;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Entries the UI didn't take in time.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/// The UI's end of the packet viewer.
+#[derive(Debug, Default)]
+pub struct PacketViewer {
+    receiver: Option<Receiver<ViewerEntry>>,
+    entries: VecDeque<ViewerEntry>,
+    /// Nothing new is shown, what comes in meanwhile is thrown away.
+    pub paused: bool,
+    pub filter: String,
+    /// The seq of the entry showing its detail.
+    pub expanded: Option<u64>,
+    /// How much of the raw payload of the expanded entry the hexdump shows.
+    pub hexdump_bytes: usize,
+}
+
+impl PacketViewer {
+    /// Connects to or disconnects from `feed` to match `enabled`.
+    pub fn sync(&mut self, enabled: bool, feed: &mut PacketViewerFeed) {
+        if enabled && !feed.is_connected() {
+            self.receiver = Some(feed.connect());
+            self.entries.clear();
+            self.expanded = None;
+        } else if !enabled && feed.is_connected() {
+            feed.disconnect();
+            self.receiver = None;
+        }
+    }
+
+    /// Takes whatever the proxy sent since the last frame.
+    pub fn receive(&mut self) {
+        let Some(receiver) = &self.receiver else {
+            return;
+        };
+        for entry in receiver.try_iter() {
+            if self.paused {
+                continue;
+            }
+            if self.entries.len() >= MAX_SHOWN_ENTRIES {
+                self.entries.pop_front();
+            }
+            self.entries.push_back(entry);
+        }
+    }
+
+    /// The entries matching [`Self::filter`] on their name or content, oldest first.
+    pub fn matching(&self) -> impl Iterator<Item = &ViewerEntry> {
+        let filter = self.filter.trim().to_lowercase();
+        self.entries
+            .iter()
+            .filter(move |entry| entry.matches(&filter))
+    }
+
+    /// Shows the detail of the entry with `seq`, or hides it if it's the one showing.
+    pub fn toggle(&mut self, seq: u64) {
+        self.expanded = (self.expanded != Some(seq)).then_some(seq);
+        self.hexdump_bytes = HEXDUMP_PAGE_BYTES;
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.expanded = None;
+    }
+}
+
+/// Offset, hex and ASCII columns of `data`, a row per 16 bytes, e.g.
+/// `00000010  48 65 6c 6c 6f 00 …  |Hello.…|`.
+pub fn hexdump_rows(data: &[u8]) -> impl Iterator<Item = String> + '_ {
+    data.chunks(HEXDUMP_ROW_BYTES)
+        .enumerate()
+        .map(|(row, bytes)| {
+            let hex = bytes
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = bytes
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+            format!(
+                "{:08x}  {:<width$}  |{}|",
+                row * HEXDUMP_ROW_BYTES,
+                hex,
+                ascii,
+                width = HEXDUMP_ROW_BYTES * 3 - 1
+            )
+        })
+}
+
+/// The bytes as hex without any spacing, for the clipboard.
+pub fn hex_string(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// A line about the packet for the list, empty for the ones that don't carry anything of note.
+fn summarize(packet: &BanchoPacket) -> String {
+    match packet {
+        BanchoPacket::SendPublicMessage(message)
+        | BanchoPacket::SendPrivateMessage(message)
+        | BanchoPacket::SendMessage(message) => {
+            format!(
+                "{} → {}: {}",
+                message.sender, message.recipient, message.text
+            )
+        }
+        BanchoPacket::ChangeAction {
+            action, info_text, ..
+        } => format!("{:?} {}", action, info_text),
+        BanchoPacket::UserStats {
+            user_id, action, ..
+        } => format!("user {} {:?}", user_id, action),
+        BanchoPacket::UserPresence { user_id, name, .. } => format!("{} ({})", name, user_id),
+        BanchoPacket::UserLogout { user_id, .. }
+        | BanchoPacket::SpectatorJoined { user_id, .. }
+        | BanchoPacket::SpectatorLeft { user_id, .. }
+        | BanchoPacket::CantSpectate { user_id, .. }
+        | BanchoPacket::FellowSpectatorJoined { user_id, .. }
+        | BanchoPacket::FellowSpectatorLeft { user_id, .. }
+        | BanchoPacket::UserSilenced { user_id, .. } => format!("user {}", user_id),
+        BanchoPacket::UserId(value) | BanchoPacket::ProtocolVersion(value) => value.to_string(),
+        BanchoPacket::Notification(text)
+        | BanchoPacket::ChannelJoinSuccess(text)
+        | BanchoPacket::ChannelKick(text)
+        | BanchoPacket::SwitchTournamentServer(text) => text.to_string(),
+        BanchoPacket::ChannelInfo(channel) | BanchoPacket::ChannelAutoJoin(channel) => {
+            format!(
+                "{} ({} users): {}",
+                channel.name, channel.player_count, channel.topic
+            )
+        }
+        BanchoPacket::FriendsList(user_ids)
+        | BanchoPacket::UserStatsRequest(user_ids)
+        | BanchoPacket::UserPresenceBundle(user_ids)
+        | BanchoPacket::UserPresenceRequest(user_ids) => format!("{} users", user_ids.len()),
+        BanchoPacket::Privilege { privileges } => privileges.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn truncate(mut text: String, max_chars: usize) -> String {
+    if let Some((end, _)) = text.char_indices().nth(max_chars) {
+        text.truncate(end);
+        text.push('…');
+    }
+    text
+}
@@ -0,0 +1,97 @@
+use std::fs;
+use std::path::Path;
+
+use bytes::Bytes;
+use color_eyre::{eyre::eyre, Result};
+use hyper::{Body, Response, StatusCode};
+use tracing::info;
+
+use crate::osus_proxy::bancho::BanchoPacket;
+
+/// What the game gets as its session token when logging in to a replay.
+const REPLAY_TOKEN: &str = "osus-proxy-replay";
+/// How long the game waits to reconnect once the replay is over, it keeps getting told to.
+const RESTART_AFTER_MS: i32 = 5000;
+
+/// Answers bancho requests with the responses of a captured session in order instead of asking
+/// the server, started with `--replay <capture directory>`.
+#[derive(Debug)]
+pub struct Replay {
+    responses: Vec<Bytes>,
+    next: usize,
+    /// Start over once every response was sent, instead of telling the game the server restarts.
+    looping: bool,
+}
+
+impl Replay {
+    /// Reads the `<seq>_s2c.bin` files written by [`super::capture::Capture`].
+    pub fn load(directory: &Path, looping: bool) -> Result<Self> {
+        let mut files = vec![];
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+            let seq = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix("_s2c.bin"))
+                .and_then(|seq| seq.parse::<u32>().ok());
+            if let Some(seq) = seq {
+                files.push((seq, path));
+            }
+        }
+        if files.is_empty() {
+            return Err(eyre!("{} has no captured responses", directory.display()));
+        }
+        files.sort();
+
+        let responses = files
+            .iter()
+            .map(|(_, path)| fs::read(path).map(Bytes::from))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        info!(
+            "Replaying {} responses from {}",
+            responses.len(),
+            directory.display()
+        );
+        Ok(Self {
+            responses,
+            next: 0,
+            looping,
+        })
+    }
+
+    /// The response to the next bancho request, a login gets the session token it needs.
+    pub fn next_response(&mut self, login: bool) -> Response<Body> {
+        if self.next == self.responses.len() && self.looping {
+            info!("Replay finished, starting over");
+            self.next = 0;
+        }
+        let body = match self.responses.get(self.next) {
+            Some(body) => {
+                self.next += 1;
+                body.clone()
+            }
+            None => {
+                info!("Replay finished, telling the game the server restarts");
+                BanchoPacket::Restart {
+                    ms: RESTART_AFTER_MS,
+                }
+                .to_bytes()
+                .unwrap_or_default()
+            }
+        };
+
+        let mut response = Response::builder().status(StatusCode::OK);
+        if login {
+            response = response.header("cho-token", REPLAY_TOKEN);
+        }
+        response.body(Body::from(body)).unwrap()
+    }
+}
+
+/// What non-bancho requests get while replaying, nothing but bancho was captured.
+pub fn not_recorded() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::from("not part of the replay"))
+        .unwrap()
+}
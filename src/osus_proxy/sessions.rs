@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, info};
+
+/// The client polls bancho every few seconds while it's open, a session quiet for this long is gone.
+const SESSION_IDLE_EXPIRY: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// `None` until the server accepts the login.
+    pub user_id: Option<i32>,
+    pub username: Option<String>,
+    pub logged_in_at: Instant,
+    /// What the client logged in with, changing these mid-session needs a relog to fully apply.
+    pub server_address: String,
+    pub fake_supporter: bool,
+    last_seen: Instant,
+}
+
+/// Every client logged in through the proxy, keyed by the token bancho gave it (`cho-token` in the
+/// login response, `osu-token` in every request after that). Lets several clients, e.g. a
+/// tournament client next to a normal one, be spoofed each as their own user.
+#[derive(Debug, Default)]
+pub struct Sessions {
+    sessions: HashMap<String, Session>,
+}
+
+impl Sessions {
+    pub fn start(
+        &mut self,
+        token: &str,
+        user_id: Option<i32>,
+        username: Option<String>,
+        server_address: &str,
+        fake_supporter: bool,
+    ) {
+        self.expire_idle();
+        let now = Instant::now();
+        info!(
+            "Session of user {:?} started, {} active",
+            user_id,
+            self.sessions.len() + 1
+        );
+        self.sessions.insert(
+            token.to_owned(),
+            Session {
+                user_id,
+                username,
+                logged_in_at: now,
+                server_address: server_address.to_owned(),
+                fake_supporter,
+                last_seen: now,
+            },
+        );
+    }
+
+    /// Looks up the session owning `token` and marks it as active.
+    pub fn touch(&mut self, token: &str) -> Option<&Session> {
+        self.expire_idle();
+        let session = self.sessions.get_mut(token)?;
+        session.last_seen = Instant::now();
+        Some(session)
+    }
+
+    pub fn end(&mut self, token: &str) {
+        if let Some(session) = self.sessions.remove(token) {
+            info!(
+                "Session of user {:?} ended, {} active",
+                session.user_id,
+                self.sessions.len()
+            );
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Session> {
+        self.sessions.values()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// A session still active on other settings than these, unless the same user logged in again
+    /// with them since. Restarting the game leaves the old session behind until it expires.
+    pub fn needing_relog(&self, server_address: &str, fake_supporter: bool) -> Option<&Session> {
+        let active = || {
+            self.sessions
+                .values()
+                .filter(|session| session.last_seen.elapsed() < SESSION_IDLE_EXPIRY)
+        };
+        let current = |session: &Session| {
+            session.server_address == server_address && session.fake_supporter == fake_supporter
+        };
+        active()
+            .filter(|session| !current(session))
+            .find(|outdated| {
+                !active().any(|session| {
+                    current(session)
+                        && session.user_id == outdated.user_id
+                        && session.logged_in_at > outdated.logged_in_at
+                })
+            })
+    }
+
+    fn expire_idle(&mut self) {
+        self.sessions.retain(|_, session| {
+            let active = session.last_seen.elapsed() < SESSION_IDLE_EXPIRY;
+            if !active {
+                debug!(
+                    "Session of user {:?} expired after {:?}",
+                    session.user_id, SESSION_IDLE_EXPIRY
+                );
+            }
+            active
+        });
+    }
+}
+
+/// The login body is the username, the password hash and the client info, one per line.
+pub fn login_username(body: &[u8]) -> Option<String> {
+    let username = std::str::from_utf8(body).ok()?.lines().next()?.trim();
+    (!username.is_empty()).then(|| username.to_owned())
+}
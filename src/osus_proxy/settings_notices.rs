@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+use crate::osus_proxy::bancho::Country;
+use crate::osus_proxy::format_utc_offset;
+use crate::preferences::Preferences;
+
+/// The preferences that change what the game shows.
+#[derive(Debug, Clone, PartialEq)]
+struct InGameSettings {
+    fake_supporter: bool,
+    fake_country: Option<Country>,
+    fake_utc_offset: Option<i8>,
+    hide_activity: bool,
+    block_spectators: bool,
+}
+
+impl InGameSettings {
+    fn of(preferences: &Preferences) -> Self {
+        Self {
+            fake_supporter: preferences.fake_supporter,
+            fake_country: preferences.fake_country.clone(),
+            fake_utc_offset: preferences.fake_utc_offset,
+            hide_activity: preferences.hide_activity,
+            block_spectators: preferences.block_spectators,
+        }
+    }
+
+    /// Settings the game only picks up when logging in say so.
+    fn describe_changes(&self, before: &Self) -> Vec<String> {
+        let mut changes = vec![];
+        if self.fake_supporter != before.fake_supporter {
+            let change = if self.fake_supporter {
+                "enabled"
+            } else {
+                "disabled"
+            };
+            changes.push(format!("fake supporter {}, relog for full effect", change));
+        }
+        if self.fake_country != before.fake_country {
+            match &self.fake_country {
+                Some(country) => changes.push(format!(
+                    "country shown as {}, relog for full effect",
+                    country
+                )),
+                None => changes.push("showing the real country, relog for full effect".to_owned()),
+            }
+        }
+        if self.fake_utc_offset != before.fake_utc_offset {
+            match self.fake_utc_offset {
+                Some(offset) => changes.push(format!(
+                    "time zone shown as {}, relog for full effect",
+                    format_utc_offset(offset)
+                )),
+                None => {
+                    changes.push("showing the real time zone, relog for full effect".to_owned())
+                }
+            }
+        }
+        if self.hide_activity != before.hide_activity {
+            let change = if self.hide_activity {
+                "hidden"
+            } else {
+                "shown"
+            };
+            changes.push(format!("activity {} from the next status update", change));
+        }
+        if self.block_spectators != before.block_spectators {
+            let change = if self.block_spectators {
+                "blocked"
+            } else {
+                "no longer blocked"
+            };
+            changes.push(format!("new spectators {}", change));
+        }
+        changes
+    }
+}
+
+/// What each logged in user's game was last told about the in-game settings, so changing them
+/// mid-session gets a notification instead of seemingly doing nothing.
+#[derive(Debug, Default)]
+pub struct SettingsNotices {
+    /// By user id.
+    told: HashMap<i32, InGameSettings>,
+}
+
+impl SettingsNotices {
+    /// What changed since the user was last told, nothing the first time they're seen.
+    pub fn changes(&mut self, user_id: i32, preferences: &Preferences) -> Vec<String> {
+        let current = InGameSettings::of(preferences);
+        match self.told.insert(user_id, current.clone()) {
+            Some(told) => current.describe_changes(&told),
+            None => vec![],
+        }
+    }
+
+    /// Logging in applies everything, the login notification says what.
+    pub fn forget(&mut self, user_id: i32) {
+        self.told.remove(&user_id);
+    }
+}
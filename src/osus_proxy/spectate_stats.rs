@@ -0,0 +1,61 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The rates are averaged over this long.
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// How much spectating traffic goes through the proxy, both ours and the player we're watching.
+#[derive(Debug, Default)]
+pub struct SpectateStats {
+    /// When, how many frames and how many bytes.
+    samples: VecDeque<(Instant, u64, u64)>,
+    pub score_frames: u64,
+}
+
+impl SpectateStats {
+    pub fn record(&mut self, frame_count: u16, bytes: usize, has_score_frame: bool) {
+        let now = Instant::now();
+        self.expire(now);
+        self.samples
+            .push_back((now, frame_count.into(), bytes as u64));
+        if has_score_frame {
+            self.score_frames += 1;
+        }
+    }
+
+    pub fn frames_per_sec(&self) -> f64 {
+        self.per_sec(|&(_, frames, _)| frames)
+    }
+
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.per_sec(|&(_, _, bytes)| bytes)
+    }
+
+    /// Whether there was any spectating within the window.
+    pub fn is_active(&self) -> bool {
+        self.samples
+            .back()
+            .is_some_and(|(at, ..)| at.elapsed() < WINDOW)
+    }
+
+    fn per_sec(&self, value: impl Fn(&(Instant, u64, u64)) -> u64) -> f64 {
+        let now = Instant::now();
+        let total: u64 = self
+            .samples
+            .iter()
+            .filter(|(at, ..)| now.duration_since(*at) < WINDOW)
+            .map(value)
+            .sum();
+        total as f64 / WINDOW.as_secs_f64()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, ..)| now.duration_since(*at) >= WINDOW)
+        {
+            self.samples.pop_front();
+        }
+    }
+}
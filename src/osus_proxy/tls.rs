@@ -0,0 +1,138 @@
+use std::time::SystemTime;
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, RootCertStore, ServerName};
+use tracing::warn;
+
+/// Never skipped, whatever the preferences say.
+const ALWAYS_VERIFIED_DOMAINS: &[&str] = &["ppy.sh"];
+
+/// Verifies certificates like rustls always does, except for one self-hosted server whose
+/// certificate can't be verified.
+pub struct ScopedInsecureVerifier {
+    inner: WebPkiVerifier,
+    insecure_domain: String,
+}
+
+impl ScopedInsecureVerifier {
+    pub fn new(roots: RootCertStore, insecure_domain: &str) -> Self {
+        Self {
+            inner: WebPkiVerifier::new(roots, None),
+            insecure_domain: insecure_domain.trim().to_ascii_lowercase(),
+        }
+    }
+
+    /// The domain itself and its subdomains, since we talk to c., osu., etc.
+    fn is_exempt(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        let in_domain = |domain: &str| host == domain || host.ends_with(&format!(".{}", domain));
+        !self.insecure_domain.is_empty()
+            && in_domain(&self.insecure_domain)
+            && !ALWAYS_VERIFIED_DOMAINS
+                .iter()
+                .any(|domain| in_domain(domain))
+    }
+}
+
+impl ServerCertVerifier for ScopedInsecureVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let ServerName::DnsName(name) = server_name {
+            if self.is_exempt(name.as_ref()) {
+                return match self.inner.verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    scts,
+                    ocsp_response,
+                    now,
+                ) {
+                    Ok(verified) => Ok(verified),
+                    Err(e) => {
+                        warn!(
+                            "Accepting the unverifiable certificate of {}: {}",
+                            name.as_ref(),
+                            e
+                        );
+                        Ok(ServerCertVerified::assertion())
+                    }
+                };
+            }
+        }
+
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )
+    }
+}
+
+/// The certificates the OS trusts, the same ones hyper-rustls' `with_native_roots` uses.
+pub fn native_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            for cert in certs {
+                if let Err(e) = roots.add(&Certificate(cert.0)) {
+                    warn!("Skipping an invalid native root certificate: {}", e);
+                }
+            }
+        }
+        Err(e) => warn!("Failed to load the native root certificates: {}", e),
+    }
+    roots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether the proxy's own certificate, which no root trusts, passes for `host`.
+    fn accepts(verifier: &ScopedInsecureVerifier, host: &str) -> bool {
+        let certificate = &crate::osus_proxy::load_certs().unwrap()[0];
+        let server_name = ServerName::try_from(host).unwrap();
+        verifier
+            .verify_server_cert(
+                certificate,
+                &[],
+                &server_name,
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+            .is_ok()
+    }
+
+    #[test]
+    fn only_the_insecure_domain_and_its_subdomains_skip_verification() {
+        let verifier = ScopedInsecureVerifier::new(RootCertStore::empty(), " Example.COM ");
+        assert!(accepts(&verifier, "example.com"));
+        assert!(accepts(&verifier, "c.example.com"));
+        assert!(accepts(&verifier, "C.EXAMPLE.COM"));
+        assert!(!accepts(&verifier, "notexample.com"));
+        assert!(!accepts(&verifier, "example.com.evil.net"));
+        assert!(!accepts(&verifier, "c.other.com"));
+        assert!(!accepts(&verifier, "c.ppy.sh"));
+    }
+
+    #[test]
+    fn nothing_skips_verification_without_an_insecure_domain_or_for_ppy_sh() {
+        let verifier = ScopedInsecureVerifier::new(RootCertStore::empty(), "");
+        assert!(!accepts(&verifier, "c.example.com"));
+
+        let verifier = ScopedInsecureVerifier::new(RootCertStore::empty(), "ppy.sh");
+        assert!(!accepts(&verifier, "ppy.sh"));
+        assert!(!accepts(&verifier, "c.ppy.sh"));
+    }
+}
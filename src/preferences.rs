@@ -1,7 +1,21 @@
-use std::fmt::{Display, Formatter};
+use crate::janitor::{self, RetentionPolicy};
+use crate::osus_proxy;
 use crate::osus_proxy::bancho::Country;
+use color_eyre::{eyre::eyre, Result};
+use schemars::schema::RootSchema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+use tracing::info;
 
-#[derive(Debug, Default, Clone, PartialEq)]
+pub const PREFERENCES_PATH: &str = "./osus-proxy.json";
+/// Bump this when the file format changes in a way older versions can't read.
+pub const PREFERENCES_VERSION: u32 = 1;
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub enum BeatmapMirror {
     ServerDefault,
     #[default]
@@ -35,14 +49,457 @@ impl Display for BeatmapMirror {
     }
 }
 
-#[derive(Debug, Clone)]
+/// What to do when the server refuses to give us a replay, e.g. because it checks for supporter.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum ReplayFallback {
+    #[default]
+    Disabled,
+    /// Let the client show "replay unavailable" instead of an error.
+    EmptyReplay,
+    /// Download it from [`Preferences::replay_fallback_url_template`] instead.
+    Url,
+}
+
+impl Display for ReplayFallback {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayFallback::Disabled => f.write_str("Disabled"),
+            ReplayFallback::EmptyReplay => f.write_str("Empty replay"),
+            ReplayFallback::Url => f.write_str("Download from URL"),
+        }
+    }
+}
+
+/// Artificial latency for testing how the client copes with a slow server, for development only.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct LatencyInjection {
+    pub enabled: bool,
+    pub delay_ms: u64,
+    /// A random amount up to this is added on top of the delay.
+    pub jitter_ms: u64,
+    pub bancho: bool,
+    pub downloads: bool,
+}
+
+impl LatencyInjection {
+    /// Delay and jitter together are never more than this.
+    pub const MAX_TOTAL_MS: u64 = 5000;
+}
+
+impl Default for LatencyInjection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            delay_ms: 200,
+            jitter_ms: 50,
+            bancho: true,
+            downloads: false,
+        }
+    }
+}
+
+/// Sanity limits on bancho bodies and on what processing may do to them, for catching bugs during
+/// development and servers sending garbage.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct ProcessingLimits {
+    /// More packets than were decoded, in a single body.
+    pub max_injected_packets: usize,
+    /// Compared to the original body, or [`Self::MIN_BODY_BUDGET`] if that's smaller.
+    pub max_body_growth_factor: f64,
+    /// Longer packets are taken for a corrupt header and the body is passed through as is.
+    pub max_packet_length: usize,
+}
+
+impl ProcessingLimits {
+    /// So that injecting into an empty poll isn't treated as infinite growth.
+    pub const MIN_BODY_BUDGET: usize = 4096;
+}
+
+impl Default for ProcessingLimits {
+    fn default() -> Self {
+        Self {
+            max_injected_packets: 16,
+            max_body_growth_factor: 4.0,
+            max_packet_length: 4 * 1024 * 1024,
+        }
+    }
+}
+
+/// What to send in X-Forwarded-For/X-Real-IP. Some servers take it literally and geolocate 127.0.0.1.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum ForwardedIpMode {
+    #[default]
+    Omit,
+    /// The address the client connected to the proxy from.
+    RemoteAddress,
+    /// [`Preferences::forwarded_ip`]
+    Custom,
+}
+
+impl Display for ForwardedIpMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ForwardedIpMode::Omit => f.write_str("Don't send"),
+            ForwardedIpMode::RemoteAddress => f.write_str("Client address"),
+            ForwardedIpMode::Custom => f.write_str("Custom IP"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct HeaderOverride {
+    pub name: String,
+    pub value: String,
+}
+
+/// Holds back private messages so nothing pops up in the game, they all come in once it's off.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct DoNotDisturb {
+    pub enabled: bool,
+    /// Always let through, compared case-insensitively.
+    pub allowed_senders: Vec<String>,
+    /// Let through messages from the users in the friends list the server sent.
+    pub allow_friends: bool,
+}
+
+impl DoNotDisturb {
+    pub fn lets_through(&self, sender: &str, sender_id: i32, friends: &[i32]) -> bool {
+        let sender = sender.trim().to_lowercase();
+        (self.allow_friends && friends.contains(&sender_id))
+            || self
+                .allowed_senders
+                .iter()
+                .any(|allowed| allowed.trim().to_lowercase() == sender)
+    }
+}
+
+impl Default for DoNotDisturb {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            allowed_senders: vec![],
+            allow_friends: true,
+        }
+    }
+}
+
+/// Desktop notifications for private messages and for public ones mentioning us, so they aren't
+/// missed while the game is in the background.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct DesktopNotifications {
+    pub enabled: bool,
+    /// Also notify about channel messages that have our name in them.
+    pub mentions: bool,
+    /// Only when the game looks like it's in the background, see
+    /// [`crate::osus_proxy::desktop_notifications::likely_unfocused`].
+    pub only_when_away: bool,
+    /// How long until the same sender can cause another notification.
+    pub cooldown_secs: u64,
+}
+
+impl Default for DesktopNotifications {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            mentions: true,
+            only_when_away: true,
+            cooldown_secs: 60,
+        }
+    }
+}
+
+/// Writes chat to text files, see [`crate::osus_proxy::chat_log::ChatLog`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct ChatLogs {
+    pub enabled: bool,
+    /// Channels are always logged, private conversations only with this.
+    pub private_messages: bool,
+}
+
+impl Default for ChatLogs {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            private_messages: true,
+        }
+    }
+}
+
+/// Answers private messages while the user is away.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct AutoReply {
+    pub enabled: bool,
+    pub text: String,
+    /// How long someone has to wait for another reply, however many messages they send.
+    pub cooldown_secs: u64,
+}
+
+impl Default for AutoReply {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            text: "I'm away right now, I'll get back to you later.".to_owned(),
+            cooldown_secs: 5 * 60,
+        }
+    }
+}
+
+/// Someone whose messages never reach the game, on any server.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct BlockedUser {
+    /// Compared case-insensitively, ignoring surrounding whitespace. Empty to block by id only.
+    pub username: String,
+    /// Ids differ between servers, names usually don't.
+    pub user_id: Option<i32>,
+}
+
+impl BlockedUser {
+    pub fn matches(&self, sender: &str, sender_id: i32) -> bool {
+        let username = self.username.trim();
+        (!username.is_empty() && username.to_lowercase() == sender.trim().to_lowercase())
+            || self.user_id == Some(sender_id)
+    }
+}
+
+/// What happens to chat messages matching [`ChatFilter::patterns`].
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum ChatFilterMode {
+    /// Replace what matched with as many asterisks.
+    #[default]
+    Censor,
+    /// Drop the whole message, it's logged with its sender and channel.
+    Drop,
+}
+
+impl Display for ChatFilterMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChatFilterMode::Censor => f.write_str("Censor with asterisks"),
+            ChatFilterMode::Drop => f.write_str("Hide the message"),
+        }
+    }
+}
+
+/// Colors of the proxy's window.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum Theme {
+    /// Dark or light like the OS, dark where it can't tell.
+    #[default]
+    System,
+    Dark,
+    Light,
+}
+
+impl Display for Theme {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Theme::System => f.write_str("Same as the system"),
+            Theme::Dark => f.write_str("Dark"),
+            Theme::Light => f.write_str("Light"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct ChatFilterPattern {
+    pub enabled: bool,
+    /// A plain substring unless [`Self::regex`] is set.
+    pub pattern: String,
+    pub regex: bool,
+    pub case_sensitive: bool,
+}
+
+impl Default for ChatFilterPattern {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            pattern: String::new(),
+            regex: false,
+            case_sensitive: false,
+        }
+    }
+}
+
+/// Censors or hides chat messages, see [`crate::osus_proxy::chat_filter::CompiledChatFilter`].
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct ChatFilter {
+    pub patterns: Vec<ChatFilterPattern>,
+    pub mode: ChatFilterMode,
+    /// Filter the messages we send too, not just the ones we receive.
+    pub outgoing: bool,
+}
+
+/// What the panic switch turned off, so it can be turned back on the same way.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SpoofingToggles {
+    pub fake_supporter: bool,
+    pub fake_country: Option<Country>,
+    #[serde(default)]
+    pub hide_activity: bool,
+    #[serde(default)]
+    pub fake_utc_offset: Option<i8>,
+}
+
+/// Connection pool settings for the client that talks to the upstream server.
+/// The defaults are the same as hyper's.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct UpstreamPoolSettings {
+    /// Reuse upstream connections between requests.
+    pub keep_alive: bool,
+    /// `None` means no limit.
+    pub max_idle_per_host: Option<usize>,
+    pub idle_timeout_secs: u64,
+}
+
+impl UpstreamPoolSettings {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+impl Default for UpstreamPoolSettings {
+    fn default() -> Self {
+        Self {
+            keep_alive: true,
+            max_idle_per_host: None,
+            idle_timeout_secs: 90,
+        }
+    }
+}
+
+/// Gives up on upstream requests that never get an answer, and on the connections they were sent on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct UpstreamWatchdog {
+    /// How long to wait for the response headers.
+    pub request_timeout_secs: u64,
+    /// Timeouts in a row after which the pooled connections are dropped, they're probably half-open.
+    pub stalled_request_threshold: u32,
+}
+
+impl UpstreamWatchdog {
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+}
+
+impl Default for UpstreamWatchdog {
+    fn default() -> Self {
+        Self {
+            request_timeout_secs: 30,
+            stalled_request_threshold: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
 pub struct Preferences {
     pub server_address: String,
+    /// Subdomains of the source domain that get proxied to the same subdomain of the server.
+    pub subdomains: Vec<String>,
     pub fake_supporter: bool,
     pub beatmap_mirror: BeatmapMirror,
+    /// The game asking for the same set again this soon after a redirect means the redirect failed.
+    pub download_retry_window_secs: u64,
+    /// Open beatmap pages on osu.ppy.sh instead of the website of the server we're connected to.
+    pub beatmap_pages_on_official_site: bool,
     pub fake_country: Option<Country>,
-    // there's no other state rn so we just keep this in preferences lol
-    pub user_id: Option<i32>,
+    /// Hours from UTC shown in our own presence, -12 to 14.
+    pub fake_utc_offset: Option<i8>,
+    /// Global rank shown in our own presence and stats, only we ever see it.
+    pub fake_rank: Option<i32>,
+    /// Show our own stats as idle, without the map being played.
+    pub hide_activity: bool,
+    /// Zero the coordinates in our own presence. Only hides them from our game and the packet log,
+    /// the server has them from the login IP and shows them to everyone else.
+    pub hide_location: bool,
+    /// Keep the game from learning that someone started watching, so it never sends them our play.
+    pub block_spectators: bool,
+    /// Drop the messages these users send us.
+    pub blocked_users: Vec<BlockedUser>,
+    pub chat_filter: ChatFilter,
+    pub auto_reply: AutoReply,
+    pub do_not_disturb: DoNotDisturb,
+    pub desktop_notifications: DesktopNotifications,
+    pub chat_logs: ChatLogs,
+    /// Keep tournament clients on the proxy when the server tells them to switch to another bancho
+    /// host, and send their bancho traffic to that host from the proxy instead.
+    pub rewrite_tournament_server_switch: bool,
+    /// Tell the user in-game what the proxy changed when logging in.
+    pub login_notification: bool,
+    /// Don't greet with the proxy status after logging in.
+    pub quiet_mode: bool,
+    /// Set while the panic switch is on, holds the toggles from before it.
+    pub spoofing_paused: Option<SpoofingToggles>,
+    /// Affects what the server stores: servers that trust X-Forwarded-For would otherwise see 127.0.0.1.
+    pub hide_forwarded_ip_on_login: bool,
+    pub forwarded_ip_mode: ForwardedIpMode,
+    pub forwarded_ip: String,
+    /// Replace the headers the client sends to the bancho and web subdomains, e.g. osu-version.
+    pub header_overrides: Vec<HeaderOverride>,
+    /// Users whose presence and stats we ask the server for periodically, so they don't go stale.
+    pub presence_watchlist: Vec<i32>,
+    /// Serve the now playing map and the server for OBS under `/__osus/overlay/`.
+    pub overlay_endpoints: bool,
+    pub replay_fallback: ReplayFallback,
+    /// `{score_id}`, `{mode}`, `{username}` and `{password_hash}` are replaced with the values from the
+    /// client's request.
+    pub replay_fallback_url_template: String,
+    pub upstream_pool: UpstreamPoolSettings,
+    /// A self-hosted server (and its subdomains) whose certificate isn't verified. Never applies to ppy.sh.
+    pub insecure_upstream_domain: String,
+    pub upstream_watchdog: UpstreamWatchdog,
+    /// Failed requests in a row after which the server is considered down.
+    pub upstream_down_threshold: u32,
+    pub latency_injection: LatencyInjection,
+    /// Warn when the time the proxy itself adds to a bancho exchange goes over this, at p95.
+    pub processing_overhead_budget_ms: u64,
+    pub processing_limits: ProcessingLimits,
+    /// Fail the request instead of passing through bodies whose packets don't decode, for debugging.
+    pub strict_packet_decoding: bool,
+    /// Re-encode every body that goes through unchanged and send the original if it comes out
+    /// different, logging where, to catch decoder bugs.
+    pub verify_roundtrip: bool,
+    /// Keep the packets going through for exporting, see [`crate::osus_proxy::packet_log::PacketLog`].
+    pub record_packet_log: bool,
+    /// Keep all of the data of packets we don't decode in the packet log instead of the start of it.
+    pub packet_log_full_dump: bool,
+    /// Show the packets going through in the UI as they come, see
+    /// [`crate::osus_proxy::packet_viewer::PacketViewer`].
+    pub capture_to_viewer: bool,
+    /// Write every bancho body to disk, see [`crate::osus_proxy::capture::Capture`].
+    pub record_bancho_traffic: bool,
+    /// Stop recording a session once its capture gets this big, spectating adds up quickly.
+    pub capture_max_session_mb: u64,
+    /// Most recently used first, including the current server address.
+    pub recent_server_addresses: Vec<String>,
+    /// Opt-in, see [`crate::telemetry::UsageReport`] for what gets sent.
+    pub usage_ping: bool,
+    /// The usage ping is asked about once, on the first start.
+    pub usage_ping_asked: bool,
+    pub usage_ping_endpoint: String,
+    /// Unix timestamp
+    pub usage_ping_last_sent: Option<u64>,
+    /// Cleanup rules for the files we leave in the data directory, applied on startup.
+    pub retention_policies: Vec<RetentionPolicy>,
+    pub theme: Theme,
+    /// Closing or minimizing the window hides it to the tray, where the proxy supports one.
+    pub minimize_to_tray: bool,
+    /// Code of the language the UI is in, e.g. `en`. Unknown ones fall back to English.
+    pub language: String,
+    /// Start hidden in the tray, or minimized where there's none, like starting with Windows does.
+    pub start_minimized: bool,
 }
 
 impl Default for Preferences {
@@ -52,10 +509,490 @@ impl Default for Preferences {
             // server_address: "cmyui.xyz".to_owned(),
             // #[cfg(not(debug_assertions))]
             server_address: "ppy.sh".to_owned(),
+            subdomains: osus_proxy::default_subdomains(),
             fake_supporter: true,
             beatmap_mirror: Default::default(),
+            download_retry_window_secs: 10,
+            beatmap_pages_on_official_site: false,
+            fake_country: None,
+            fake_utc_offset: None,
+            fake_rank: None,
+            hide_activity: false,
+            hide_location: false,
+            block_spectators: false,
+            blocked_users: vec![],
+            chat_filter: Default::default(),
+            auto_reply: Default::default(),
+            do_not_disturb: Default::default(),
+            desktop_notifications: Default::default(),
+            chat_logs: Default::default(),
+            rewrite_tournament_server_switch: true,
+            login_notification: true,
+            quiet_mode: false,
+            spoofing_paused: None,
+            hide_forwarded_ip_on_login: false,
+            forwarded_ip_mode: Default::default(),
+            forwarded_ip: String::new(),
+            header_overrides: vec![],
+            presence_watchlist: vec![],
+            overlay_endpoints: false,
+            replay_fallback: Default::default(),
+            replay_fallback_url_template: String::new(),
+            upstream_pool: Default::default(),
+            insecure_upstream_domain: String::new(),
+            upstream_watchdog: Default::default(),
+            upstream_down_threshold: 3,
+            latency_injection: Default::default(),
+            processing_overhead_budget_ms: 5,
+            processing_limits: Default::default(),
+            strict_packet_decoding: false,
+            verify_roundtrip: false,
+            record_packet_log: false,
+            capture_to_viewer: false,
+            packet_log_full_dump: false,
+            record_bancho_traffic: false,
+            capture_max_session_mb: 256,
+            recent_server_addresses: vec![],
+            retention_policies: janitor::default_retention_policies(),
+            usage_ping: false,
+            usage_ping_asked: false,
+            usage_ping_endpoint: String::new(),
+            usage_ping_last_sent: None,
+            theme: Default::default(),
+            minimize_to_tray: false,
+            language: "en".to_owned(),
+            start_minimized: false,
+        }
+    }
+}
+
+const MAX_RECENT_SERVER_ADDRESSES: usize = 5;
+
+impl Preferences {
+    pub fn remember_server_address(&mut self, server_address: &str) {
+        if self.recent_server_addresses.first().map(String::as_str) == Some(server_address) {
+            return;
+        }
+        self.recent_server_addresses.retain(|x| x != server_address);
+        self.recent_server_addresses
+            .insert(0, server_address.to_owned());
+        self.recent_server_addresses
+            .truncate(MAX_RECENT_SERVER_ADDRESSES);
+    }
+
+    /// The panic switch, turns off everything that changes what others see about us.
+    pub fn pause_spoofing(&mut self) {
+        if self.spoofing_paused.is_some() {
+            return;
+        }
+        info!("Pausing all spoofing");
+        self.spoofing_paused = Some(SpoofingToggles {
+            fake_supporter: std::mem::take(&mut self.fake_supporter),
+            fake_country: self.fake_country.take(),
+            hide_activity: std::mem::take(&mut self.hide_activity),
+            fake_utc_offset: self.fake_utc_offset.take(),
+        });
+    }
+
+    pub fn restore_spoofing(&mut self) {
+        if let Some(toggles) = self.spoofing_paused.take() {
+            info!("Restoring spoofing");
+            self.fake_supporter = toggles.fake_supporter;
+            self.fake_country = toggles.fake_country;
+            self.hide_activity = toggles.hide_activity;
+            self.fake_utc_offset = toggles.fake_utc_offset;
+        }
+    }
+}
+
+/// What's actually stored in [`PREFERENCES_PATH`].
+#[derive(Serialize, JsonSchema)]
+struct PreferencesFile<'a> {
+    version: u32,
+    #[serde(flatten)]
+    preferences: &'a Preferences,
+}
+
+/// Runtime values that must never end up in the preferences file, keep them out with `#[serde(skip)]`.
+const SENSITIVE_KEYS: &[&str] = &[
+    "osu_token",
+    "cho_token",
+    "token",
+    "bearer_token",
+    "api_token",
+    "password",
+    "password_md5",
+    "username",
+    "user_id",
+];
+/// Way more than the preferences should ever need.
+const MAX_PREFERENCES_FILE_SIZE: usize = 64 * 1024;
+
+impl Preferences {
+    /// Loads the preferences file, or the defaults if there isn't one yet.
+    pub fn load() -> Result<Self> {
+        let json = match std::fs::read_to_string(PREFERENCES_PATH) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut value: serde_json::Value = serde_json::from_str(&json)
+            .map_err(|e| eyre!("invalid {}: {}", PREFERENCES_PATH, e))?;
+        let version = value
+            .as_object_mut()
+            .and_then(|object| object.remove("version"))
+            .and_then(|version| version.as_u64())
+            .ok_or_else(|| {
+                eyre!(
+                    "invalid {}: version: missing or not a number",
+                    PREFERENCES_PATH
+                )
+            })?;
+        if version > PREFERENCES_VERSION as u64 {
+            return Err(eyre!(
+                "{} was written by a newer version (format version {}, we support up to {})",
+                PREFERENCES_PATH,
+                version,
+                PREFERENCES_VERSION
+            ));
+        }
+
+        // Not going through PreferencesFile, flatten would lose the path in error messages
+        serde_path_to_error::deserialize(value)
+            .map_err(|e| eyre!("invalid {}: {}", PREFERENCES_PATH, e))
+    }
+
+    /// The contents of the preferences file, compare with what was last written to tell whether anything changed.
+    pub fn to_file_contents(&self) -> Result<String> {
+        let json = serde_json::to_string_pretty(&PreferencesFile {
+            version: PREFERENCES_VERSION,
+            preferences: self,
+        })?;
+
+        debug_assert!(
+            json.len() < MAX_PREFERENCES_FILE_SIZE,
+            "preferences serialized to {} bytes",
+            json.len()
+        );
+        debug_assert!(
+            !contains_sensitive_keys(&serde_json::from_str(&json)?),
+            "sensitive runtime values ended up in the preferences"
+        );
+
+        Ok(json)
+    }
+
+    /// Applies the fields `edited` changed from `before` on top of these preferences, so fields that
+    /// changed here in the meantime, e.g. by the proxy while the UI edited a copy, aren't undone.
+    pub fn merge_edits(&mut self, before: &Preferences, edited: &Preferences) -> Result<()> {
+        let before = serde_json::to_value(before)?;
+        let edited = serde_json::to_value(edited)?;
+        let mut merged = serde_json::to_value(&*self)?;
+        if let (Some(before), Some(edited), Some(merged)) = (
+            before.as_object(),
+            edited.as_object(),
+            merged.as_object_mut(),
+        ) {
+            for (field, value) in edited {
+                if before.get(field) != Some(value) {
+                    merged.insert(field.clone(), value.clone());
+                }
+            }
+        }
+        *self = serde_json::from_value(merged)?;
+        Ok(())
+    }
+
+    /// Writes to a temporary file first, so a crash in the middle can't leave a truncated file behind.
+    pub fn write_file(contents: &str) -> Result<()> {
+        write_file_atomically(Path::new(PREFERENCES_PATH), contents)
+    }
+
+    /// Moves an unreadable preferences file out of the way so saving doesn't overwrite it.
+    pub fn back_up_invalid_file() -> io::Result<String> {
+        let backup_path = format!("{}.invalid", PREFERENCES_PATH);
+        std::fs::rename(PREFERENCES_PATH, &backup_path)?;
+        Ok(backup_path)
+    }
+
+    /// JSON Schema of the preferences file, for tools that want to generate or edit it.
+    pub fn schema() -> RootSchema {
+        let mut schema = schemars::schema_for!(PreferencesFile<'static>);
+        let metadata = schema.schema.metadata();
+        metadata.title = Some("osus-proxy preferences".to_owned());
+        metadata.description = Some(format!("Format version {}", PREFERENCES_VERSION));
+        schema
+    }
+}
+
+/// Through `<path>.tmp`, renamed over `path` once it's all on disk.
+fn write_file_atomically(path: &Path, contents: &str) -> Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let mut file = std::fs::File::create(&temp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Only the top-level keys, where runtime values would end up. Nested ones like the username of a
+/// blocked user are what the user typed in.
+fn contains_sensitive_keys(value: &serde_json::Value) -> bool {
+    value.as_object().is_some_and(|object| {
+        object
+            .keys()
+            .any(|key| SENSITIVE_KEYS.contains(&key.as_str()))
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn spoofing() -> Preferences {
+        Preferences {
+            fake_supporter: true,
+            fake_country: Some(Country::Australia),
+            hide_activity: true,
+            fake_utc_offset: Some(-5),
+            ..Default::default()
+        }
+    }
+
+    fn toggles(preferences: &Preferences) -> SpoofingToggles {
+        SpoofingToggles {
+            fake_supporter: preferences.fake_supporter,
+            fake_country: preferences.fake_country.clone(),
+            hide_activity: preferences.hide_activity,
+            fake_utc_offset: preferences.fake_utc_offset,
+        }
+    }
+
+    #[test]
+    fn pausing_spoofing_turns_it_all_off_and_restoring_brings_it_back() {
+        let mut preferences = spoofing();
+        let before = toggles(&preferences);
+        preferences.pause_spoofing();
+        let all_off = SpoofingToggles {
+            fake_supporter: false,
             fake_country: None,
-            user_id: None,
+            hide_activity: false,
+            fake_utc_offset: None,
+        };
+        assert_eq!(toggles(&preferences), all_off);
+        assert_eq!(preferences.spoofing_paused, Some(before.clone()));
+
+        preferences.restore_spoofing();
+        assert_eq!(toggles(&preferences), before);
+        assert_eq!(preferences.spoofing_paused, None);
+    }
+
+    #[test]
+    fn pausing_twice_keeps_the_toggles_from_before_the_first_time() {
+        let mut preferences = spoofing();
+        let before = toggles(&preferences);
+        preferences.pause_spoofing();
+        // Turned back on by hand while paused, the switch still restores what it turned off
+        preferences.fake_supporter = true;
+        preferences.pause_spoofing();
+        assert_eq!(preferences.spoofing_paused, Some(before.clone()));
+        preferences.restore_spoofing();
+        assert_eq!(toggles(&preferences), before);
+    }
+
+    #[test]
+    fn restoring_without_pausing_changes_nothing() {
+        let mut preferences = spoofing();
+        let before = toggles(&preferences);
+        preferences.restore_spoofing();
+        assert_eq!(toggles(&preferences), before);
+    }
+
+    #[test]
+    fn paused_toggles_survive_a_restart() {
+        let mut preferences = spoofing();
+        let before = toggles(&preferences);
+        preferences.pause_spoofing();
+        let json = serde_json::to_string(&preferences).unwrap();
+        let mut loaded: Preferences = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.spoofing_paused, Some(before.clone()));
+        loaded.restore_spoofing();
+        assert_eq!(toggles(&loaded), before);
+    }
+
+    #[test]
+    fn merging_edits_keeps_what_changed_meanwhile() {
+        let before = Preferences::default();
+        let mut edited = before.clone();
+        edited.quiet_mode = !before.quiet_mode;
+        edited.pause_spoofing();
+        let mut current = before.clone();
+        current.remember_server_address("elsewhere.example");
+        current.merge_edits(&before, &edited).unwrap();
+        assert_eq!(current.quiet_mode, edited.quiet_mode);
+        assert_eq!(current.spoofing_paused, edited.spoofing_paused);
+        assert_eq!(
+            current.recent_server_addresses.first().map(String::as_str),
+            Some("elsewhere.example")
+        );
+        // Nothing edited, nothing changes
+        let unchanged = current.clone();
+        current.merge_edits(&before, &before).unwrap();
+        assert_eq!(current, unchanged);
+    }
+
+    #[test]
+    fn blocked_users_can_be_saved() {
+        let preferences = Preferences {
+            blocked_users: vec![BlockedUser {
+                username: "spammer".to_owned(),
+                user_id: Some(1000),
+            }],
+            ..Default::default()
+        };
+        let contents = preferences.to_file_contents().unwrap();
+        let saved: serde_json::Value = serde_json::from_str(&contents).unwrap();
+        assert_eq!(saved["blocked_users"][0]["username"], "spammer");
+        assert_eq!(saved["blocked_users"][0]["user_id"], 1000);
+    }
+
+    /// Every field set to something other than its default, so whatever a field holds gets serialized.
+    pub(crate) fn fully_populated() -> Preferences {
+        Preferences {
+            server_address: "example.com".to_owned(),
+            subdomains: vec!["c".to_owned(), "osu".to_owned()],
+            fake_supporter: true,
+            beatmap_mirror: BeatmapMirror::Nerinyan,
+            download_retry_window_secs: 30,
+            beatmap_pages_on_official_site: true,
+            fake_country: Some(Country::Australia),
+            fake_utc_offset: Some(10),
+            fake_rank: Some(1),
+            hide_activity: true,
+            hide_location: true,
+            block_spectators: true,
+            blocked_users: vec![BlockedUser {
+                username: "spammer".to_owned(),
+                user_id: Some(1000),
+            }],
+            chat_filter: ChatFilter {
+                patterns: vec![ChatFilterPattern {
+                    pattern: "word".to_owned(),
+                    ..Default::default()
+                }],
+                mode: ChatFilterMode::Drop,
+                outgoing: true,
+            },
+            auto_reply: AutoReply {
+                enabled: true,
+                text: "brb".to_owned(),
+                ..Default::default()
+            },
+            do_not_disturb: DoNotDisturb {
+                enabled: true,
+                allowed_senders: vec!["friend".to_owned()],
+                ..Default::default()
+            },
+            desktop_notifications: DesktopNotifications {
+                enabled: true,
+                ..Default::default()
+            },
+            chat_logs: ChatLogs {
+                enabled: true,
+                ..Default::default()
+            },
+            rewrite_tournament_server_switch: false,
+            login_notification: false,
+            quiet_mode: true,
+            spoofing_paused: Some(toggles(&spoofing())),
+            hide_forwarded_ip_on_login: true,
+            forwarded_ip_mode: ForwardedIpMode::Custom,
+            forwarded_ip: "203.0.113.1".to_owned(),
+            header_overrides: vec![HeaderOverride {
+                name: "osu-version".to_owned(),
+                value: "20231030".to_owned(),
+            }],
+            presence_watchlist: vec![2, 3],
+            overlay_endpoints: true,
+            replay_fallback: ReplayFallback::Url,
+            replay_fallback_url_template: "https://replays.example.com/{score_id}".to_owned(),
+            upstream_pool: UpstreamPoolSettings::default(),
+            insecure_upstream_domain: "self-hosted.example".to_owned(),
+            upstream_watchdog: UpstreamWatchdog::default(),
+            upstream_down_threshold: 5,
+            latency_injection: LatencyInjection {
+                enabled: true,
+                ..Default::default()
+            },
+            processing_overhead_budget_ms: 10,
+            processing_limits: ProcessingLimits::default(),
+            strict_packet_decoding: true,
+            verify_roundtrip: true,
+            record_packet_log: true,
+            packet_log_full_dump: true,
+            capture_to_viewer: true,
+            record_bancho_traffic: true,
+            capture_max_session_mb: 64,
+            recent_server_addresses: vec!["example.com".to_owned(), "ppy.sh".to_owned()],
+            usage_ping: true,
+            usage_ping_asked: true,
+            usage_ping_endpoint: "https://stats.example.com/ping".to_owned(),
+            usage_ping_last_sent: Some(1_700_000_000),
+            retention_policies: vec![RetentionPolicy {
+                directory: "captures".into(),
+                extensions: vec!["bin".to_owned()],
+                max_age_days: Some(30),
+                max_total_bytes: Some(1 << 30),
+            }],
+            theme: Theme::Dark,
+            minimize_to_tray: true,
+            language: "es".to_owned(),
+            start_minimized: true,
         }
     }
+
+    #[test]
+    fn saved_preferences_hold_no_session_values() {
+        let contents = fully_populated().to_file_contents().unwrap();
+        let saved: serde_json::Value = serde_json::from_str(&contents).unwrap();
+        let keys = saved.as_object().unwrap().keys().collect::<Vec<_>>();
+        for sensitive in SENSITIVE_KEYS {
+            assert!(
+                !keys.iter().any(|key| key == sensitive),
+                "{} was saved",
+                sensitive
+            );
+        }
+        // Everything else makes it into the file and back
+        let loaded: Preferences = serde_json::from_value(saved).unwrap();
+        assert_eq!(loaded, fully_populated());
+    }
+
+    /// A directory of its own under the system's temporary directory, emptied first.
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("osus-proxy-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn writes_through_a_temporary_file() {
+        let dir = temp_dir("preferences-write");
+        let path = dir.join("osus-proxy.json");
+        std::fs::write(&path, "old").unwrap();
+        write_file_atomically(&path, "new").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert!(!dir.join("osus-proxy.json.tmp").exists());
+
+        // The file itself is only ever replaced, so a failed write leaves it as it was
+        std::fs::create_dir(dir.join("osus-proxy.json.tmp")).unwrap();
+        assert!(write_file_atomically(&path, "newer").is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,175 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use http::StatusCode;
+use serde::Serialize;
+use tracing::debug;
+
+use crate::osus_proxy::auto_reply::AutoReplies;
+use crate::osus_proxy::availability::UpstreamAvailability;
+use crate::osus_proxy::bancho::{BanchoPrivileges, GameMode, LoginFailure};
+use crate::osus_proxy::capture::Capture;
+use crate::osus_proxy::chat_filter::CompiledChatFilter;
+use crate::osus_proxy::chat_log::ChatLog;
+use crate::osus_proxy::coverage::ProtocolCoverage;
+use crate::osus_proxy::desktop_notifications::DesktopNotifier;
+use crate::osus_proxy::do_not_disturb::HeldMessages;
+use crate::osus_proxy::idle_failures::IdleFailures;
+use crate::osus_proxy::injections::PendingInjections;
+use crate::osus_proxy::known_users::KnownUsers;
+use crate::osus_proxy::link_origins::LinkOrigins;
+use crate::osus_proxy::liveness::Liveness;
+use crate::osus_proxy::mirror_stats::MirrorStats;
+use crate::osus_proxy::overhead::ProcessingOverhead;
+use crate::osus_proxy::packet_log::PacketLog;
+use crate::osus_proxy::packet_stats::PacketStats;
+use crate::osus_proxy::packet_viewer::PacketViewerFeed;
+use crate::osus_proxy::replay::Replay;
+use crate::osus_proxy::sessions::Sessions;
+use crate::osus_proxy::settings_notices::SettingsNotices;
+use crate::osus_proxy::spectate_stats::SpectateStats;
+use crate::osus_proxy::UpstreamClient;
+
+/// Runtime state of the proxy that the UI wants to see but isn't something the user picks.
+#[derive(Debug, Default)]
+pub struct ProxyState {
+    pub protocol_coverage: ProtocolCoverage,
+    pub packet_log: PacketLog,
+    pub packet_stats: PacketStats,
+    pub packet_viewer: PacketViewerFeed,
+    pub capture: Capture,
+    pub chat_log: ChatLog,
+    /// Only when started with `--replay`.
+    pub replay: Option<Replay>,
+    pub chat_filter: CompiledChatFilter,
+    /// Messages dropped because of [`crate::preferences::Preferences::blocked_users`] this session,
+    /// by sender name.
+    pub blocked_messages: BTreeMap<String, u64>,
+    pub auto_replies: AutoReplies,
+    pub held_messages: HeldMessages,
+    pub desktop_notifier: DesktopNotifier,
+    pub known_users: KnownUsers,
+    pub pending_injections: PendingInjections,
+    pub settings_notices: SettingsNotices,
+    pub upstream_availability: UpstreamAvailability,
+    pub mirror_stats: MirrorStats,
+    pub upstream_client: Option<UpstreamClient>,
+    /// Where the beatmap links rewritten to point at the proxy came from.
+    pub link_origins: LinkOrigins,
+    /// Clients logged in through the proxy.
+    pub sessions: Sessions,
+    /// Where the proxy listens, or why it couldn't. `None` until it tried.
+    pub listener: Option<Result<SocketAddr, String>>,
+    /// When the last upstream request failed without a response, and for what.
+    pub last_upstream_error: Option<(Instant, String)>,
+    /// The error bancho responded with instead of packets, cleared by the next response with packets.
+    pub upstream_error: Option<UpstreamError>,
+    /// Why the server rejected the last login, cleared by the next successful one.
+    pub login_failure: Option<LoginFailure>,
+    /// What the server itself granted in the last Privilege packet, before any faking.
+    pub last_privileges: Option<BanchoPrivileges>,
+    /// The protocol version the server sent on the last login.
+    pub protocol_version: Option<i32>,
+    /// The server that told a tournament client to switch and the bancho host it switched to,
+    /// bancho traffic goes there instead of the server's own subdomains while it's selected.
+    pub bancho_host_override: Option<(String, String)>,
+    /// The last friends list the server sent.
+    pub friends: Vec<i32>,
+    /// Users the server sent the presence of, or listed as online, since the last login, minus
+    /// those who logged out.
+    pub online_users: HashSet<i32>,
+    /// The channels each logged in user is in, by user id.
+    pub joined_channels: HashMap<i32, BTreeSet<String>>,
+    /// How long the server said its restart takes, cleared by the next successful login.
+    pub server_restart: Option<Duration>,
+    /// When the silence the server told us about ends, messages sent until then are dropped.
+    pub silenced_until: Option<Instant>,
+    /// Upstream requests in a row that got no response in time.
+    pub consecutive_timeouts: u32,
+    pub idle_failures: IdleFailures,
+    /// The last action the client sent, as it was logged.
+    pub last_action_summary: Option<String>,
+    /// The map being played right now, if any.
+    pub now_playing: Option<NowPlaying>,
+    pub last_watchlist_refresh: Option<Instant>,
+    /// Set by `!osus ping` or the UI, the next bancho exchange gets timed.
+    pub ping_requested: bool,
+    pub last_ping: Option<PingResult>,
+    pub bancho_exchanges: u64,
+    pub liveness: Liveness,
+    pub processing_overhead: ProcessingOverhead,
+    pub spectate_stats: SpectateStats,
+    pub startup_timeline: StartupTimeline,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NowPlaying {
+    pub map_id: i32,
+    /// What the client shows as the action text, usually "Artist - Title [Difficulty]".
+    pub info_text: String,
+    pub mode: GameMode,
+}
+
+/// A bancho response that was passed through to the client as is, as it couldn't have any packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpstreamError {
+    pub status: StatusCode,
+    /// An error page, from a reverse proxy in front of the server most of the time.
+    pub html: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PingResult {
+    pub round_trip: Duration,
+    pub exchange_bytes: usize,
+}
+
+impl std::fmt::Display for PingResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "proxy→server: {} ms, exchange size: {:.1} KB",
+            self.round_trip.as_millis(),
+            self.exchange_bytes as f64 / 1024.0
+        )
+    }
+}
+
+/// When each startup phase finished, counted from the start of `main`.
+#[derive(Debug)]
+pub struct StartupTimeline {
+    started: Instant,
+    phases: Vec<(&'static str, Duration)>,
+    /// `--profile-startup`
+    print: bool,
+}
+
+impl StartupTimeline {
+    pub fn new(print: bool) -> Self {
+        Self {
+            started: Instant::now(),
+            phases: vec![],
+            print,
+        }
+    }
+
+    /// Only the first call for each phase counts, so it's fine to call this every frame.
+    pub fn mark(&mut self, phase: &'static str) {
+        if self.phases.iter().any(|(marked, _)| *marked == phase) {
+            return;
+        }
+        let elapsed = self.started.elapsed();
+        debug!("Startup: {} after {} ms", phase, elapsed.as_millis());
+        if self.print {
+            println!("{:>6} ms  {}", elapsed.as_millis(), phase);
+        }
+        self.phases.push((phase, elapsed));
+    }
+}
+
+impl Default for StartupTimeline {
+    fn default() -> Self {
+        Self::new(false)
+    }
+}
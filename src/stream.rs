@@ -0,0 +1,347 @@
+use bytes::{Bytes, BytesMut};
+use tracing::trace;
+
+use crate::bancho::{BanchoPacket, BanchoPacketHeader, PacketDirection, PACKET_HEADER_LENGTH};
+use crate::codec::CodecError;
+use crate::logging::RateLimitedWarning;
+use crate::HEXDUMP_BYTES;
+
+static LEFTOVER_BYTES_WARNING: RateLimitedWarning =
+    RateLimitedWarning::new("Leftover bytes after the last packet");
+static BOGUS_LENGTH_WARNING: RateLimitedWarning =
+    RateLimitedWarning::new("Packets claiming a bogus length");
+
+/// Splits a bancho body into packets as it arrives in chunks. A packet cut off at the end of a
+/// chunk is kept until the rest of it comes, complete ones are sliced out without copying.
+pub struct PacketSplitter {
+    direction: PacketDirection,
+    max_packet_length: usize,
+    /// Received but not split off yet.
+    pending: Bytes,
+    /// Chunks that came after `pending` while waiting for the rest of its first packet. They're
+    /// joined once it's all here, so a long packet coming in many chunks is only copied once.
+    waiting: Vec<Bytes>,
+    /// `pending` and `waiting` together.
+    buffered: usize,
+    /// Where `pending` starts in the body.
+    offset: usize,
+}
+
+impl PacketSplitter {
+    pub fn new(direction: PacketDirection, max_packet_length: usize) -> Self {
+        Self {
+            direction,
+            max_packet_length,
+            pending: Bytes::new(),
+            waiting: vec![],
+            buffered: 0,
+            offset: 0,
+        }
+    }
+
+    pub fn push(&mut self, chunk: Bytes) {
+        self.buffered += chunk.len();
+        if self.pending.is_empty() && self.waiting.is_empty() {
+            self.pending = chunk;
+        } else {
+            self.waiting.push(chunk);
+        }
+    }
+
+    /// The packets completed so far, along with the bytes they were decoded from. Fails on a
+    /// packet claiming to be longer than `max_packet_length`, a corrupt header would otherwise have
+    /// us wait for and buffer whatever it says.
+    pub fn split(&mut self) -> Result<(Bytes, Vec<BanchoPacket>), CodecError> {
+        if self.first_packet_buffered() {
+            self.join();
+        }
+
+        let mut packets = vec![];
+        let mut position = 0;
+        while self.pending.len() - position >= PACKET_HEADER_LENGTH {
+            let header = self.header_at(position);
+            let length = header.length() as usize;
+            if length > self.max_packet_length {
+                self.warn_bogus_length(position, &header);
+                return Err(CodecError::PacketTooLong {
+                    id: header.id(),
+                    length,
+                    offset: self.offset + position,
+                });
+            }
+            let data_offset = position + PACKET_HEADER_LENGTH;
+            let end = data_offset + length;
+            if end > self.pending.len() {
+                break;
+            }
+            let packet = BanchoPacket::from_header_and_bytes(
+                &header,
+                self.pending.slice(data_offset..end),
+                self.offset + data_offset,
+                self.direction,
+            );
+            trace!("{} {}, {} bytes", self.direction, packet, length);
+            packets.push(packet);
+            position = end;
+        }
+
+        self.offset += position;
+        self.buffered -= position;
+        Ok((self.pending.split_to(position), packets))
+    }
+
+    /// Once the body ended, fails if its last packet claims to be longer than what came. Less
+    /// than a header's worth is dropped with a warning.
+    pub fn finish(&mut self) -> Result<(), CodecError> {
+        self.join();
+        if self.pending.len() >= PACKET_HEADER_LENGTH {
+            let header = self.header_at(0);
+            self.warn_bogus_length(0, &header);
+            return Err(CodecError::Truncated {
+                id: header.id(),
+                length: header.length() as usize,
+                offset: self.offset,
+            });
+        } else if !self.pending.is_empty() {
+            let leftover = self.take_pending();
+            LEFTOVER_BYTES_WARNING.warn(|| {
+                format!(
+                    "Encountered {} leftover bytes:\n{}",
+                    leftover.len(),
+                    rhexdump::rhexdumps!(&leftover)
+                )
+            });
+        }
+        Ok(())
+    }
+
+    /// Whatever wasn't split off, to pass it through as is after a failure.
+    pub fn take_pending(&mut self) -> Bytes {
+        self.join();
+        self.offset += self.pending.len();
+        self.buffered = 0;
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Whether there's enough for the first packet, or enough of its header to tell it claims a
+    /// bogus length.
+    fn first_packet_buffered(&self) -> bool {
+        if self.waiting.is_empty() || self.buffered < PACKET_HEADER_LENGTH {
+            return false;
+        }
+        // The header itself may be cut off too
+        let mut header_bytes = [0; PACKET_HEADER_LENGTH];
+        let buffered_bytes = std::iter::once(&self.pending)
+            .chain(&self.waiting)
+            .flat_map(|piece| piece.iter());
+        for (header_byte, byte) in header_bytes.iter_mut().zip(buffered_bytes) {
+            *header_byte = *byte;
+        }
+        let length = BanchoPacketHeader::from_bytes(header_bytes).length() as usize;
+        length > self.max_packet_length || self.buffered >= PACKET_HEADER_LENGTH + length
+    }
+
+    /// Copies `waiting` into `pending`, after it.
+    fn join(&mut self) {
+        if self.waiting.is_empty() {
+            return;
+        }
+        let mut joined = BytesMut::with_capacity(self.buffered);
+        joined.extend_from_slice(&self.pending);
+        for piece in self.waiting.drain(..) {
+            joined.extend_from_slice(&piece);
+        }
+        self.pending = joined.freeze();
+    }
+
+    fn header_at(&self, position: usize) -> BanchoPacketHeader {
+        let mut header_bytes = [0; PACKET_HEADER_LENGTH];
+        header_bytes.copy_from_slice(&self.pending[position..position + PACKET_HEADER_LENGTH]);
+        BanchoPacketHeader::from_bytes(header_bytes)
+    }
+
+    fn warn_bogus_length(&self, position: usize, header: &BanchoPacketHeader) {
+        let rest = &self.pending[position..self.pending.len().min(position + HEXDUMP_BYTES)];
+        BOGUS_LENGTH_WARNING.warn(|| {
+            format!(
+                "Packet {} at byte {} claims to be {} bytes long, {} bytes came after its header:\n{}",
+                header.id(),
+                self.offset + position,
+                header.length(),
+                self.pending.len() - position - PACKET_HEADER_LENGTH,
+                rhexdump::rhexdumps!(rest)
+            )
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::encode_bancho_packets;
+
+    const MAX_PACKET_LENGTH: usize = 1024;
+
+    fn splitter() -> PacketSplitter {
+        PacketSplitter::new(PacketDirection::ServerToClient, MAX_PACKET_LENGTH)
+    }
+
+    fn body(packets: Vec<BanchoPacket>) -> Bytes {
+        encode_bancho_packets(packets, 0).unwrap()
+    }
+
+    fn notification(text: &str) -> BanchoPacket {
+        BanchoPacket::Notification(text.to_owned().into())
+    }
+
+    /// Pushes `body` in chunks split at `splits`, splitting after each, and returns the packets
+    /// written out again along with the bytes they came from.
+    fn split_in_chunks(
+        splitter: &mut PacketSplitter,
+        body: &Bytes,
+        splits: &[usize],
+    ) -> (Bytes, Bytes) {
+        let mut consumed = BytesMut::new();
+        let mut packets = vec![];
+        let mut start = 0;
+        for &end in splits.iter().chain([body.len()].iter()) {
+            splitter.push(body.slice(start..end));
+            let (bytes, split) = splitter.split().unwrap();
+            consumed.extend_from_slice(&bytes);
+            packets.extend(split);
+            start = end;
+        }
+        (
+            consumed.freeze(),
+            encode_bancho_packets(packets, 0).unwrap(),
+        )
+    }
+
+    #[test]
+    fn waits_for_a_header_cut_off_between_chunks() {
+        let body = body(vec![
+            notification("hello"),
+            BanchoPacket::ProtocolVersion(19),
+        ]);
+        let mut splitter = splitter();
+
+        splitter.push(body.slice(..3));
+        let (consumed, packets) = splitter.split().unwrap();
+        assert!(consumed.is_empty());
+        assert!(packets.is_empty());
+
+        let (consumed, packets) = split_in_chunks(&mut splitter, &body.slice(3..), &[]);
+        assert_eq!(consumed, body);
+        assert_eq!(packets, body);
+        splitter.finish().unwrap();
+    }
+
+    #[test]
+    fn joins_a_packet_coming_in_many_chunks() {
+        let body = body(vec![
+            notification(&"a".repeat(500)),
+            BanchoPacket::ProtocolVersion(19),
+        ]);
+        let splits: Vec<_> = (1..body.len()).collect();
+        let mut splitter = splitter();
+
+        let (consumed, packets) = split_in_chunks(&mut splitter, &body, &splits);
+        assert_eq!(consumed, body);
+        assert_eq!(packets, body);
+        assert_eq!(splitter.offset, body.len());
+        splitter.finish().unwrap();
+
+        // Nothing is copied until all of the packet is here
+        let mut splitter = self::splitter();
+        for start in 0..100 {
+            splitter.push(body.slice(start..start + 1));
+            splitter.split().unwrap();
+        }
+        assert_eq!(splitter.pending.len(), 1);
+        assert_eq!(splitter.waiting.len(), 99);
+    }
+
+    #[test]
+    fn fails_on_a_packet_claiming_to_be_too_long() {
+        let first = body(vec![BanchoPacket::ProtocolVersion(19)]);
+        let mut bogus = first.to_vec();
+        bogus.extend_from_slice(&[5, 0, 0]);
+        bogus.extend_from_slice(&(MAX_PACKET_LENGTH as u32 + 1).to_le_bytes());
+        let bogus = Bytes::from(bogus);
+
+        // Even before the rest of its header came
+        let mut splitter = splitter();
+        splitter.push(bogus.slice(..first.len() + 2));
+        splitter.split().unwrap();
+        splitter.push(bogus.slice(first.len() + 2..));
+        let error = splitter.split().unwrap_err();
+        assert!(
+            matches!(
+                error,
+                CodecError::PacketTooLong { id: 5, length, offset }
+                    if length == MAX_PACKET_LENGTH + 1 && offset == first.len()
+            ),
+            "{:?}",
+            error
+        );
+
+        // Whatever is left is passed through as is
+        assert_eq!(splitter.take_pending(), bogus.slice(first.len()..));
+        assert!(splitter.take_pending().is_empty());
+        splitter.finish().unwrap();
+    }
+
+    #[test]
+    fn takes_everything_pending_after_a_failure() {
+        let first = body(vec![BanchoPacket::ProtocolVersion(19)]);
+        let mut bogus = first.to_vec();
+        bogus.extend_from_slice(&[5, 0, 0]);
+        bogus.extend_from_slice(&u32::MAX.to_le_bytes());
+        let bogus = Bytes::from(bogus);
+
+        let mut splitter = splitter();
+        splitter.push(bogus.clone());
+        let error = splitter.split().unwrap_err();
+        // Nothing was split off before the bogus packet either
+        assert!(matches!(error, CodecError::PacketTooLong { offset, .. } if offset == first.len()));
+        assert_eq!(splitter.take_pending(), bogus);
+    }
+
+    #[test]
+    fn fails_on_a_truncated_last_packet() {
+        let body = body(vec![
+            BanchoPacket::ProtocolVersion(19),
+            notification("hello"),
+        ]);
+        let truncated = body.slice(..body.len() - 1);
+        let mut splitter = splitter();
+
+        let (consumed, _) = split_in_chunks(&mut splitter, &truncated, &[9, 14]);
+        let text_length = body.len() - consumed.len() - PACKET_HEADER_LENGTH;
+        let error = splitter.finish().unwrap_err();
+        assert!(
+            matches!(
+                error,
+                CodecError::Truncated { id: 24, length, offset } if length == text_length && offset == consumed.len()
+            ),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn drops_leftovers_shorter_than_a_header() {
+        let body = body(vec![BanchoPacket::ProtocolVersion(19)]);
+        let mut with_leftovers = body.to_vec();
+        with_leftovers.extend_from_slice(&[1, 2, 3]);
+        let with_leftovers = Bytes::from(with_leftovers);
+        let mut splitter = splitter();
+
+        let (consumed, packets) =
+            split_in_chunks(&mut splitter, &with_leftovers, &[body.len() + 1]);
+        assert_eq!(consumed, body);
+        assert_eq!(packets, body);
+        splitter.finish().unwrap();
+        assert!(splitter.take_pending().is_empty());
+    }
+}
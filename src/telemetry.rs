@@ -0,0 +1,278 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::{eyre::eyre, Result};
+use hyper::{header, Body, Method, Request};
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::osus_proxy;
+use crate::preferences::{BeatmapMirror, Preferences, ReplayFallback};
+use crate::state::ProxyState;
+
+/// Setting this to anything turns the usage ping off no matter what the preferences say.
+pub const KILL_SWITCH_VARIABLE: &str = "OSUS_PROXY_NO_TELEMETRY";
+const SEND_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Everything the usage ping sends. Only booleans and coarse numbers, never anything that could
+/// tell who the user is, where they play or what they say.
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+    version: &'static str,
+    os: &'static str,
+    features: FeatureUsage,
+    /// Rounded down to a power of ten.
+    bancho_exchanges: u64,
+    /// Rounded down to a power of ten.
+    mirror_downloads: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct FeatureUsage {
+    fake_supporter: bool,
+    fake_country: bool,
+    fake_utc_offset: bool,
+    fake_rank: bool,
+    hide_activity: bool,
+    hide_location: bool,
+    block_spectators: bool,
+    chat_filter: bool,
+    chat_logs: bool,
+    blocked_users: bool,
+    auto_reply: bool,
+    desktop_notifications: bool,
+    beatmap_mirror: bool,
+    beatmap_pages_on_official_site: bool,
+    replay_fallback: bool,
+    presence_watchlist: bool,
+    header_overrides: bool,
+    overlay_endpoints: bool,
+    latency_injection: bool,
+    spoofing_paused: bool,
+}
+
+impl UsageReport {
+    pub fn new(preferences: &Preferences, state: &ProxyState) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            os: std::env::consts::OS,
+            features: FeatureUsage {
+                fake_supporter: preferences.fake_supporter,
+                fake_country: preferences.fake_country.is_some(),
+                fake_utc_offset: preferences.fake_utc_offset.is_some(),
+                fake_rank: preferences.fake_rank.is_some(),
+                hide_activity: preferences.hide_activity,
+                hide_location: preferences.hide_location,
+                block_spectators: preferences.block_spectators,
+                chat_filter: preferences
+                    .chat_filter
+                    .patterns
+                    .iter()
+                    .any(|pattern| pattern.enabled),
+                chat_logs: preferences.chat_logs.enabled,
+                blocked_users: !preferences.blocked_users.is_empty(),
+                auto_reply: preferences.auto_reply.enabled,
+                desktop_notifications: preferences.desktop_notifications.enabled,
+                beatmap_mirror: preferences.beatmap_mirror != BeatmapMirror::ServerDefault,
+                beatmap_pages_on_official_site: preferences.beatmap_pages_on_official_site,
+                replay_fallback: preferences.replay_fallback != ReplayFallback::Disabled,
+                presence_watchlist: !preferences.presence_watchlist.is_empty(),
+                header_overrides: !preferences.header_overrides.is_empty(),
+                overlay_endpoints: preferences.overlay_endpoints,
+                latency_injection: preferences.latency_injection.enabled,
+                spoofing_paused: preferences.spoofing_paused.is_some(),
+            },
+            bancho_exchanges: coarse(state.bancho_exchanges),
+            mirror_downloads: coarse(state.mirror_stats.total_downloads()),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+/// Sends the usage ping at most once a day, for as long as the user has it turned on.
+pub async fn run(preferences: Arc<Mutex<Preferences>>, state: Arc<Mutex<ProxyState>>) {
+    loop {
+        if let Err(e) = send_if_due(&preferences, &state).await {
+            warn!("Failed to send the usage ping: {}", e);
+        }
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+async fn send_if_due(preferences: &Mutex<Preferences>, state: &Mutex<ProxyState>) -> Result<()> {
+    if std::env::var_os(KILL_SWITCH_VARIABLE).is_some() {
+        return Ok(());
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let (endpoint, json) = {
+        let preferences = preferences.lock().await;
+        if !preferences.usage_ping || preferences.usage_ping_endpoint.trim().is_empty() {
+            return Ok(());
+        }
+        let due = preferences
+            .usage_ping_last_sent
+            .is_none_or(|last_sent| now.saturating_sub(last_sent) >= SEND_INTERVAL.as_secs());
+        if !due {
+            return Ok(());
+        }
+        let state = state.lock().await;
+        (
+            preferences.usage_ping_endpoint.trim().to_owned(),
+            UsageReport::new(&preferences, &state).to_json()?,
+        )
+    };
+
+    debug!("Sending the usage ping to {}", endpoint);
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(&endpoint)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(json))?;
+    let response = osus_proxy::build_client(&Default::default(), "")
+        .request(request)
+        .await?;
+    if !response.status().is_success() {
+        return Err(eyre!("{} responded with {}", endpoint, response.status()));
+    }
+
+    info!("Sent the usage ping");
+    preferences.lock().await.usage_ping_last_sent = Some(now);
+    Ok(())
+}
+
+fn coarse(count: u64) -> u64 {
+    if count == 0 {
+        return 0;
+    }
+    10u64.pow(count.ilog10())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+
+    use super::*;
+    use crate::preferences::tests::fully_populated;
+
+    #[test]
+    fn the_report_holds_only_booleans_and_counts() {
+        let preferences = fully_populated();
+        let state = ProxyState {
+            bancho_exchanges: 12345,
+            bancho_host_override: Some((
+                preferences.server_address.clone(),
+                "10.0.0.1:13381".to_owned(),
+            )),
+            ..Default::default()
+        };
+        let json = UsageReport::new(&preferences, &state).to_json().unwrap();
+        let report: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(report["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(report["os"], std::env::consts::OS);
+        assert_eq!(report["bancho_exchanges"], 10000);
+        assert_eq!(report["mirror_downloads"], 0);
+        let features = report["features"].as_object().unwrap();
+        assert!(features.values().all(serde_json::Value::is_boolean));
+        assert_eq!(report.as_object().unwrap().len(), 5);
+
+        let blocked_user = &preferences.blocked_users[0].username;
+        let chat_pattern = &preferences.chat_filter.patterns[0].pattern;
+        for private in [
+            &preferences.server_address,
+            &preferences.usage_ping_endpoint,
+            &preferences.forwarded_ip,
+            blocked_user,
+            chat_pattern,
+            &preferences.auto_reply.text,
+            "10.0.0.1",
+        ] {
+            assert!(!json.contains(private), "{} in {}", private, json);
+        }
+        for address in &preferences.recent_server_addresses {
+            assert!(!json.contains(address.as_str()), "{} in {}", address, json);
+        }
+    }
+
+    #[test]
+    fn counts_are_rounded_down_to_a_power_of_ten() {
+        assert_eq!(coarse(0), 0);
+        assert_eq!(coarse(1), 1);
+        assert_eq!(coarse(9), 1);
+        assert_eq!(coarse(10), 10);
+        assert_eq!(coarse(99), 10);
+        assert_eq!(coarse(12345), 10000);
+        assert_eq!(coarse(u64::MAX), 10u64.pow(19));
+    }
+
+    /// Answers every request with a 200, counting them.
+    async fn mock_endpoint() -> (String, Arc<AtomicUsize>) {
+        let received = Arc::new(AtomicUsize::new(0));
+        let make_service = {
+            let received = received.clone();
+            make_service_fn(move |_| {
+                let received = received.clone();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |_| {
+                        received.fetch_add(1, Ordering::SeqCst);
+                        async { Ok::<_, hyper::Error>(Response::new(Body::empty())) }
+                    }))
+                }
+            })
+        };
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let endpoint = format!("http://{}/ping", server.local_addr());
+        tokio::spawn(server);
+        (endpoint, received)
+    }
+
+    // The only test touching the kill switch, other tests running meanwhile don't read it
+    #[tokio::test]
+    async fn sends_at_most_daily_unless_killed() {
+        let (endpoint, received) = mock_endpoint().await;
+        let preferences = Mutex::new(Preferences {
+            usage_ping: true,
+            usage_ping_endpoint: endpoint,
+            ..Default::default()
+        });
+        let state = Mutex::new(ProxyState::default());
+
+        std::env::set_var(KILL_SWITCH_VARIABLE, "1");
+        let killed = send_if_due(&preferences, &state).await;
+        std::env::remove_var(KILL_SWITCH_VARIABLE);
+        killed.unwrap();
+        assert_eq!(received.load(Ordering::SeqCst), 0);
+        assert_eq!(preferences.lock().await.usage_ping_last_sent, None);
+
+        send_if_due(&preferences, &state).await.unwrap();
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+        let last_sent = preferences.lock().await.usage_ping_last_sent.unwrap();
+
+        // Not again the same day
+        send_if_due(&preferences, &state).await.unwrap();
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+        preferences.lock().await.usage_ping_last_sent =
+            Some(last_sent - SEND_INTERVAL.as_secs() + 60);
+        send_if_due(&preferences, &state).await.unwrap();
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+
+        preferences.lock().await.usage_ping_last_sent = Some(last_sent - SEND_INTERVAL.as_secs());
+        send_if_due(&preferences, &state).await.unwrap();
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+
+        preferences.lock().await.usage_ping_last_sent = None;
+        preferences.lock().await.usage_ping = false;
+        send_if_due(&preferences, &state).await.unwrap();
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+    }
+}
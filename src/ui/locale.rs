@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use tracing::{info, warn};
+
+use crate::janitor::DATA_DIRECTORY;
+
+/// The languages built in, by code. English comes first, it's what the others fall back to for
+/// strings they don't have.
+const BUNDLED_LANGUAGES: &[(&str, &str)] = &[
+    ("en", include_str!("locales/en.lang")),
+    ("es", include_str!("locales/es.lang")),
+];
+/// Relative to [`DATA_DIRECTORY`]. A `<code>.lang` in there adds a language, or replaces the
+/// built in one with the same code, without having to rebuild.
+pub const LOCALES_DIRECTORY: &str = "locales";
+
+#[derive(Debug)]
+struct Language {
+    code: String,
+    strings: HashMap<String, String>,
+}
+
+impl Language {
+    /// `key = value` lines, `#` starts a comment line and `\n` in a value is a line break.
+    fn parse(code: &str, source: &str) -> Self {
+        let mut strings = HashMap::new();
+        for (number, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((key, value)) => {
+                    strings.insert(key.trim().to_owned(), value.trim().replace("\\n", "\n"));
+                }
+                None => warn!(
+                    "Ignoring line {} of the {} language file, it has no '='",
+                    number + 1,
+                    code
+                ),
+            }
+        }
+        Self {
+            code: code.to_owned(),
+            strings,
+        }
+    }
+
+    /// What the language calls itself, for the language selector.
+    fn name(&self) -> &str {
+        self.strings
+            .get("language-name")
+            .map(String::as_str)
+            .unwrap_or(&self.code)
+    }
+}
+
+fn languages() -> &'static [Language] {
+    static LANGUAGES: OnceLock<Vec<Language>> = OnceLock::new();
+    LANGUAGES.get_or_init(|| {
+        let mut languages = BUNDLED_LANGUAGES
+            .iter()
+            .map(|(code, source)| Language::parse(code, source))
+            .collect::<Vec<_>>();
+        for language in load_language_files() {
+            match languages
+                .iter_mut()
+                .find(|bundled| bundled.code == language.code)
+            {
+                Some(bundled) => *bundled = language,
+                None => languages.push(language),
+            }
+        }
+        languages
+    })
+}
+
+fn load_language_files() -> Vec<Language> {
+    let directory = Path::new(DATA_DIRECTORY).join(LOCALES_DIRECTORY);
+    let Ok(entries) = std::fs::read_dir(&directory) else {
+        return vec![];
+    };
+    let mut languages = vec![];
+    for path in entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        let Some(code) = path.file_stem().and_then(|stem| stem.to_str()).filter(|_| {
+            path.extension()
+                .is_some_and(|extension| extension == "lang")
+        }) else {
+            continue;
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(source) => {
+                info!("Loaded the {} language from {}", code, path.display());
+                languages.push(Language::parse(code, &source));
+            }
+            Err(e) => warn!("Failed to read the language file {}: {}", path.display(), e),
+        }
+    }
+    languages
+}
+
+/// Codes and names of the languages to choose from, English first.
+pub fn available_languages() -> impl Iterator<Item = (&'static str, &'static str)> {
+    languages()
+        .iter()
+        .map(|language| (language.code.as_str(), language.name()))
+}
+
+/// The strings of the UI in one language, looked up by key. Cheap to make, the language files
+/// are only read once.
+#[derive(Debug, Clone, Copy)]
+pub struct Locale {
+    language: &'static Language,
+    english: &'static Language,
+}
+
+impl Locale {
+    /// Falls back to English for a language we don't have.
+    pub fn new(code: &str) -> Self {
+        let languages = languages();
+        let english = &languages[0];
+        Self {
+            language: languages
+                .iter()
+                .find(|language| language.code == code)
+                .unwrap_or(english),
+            english,
+        }
+    }
+
+    /// The string for `key`, in English if the language doesn't have it, or the key itself if
+    /// nobody does.
+    pub fn get(&self, key: &'static str) -> &'static str {
+        self.lookup(key).unwrap_or(key)
+    }
+
+    /// Like [`Self::get`] with every `{name}` replaced by its argument.
+    pub fn format(&self, key: &'static str, args: &[(&str, &dyn Display)]) -> String {
+        let mut text = self.get(key).to_owned();
+        for (name, value) in args {
+            text = text.replace(&format!("{{{}}}", name), &value.to_string());
+        }
+        text
+    }
+
+    /// The string for an enum value, keyed as `<prefix>-<variant>` like `theme-Dark`, or what
+    /// its `Display` says if there's none.
+    pub fn name_of<T: Debug + Display>(&self, prefix: &str, value: &T) -> String {
+        match self.lookup(&format!("{}-{:?}", prefix, value)) {
+            Some(text) => text.to_owned(),
+            None => value.to_string(),
+        }
+    }
+
+    fn lookup(&self, key: &str) -> Option<&'static str> {
+        self.language
+            .strings
+            .get(key)
+            .or_else(|| self.english.strings.get(key))
+            .map(String::as_str)
+    }
+}
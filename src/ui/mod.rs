@@ -1,10 +1,53 @@
-use crate::preferences::{BeatmapMirror, Preferences};
+#[cfg(windows)]
+use crate::autostart;
+use crate::diagnostics;
+use crate::janitor;
+use crate::log_buffer::{LogBuffer, LogView};
+use crate::osus_proxy;
+use crate::osus_proxy::bancho::{
+    self, BanchoPrivileges, Country, LoginFailure, PacketDirection, BANCHO_PROTOCOL_VERSION,
+};
+use crate::osus_proxy::capture;
+use crate::osus_proxy::chat_filter;
+use crate::osus_proxy::chat_log;
+use crate::osus_proxy::known_users::KnownUser;
+use crate::osus_proxy::mirror_stats::MirrorStats;
+use crate::osus_proxy::packet_log::PACKET_LOG_PATH;
+use crate::osus_proxy::packet_viewer::{self, PacketViewer};
+use crate::osus_proxy::sessions::Session;
+use crate::preferences::{
+    BeatmapMirror, BlockedUser, ChatFilterMode, ChatFilterPattern, ForwardedIpMode, HeaderOverride,
+    LatencyInjection, Preferences, ReplayFallback, Theme,
+};
+use crate::state::{PingResult, ProxyState, UpstreamError};
+use crate::telemetry::{self, UsageReport};
+use crate::ui::locale::Locale;
+use crate::ui::tray::{Tray, TrayAction};
+use crate::uninstall::{self, UninstallReport};
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use strum::IntoEnumIterator;
 use tokio::sync::Mutex;
-use crate::osus_proxy::bancho::Country;
+use tracing::{error, info, Level};
 
-pub fn run(preferences: Arc<Mutex<Preferences>>) -> eframe::Result<()> {
+mod locale;
+mod tray;
+
+pub const PROTOCOL_COVERAGE_EXPORT_PATH: &str = "./protocol-coverage.json";
+/// Typing in a text field changes the preferences on every key, they're saved once that stops for this long.
+const PREFERENCES_SAVE_DELAY: Duration = Duration::from_secs(1);
+
+pub fn run(
+    shared_preferences: Arc<Mutex<Preferences>>,
+    shared_state: Arc<Mutex<ProxyState>>,
+    log_buffer: LogBuffer,
+    start_minimized: bool,
+) -> eframe::Result<()> {
     let tokio_rt = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
@@ -14,65 +57,1667 @@ pub fn run(preferences: Arc<Mutex<Preferences>>) -> eframe::Result<()> {
         ..Default::default()
     };
 
-    eframe::run_simple_native("osus Proxy", options, move |ctx, _frame| {
-        let mut preferences = tokio_rt.block_on(preferences.lock());
+    let preferences = tokio_rt.block_on(shared_preferences.lock()).clone();
+    let mut saved_preferences = preferences.to_file_contents().unwrap_or_default();
+    // What's waiting for the save delay and since when, saved on quitting if it's still waiting then
+    let unsaved_preferences = Rc::new(RefCell::new(None::<(String, Instant)>));
+    let quit_unsaved_preferences = unsaved_preferences.clone();
+
+    let mut watchlist_text = ids_to_text(&preferences.presence_watchlist);
+    let mut subdomains_text = preferences.subdomains.join(", ");
+    let mut allowed_senders_text = preferences.do_not_disturb.allowed_senders.join(", ");
+    let mut statistics_sort = StatisticsColumn::Count;
+    let mut packet_viewer = PacketViewer::default();
+    let mut log_view = LogView::new(log_buffer.clone());
+    let mut usage_ping_preview = None;
+    let mut uninstall_keep_config = false;
+    let mut uninstall_confirming = false;
+    let mut uninstall_report = None;
+    let window = Rc::new(WindowState::default());
+    let mut tray = None;
+    let mut tray_added = false;
+    let mut was_minimized = false;
+    #[cfg(windows)]
+    let (mut autostart_registration, mut autostart_error) = match autostart::registration() {
+        Ok(registration) => (registration, None),
+        Err(e) => {
+            error!("Failed to read the startup entry: {}", e);
+            (None, Some(e))
+        }
+    };
+
+    let app_window = window.clone();
+    let update = move |ctx: &egui::Context, frame: &mut eframe::Frame| {
+        // Copies, so the proxy isn't kept waiting on the locks while the frame is laid out
+        let mut preferences = tokio_rt.block_on(shared_preferences.lock()).clone();
+        let edited_from = preferences.clone();
+        let state = {
+            let mut state = tokio_rt.block_on(shared_state.lock());
+            // Even while the section is collapsed, so the channel doesn't fill up
+            packet_viewer.sync(preferences.capture_to_viewer, &mut state.packet_viewer);
+            state.startup_timeline.mark("window shown");
+            StateSnapshot::new(&mut state, &preferences)
+        };
+        let lock_state = || tokio_rt.block_on(shared_state.lock());
+        let locale = Locale::new(&preferences.language);
+        if !tray_added {
+            tray_added = true;
+            tray = Tray::new(ctx, locale);
+            if start_minimized || preferences.start_minimized {
+                if tray.is_some() {
+                    frame.set_visible(false);
+                } else {
+                    frame.set_minimized(true);
+                }
+            }
+        }
+        if let Some(tray) = &mut tray {
+            tray.set_status(match &state.listener {
+                Some(Ok(addr)) => locale.format("tray-proxy-running", &[("address", addr)]),
+                Some(Err(e)) => locale.format("tray-proxy-failed", &[("error", e)]),
+                None => locale.get("tray-proxy-starting").to_owned(),
+            });
+            while let Some(action) = tray.poll() {
+                match action {
+                    TrayAction::ShowWindow => {
+                        frame.set_visible(true);
+                        frame.set_minimized(false);
+                        frame.focus();
+                    }
+                    TrayAction::CheckForUpdates => {
+                        if let Err(e) = janitor::open_externally(tray::RELEASES_URL) {
+                            error!("Failed to open {}: {}", tray::RELEASES_URL, e);
+                        }
+                    }
+                    TrayAction::Quit => {
+                        window.quitting.set(true);
+                        frame.close();
+                    }
+                }
+            }
+        }
+        window
+            .hide_on_close
+            .set(tray.is_some() && preferences.minimize_to_tray);
+        // Only right after being minimized, the window may still say so for a frame after being shown again
+        let minimized = frame.info().window_info.minimized;
+        if window.close_requested.take()
+            || (window.hide_on_close.get() && minimized && !was_minimized)
+        {
+            frame.set_visible(false);
+        }
+        was_minimized = minimized;
+        if state.focus_requested {
+            frame.set_visible(true);
+            frame.focus();
+        }
+        let dark_mode = match preferences.theme {
+            Theme::System => frame.info().system_theme != Some(eframe::Theme::Light),
+            Theme::Dark => true,
+            Theme::Light => false,
+        };
+        // Only when it changes, setting the visuals makes egui lay everything out again
+        if ctx.style().visuals.dark_mode != dark_mode {
+            ctx.set_visuals(if dark_mode {
+                egui::Visuals::dark()
+            } else {
+                egui::Visuals::light()
+            });
+        }
+        packet_viewer.receive();
+        if preferences.capture_to_viewer {
+            ctx.request_repaint_after(Duration::from_millis(250));
+        }
         egui::CentralPanel::default().show(ctx, |ui| {
-            ui.heading("General purpose proxy for osu!bancho server");
-            ui.checkbox(&mut preferences.fake_supporter, "Fake osu!supporter");
+            ui.heading(locale.get("heading"));
+            if preferences.latency_injection.enabled {
+                ui.colored_label(
+                    egui::Color32::YELLOW,
+                    locale.get("latency-injection-warning"),
+                );
+            }
+            if ctx.input(|input| {
+                input.modifiers.ctrl && input.modifiers.shift && input.key_pressed(egui::Key::O)
+            }) {
+                if preferences.spoofing_paused.is_some() {
+                    preferences.restore_spoofing();
+                } else {
+                    preferences.pause_spoofing();
+                }
+            }
+            if preferences.spoofing_paused.is_some() {
+                ui.horizontal(|ui| {
+                    ui.colored_label(egui::Color32::YELLOW, locale.get("spoofing-paused"));
+                    if ui.button(locale.get("restore")).clicked() {
+                        preferences.restore_spoofing();
+                    }
+                });
+            } else if ui
+                .button(locale.get("pause-spoofing"))
+                .on_hover_text(locale.get("pause-spoofing-hint"))
+                .clicked()
+            {
+                preferences.pause_spoofing();
+            }
+            if let Some(server) = &state.relogin_server {
+                // Amber, so it's not mistaken for the other warnings
+                ui.colored_label(
+                    egui::Color32::from_rgb(0xff, 0xbf, 0x00),
+                    locale.format("relogin-required", &[("server", server)]),
+                );
+            }
+            if let Some(failure) = &state.login_failure {
+                ui.colored_label(
+                    egui::Color32::RED,
+                    locale.format(
+                        "login-rejected",
+                        &[("server", &preferences.server_address), ("reason", failure)],
+                    ),
+                );
+            }
+            if let Some(error) = state.upstream_error {
+                let key = if error.html {
+                    "upstream-error-page"
+                } else {
+                    "upstream-error"
+                };
+                ui.colored_label(
+                    egui::Color32::RED,
+                    locale.format(
+                        key,
+                        &[
+                            ("server", &preferences.server_address),
+                            ("status", &error.status),
+                        ],
+                    ),
+                );
+            }
+            if let Some(version) = state
+                .protocol_version
+                .filter(|&version| version != BANCHO_PROTOCOL_VERSION)
+            {
+                ui.colored_label(
+                    egui::Color32::YELLOW,
+                    locale.format(
+                        "protocol-version-mismatch",
+                        &[
+                            ("server", &preferences.server_address),
+                            ("version", &version),
+                            ("expected", &BANCHO_PROTOCOL_VERSION),
+                        ],
+                    ),
+                );
+            }
+            if let Some(until) = state.silenced_until {
+                let remaining = until.saturating_duration_since(Instant::now());
+                if !remaining.is_zero() {
+                    ui.colored_label(
+                        egui::Color32::RED,
+                        locale.format(
+                            "silenced",
+                            &[("minutes", &remaining.as_secs().div_ceil(60))],
+                        ),
+                    );
+                }
+            }
+            if let Some(delay) = state.server_restart {
+                ui.colored_label(
+                    egui::Color32::YELLOW,
+                    locale.format(
+                        "server-restarting",
+                        &[
+                            ("server", &preferences.server_address),
+                            ("delay", &delay.as_millis()),
+                        ],
+                    ),
+                );
+            }
+            for host in &state.down_hosts {
+                ui.colored_label(
+                    egui::Color32::RED,
+                    locale.format("host-unreachable", &[("host", &host)]),
+                );
+            }
+            ui.checkbox(
+                &mut preferences.fake_supporter,
+                locale.get("fake-supporter"),
+            );
+            if let Some(privileges) = state.last_privileges {
+                let natively = locale.get(if privileges.contains(BanchoPrivileges::SUPPORTER) {
+                    "yes"
+                } else {
+                    "no"
+                });
+                ui.label(locale.format("native-supporter", &[("supporter", &natively)]));
+            }
+            ui.checkbox(
+                &mut preferences.login_notification,
+                locale.get("login-notification"),
+            );
+            ui.checkbox(&mut preferences.quiet_mode, locale.get("quiet-mode"));
             ui.vertical(|ui| {
-                let label = ui.label("Server Address");
+                let label = ui.label(locale.get("server-address"));
                 ui.text_edit_singleline(&mut preferences.server_address)
                     .labelled_by(label.id);
             });
 
-            egui::ComboBox::from_label("Beatmap Download Mirror")
-                .selected_text(format!("{:?}", &preferences.beatmap_mirror))
+            egui::ComboBox::from_label(locale.get("beatmap-mirror"))
+                .selected_text(locale.name_of("mirror", &preferences.beatmap_mirror))
                 .width(ui.available_width() * 0.75)
                 .show_ui(ui, |ui| {
                     ui.selectable_value(
                         &mut preferences.beatmap_mirror,
                         BeatmapMirror::Chimu,
-                        format!("{} (recommended, probably fastest for most people)", &BeatmapMirror::Chimu),
-                    );
-                    ui.selectable_value(
-                        &mut preferences.beatmap_mirror,
-                        BeatmapMirror::BeatConnect,
-                        "BeatConnect",
-                    );
-                    ui.selectable_value(
-                        &mut preferences.beatmap_mirror,
-                        BeatmapMirror::Nerinyan,
-                        "nerinyan.moe",
+                        with_mirror_stats(
+                            locale.format(
+                                "mirror-recommended",
+                                &[("mirror", &locale.name_of("mirror", &BeatmapMirror::Chimu))],
+                            ),
+                            &state.mirror_stats,
+                            &BeatmapMirror::Chimu,
+                            locale,
+                        ),
                     );
+                    for mirror in [BeatmapMirror::BeatConnect, BeatmapMirror::Nerinyan] {
+                        let name = locale.name_of("mirror", &mirror);
+                        let text = with_mirror_stats(name, &state.mirror_stats, &mirror, locale);
+                        ui.selectable_value(&mut preferences.beatmap_mirror, mirror, text);
+                    }
                     ui.selectable_value(
                         &mut preferences.beatmap_mirror,
                         BeatmapMirror::ServerDefault,
-                        format!("{} (not recommended with 'Fake osu!supporter', they might be able to detect it)", &BeatmapMirror::ServerDefault),
+                        locale.format(
+                            "mirror-not-recommended",
+                            &[(
+                                "mirror",
+                                &locale.name_of("mirror", &BeatmapMirror::ServerDefault),
+                            )],
+                        ),
                     );
                 });
+            ui.horizontal(|ui| {
+                ui.label(locale.get("download-retry-window"));
+                ui.add(
+                    egui::DragValue::new(&mut preferences.download_retry_window_secs)
+                        .clamp_range(1..=120)
+                        .suffix(" s"),
+                );
+            });
+            if ui.button(locale.get("reset-download-stats")).clicked() {
+                let mut state = lock_state();
+                state.mirror_stats.reset();
+                match state.mirror_stats.to_json() {
+                    Ok(json) => {
+                        if let Err(e) = MirrorStats::write_file(&json) {
+                            error!("Failed to save the mirror stats: {}", e);
+                        }
+                    }
+                    Err(e) => error!("Failed to serialize the mirror stats: {}", e),
+                }
+            }
+            ui.checkbox(
+                &mut preferences.beatmap_pages_on_official_site,
+                locale.get("beatmap-pages-official"),
+            );
 
             let country_text = if let Some(country) = &preferences.fake_country {
-                country.to_string()
+                locale.name_of("country", country)
             } else {
-                "None".to_string()
+                locale.get("none").to_string()
             };
-            egui::ComboBox::from_label("Fake Country (Client-side)")
+            egui::ComboBox::from_label(locale.get("fake-country"))
                 .selected_text(country_text)
                 .show_ui(ui, |ui| {
-                    ui.selectable_value(
-                        &mut preferences.fake_country,
-                        None,
-                        "None",
-                    );
+                    ui.selectable_value(&mut preferences.fake_country, None, locale.get("none"));
                     for country in Country::iter() {
-                        let text = format!("{}", &country);
+                        let text = locale.name_of("country", &country);
+                        ui.selectable_value(&mut preferences.fake_country, Some(country), text);
+                    }
+                });
+            let utc_offset_text = match preferences.fake_utc_offset {
+                Some(offset) => osus_proxy::format_utc_offset(offset),
+                None => locale.get("none").to_owned(),
+            };
+            egui::ComboBox::from_label(locale.get("fake-utc-offset"))
+                .selected_text(utc_offset_text)
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut preferences.fake_utc_offset, None, locale.get("none"));
+                    for offset in -12..=14 {
                         ui.selectable_value(
-                            &mut preferences.fake_country,
-                            Some(country),
-                            text,
+                            &mut preferences.fake_utc_offset,
+                            Some(offset),
+                            osus_proxy::format_utc_offset(offset),
+                        );
+                    }
+                });
+            ui.horizontal(|ui| {
+                let mut faking_rank = preferences.fake_rank.is_some();
+                if ui
+                    .checkbox(&mut faking_rank, locale.get("fake-rank"))
+                    .changed()
+                {
+                    preferences.fake_rank = faking_rank.then_some(1);
+                }
+                if let Some(rank) = &mut preferences.fake_rank {
+                    ui.add(
+                        egui::DragValue::new(rank)
+                            .clamp_range(1..=i32::MAX)
+                            .prefix("#"),
+                    );
+                }
+            });
+
+            ui.checkbox(&mut preferences.hide_activity, locale.get("hide-activity"));
+            ui.checkbox(&mut preferences.hide_location, locale.get("hide-location"))
+                .on_hover_text(locale.get("hide-location-hint"));
+            ui.checkbox(
+                &mut preferences.block_spectators,
+                locale.get("block-spectators"),
+            );
+            ui.checkbox(
+                &mut preferences.rewrite_tournament_server_switch,
+                locale.get("rewrite-tournament-server-switch"),
+            );
+            if let Some((server, host)) = &state.bancho_host_override {
+                if *server == preferences.server_address {
+                    ui.label(locale.format("bancho-host-override", &[("host", host)]));
+                }
+            }
+
+            egui::ComboBox::from_label(locale.get("forwarded-ip-mode"))
+                .selected_text(locale.name_of("forwarded-ip-mode", &preferences.forwarded_ip_mode))
+                .show_ui(ui, |ui| {
+                    for mode in [
+                        ForwardedIpMode::Omit,
+                        ForwardedIpMode::RemoteAddress,
+                        ForwardedIpMode::Custom,
+                    ] {
+                        let text = locale.name_of("forwarded-ip-mode", &mode);
+                        ui.selectable_value(&mut preferences.forwarded_ip_mode, mode, text);
+                    }
+                });
+            if preferences.forwarded_ip_mode == ForwardedIpMode::Custom {
+                ui.horizontal(|ui| {
+                    ui.text_edit_singleline(&mut preferences.forwarded_ip);
+                    if preferences
+                        .forwarded_ip
+                        .trim()
+                        .parse::<std::net::IpAddr>()
+                        .is_err()
+                    {
+                        ui.colored_label(egui::Color32::RED, locale.get("invalid-forwarded-ip"));
+                    }
+                });
+            }
+            ui.checkbox(
+                &mut preferences.hide_forwarded_ip_on_login,
+                locale.get("hide-forwarded-ip-on-login"),
+            );
+
+            egui::ComboBox::from_label(locale.get("replay-fallback"))
+                .selected_text(locale.name_of("replay-fallback", &preferences.replay_fallback))
+                .show_ui(ui, |ui| {
+                    for fallback in [
+                        ReplayFallback::Disabled,
+                        ReplayFallback::EmptyReplay,
+                        ReplayFallback::Url,
+                    ] {
+                        let text = locale.name_of("replay-fallback", &fallback);
+                        ui.selectable_value(&mut preferences.replay_fallback, fallback, text);
+                    }
+                });
+            if preferences.replay_fallback == ReplayFallback::Url {
+                ui.vertical(|ui| {
+                    let label = ui.label(locale.get("replay-fallback-url"));
+                    ui.text_edit_singleline(&mut preferences.replay_fallback_url_template)
+                        .labelled_by(label.id);
+                });
+            }
+
+            ui.vertical(|ui| {
+                let label = ui.label(locale.get("presence-watchlist"));
+                let response = ui
+                    .text_edit_singleline(&mut watchlist_text)
+                    .labelled_by(label.id);
+                if response.changed() {
+                    preferences.presence_watchlist = text_to_ids(&watchlist_text);
+                }
+            });
+            ui.checkbox(
+                &mut preferences.overlay_endpoints,
+                locale.format(
+                    "overlay-endpoints",
+                    &[(
+                        "url",
+                        &format!(
+                            "https://{}{}",
+                            osus_proxy::source_host("osu"),
+                            osus_proxy::overlay::OVERLAY_PATH_PREFIX
+                        ),
+                    )],
+                ),
+            );
+
+            for session in state.sessions.iter() {
+                ui.label(
+                    locale.format(
+                        "logged-in-as",
+                        &[
+                            (
+                                "username",
+                                &session
+                                    .username
+                                    .as_deref()
+                                    .unwrap_or(locale.get("unknown-user")),
+                            ),
+                            ("minutes", &(session.logged_in_at.elapsed().as_secs() / 60)),
+                        ],
+                    ),
+                );
+            }
+            if let Some((since_last_contact, pings, stalled)) =
+                state.liveness.filter(|_| !state.sessions.is_empty())
+            {
+                let contact = locale.format(
+                    "last-contact",
+                    &[
+                        ("seconds", &since_last_contact.as_secs()),
+                        ("pings", &pings),
+                    ],
+                );
+                if stalled {
+                    ui.colored_label(
+                        egui::Color32::RED,
+                        locale.format("connection-stalled", &[("contact", &contact)]),
+                    );
+                } else {
+                    ui.label(contact);
+                }
+                // Keep the counter ticking
+                ctx.request_repaint_after(Duration::from_secs(1));
+            }
+            if !state.sessions.is_empty() && state.online_users > 0 {
+                ui.label(locale.format(
+                    "players-online",
+                    &[
+                        ("count", &state.online_users),
+                        ("server", &preferences.server_address),
+                    ],
+                ));
+            }
+            if !state.sessions.is_empty() && state.friends > 0 {
+                ui.label(locale.format(
+                    "friends-online",
+                    &[("online", &state.friends_online), ("total", &state.friends)],
+                ));
+            }
+
+            ui.horizontal(|ui| {
+                if ui.button(locale.get("measure-latency")).clicked() {
+                    lock_state().ping_requested = true;
+                }
+                if state.ping_requested {
+                    ui.label(locale.get("waiting-for-exchange"));
+                } else if let Some(ping) = &state.last_ping {
+                    ui.label(locale.format(
+                        "ping-result",
+                        &[
+                            ("round_trip", &ping.round_trip.as_millis()),
+                            (
+                                "size",
+                                &format!("{:.1}", ping.exchange_bytes as f64 / 1024.0),
+                            ),
+                        ],
+                    ));
+                }
+            });
+            ui.label(locale.get("ping-hint"));
+            if let Some((p50, p95)) = state.processing_overhead {
+                ui.label(locale.format(
+                    "processing-overhead",
+                    &[
+                        ("p50", &format!("{:.2}", p50.as_secs_f64() * 1000.0)),
+                        ("p95", &format!("{:.2}", p95.as_secs_f64() * 1000.0)),
+                    ],
+                ));
+            }
+
+            if let Some((frames_per_sec, bytes_per_sec, score_frames)) = state.spectating {
+                ui.label(locale.format(
+                    "spectating",
+                    &[
+                        ("frames", &format!("{:.0}", frames_per_sec)),
+                        ("speed", &format!("{:.1}", bytes_per_sec / 1024.0)),
+                        ("score_frames", &score_frames),
+                    ],
+                ));
+            }
+
+            ui.collapsing(locale.get("section-do-not-disturb"), |ui| {
+                let do_not_disturb = &mut preferences.do_not_disturb;
+                ui.checkbox(
+                    &mut do_not_disturb.enabled,
+                    locale.get("do-not-disturb-enabled"),
+                );
+                ui.checkbox(
+                    &mut do_not_disturb.allow_friends,
+                    locale.get("do-not-disturb-allow-friends"),
+                );
+                ui.vertical(|ui| {
+                    let label = ui.label(locale.get("do-not-disturb-allowed-senders"));
+                    let response = ui
+                        .text_edit_singleline(&mut allowed_senders_text)
+                        .labelled_by(label.id);
+                    if response.changed() {
+                        do_not_disturb.allowed_senders = allowed_senders_text
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|sender| !sender.is_empty())
+                            .map(str::to_owned)
+                            .collect();
+                    }
+                });
+                if let Some(count) = state.held_messages {
+                    ui.label(locale.format("messages-held", &[("count", &count)]));
+                }
+            });
+
+            ui.collapsing(locale.get("section-desktop-notifications"), |ui| {
+                let notifications = &mut preferences.desktop_notifications;
+                ui.checkbox(
+                    &mut notifications.enabled,
+                    locale.get("notifications-enabled"),
+                );
+                ui.checkbox(
+                    &mut notifications.mentions,
+                    locale.get("notifications-mentions"),
+                );
+                ui.checkbox(
+                    &mut notifications.only_when_away,
+                    locale.get("notifications-only-when-away"),
+                )
+                .on_hover_text(locale.get("notifications-only-when-away-hint"));
+                ui.horizontal(|ui| {
+                    ui.label(locale.get("notifications-cooldown"));
+                    ui.add(
+                        egui::DragValue::new(&mut notifications.cooldown_secs)
+                            .clamp_range(0..=60 * 60)
+                            .suffix(" s"),
+                    );
+                });
+            });
+
+            ui.collapsing(locale.get("section-auto-reply"), |ui| {
+                let auto_reply = &mut preferences.auto_reply;
+                ui.checkbox(&mut auto_reply.enabled, locale.get("auto-reply-enabled"));
+                ui.add(
+                    egui::TextEdit::singleline(&mut auto_reply.text)
+                        .hint_text(locale.get("auto-reply-hint")),
+                );
+                ui.horizontal(|ui| {
+                    ui.label(locale.get("auto-reply-cooldown"));
+                    ui.add(
+                        egui::DragValue::new(&mut auto_reply.cooldown_secs)
+                            .clamp_range(10..=24 * 60 * 60)
+                            .suffix(" s"),
+                    );
+                });
+            });
+
+            ui.collapsing(locale.get("section-blocked-users"), |ui| {
+                ui.label(locale.get("blocked-users-description"));
+                let mut removed = None;
+                for (i, blocked) in preferences.blocked_users.iter_mut().enumerate() {
+                    ui.horizontal(|ui| {
+                        let username = egui::TextEdit::singleline(&mut blocked.username);
+                        ui.add(username.hint_text(locale.get("username-hint")));
+                        let mut by_id = blocked.user_id.is_some();
+                        if ui
+                            .checkbox(&mut by_id, locale.get("blocked-by-id"))
+                            .changed()
+                        {
+                            blocked.user_id = by_id.then_some(0);
+                        }
+                        let known_user = state.blocked_known_users.get(i).and_then(Option::as_ref);
+                        if let Some(user_id) = &mut blocked.user_id {
+                            ui.add(egui::DragValue::new(user_id).clamp_range(0..=i32::MAX));
+                        } else if let Some((user_id, known)) = known_user {
+                            let seen = locale.format(
+                                "known-user",
+                                &[
+                                    ("user_id", &user_id),
+                                    ("country", &locale.name_of("country", &known.country)),
+                                    ("privileges", &known.privileges),
+                                ],
+                            );
+                            if ui
+                                .button(locale.get("use-id"))
+                                .on_hover_text(seen)
+                                .clicked()
+                            {
+                                blocked.user_id = Some(*user_id);
+                            }
+                        }
+                        if ui.button(locale.get("remove")).clicked() {
+                            removed = Some(i);
+                        }
+                    });
+                }
+                if let Some(i) = removed {
+                    preferences.blocked_users.remove(i);
+                }
+                if ui.button(locale.get("add-user")).clicked() {
+                    preferences.blocked_users.push(BlockedUser::default());
+                }
+                if let Some(count) = state.known_users {
+                    ui.label(locale.format("users-seen", &[("count", &count)]));
+                }
+                for (sender, count) in &state.blocked_messages {
+                    ui.label(
+                        locale.format("messages-blocked", &[("sender", sender), ("count", count)]),
+                    );
+                }
+            });
+
+            ui.collapsing(locale.get("section-chat-filter"), |ui| {
+                let chat_filter = &mut preferences.chat_filter;
+                egui::ComboBox::from_label(locale.get("chat-filter-mode"))
+                    .selected_text(locale.name_of("chat-filter-mode", &chat_filter.mode))
+                    .show_ui(ui, |ui| {
+                        for mode in [ChatFilterMode::Censor, ChatFilterMode::Drop] {
+                            let text = locale.name_of("chat-filter-mode", &mode);
+                            ui.selectable_value(&mut chat_filter.mode, mode, text);
+                        }
+                    });
+                ui.checkbox(
+                    &mut chat_filter.outgoing,
+                    locale.get("chat-filter-outgoing"),
+                );
+                let mut removed = None;
+                for (i, pattern) in chat_filter.patterns.iter_mut().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut pattern.enabled, "");
+                        let text = egui::TextEdit::singleline(&mut pattern.pattern);
+                        ui.add(text.hint_text(locale.get("chat-filter-pattern-hint")));
+                        ui.checkbox(&mut pattern.regex, locale.get("chat-filter-regex"));
+                        ui.checkbox(
+                            &mut pattern.case_sensitive,
+                            locale.get("chat-filter-match-case"),
+                        );
+                        if ui.button(locale.get("remove")).clicked() {
+                            removed = Some(i);
+                        }
+                    });
+                    if pattern.regex {
+                        if let Err(e) = chat_filter::build_regex(pattern) {
+                            ui.colored_label(
+                                egui::Color32::RED,
+                                locale.format("invalid-regex", &[("error", &e)]),
+                            );
+                        }
+                    }
+                }
+                if let Some(i) = removed {
+                    chat_filter.patterns.remove(i);
+                }
+                if ui.button(locale.get("add-pattern")).clicked() {
+                    chat_filter.patterns.push(ChatFilterPattern::default());
+                }
+            });
+
+            ui.collapsing(locale.get("section-appearance"), |ui| {
+                if tray.is_some() {
+                    ui.checkbox(
+                        &mut preferences.minimize_to_tray,
+                        locale.get("minimize-to-tray"),
+                    )
+                    .on_hover_text(locale.get("minimize-to-tray-hint"));
+                }
+                egui::ComboBox::from_label(locale.get("theme"))
+                    .selected_text(locale.name_of("theme", &preferences.theme))
+                    .show_ui(ui, |ui| {
+                        for theme in [Theme::System, Theme::Dark, Theme::Light] {
+                            ui.selectable_value(
+                                &mut preferences.theme,
+                                theme,
+                                locale.name_of("theme", &theme),
+                            );
+                        }
+                    });
+                egui::ComboBox::from_label(locale.get("language"))
+                    .selected_text(locale.get("language-name"))
+                    .show_ui(ui, |ui| {
+                        for (code, name) in locale::available_languages() {
+                            ui.selectable_value(&mut preferences.language, code.to_owned(), name);
+                        }
+                    });
+            });
+
+            #[cfg(windows)]
+            ui.collapsing(locale.get("section-startup"), |ui| {
+                let mut change = None;
+                let mut enabled = autostart_registration.is_some();
+                if ui
+                    .checkbox(&mut enabled, locale.get("start-with-windows"))
+                    .changed()
+                {
+                    change = Some(enabled);
+                }
+                if let Some(registration) =
+                    autostart_registration.as_ref().filter(|r| !r.is_current())
+                {
+                    let path = registration.executable.display();
+                    let key = if registration.executable.exists() {
+                        "autostart-other-copy"
+                    } else {
+                        "autostart-missing"
+                    };
+                    ui.colored_label(
+                        egui::Color32::YELLOW,
+                        locale.format(key, &[("path", &path)]),
+                    );
+                    if ui.button(locale.get("autostart-start-this-copy")).clicked() {
+                        change = Some(true);
+                    }
+                }
+                if let Some(enable) = change {
+                    let result = if enable {
+                        autostart::enable()
+                    } else {
+                        autostart::disable()
+                    };
+                    // Read back rather than assumed, so the checkbox shows what Windows will do
+                    match result.and_then(|()| autostart::registration()) {
+                        Ok(registration) => {
+                            autostart_registration = registration;
+                            autostart_error = None;
+                        }
+                        Err(e) => {
+                            error!("Failed to change the startup entry: {}", e);
+                            autostart_error = Some(e);
+                        }
+                    }
+                }
+                if let Some(e) = &autostart_error {
+                    let key = if e.kind() == std::io::ErrorKind::PermissionDenied {
+                        "autostart-access-denied"
+                    } else {
+                        "autostart-failed"
+                    };
+                    ui.colored_label(egui::Color32::RED, locale.format(key, &[("error", e)]));
+                }
+                ui.checkbox(
+                    &mut preferences.start_minimized,
+                    locale.get("start-minimized"),
+                )
+                .on_hover_text(locale.get("start-minimized-hint"));
+            });
+
+            ui.collapsing(locale.get("section-advanced"), |ui| {
+                ui.vertical(|ui| {
+                    let label = ui.label(locale.get("subdomains"));
+                    let response = ui
+                        .text_edit_singleline(&mut subdomains_text)
+                        .labelled_by(label.id);
+                    if response.changed() {
+                        preferences.subdomains = subdomains_text
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|subdomain| !subdomain.is_empty())
+                            .map(str::to_owned)
+                            .collect();
+                    }
+                });
+
+                let pool = &mut preferences.upstream_pool;
+                ui.checkbox(&mut pool.keep_alive, locale.get("keep-alive"));
+                ui.add_enabled_ui(pool.keep_alive, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label(locale.get("idle-timeout"));
+                        ui.add(
+                            egui::DragValue::new(&mut pool.idle_timeout_secs)
+                                .clamp_range(1..=600)
+                                .suffix(" s"),
+                        );
+                    });
+                    ui.horizontal(|ui| {
+                        let mut limited = pool.max_idle_per_host.is_some();
+                        if ui
+                            .checkbox(&mut limited, locale.get("limit-idle-per-host"))
+                            .changed()
+                        {
+                            pool.max_idle_per_host = if limited { Some(1) } else { None };
+                        }
+                        if let Some(max_idle_per_host) = &mut pool.max_idle_per_host {
+                            ui.add(egui::DragValue::new(max_idle_per_host).clamp_range(1..=64));
+                        }
+                    });
+                });
+                ui.label(locale.get("idle-timeout-hint"));
+                ui.vertical(|ui| {
+                    let label = ui.label(locale.get("insecure-upstream-domain"));
+                    ui.text_edit_singleline(&mut preferences.insecure_upstream_domain)
+                        .labelled_by(label.id);
+                });
+                let watchdog = &mut preferences.upstream_watchdog;
+                ui.horizontal(|ui| {
+                    ui.label(locale.get("request-timeout"));
+                    ui.add(
+                        egui::DragValue::new(&mut watchdog.request_timeout_secs)
+                            .clamp_range(1..=600)
+                            .suffix(" s"),
+                    );
+                });
+                ui.horizontal(|ui| {
+                    ui.label(locale.get("stalled-request-threshold"));
+                    ui.add(
+                        egui::DragValue::new(&mut watchdog.stalled_request_threshold)
+                            .clamp_range(1..=100)
+                            .suffix(format!(" {}", locale.get("stalled-request-threshold-unit"))),
+                    );
+                });
+                ui.horizontal(|ui| {
+                    ui.label(locale.get("upstream-down-threshold"));
+                    ui.add(
+                        egui::DragValue::new(&mut preferences.upstream_down_threshold)
+                            .clamp_range(1..=100)
+                            .suffix(format!(" {}", locale.get("upstream-down-threshold-unit"))),
+                    );
+                });
+
+                ui.separator();
+                ui.label(locale.get("header-overrides"));
+                let mut removed = None;
+                for (i, header_override) in preferences.header_overrides.iter_mut().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::TextEdit::singleline(&mut header_override.name)
+                                .hint_text("osu-version"),
+                        );
+                        let value = egui::TextEdit::singleline(&mut header_override.value);
+                        ui.add(value.hint_text(locale.get("header-value-hint")));
+                        if ui.button(locale.get("remove")).clicked() {
+                            removed = Some(i);
+                        }
+                    });
+                }
+                if let Some(i) = removed {
+                    preferences.header_overrides.remove(i);
+                }
+                if ui.button(locale.get("add-header")).clicked() {
+                    preferences.header_overrides.push(HeaderOverride::default());
+                }
+
+                ui.separator();
+                ui.label(locale.get("debugging"));
+                ui.horizontal(|ui| {
+                    ui.label(locale.get("processing-overhead-budget"));
+                    ui.add(
+                        egui::DragValue::new(&mut preferences.processing_overhead_budget_ms)
+                            .clamp_range(1..=1000)
+                            .suffix(format!(
+                                " {}",
+                                locale.get("processing-overhead-budget-unit")
+                            )),
+                    );
+                });
+                let limits = &mut preferences.processing_limits;
+                ui.horizontal(|ui| {
+                    ui.label(locale.get("max-injected-packets"));
+                    ui.add(
+                        egui::DragValue::new(&mut limits.max_injected_packets)
+                            .clamp_range(0..=1000)
+                            .suffix(format!(" {}", locale.get("packets-unit"))),
+                    );
+                });
+                ui.horizontal(|ui| {
+                    ui.label(locale.get("max-body-growth"));
+                    ui.add(
+                        egui::DragValue::new(&mut limits.max_body_growth_factor)
+                            .clamp_range(1.0..=100.0)
+                            .speed(0.1)
+                            .suffix("×"),
+                    );
+                });
+                ui.horizontal(|ui| {
+                    ui.label(locale.get("max-packet-length"));
+                    ui.add(
+                        egui::DragValue::new(&mut limits.max_packet_length)
+                            .clamp_range(1024..=256 * 1024 * 1024)
+                            .speed(1024)
+                            .suffix(format!(" {}", locale.get("bytes-unit"))),
+                    );
+                });
+                ui.checkbox(
+                    &mut preferences.strict_packet_decoding,
+                    locale.get("strict-packet-decoding"),
+                );
+                ui.checkbox(
+                    &mut preferences.verify_roundtrip,
+                    locale.get("verify-roundtrip"),
+                );
+                let latency_injection = &mut preferences.latency_injection;
+                ui.checkbox(
+                    &mut latency_injection.enabled,
+                    locale.get("latency-injection"),
+                );
+                ui.add_enabled_ui(latency_injection.enabled, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label(locale.get("latency-injection-delay"));
+                        ui.add(
+                            egui::DragValue::new(&mut latency_injection.delay_ms)
+                                .clamp_range(0..=LatencyInjection::MAX_TOTAL_MS)
+                                .suffix(" ms"),
+                        );
+                        ui.label(locale.get("latency-injection-jitter"));
+                        ui.add(
+                            egui::DragValue::new(&mut latency_injection.jitter_ms)
+                                .clamp_range(0..=LatencyInjection::MAX_TOTAL_MS)
+                                .suffix(" ms"),
                         );
+                    });
+                    ui.checkbox(
+                        &mut latency_injection.bancho,
+                        locale.get("latency-injection-bancho"),
+                    );
+                    ui.checkbox(
+                        &mut latency_injection.downloads,
+                        locale.get("latency-injection-downloads"),
+                    );
+                });
+            });
+
+            ui.collapsing(locale.get("section-statistics"), |ui| {
+                ui.label(locale.get("statistics-description"));
+                let (mut counters, untracked_count, empty) = {
+                    let state = lock_state();
+                    let packet_stats = &state.packet_stats;
+                    let counters = packet_stats
+                        .counters()
+                        .map(|counter| {
+                            (
+                                counter.clone(),
+                                counter.name(),
+                                packet_stats.last_minute(counter),
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                    (
+                        counters,
+                        packet_stats.untracked_count(),
+                        packet_stats.is_empty(),
+                    )
+                };
+                match statistics_sort {
+                    StatisticsColumn::Packet => counters.sort_by(|a, b| a.1.cmp(&b.1)),
+                    StatisticsColumn::Direction => counters.sort_by_key(|(counter, name, _)| {
+                        (
+                            counter.direction == PacketDirection::ServerToClient,
+                            name.clone(),
+                        )
+                    }),
+                    StatisticsColumn::Count => {
+                        counters.sort_by_key(|(counter, ..)| Reverse(counter.count))
+                    }
+                    StatisticsColumn::Bytes => {
+                        counters.sort_by_key(|(counter, ..)| Reverse(counter.bytes))
+                    }
+                    StatisticsColumn::LastMinute => {
+                        counters.sort_by_key(|(_, _, last_minute)| Reverse(*last_minute))
+                    }
+                }
+                egui::Grid::new("packet_stats")
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for (column, header) in [
+                            (StatisticsColumn::Packet, "statistics-packet"),
+                            (StatisticsColumn::Direction, "statistics-direction"),
+                            (StatisticsColumn::Count, "statistics-count"),
+                            (StatisticsColumn::Bytes, "statistics-bytes"),
+                            (StatisticsColumn::LastMinute, "statistics-last-minute"),
+                        ] {
+                            ui.selectable_value(&mut statistics_sort, column, locale.get(header));
+                        }
+                        ui.end_row();
+                        for (counter, name, last_minute) in &counters {
+                            ui.label(name);
+                            ui.label(counter.direction.to_string());
+                            ui.label(counter.count.to_string());
+                            ui.label(counter.bytes.to_string());
+                            ui.label(last_minute.to_string());
+                            ui.end_row();
+                        }
+                    });
+                if untracked_count > 0 {
+                    ui.label(locale.format("untracked-packets", &[("count", &untracked_count)]));
+                }
+                if ui
+                    .add_enabled(!empty, egui::Button::new(locale.get("reset")))
+                    .clicked()
+                {
+                    lock_state().packet_stats.reset();
+                }
+            });
+
+            ui.collapsing(locale.get("section-protocol-coverage"), |ui| {
+                ui.label(locale.get("protocol-coverage-description"));
+                let (entries, untracked_count, empty) = {
+                    let state = lock_state();
+                    let coverage = &state.protocol_coverage;
+                    (
+                        coverage.entries().cloned().collect::<Vec<_>>(),
+                        coverage.untracked_count(),
+                        coverage.is_empty(),
+                    )
+                };
+                if empty {
+                    ui.label(locale.get("protocol-coverage-empty"));
+                }
+                for entry in &entries {
+                    ui.label(locale.format(
+                        "protocol-coverage-entry",
+                        &[
+                            ("id", &entry.id),
+                            ("count", &entry.count),
+                            ("direction", &format!("{:?}", entry.first_seen_direction)),
+                            ("length", &entry.sample_length),
+                        ],
+                    ));
+                }
+                if untracked_count > 0 {
+                    ui.label(locale.format("untracked-packets", &[("count", &untracked_count)]));
+                }
+                if ui.button(locale.get("export-json")).clicked() {
+                    let json = lock_state().protocol_coverage.to_json();
+                    match json
+                        .map_err(std::io::Error::from)
+                        .and_then(|json| std::fs::write(PROTOCOL_COVERAGE_EXPORT_PATH, json))
+                    {
+                        Ok(()) => info!(
+                            "Exported protocol coverage to {}",
+                            PROTOCOL_COVERAGE_EXPORT_PATH
+                        ),
+                        Err(e) => error!("Failed to export protocol coverage: {}", e),
+                    }
+                }
+            });
+
+            ui.collapsing(locale.get("section-packet-log"), |ui| {
+                ui.checkbox(
+                    &mut preferences.record_packet_log,
+                    locale.get("record-packet-log"),
+                );
+                ui.checkbox(
+                    &mut preferences.packet_log_full_dump,
+                    locale.get("packet-log-full-dump"),
+                );
+                let (recorded, dropped, empty) = {
+                    let state = lock_state();
+                    let packet_log = &state.packet_log;
+                    (
+                        packet_log.len(),
+                        packet_log.dropped(),
+                        packet_log.is_empty(),
+                    )
+                };
+                ui.label(locale.format("packets-recorded", &[("count", &recorded)]));
+                if dropped > 0 {
+                    ui.label(locale.format("packets-dropped", &[("count", &dropped)]));
+                }
+                ui.horizontal(|ui| {
+                    let export = egui::Button::new(locale.get("export-packet-log"));
+                    if ui.add_enabled(!empty, export).clicked() {
+                        let written = lock_state().packet_log.write_file();
+                        match written {
+                            Ok(()) => info!("Exported the packet log to {}", PACKET_LOG_PATH),
+                            Err(e) => error!("Failed to export the packet log: {}", e),
+                        }
+                    }
+                    if ui.button(locale.get("clear")).clicked() {
+                        lock_state().packet_log.clear();
+                    }
+                });
+            });
+
+            ui.collapsing(locale.get("section-logs"), |ui| {
+                log_view.refresh();
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_id_source("log_level")
+                        .selected_text(log_view.level.to_string())
+                        .show_ui(ui, |ui| {
+                            for level in [Level::ERROR, Level::WARN, Level::INFO, Level::DEBUG] {
+                                ui.selectable_value(&mut log_view.level, level, level.to_string());
+                            }
+                        });
+                    ui.add(
+                        egui::TextEdit::singleline(&mut log_view.search)
+                            .hint_text(locale.get("search-hint")),
+                    );
+                    ui.checkbox(&mut log_view.auto_scroll, locale.get("auto-scroll"));
+                    if ui
+                        .button(locale.format("copy-last-lines", &[("count", &200)]))
+                        .clicked()
+                    {
+                        ui.output_mut(|output| output.copied_text = log_view.last_lines_text(200));
                     }
                 });
+                egui::ScrollArea::vertical()
+                    .id_source("logs")
+                    .max_height(320.0)
+                    .stick_to_bottom(log_view.auto_scroll)
+                    .show(ui, |ui| {
+                        for line in log_view.matching() {
+                            let text = egui::RichText::new(line.to_string()).monospace();
+                            let text = match line.level {
+                                Level::ERROR => text.color(egui::Color32::RED),
+                                Level::WARN => text.color(egui::Color32::YELLOW),
+                                Level::DEBUG | Level::TRACE => text.weak(),
+                                _ => text,
+                            };
+                            ui.label(text);
+                        }
+                    });
+                // New lines show up without having to move the mouse
+                ctx.request_repaint_after(Duration::from_secs(1));
+            });
+
+            ui.collapsing(locale.get("section-packet-viewer"), |ui| {
+                ui.checkbox(
+                    &mut preferences.capture_to_viewer,
+                    locale.get("capture-to-viewer"),
+                )
+                .on_hover_text(locale.get("capture-to-viewer-hint"));
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut packet_viewer.paused, locale.get("pause"));
+                    let filter = egui::TextEdit::singleline(&mut packet_viewer.filter);
+                    ui.add(filter.hint_text(locale.get("packet-viewer-filter-hint")));
+                    if ui.button(locale.get("clear")).clicked() {
+                        packet_viewer.clear();
+                    }
+                });
+                if state.packet_viewer_dropped > 0 {
+                    ui.label(locale.format(
+                        "packet-viewer-dropped",
+                        &[("count", &state.packet_viewer_dropped)],
+                    ));
+                }
+                let mut clicked = None;
+                let mut show_more = false;
+                egui::ScrollArea::vertical()
+                    .max_height(320.0)
+                    .stick_to_bottom(true)
+                    .show(ui, |ui| {
+                        for entry in packet_viewer.matching() {
+                            let line = format!(
+                                "{} {} {}({}) {} {} {}",
+                                entry.time(),
+                                entry.direction,
+                                entry.name,
+                                entry.id,
+                                entry.length,
+                                locale.get("bytes-unit"),
+                                entry.summary
+                            );
+                            let expanded = packet_viewer.expanded == Some(entry.seq);
+                            if ui
+                                .selectable_label(expanded, egui::RichText::new(line).monospace())
+                                .clicked()
+                            {
+                                clicked = Some(entry.seq);
+                            }
+                            if !expanded {
+                                continue;
+                            }
+                            let Some(raw) = &entry.raw else {
+                                ui.code(&entry.detail);
+                                continue;
+                            };
+                            let known_as = match bancho::packet_name(entry.id, entry.direction) {
+                                Some(name) => locale.format("packet-known-as", &[("name", &name)]),
+                                None => locale.get("packet-unknown-id").to_owned(),
+                            };
+                            ui.horizontal(|ui| {
+                                ui.label(locale.format(
+                                    "packet-raw-data",
+                                    &[
+                                        ("id", &entry.id),
+                                        ("known_as", &known_as),
+                                        ("length", &raw.len()),
+                                    ],
+                                ));
+                                if ui.button(locale.get("copy-hex")).clicked() {
+                                    ui.output_mut(|output| {
+                                        output.copied_text = packet_viewer::hex_string(raw)
+                                    });
+                                }
+                            });
+                            let shown = &raw[..raw.len().min(packet_viewer.hexdump_bytes)];
+                            let hexdump = packet_viewer::hexdump_rows(shown)
+                                .collect::<Vec<_>>()
+                                .join("\n");
+                            ui.code(hexdump);
+                            if shown.len() < raw.len() {
+                                let more = locale
+                                    .format("show-more", &[("count", &(raw.len() - shown.len()))]);
+                                if ui.button(more).clicked() {
+                                    show_more = true;
+                                }
+                            }
+                        }
+                    });
+                if let Some(seq) = clicked {
+                    packet_viewer.toggle(seq);
+                }
+                if show_more {
+                    packet_viewer.hexdump_bytes += packet_viewer::HEXDUMP_PAGE_BYTES;
+                }
+            });
+
+            ui.collapsing(locale.get("section-chat-logs"), |ui| {
+                let chat_logs = &mut preferences.chat_logs;
+                ui.checkbox(&mut chat_logs.enabled, locale.get("chat-logs-enabled"));
+                ui.checkbox(
+                    &mut chat_logs.private_messages,
+                    locale.get("chat-logs-private-messages"),
+                );
+                if ui.button(locale.get("open-chat-log-folder")).clicked() {
+                    if let Err(e) = chat_log::open_directory() {
+                        error!("Failed to open the chat log folder: {}", e);
+                    }
+                }
+            });
+
+            ui.collapsing(locale.get("section-capture"), |ui| {
+                ui.checkbox(
+                    &mut preferences.record_bancho_traffic,
+                    locale.get("record-bancho-traffic"),
+                );
+                ui.horizontal(|ui| {
+                    ui.label(locale.get("capture-max-session"));
+                    ui.add(
+                        egui::DragValue::new(&mut preferences.capture_max_session_mb)
+                            .clamp_range(1..=16 * 1024)
+                            .suffix(" MB"),
+                    );
+                });
+                if ui.button(locale.get("open-capture-folder")).clicked() {
+                    if let Err(e) = capture::open_directory() {
+                        error!("Failed to open the capture folder: {}", e);
+                    }
+                }
+            });
+
+            ui.collapsing(locale.get("section-usage-ping"), |ui| {
+                ui.label(locale.get("usage-ping-description"));
+                ui.checkbox(
+                    &mut preferences.usage_ping,
+                    locale.get("usage-ping-enabled"),
+                );
+                ui.vertical(|ui| {
+                    let label = ui.label(locale.get("usage-ping-endpoint"));
+                    ui.text_edit_singleline(&mut preferences.usage_ping_endpoint)
+                        .labelled_by(label.id);
+                });
+                if std::env::var_os(telemetry::KILL_SWITCH_VARIABLE).is_some() {
+                    ui.label(locale.format(
+                        "usage-ping-killed",
+                        &[("variable", &telemetry::KILL_SWITCH_VARIABLE)],
+                    ));
+                }
+                if ui.button(locale.get("preview")).clicked() {
+                    usage_ping_preview = Some(
+                        UsageReport::new(&preferences, &lock_state())
+                            .to_json()
+                            .unwrap_or_else(|e| e.to_string()),
+                    );
+                }
+                if let Some(preview) = &usage_ping_preview {
+                    ui.monospace(preview);
+                }
+            });
+
+            ui.collapsing(locale.get("section-diagnostics"), |ui| {
+                ui.label(locale.get("diagnostics-description"));
+                ui.horizontal(|ui| {
+                    if ui.button(locale.get("copy-diagnostics")).clicked() {
+                        let diagnostics =
+                            diagnostics::collect(&preferences, &lock_state(), &log_buffer);
+                        ui.output_mut(|output| output.copied_text = diagnostics);
+                        info!("Copied the diagnostics to the clipboard");
+                    }
+                    if ui.button(locale.get("save-diagnostics")).clicked() {
+                        let diagnostics =
+                            diagnostics::collect(&preferences, &lock_state(), &log_buffer);
+                        match diagnostics::write_file(&diagnostics) {
+                            Ok(path) => info!("Wrote the diagnostics to {}", path.display()),
+                            Err(e) => error!("Failed to write the diagnostics: {}", e),
+                        }
+                    }
+                });
+            });
+
+            ui.collapsing(locale.get("section-uninstall"), |ui| {
+                if let Some(report) = &uninstall_report {
+                    ui.label(uninstall_report_text(report, locale));
+                    ui.label(locale.get("uninstall-done"));
+                    return;
+                }
+                ui.label(locale.get("uninstall-description"));
+                ui.checkbox(
+                    &mut uninstall_keep_config,
+                    locale.get("uninstall-keep-preferences"),
+                );
+                if !uninstall_confirming {
+                    if ui.button(locale.get("uninstall")).clicked() {
+                        uninstall_confirming = true;
+                    }
+                    return;
+                }
+                ui.horizontal(|ui| {
+                    ui.label(locale.get("uninstall-confirm"));
+                    if ui.button(locale.get("uninstall-yes")).clicked() {
+                        uninstall_report = Some(uninstall::run(uninstall_keep_config));
+                    }
+                    if ui.button(locale.get("cancel")).clicked() {
+                        uninstall_confirming = false;
+                    }
+                });
+            });
+        });
+
+        // Asked once, on the first start. The checkbox in its section is there for changing your mind later.
+        let killed = std::env::var_os(telemetry::KILL_SWITCH_VARIABLE).is_some();
+        if !preferences.usage_ping_asked && !killed {
+            egui::Window::new(locale.get("section-usage-ping"))
+                .collapsible(false)
+                .resizable(false)
+                .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+                .show(ctx, |ui| {
+                    ui.label(locale.get("usage-ping-description"));
+                    ui.label(locale.get("usage-ping-question"));
+                    ui.horizontal(|ui| {
+                        if ui.button(locale.get("usage-ping-accept")).clicked() {
+                            preferences.usage_ping = true;
+                            preferences.usage_ping_asked = true;
+                        }
+                        if ui.button(locale.get("usage-ping-decline")).clicked() {
+                            preferences.usage_ping_asked = true;
+                        }
+                    });
+                });
+        }
+
+        if preferences != edited_from {
+            let mut shared_preferences = tokio_rt.block_on(shared_preferences.lock());
+            if let Err(e) = shared_preferences.merge_edits(&edited_from, &preferences) {
+                error!("Failed to apply the changed preferences: {}", e);
+            }
+            preferences = shared_preferences.clone();
+        }
+        let mut unsaved = unsaved_preferences.borrow_mut();
+        if uninstall_report.is_some() {
+            // Saving now would bring the preferences file right back
+            *unsaved = None;
+            return;
+        }
+        match preferences.to_file_contents() {
+            Ok(contents) if contents != saved_preferences => {
+                let changed_at = match &*unsaved {
+                    Some((waiting, changed_at)) if *waiting == contents => *changed_at,
+                    _ => Instant::now(),
+                };
+                let waited = changed_at.elapsed();
+                if waited < PREFERENCES_SAVE_DELAY {
+                    *unsaved = Some((contents, changed_at));
+                    ctx.request_repaint_after(PREFERENCES_SAVE_DELAY - waited);
+                } else {
+                    *unsaved = None;
+                    save_preferences(&contents);
+                    // Even if it failed, otherwise we'd try again every frame
+                    saved_preferences = contents;
+                }
+            }
+            // Changed back before they were saved
+            Ok(_) => *unsaved = None,
+            Err(e) => error!("Failed to serialize preferences: {}", e),
+        }
+    };
+
+    let result = eframe::run_native(
+        "osus Proxy",
+        options,
+        Box::new(|_| {
+            Box::new(ProxyApp {
+                update,
+                window: app_window,
+                ctx: None,
+            })
+        }),
+    );
+    if let Some((contents, _)) = quit_unsaved_preferences.take() {
+        save_preferences(&contents);
+    }
+    result
+}
+
+fn save_preferences(contents: &str) {
+    if let Err(e) = Preferences::write_file(contents) {
+        error!("Failed to save preferences: {}", e);
+    }
+}
+
+/// What the UI and [`ProxyApp`] tell each other about the window, eframe only lets the app know
+/// about it being closed.
+#[derive(Debug, Default)]
+struct WindowState {
+    /// Closing the window hides it to the tray instead.
+    hide_on_close: Cell<bool>,
+    /// Closing the window was turned into hiding it, for the UI to do on the next frame.
+    close_requested: Cell<bool>,
+    /// Quit from the tray, the window closes for real even when it'd otherwise go to the tray.
+    quitting: Cell<bool>,
+}
+
+struct ProxyApp<F> {
+    update: F,
+    window: Rc<WindowState>,
+    /// For waking the UI up to hide the window after a close was turned down.
+    ctx: Option<egui::Context>,
+}
+
+impl<F: FnMut(&egui::Context, &mut eframe::Frame)> eframe::App for ProxyApp<F> {
+    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        self.ctx.get_or_insert_with(|| ctx.clone());
+        (self.update)(ctx, frame);
+    }
+
+    fn on_close_event(&mut self) -> bool {
+        if !self.window.hide_on_close.get() || self.window.quitting.get() {
+            return true;
+        }
+        self.window.close_requested.set(true);
+        if let Some(ctx) = &self.ctx {
+            ctx.request_repaint();
+        }
+        false
+    }
+}
+
+/// What a frame shows of the [`ProxyState`], copied under a short lock so the proxy isn't kept
+/// waiting while the frame is laid out. Sections that show more lock it just long enough to copy that.
+struct StateSnapshot {
+    listener: Option<Result<SocketAddr, String>>,
+    focus_requested: bool,
+    /// The server of a session that has to log in again for changed preferences to apply.
+    relogin_server: Option<String>,
+    login_failure: Option<LoginFailure>,
+    upstream_error: Option<UpstreamError>,
+    protocol_version: Option<i32>,
+    silenced_until: Option<Instant>,
+    server_restart: Option<Duration>,
+    down_hosts: Vec<String>,
+    last_privileges: Option<BanchoPrivileges>,
+    mirror_stats: MirrorStats,
+    bancho_host_override: Option<(String, String)>,
+    sessions: Vec<Session>,
+    /// Since the last contact, pings per minute and whether the connection looks stalled.
+    liveness: Option<(Duration, usize, bool)>,
+    online_users: usize,
+    friends: usize,
+    friends_online: usize,
+    ping_requested: bool,
+    last_ping: Option<PingResult>,
+    /// The median and the 95th percentile.
+    processing_overhead: Option<(Duration, Duration)>,
+    /// Frames and bytes per second and score frames, while spectating.
+    spectating: Option<(f64, f64, u64)>,
+    /// `None` when there are none, like the other counts that are only shown when there are some.
+    held_messages: Option<usize>,
+    known_users: Option<usize>,
+    /// Who the users blocked by name are, by their index in the blocked users.
+    blocked_known_users: Vec<Option<(i32, KnownUser)>>,
+    blocked_messages: BTreeMap<String, u64>,
+    packet_viewer_dropped: u64,
+}
+
+impl StateSnapshot {
+    /// Takes the window focus request, the frame acts on it.
+    fn new(state: &mut ProxyState, preferences: &Preferences) -> Self {
+        let relogin_server = state
+            .sessions
+            .needing_relog(&preferences.server_address, preferences.fake_supporter)
+            .map(|session| session.server_address.clone());
+        let liveness = state
+            .liveness
+            .since_last_contact()
+            .map(|since_last_contact| {
+                (
+                    since_last_contact,
+                    state.liveness.pings_per_minute(),
+                    state.liveness.is_stalled(),
+                )
+            });
+        let processing_overhead = state
+            .processing_overhead
+            .percentile(50)
+            .zip(state.processing_overhead.percentile(95));
+        let spectate_stats = &state.spectate_stats;
+        let spectating = spectate_stats.is_active().then(|| {
+            (
+                spectate_stats.frames_per_sec(),
+                spectate_stats.bytes_per_sec(),
+                spectate_stats.score_frames,
+            )
         });
-    })
+        let blocked_known_users = preferences
+            .blocked_users
+            .iter()
+            .map(|blocked| {
+                let (user_id, known) = state.known_users.find_by_name(&blocked.username)?;
+                Some((user_id, known.clone()))
+            })
+            .collect();
+        Self {
+            listener: state.listener.clone(),
+            focus_requested: std::mem::take(&mut state.desktop_notifier.focus_requested),
+            relogin_server,
+            login_failure: state.login_failure,
+            upstream_error: state.upstream_error,
+            protocol_version: state.protocol_version,
+            silenced_until: state.silenced_until,
+            server_restart: state.server_restart,
+            down_hosts: state
+                .upstream_availability
+                .down_hosts()
+                .map(str::to_owned)
+                .collect(),
+            last_privileges: state.last_privileges,
+            mirror_stats: state.mirror_stats.clone(),
+            bancho_host_override: state.bancho_host_override.clone(),
+            sessions: state.sessions.iter().cloned().collect(),
+            liveness,
+            online_users: state.online_users.len(),
+            friends: state.friends.len(),
+            friends_online: state
+                .friends
+                .iter()
+                .filter(|user_id| state.online_users.contains(user_id))
+                .count(),
+            ping_requested: state.ping_requested,
+            last_ping: state.last_ping,
+            processing_overhead,
+            spectating,
+            held_messages: (!state.held_messages.is_empty()).then(|| state.held_messages.len()),
+            known_users: (!state.known_users.is_empty()).then(|| state.known_users.len()),
+            blocked_known_users,
+            blocked_messages: state.blocked_messages.clone(),
+            packet_viewer_dropped: state.packet_viewer.dropped(),
+        }
+    }
+}
+
+/// What the packet statistics are sorted by, numbers go from the highest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StatisticsColumn {
+    Packet,
+    Direction,
+    Count,
+    Bytes,
+    LastMinute,
+}
+
+fn with_mirror_stats(
+    text: String,
+    stats: &MirrorStats,
+    mirror: &BeatmapMirror,
+    locale: Locale,
+) -> String {
+    let Some(counts) = stats.get(mirror) else {
+        return text;
+    };
+    let key = if counts.proxy_downloads {
+        "mirror-counts-proxy-downloads"
+    } else {
+        "mirror-counts"
+    };
+    let counts = locale.format(
+        key,
+        &[
+            ("downloads", &counts.downloads),
+            ("failed", &counts.failed_redirects),
+        ],
+    );
+    format!("{} — {}", text, counts)
+}
+
+/// [`UninstallReport`]'s `Display` in the language of the UI.
+fn uninstall_report_text(report: &UninstallReport, locale: Locale) -> String {
+    let mut lines = vec![];
+    if report.removed.is_empty() {
+        lines.push(locale.get("uninstall-nothing-removed").to_owned());
+    }
+    for removed in &report.removed {
+        lines.push(locale.format("uninstall-removed", &[("path", removed)]));
+    }
+    for failed in &report.failed {
+        lines.push(locale.format("uninstall-failed", &[("reason", failed)]));
+    }
+    if !report.manual.is_empty() {
+        lines.push(locale.get("uninstall-manual").to_owned());
+        lines.extend(report.manual.iter().map(|manual| format!("  {}", manual)));
+    }
+    lines.join("\n")
+}
+
+fn ids_to_text(ids: &[i32]) -> String {
+    ids.iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn text_to_ids(text: &str) -> Vec<i32> {
+    text.split(',')
+        .filter_map(|id| id.trim().parse().ok())
+        .collect()
 }
@@ -0,0 +1,173 @@
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+pub use supported::Tray;
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+pub use unsupported::Tray;
+
+/// Where "Check for updates" in the tray menu leads.
+pub const RELEASES_URL: &str = "https://github.com/zihadmahiuddin/osus-proxy/releases";
+
+/// What was picked in the tray menu, or double-clicking the icon for showing the window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+// Never picked where there's no tray
+#[cfg_attr(not(any(target_os = "windows", target_os = "macos")), allow(dead_code))]
+pub enum TrayAction {
+    ShowWindow,
+    CheckForUpdates,
+    Quit,
+}
+
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+mod supported {
+    use std::sync::mpsc::{self, Receiver};
+
+    use tracing::warn;
+    use tray_icon::menu::{Menu, MenuEvent, MenuId, MenuItem, PredefinedMenuItem};
+    use tray_icon::{ClickType, Icon, TrayIcon, TrayIconBuilder, TrayIconEvent};
+
+    use super::TrayAction;
+    use crate::ui::locale::Locale;
+
+    const ICON_SIZE: u32 = 32;
+
+    enum TrayEvent {
+        Menu(MenuId),
+        DoubleClick,
+    }
+
+    /// The icon in the notification area, there for as long as this is.
+    pub struct Tray {
+        _icon: TrayIcon,
+        status: MenuItem,
+        status_text: String,
+        show_window: MenuId,
+        check_for_updates: MenuId,
+        quit: MenuId,
+        events: Receiver<TrayEvent>,
+    }
+
+    impl Tray {
+        /// Has to be called from the UI thread once the window is up, macOS wants its event loop
+        /// running first.
+        pub fn new(ctx: &egui::Context, locale: Locale) -> Option<Self> {
+            match Self::build(ctx, locale) {
+                Ok(tray) => Some(tray),
+                Err(e) => {
+                    warn!("Failed to add the tray icon: {}", e);
+                    None
+                }
+            }
+        }
+
+        fn build(ctx: &egui::Context, locale: Locale) -> Result<Self, Box<dyn std::error::Error>> {
+            let show_window = MenuItem::new(locale.get("tray-show-window"), true, None);
+            let status = MenuItem::new(locale.get("tray-proxy-starting"), false, None);
+            let check_for_updates = MenuItem::new(locale.get("tray-check-for-updates"), true, None);
+            let quit = MenuItem::new(locale.get("tray-quit"), true, None);
+            let menu = Menu::new();
+            menu.append_items(&[
+                &show_window,
+                &status,
+                &check_for_updates,
+                &PredefinedMenuItem::separator(),
+                &quit,
+            ])?;
+            let icon = TrayIconBuilder::new()
+                .with_menu(Box::new(menu))
+                .with_tooltip("osus Proxy")
+                .with_icon(Icon::from_rgba(icon_rgba(), ICON_SIZE, ICON_SIZE)?)
+                .build()?;
+
+            // The handlers run on whatever thread the OS delivers the events on, and the window
+            // may be hidden and not repainting, so they wake the UI up to pick them up.
+            let (sender, events) = mpsc::channel();
+            let menu_sender = sender.clone();
+            let menu_ctx = ctx.clone();
+            MenuEvent::set_event_handler(Some(move |event: MenuEvent| {
+                let _ = menu_sender.send(TrayEvent::Menu(event.id));
+                menu_ctx.request_repaint();
+            }));
+            let icon_ctx = ctx.clone();
+            TrayIconEvent::set_event_handler(Some(move |event: TrayIconEvent| {
+                if event.click_type == ClickType::Double {
+                    let _ = sender.send(TrayEvent::DoubleClick);
+                    icon_ctx.request_repaint();
+                }
+            }));
+
+            Ok(Self {
+                _icon: icon,
+                status_text: locale.get("tray-proxy-starting").to_owned(),
+                status,
+                show_window: show_window.id().clone(),
+                check_for_updates: check_for_updates.id().clone(),
+                quit: quit.id().clone(),
+                events,
+            })
+        }
+
+        /// The greyed out line saying whether the proxy is running.
+        pub fn set_status(&mut self, text: String) {
+            if text != self.status_text {
+                self.status.set_text(&text);
+                self.status_text = text;
+            }
+        }
+
+        /// The next thing picked since the last call.
+        pub fn poll(&self) -> Option<TrayAction> {
+            loop {
+                match self.events.try_recv().ok()? {
+                    TrayEvent::DoubleClick => return Some(TrayAction::ShowWindow),
+                    TrayEvent::Menu(id) if id == self.show_window => {
+                        return Some(TrayAction::ShowWindow)
+                    }
+                    TrayEvent::Menu(id) if id == self.check_for_updates => {
+                        return Some(TrayAction::CheckForUpdates)
+                    }
+                    TrayEvent::Menu(id) if id == self.quit => return Some(TrayAction::Quit),
+                    TrayEvent::Menu(_) => {}
+                }
+            }
+        }
+    }
+
+    /// A pink dot, there's no icon file to ship.
+    fn icon_rgba() -> Vec<u8> {
+        let center = (ICON_SIZE as f32 - 1.0) / 2.0;
+        let radius = ICON_SIZE as f32 / 2.0 - 1.0;
+        let mut rgba = Vec::with_capacity((ICON_SIZE * ICON_SIZE * 4) as usize);
+        for y in 0..ICON_SIZE {
+            for x in 0..ICON_SIZE {
+                let distance = ((x as f32 - center).powi(2) + (y as f32 - center).powi(2)).sqrt();
+                // A pixel of soft edge
+                let alpha = (radius + 0.5 - distance).clamp(0.0, 1.0);
+                rgba.extend_from_slice(&[0xff, 0x66, 0xaa, (alpha * 255.0) as u8]);
+            }
+        }
+        rgba
+    }
+}
+
+/// Linux and the like would need a GTK event loop of their own for a tray icon. There's none there,
+/// and the option to go to the tray isn't shown.
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+mod unsupported {
+    use super::TrayAction;
+    use crate::ui::locale::Locale;
+
+    pub enum Tray {}
+
+    impl Tray {
+        pub fn new(_ctx: &egui::Context, _locale: Locale) -> Option<Self> {
+            None
+        }
+
+        pub fn set_status(&mut self, _text: String) {
+            match *self {}
+        }
+
+        pub fn poll(&self) -> Option<TrayAction> {
+            match *self {}
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::Path;
+
+use tracing::{info, warn};
+
+#[cfg(windows)]
+use crate::autostart;
+use crate::hosts::{self, HOSTS_PATH};
+use crate::janitor::DATA_DIRECTORY;
+use crate::osus_proxy::capture::CAPTURES_DIRECTORY;
+use crate::osus_proxy::mirror_stats::MIRROR_STATS_PATH;
+use crate::osus_proxy::packet_log::PACKET_LOG_PATH;
+use crate::osus_proxy::SOURCE_DOMAIN;
+use crate::preferences::PREFERENCES_PATH;
+use crate::ui::PROTOCOL_COVERAGE_EXPORT_PATH;
+use crate::LOG_FILE_NAME;
+
+#[derive(Debug, Default)]
+pub struct UninstallReport {
+    pub removed: Vec<String>,
+    pub failed: Vec<String>,
+    /// Things we didn't set up ourselves, so we leave them to the user.
+    pub manual: Vec<String>,
+}
+
+impl Display for UninstallReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.removed.is_empty() {
+            writeln!(f, "Nothing to remove")?;
+        }
+        for removed in &self.removed {
+            writeln!(f, "Removed {}", removed)?;
+        }
+        for failed in &self.failed {
+            writeln!(f, "Failed: {}", failed)?;
+        }
+        if !self.manual.is_empty() {
+            writeln!(f, "Needs manual attention:")?;
+            for manual in &self.manual {
+                writeln!(f, "  {}", manual)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Removes everything we left behind. Every step runs even if an earlier one failed.
+pub fn run(keep_config: bool) -> UninstallReport {
+    let mut report = UninstallReport::default();
+
+    let mut paths = vec![
+        Path::new(DATA_DIRECTORY)
+            .join(LOG_FILE_NAME)
+            .display()
+            .to_string(),
+        PROTOCOL_COVERAGE_EXPORT_PATH.to_owned(),
+        MIRROR_STATS_PATH.to_owned(),
+        PACKET_LOG_PATH.to_owned(),
+    ];
+    if !keep_config {
+        paths.push(PREFERENCES_PATH.to_owned());
+        paths.push(format!("{}.tmp", PREFERENCES_PATH));
+        paths.push(format!("{}.invalid", PREFERENCES_PATH));
+    }
+    for path in &paths {
+        remove_file(Path::new(path), &mut report);
+    }
+    remove_directory(
+        &Path::new(DATA_DIRECTORY).join(CAPTURES_DIRECTORY),
+        &mut report,
+    );
+
+    match hosts::lines_mentioning(SOURCE_DOMAIN) {
+        Ok(entries) if entries.is_empty() => {}
+        Ok(entries) => report.manual.push(format!(
+            "{} still points the proxied hosts at the proxy, remove these lines as administrator: {}",
+            HOSTS_PATH,
+            entries.join(" | ")
+        )),
+        Err(e) => report.failed.push(format!("reading {}: {}", HOSTS_PATH, e)),
+    }
+
+    #[cfg(windows)]
+    match autostart::registration()
+        .and_then(|registration| registration.map(|_| autostart::disable()).transpose())
+    {
+        Ok(Some(())) => report.removed.push(format!(
+            r"HKEY_CURRENT_USER\{}\{}",
+            autostart::RUN_KEY,
+            autostart::VALUE_NAME
+        )),
+        Ok(None) => {}
+        Err(e) => report
+            .failed
+            .push(format!("removing the startup entry: {}", e)),
+    }
+
+    // The certificate is embedded in the binary and only ever trusted by hand
+    report.manual.push(
+        "If you trusted the osus-proxy certificate, remove it from your trusted root certificates"
+            .to_owned(),
+    );
+
+    info!("Uninstall finished: {:?}", report);
+    report
+}
+
+/// With everything in it, only for directories that are ours alone.
+fn remove_directory(path: &Path, report: &mut UninstallReport) {
+    match std::fs::remove_dir_all(path) {
+        Ok(()) => report.removed.push(path.display().to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            warn!("Failed to remove {}: {}", path.display(), e);
+            report
+                .failed
+                .push(format!("removing {}: {}", path.display(), e));
+        }
+    }
+}
+
+fn remove_file(path: &Path, report: &mut UninstallReport) {
+    match std::fs::remove_file(path) {
+        Ok(()) => report.removed.push(path.display().to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            warn!("Failed to remove {}: {}", path.display(), e);
+            report
+                .failed
+                .push(format!("removing {}: {}", path.display(), e));
+        }
+    }
+}